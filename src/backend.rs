@@ -0,0 +1,464 @@
+//! The external programs that can download torrents, behind the `Downloader` trait.
+
+use crate::download::{run_download, DownloadError, DownloadOptions, DownloadOutcome, DownloadTask};
+use crate::files::{select_files, torrent_files};
+use crate::log::{emit, Level};
+use crate::proxy::{ProxyKind, Socks5Proxy};
+use crate::source::TorrentSource;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How far a download has progressed, as reported by the downloader's status line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// The share of the torrent downloaded so far, from 0 to 100.
+    pub percent: f64,
+    /// The current download rate as printed by the downloader, e.g. `512 kB/s`.
+    pub rate: Option<String>,
+    /// How many bytes have been downloaded so far, if the downloader prints it.
+    pub downloaded: Option<u64>,
+    /// How many peers the downloader is connected to, if it prints it.
+    pub peers: Option<u32>,
+}
+
+impl Progress {
+    /// The current download rate in bytes per second, if `rate` can be parsed.
+    pub fn rate_bytes_per_sec(&self) -> Option<f64> {
+        let rate = self.rate.as_deref()?.trim().strip_suffix("/s")?;
+        parse_size(rate)
+    }
+}
+
+/// Parses a size printed by a downloader, such as `512 kB`, `1.2MiB`, or `0 B`, into bytes.
+/// Decimal units (`kB`, `MB`) are powers of 1000 and binary units (`KiB`, `MiB`) powers of 1024.
+pub(crate) fn parse_size(size: &str) -> Option<f64> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1.0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some(number * multiplier)
+}
+
+/// An external program that downloads a torrent through a proxy.
+/// Implementations describe how to invoke the program and how to read its output;
+/// running it, echoing its output, and seeding control are shared by `download`.
+pub trait Downloader: Send + Sync {
+    /// The name of the program, as looked up on `PATH` unless `DownloadOptions::program` overrides it.
+    fn program(&self) -> &'static str;
+
+    /// The Debian/Ubuntu package that provides the program, for install hints.
+    fn package(&self) -> &'static str;
+
+    /// Builds the command that downloads `source` through `proxy`, or directly without one, or an error
+    /// if the program cannot honor the proxy or options.
+    fn command(&self, source: &TorrentSource, options: &DownloadOptions, proxy: Option<&Socks5Proxy>) -> Result<Command, DownloadError>;
+
+    /// Parses the upload ratio out of a line of the program's output, or `None` while the torrent is not seeding.
+    fn seeding_ratio(&self, line: &str) -> Option<f64>;
+
+    /// Parses the download progress out of a line of the program's output, or `None` if it is not a progress line.
+    fn progress(&self, line: &str) -> Option<Progress>;
+
+    /// Downloads `source` into `options.torrent_dir(source)` through `proxy`, or directly without one.
+    /// The slot in `task` is released as soon as the torrent starts seeding, and its bar follows the progress.
+    fn download<'a>(
+        &'a self,
+        source: &'a TorrentSource,
+        options: &'a DownloadOptions,
+        proxy: Option<&'a Socks5Proxy>,
+        task: &'a mut DownloadTask,
+    ) -> Pin<Box<dyn Future<Output = Result<DownloadOutcome, DownloadError>> + Send + 'a>> {
+        Box::pin(run_download(self, source, options, proxy, task))
+    }
+}
+
+/// Which `Downloader` is used, selected with `--backend`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// `transmission-cli`.
+    #[default]
+    Transmission,
+    /// `aria2c`. It only supports HTTP proxies.
+    Aria2,
+}
+
+impl Backend {
+    /// The downloader for this backend.
+    pub fn downloader(self) -> &'static dyn Downloader {
+        match self {
+            Backend::Transmission => &Transmission,
+            Backend::Aria2 => &Aria2,
+        }
+    }
+}
+
+/// Downloads with `transmission-cli`, passing the proxy through the `ALL_PROXY` environment variable.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transmission;
+
+impl Downloader for Transmission {
+    fn program(&self) -> &'static str {
+        "transmission-cli"
+    }
+
+    fn package(&self) -> &'static str {
+        "transmission-cli"
+    }
+
+    fn command(&self, source: &TorrentSource, options: &DownloadOptions, proxy: Option<&Socks5Proxy>) -> Result<Command, DownloadError> {
+        if !options.wanted_files(source).is_empty() {
+            return Err(DownloadError::FileSelection("transmission-cli cannot leave files of a torrent out; select files with --backend aria2".to_string()));
+        }
+        let mut command = Command::new(options.executable(self));
+        command.arg(source.as_transmission_arg()).arg("-w").arg(options.torrent_dir(source));
+        if options.incomplete_dir.is_none() {
+            command.arg("--no-incomplete");  // Ensures that incomplete downloads are not kept.
+        }
+        command.arg("--debug");  // Enables debug logging for transmission-cli.
+        if let Some(limit) = options.download_limit {
+            command.arg("-d").arg(limit.to_string());  // Caps the download speed in KB/s.
+        }
+        if let Some(limit) = options.upload_limit {
+            command.arg("-u").arg(limit.to_string());  // Caps the upload speed in KB/s.
+        }
+        let protocols = &options.peer_protocols;
+        let (dht, utp, pex) = (protocols.dht(proxy.is_some()), protocols.utp(proxy.is_some()), protocols.pex());
+        let incomplete_dir = options.incomplete_dir.as_deref();
+        if !(dht && utp && pex) || incomplete_dir.is_some() {
+            // transmission-cli has no options for these, only settings in the settings.json of its config directory.
+            command.arg("-g").arg(transmission_config_dir(dht, utp, pex, incomplete_dir).map_err(DownloadError::Io)?);
+        }
+        if let Some(proxy) = proxy {
+            command.env("ALL_PROXY", proxy.url(false));  // Sets the proxy environment variable for transmission-cli.
+        }
+        Ok(command)
+    }
+
+    /// Parses status lines such as `Seeding, uploading to 2 of 5 peer(s), 12 kB/s [0.42]`.
+    fn seeding_ratio(&self, line: &str) -> Option<f64> {
+        if !line.trim_start().starts_with("Seeding") {
+            return None;
+        }
+        let ratio = line.rsplit_once('[').and_then(|(_, rest)| rest.split_once(']')).map(|(ratio, _)| ratio.trim());
+        Some(match ratio {
+            Some("Inf") => f64::INFINITY,
+            Some(ratio) => ratio.parse().unwrap_or(0.0),
+            None => 0.0,
+        })
+    }
+
+    /// Parses status lines such as `Progress: 45.3%, dl from 3 of 10 peers (512 kB/s), ul to 1 (12 kB/s) [0.01]`.
+    fn progress(&self, line: &str) -> Option<Progress> {
+        let rest = line.trim_start().strip_prefix("Progress:")?;
+        let (percent, rest) = rest.split_once('%')?;
+        let rate = rest.split_once('(').and_then(|(_, rest)| rest.split_once(')')).map(|(rate, _)| rate.trim().to_string());
+        // The peers are counted in "dl from 3 of 10 peers": 10 are connected, 3 of which are sending data.
+        let peers = rest.split_once(" of ").and_then(|(_, rest)| rest.split_whitespace().next()).and_then(|peers| peers.parse().ok());
+        Some(Progress { percent: percent.trim().parse().ok()?, rate, downloaded: None, peers })
+    }
+}
+
+/// Serializes writing the `transmission_config_dir` settings, so that parallel downloads never read a partial file.
+static CONFIG_DIRS: Mutex<()> = Mutex::new(());
+
+/// Returns a transmission-cli config directory whose settings.json enables DHT and local peer discovery, uTP, and
+/// peer exchange only as asked, and keeps the partial files in `incomplete_dir`, if given. Its resume files let a
+/// retry pick up where the last attempt stopped, like the default config directory does. The directory is kept
+/// in `incomplete_dir`, so that the next run finds the resume files of the partial files again, or otherwise in
+/// the temporary directory for the rest of the run.
+fn transmission_config_dir(dht: bool, utp: bool, pex: bool, incomplete_dir: Option<&Path>) -> io::Result<PathBuf> {
+    let protocols = format!("{}{}{}", dht as u8, utp as u8, pex as u8);
+    let mut settings = json!({"dht-enabled": dht, "lpd-enabled": dht, "utp-enabled": utp, "pex-enabled": pex});
+    let dir = match incomplete_dir {
+        Some(incomplete_dir) => {
+            let incomplete_dir = std::path::absolute(incomplete_dir)?;
+            settings["incomplete-dir"] = json!(incomplete_dir);
+            settings["incomplete-dir-enabled"] = json!(true);
+            incomplete_dir.join(format!(".batch_tor-transmission-{}", protocols))
+        }
+        None => std::env::temp_dir().join(format!("batch_tor-transmission-{}-{}", std::process::id(), protocols)),
+    };
+    let path = dir.join("settings.json");
+    let contents = format!("{:#}\n", settings);
+    let _written = CONFIG_DIRS.lock().expect("Config directory lock poisoned");
+    if std::fs::read_to_string(&path).ok().as_deref() != Some(contents.as_str()) {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&path, contents)?;
+    }
+    Ok(dir)
+}
+
+/// Downloads with `aria2c`. aria2c stops seeding by itself, so the seeding targets are passed as options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aria2;
+
+impl Downloader for Aria2 {
+    fn program(&self) -> &'static str {
+        "aria2c"
+    }
+
+    fn package(&self) -> &'static str {
+        "aria2"
+    }
+
+    fn command(&self, source: &TorrentSource, options: &DownloadOptions, proxy: Option<&Socks5Proxy>) -> Result<Command, DownloadError> {
+        let mut command = aria2_command(options, proxy)?;
+        let wanted = options.wanted_files(source);
+        if wanted.is_empty() {
+            command.arg(source.as_transmission_arg());
+        } else {
+            // The files are numbered in the `.torrent` file, which `download` fetched first for magnets and URLs.
+            let torrent = match source {
+                TorrentSource::File(path) => path.clone(),
+                _ => metadata_path(source),
+            };
+            let data = std::fs::read(&torrent).map_err(DownloadError::Io)?;
+            let files = torrent_files(&data).ok_or_else(|| DownloadError::FileSelection(format!("could not read the file list of {}", source)))?;
+            let selected = select_files(&files, wanted);
+            if selected.is_empty() {
+                return Err(DownloadError::FileSelection(format!("none of the {} files of {} matches the file filter", files.len(), source)));
+            }
+            let names: Vec<&str> = selected.iter().map(|(_, name)| *name).collect();
+            emit(
+                Level::Info,
+                "files_selected",
+                format!("[{}] Downloading {} of {} files: {}", source, selected.len(), files.len(), names.join(", ")),
+                json!({"magnet": source.to_string(), "files": names, "total": files.len()}),
+            );
+            let indices: Vec<String> = selected.iter().map(|(index, _)| index.to_string()).collect();
+            command.arg(torrent).arg(format!("--select-file={}", indices.join(",")));
+        }
+        command.arg(format!("--dir={}", options.torrent_dir(source).display())).arg("--console-log-level=notice");
+        if let Some(limit) = options.download_limit {
+            command.arg(format!("--max-download-limit={}K", limit));
+        }
+        if let Some(limit) = options.upload_limit {
+            command.arg(format!("--max-upload-limit={}K", limit));
+        }
+        if options.incomplete_dir.is_some() {
+            command.arg("--continue=true");  // aria2c keeps partial files next to the finished ones, with a `.aria2` control file.
+        }
+        match (options.seed_ratio, options.seed_time) {
+            (None, None) => {
+                command.arg("--seed-time=0");  // Stops as soon as the download completes.
+            }
+            (ratio, time) => {
+                if let Some(ratio) = ratio {
+                    command.arg(format!("--seed-ratio={}", ratio));
+                }
+                if let Some(time) = time {
+                    command.arg(format!("--seed-time={}", time.as_secs_f64() / 60.0));  // aria2c takes minutes.
+                }
+            }
+        }
+        Ok(command)
+    }
+
+    /// Downloads `source` like `run_download`, but with files to select, first fetches the `.torrent` file of a
+    /// magnet link or URL, whose file list tells how aria2c numbers the files. The file is removed afterwards.
+    fn download<'a>(
+        &'a self,
+        source: &'a TorrentSource,
+        options: &'a DownloadOptions,
+        proxy: Option<&'a Socks5Proxy>,
+        task: &'a mut DownloadTask,
+    ) -> Pin<Box<dyn Future<Output = Result<DownloadOutcome, DownloadError>> + Send + 'a>> {
+        Box::pin(async move {
+            if options.wanted_files(source).is_empty() || matches!(source, TorrentSource::File(_)) {
+                return run_download(self, source, options, proxy, task).await;
+            }
+            let path = metadata_path(source);
+            let result = match fetch_metadata(source, options, proxy).await {
+                Ok(()) => run_download(self, source, options, proxy, task).await,
+                Err(e) => Err(e),
+            };
+            let _ = std::fs::remove_file(&path);
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::remove_dir(dir);  // Fails, as it should, while other torrents still use it.
+            }
+            result
+        })
+    }
+
+    /// Parses console readouts such as `[#2089b0 SEED(0.42) CN:2 SD:0 UL:12KiB(1.0MiB)]`.
+    fn seeding_ratio(&self, line: &str) -> Option<f64> {
+        let (_, rest) = line.split_once("SEED(")?;
+        let ratio = rest.split_once(')').map(|(ratio, _)| ratio.trim());
+        Some(ratio.and_then(|ratio| ratio.parse().ok()).unwrap_or(0.0))
+    }
+
+    /// Parses console readouts such as `[#2089b0 12MiB/100MiB(12%) CN:5 SD:3 DL:1.2MiB ETA:1m]`.
+    fn progress(&self, line: &str) -> Option<Progress> {
+        let readout = line.trim().strip_prefix("[#")?;
+        let (sizes, rest) = readout.split_once('(')?;
+        let downloaded = sizes.split_whitespace().last().and_then(|sizes| sizes.split_once('/')).and_then(|(done, _)| parse_size(done));
+        let (percent, rest) = rest.split_once("%)")?;
+        let rate = rest
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("DL:"))
+            .map(|rate| format!("{}/s", rate.trim_end_matches(']')));
+        let peers = rest.split_whitespace().find_map(|field| field.strip_prefix("CN:")).and_then(|peers| peers.parse().ok());
+        Some(Progress { percent: percent.parse().ok()?, rate, downloaded: downloaded.map(|bytes| bytes as u64), peers })
+    }
+}
+
+/// How long aria2c may take to fetch the `.torrent` file of a magnet link or URL whose files are selected.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Returns an aria2c command with the options shared by downloads and metadata fetches: the proxy, the
+/// peer protocols, and plain output.
+fn aria2_command(options: &DownloadOptions, proxy: Option<&Socks5Proxy>) -> Result<Command, DownloadError> {
+    let mut command = Command::new(options.executable(&Aria2));
+    if let Some(proxy) = proxy {
+        // aria2c's `--all-proxy` only speaks HTTP, so SOCKS proxies cannot be used with it.
+        if proxy.kind != ProxyKind::Http {
+            return Err(DownloadError::UnsupportedProxy(format!("aria2c only supports HTTP proxies, not {} proxy {}", proxy.kind.scheme(false), proxy)));
+        }
+        command.arg(format!("--all-proxy={}", proxy.url(false)));
+    }
+    command.arg("--enable-color=false");
+    // aria2c does not speak uTP; its peer connections always use TCP.
+    if !options.peer_protocols.dht(proxy.is_some()) {
+        command.arg("--enable-dht=false").arg("--enable-dht6=false").arg("--bt-enable-lpd=false");
+    }
+    if !options.peer_protocols.pex() {
+        command.arg("--enable-peer-exchange=false");
+    }
+    Ok(command)
+}
+
+/// Where the `.torrent` file of a magnet link or URL is kept while it downloads: in the temporary directory, named after the info hash the way aria2c names the metadata it saves, or after a hash of the URL.
+fn metadata_path(source: &TorrentSource) -> PathBuf {
+    let name = match source {
+        TorrentSource::Magnet(info) => format!("{}.torrent", info.info_hash),
+        other => {
+            let mut hasher = DefaultHasher::new();
+            other.to_string().hash(&mut hasher);
+            format!("{:016x}.torrent", hasher.finish())
+        }
+    };
+    std::env::temp_dir().join(format!("batch_tor-metadata-{}", std::process::id())).join(name)
+}
+
+/// Fetches the `.torrent` file of a magnet link from peers, or of a URL from the web, through `proxy`, into
+/// `metadata_path`, without downloading the content.
+async fn fetch_metadata(source: &TorrentSource, options: &DownloadOptions, proxy: Option<&Socks5Proxy>) -> Result<(), DownloadError> {
+    let path = metadata_path(source);
+    let dir = path.parent().expect("Metadata path without a directory");
+    std::fs::create_dir_all(dir).map_err(DownloadError::Io)?;
+    let mut command = aria2_command(options, proxy)?;
+    command.arg(format!("--dir={}", dir.display())).arg("--console-log-level=error").arg("--summary-interval=0");
+    match source {
+        TorrentSource::Magnet(_) => command.arg("--bt-metadata-only=true").arg("--bt-save-metadata=true"),
+        _ => command.arg("--follow-torrent=false").arg(format!("--out={}", path.file_name().expect("Metadata path without a name").to_string_lossy())),
+    };
+    command.arg(source.as_transmission_arg()).stdout(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+    emit(Level::Info, "metadata_fetch", format!("[{}] Fetching the file list", source), json!({"magnet": source.to_string()}));
+    let status = tokio::time::timeout(METADATA_TIMEOUT, command.status()).await.map_err(|_| DownloadError::TimedOut(METADATA_TIMEOUT))?.map_err(DownloadError::Io)?;
+    if !status.success() {
+        return Err(DownloadError::Exit(status));
+    }
+    if !path.is_file() {
+        return Err(DownloadError::FileSelection(format!("aria2c did not save the file list of {}", source)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::PeerProtocols;
+
+    #[test]
+    fn sizes_use_decimal_and_binary_units() {
+        assert_eq!(parse_size("512 kB"), Some(512_000.0));
+        assert_eq!(parse_size("1.5MiB"), Some(1.5 * 1024.0 * 1024.0));
+        assert_eq!(parse_size("0 B"), Some(0.0));
+        assert_eq!(parse_size("fast"), None);
+    }
+
+    #[test]
+    fn progress_lines_carry_rates_and_sizes() {
+        let progress = Transmission.progress("Progress: 45.3%, dl from 3 of 10 peers (512 kB/s), ul to 1 (12 kB/s) [0.01]").unwrap();
+        assert_eq!((progress.percent, progress.downloaded, progress.rate_bytes_per_sec(), progress.peers), (45.3, None, Some(512_000.0), Some(10)));
+        let progress = Aria2.progress("[#2089b0 12MiB/100MiB(12%) CN:5 SD:3 DL:1.0MiB ETA:1m]").unwrap();
+        assert_eq!((progress.percent, progress.downloaded, progress.rate_bytes_per_sec()), (12.0, Some(12 * 1024 * 1024), Some(1024.0 * 1024.0)));
+        assert_eq!(progress.peers, Some(5));
+    }
+
+    #[test]
+    fn udp_protocols_are_off_through_a_proxy() {
+        let source = TorrentSource::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567").unwrap().unwrap();
+        let proxy = Socks5Proxy::parse("127.0.0.1:1080").unwrap();
+        let args = |options: &DownloadOptions, proxy: Option<&Socks5Proxy>| -> Vec<String> {
+            let command = Transmission.command(&source, options, proxy).unwrap();
+            command.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+        };
+        let options = DownloadOptions::default();
+        assert!(!args(&options, None).contains(&"-g".to_string()));
+        let proxied = args(&options, Some(&proxy));
+        let dir = &proxied[proxied.iter().position(|arg| arg == "-g").unwrap() + 1];
+        let settings = std::fs::read_to_string(Path::new(dir).join("settings.json"));
+        let _ = std::fs::remove_dir_all(dir);
+        let settings: serde_json::Value = serde_json::from_str(&settings.unwrap()).unwrap();
+        assert_eq!(settings, json!({"dht-enabled": false, "lpd-enabled": false, "utp-enabled": false, "pex-enabled": true}));
+
+        let allowed = DownloadOptions { peer_protocols: PeerProtocols { allow_udp: true, ..Default::default() }, ..Default::default() };
+        assert!(!args(&allowed, Some(&proxy)).contains(&"-g".to_string()));
+        let no_pex = PeerProtocols { disable_pex: true, ..Default::default() };
+        assert!((no_pex.dht(false), no_pex.utp(false), no_pex.pex()) == (true, true, false));
+    }
+
+    #[test]
+    fn kept_partial_files_go_to_the_incomplete_dir() {
+        let source = TorrentSource::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567").unwrap().unwrap();
+        let incomplete = std::env::temp_dir().join(format!("batch_tor-incomplete-{}", std::process::id()));
+        let options = DownloadOptions { incomplete_dir: Some(incomplete.clone()), ..Default::default() };
+        let command = Transmission.command(&source, &options, None).unwrap();
+        let args: Vec<String> = command.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        let settings = std::fs::read_to_string(incomplete.join(".batch_tor-transmission-111/settings.json"));
+        let _ = std::fs::remove_dir_all(&incomplete);
+        assert!(!args.contains(&"--no-incomplete".to_string()));
+        assert_eq!(args.iter().position(|arg| arg == "-g").map(|index| &args[index + 1]), Some(&incomplete.join(".batch_tor-transmission-111").display().to_string()));
+        let settings: serde_json::Value = serde_json::from_str(&settings.unwrap()).unwrap();
+        assert_eq!((&settings["incomplete-dir"], &settings["incomplete-dir-enabled"]), (&json!(incomplete), &json!(true)));
+    }
+
+    #[test]
+    fn aria2_downloads_only_the_selected_files() {
+        let torrent = std::env::temp_dir().join(format!("batch_tor-select-{}.torrent", std::process::id()));
+        std::fs::write(&torrent, b"d4:infod5:filesld6:lengthi100e4:pathl5:a.mkveed6:lengthi23e4:pathl5:b.nfoeee4:name1:dee").unwrap();
+        let source = TorrentSource::File(torrent.clone());
+        let options = DownloadOptions { file_filter: vec![glob::Pattern::new("*.mkv").unwrap()], ..Default::default() };
+        let command = Aria2.command(&source, &options, None);
+        let nothing = Aria2.command(&source, &DownloadOptions { file_filter: vec![glob::Pattern::new("*.iso").unwrap()], ..Default::default() }, None);
+        let _ = std::fs::remove_file(&torrent);
+        let args: Vec<String> = command.unwrap().as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert!(args.contains(&torrent.display().to_string()) && args.contains(&"--select-file=1".to_string()));
+        assert!(matches!(nothing, Err(DownloadError::FileSelection(_))));
+        assert!(matches!(Transmission.command(&source, &options, None), Err(DownloadError::FileSelection(_))));
+    }
+}
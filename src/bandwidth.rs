@@ -0,0 +1,113 @@
+//! `--total-download-limit`: one download speed limit shared by all the downloads of a batch.
+//!
+//! The downloaders run as separate processes that each only enforce a limit of their own, so the total is divided
+//! into equal shares, one per active download, passed as its `--download-limit`. When downloads start or finish the
+//! shares change, and every running downloader whose share changed is restarted with its new one, resuming from the
+//! data it already has. This is an approximation: until the restarts, which wait for `RESHARE_DELAY` so that downloads
+//! starting or finishing together cause one restart, the sum of the limits can exceed the total for a moment; a
+//! download that cannot use its whole share leaves the rest unused; and every restart costs the time the downloader
+//! takes to verify its data and find its peers again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long the number of active downloads must stay the same before the downloads are restarted with their new shares.
+pub const RESHARE_DELAY: Duration = Duration::from_secs(15);
+
+/// The shares of a total download speed limit among the active downloads.
+#[derive(Debug)]
+pub struct BandwidthShare {
+    total: u32,  // In KB/s.
+    active: watch::Sender<usize>,
+}
+
+impl BandwidthShare {
+    /// Divides `total`, in KB/s, among the downloads that join it.
+    pub fn new(total: u32) -> BandwidthShare {
+        BandwidthShare { total, active: watch::Sender::new(0) }
+    }
+
+    /// The share of each of `active` downloads, in KB/s: an equal part of the total, at least 1 KB/s, and at most `cap`,
+    /// the limit of each download, if any.
+    pub fn share(&self, active: usize, cap: Option<u32>) -> u32 {
+        let part = self.total / u32::try_from(active.max(1)).unwrap_or(u32::MAX);
+        part.max(1).min(cap.unwrap_or(u32::MAX))
+    }
+
+    /// Counts a download as active until the returned guard leaves or is dropped; `cap` is its own limit, if any.
+    pub fn join(self: &Arc<Self>, cap: Option<u32>) -> ShareGuard {
+        self.active.send_modify(|active| *active += 1);
+        let limit = self.share(*self.active.borrow(), cap);
+        ShareGuard { share: Arc::clone(self), cap, limit, left: AtomicBool::new(false) }
+    }
+}
+
+/// An active download of a `BandwidthShare`.
+#[derive(Debug)]
+pub struct ShareGuard {
+    share: Arc<BandwidthShare>,
+    cap: Option<u32>,
+    limit: u32,  // The share when the download joined, which its downloader runs with.
+    left: AtomicBool,
+}
+
+impl ShareGuard {
+    /// The download speed limit, in KB/s, of the download: its share when it joined.
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn current(&self) -> u32 {
+        self.share.share(*self.share.active.borrow(), self.cap)
+    }
+
+    /// Stops counting the download as active, e.g. once it seeds, so the other downloads get its share.
+    pub fn leave(&self) {
+        if !self.left.swap(true, Ordering::SeqCst) {
+            self.share.active.send_modify(|active| *active -= 1);
+        }
+    }
+
+    /// Completes with the new share of the download once it differs from `limit` and the number of active downloads
+    /// has not changed for `RESHARE_DELAY`. Never completes once the download left.
+    pub async fn reshared(&self) -> u32 {
+        let mut active = self.share.active.subscribe();
+        loop {
+            if !self.left.load(Ordering::SeqCst) && self.current() != self.limit {
+                while matches!(tokio::time::timeout(RESHARE_DELAY, active.changed()).await, Ok(Ok(()))) {}
+                if !self.left.load(Ordering::SeqCst) && self.current() != self.limit {
+                    return self.current();
+                }
+            }
+            let _ = active.changed().await;  // The sender lives as long as `self.share`, so this never fails.
+        }
+    }
+}
+
+impl Drop for ShareGuard {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_follow_the_active_downloads() {
+        let share = Arc::new(BandwidthShare::new(1000));
+        let first = share.join(None);
+        assert_eq!((first.limit(), share.join(Some(300)).limit()), (1000, 300));  // The second one left right away.
+        let second = share.join(None);
+        assert_eq!(second.limit(), 500);
+        assert_eq!((first.limit(), first.current()), (1000, 500));
+        second.leave();
+        assert_eq!(share.join(None).limit(), 500);
+        drop(second);  // Leaving twice counts once.
+        assert_eq!((share.share(3, None), share.share(2000, None)), (333, 1));
+        assert_eq!(*share.active.borrow(), 1);
+    }
+}
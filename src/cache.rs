@@ -0,0 +1,278 @@
+//! The on-disk cache of proxies that passed the check in previous runs.
+
+use crate::log::{emit, Level};
+use crate::proxy::{ProxyKind, ProxyResult, Socks5Proxy};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How `--proxy-cache-policy` updates the cache with the proxies that passed the check in a run.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    /// Merge them into the cached proxies, keeping those that were not checked again unless they have expired.
+    Append,
+    /// Replace the cached proxies with them.
+    #[default]
+    Replace,
+}
+
+/// The on-disk format of the proxy cache written by `--proxy-cache`.
+/// Unknown fields are ignored and missing ones fall back to defaults, so newer versions
+/// of the tool can add fields without breaking older cache files.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProxyCache {
+    #[serde(default = "ProxyCache::format_version")]
+    version: u32,
+    #[serde(default)]
+    proxies: Vec<CachedProxy>,
+}
+
+/// A proxy that passed the check in a previous run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedProxy {
+    #[serde(default = "CachedProxy::default_kind")]
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// When the proxy last passed the check, in seconds since the Unix epoch.
+    validated_at: u64,
+    #[serde(default)]
+    latency_ms: u64,
+    /// The average download speed of the torrents downloaded through the proxy, in bytes per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_speed_bps: Option<u64>,
+    /// How many torrents `download_speed_bps` averages.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    downloads: u32,
+}
+
+fn is_zero(count: &u32) -> bool {
+    *count == 0
+}
+
+impl ProxyCache {
+    fn format_version() -> u32 {
+        1
+    }
+}
+
+impl CachedProxy {
+    fn default_kind() -> ProxyKind {
+        ProxyKind::Socks5
+    }
+
+    fn from_result(result: &ProxyResult, validated_at: u64) -> CachedProxy {
+        let (username, password) = match &result.proxy.auth {
+            Some((user, pass)) => (Some(user.clone()), Some(pass.clone())),
+            None => (None, None),
+        };
+        CachedProxy {
+            kind: result.proxy.kind,
+            host: result.proxy.host.clone(),
+            port: result.proxy.port,
+            username,
+            password,
+            validated_at,
+            latency_ms: result.latency.as_millis() as u64,
+            download_speed_bps: None,
+            downloads: 0,
+        }
+    }
+
+    /// Takes over the download history of the entry of `cached` with the same address, if any.
+    fn with_history_of(mut self, cached: &[CachedProxy]) -> CachedProxy {
+        if let Some(previous) = cached.iter().find(|previous| previous.same_address(&self)) {
+            self.download_speed_bps = previous.download_speed_bps;
+            self.downloads = previous.downloads;
+        }
+        self
+    }
+
+    /// Folds the average speed of `count` more downloads, `total_speed` in all, into the running average.
+    fn record_downloads(&mut self, total_speed: f64, count: u32) {
+        let previous = self.download_speed_bps.unwrap_or_default() as f64 * f64::from(self.downloads);
+        self.downloads += count;
+        self.download_speed_bps = Some(((previous + total_speed) / f64::from(self.downloads)) as u64);
+    }
+
+    /// Whether `other` is the same proxy; entries are told apart by host and port alone.
+    fn same_address(&self, other: &CachedProxy) -> bool {
+        self.host == other.host && self.port == other.port
+    }
+
+    fn proxy(&self) -> Socks5Proxy {
+        Socks5Proxy {
+            kind: self.kind,
+            host: self.host.clone(),
+            port: self.port,
+            auth: self.username.clone().map(|user| (user, self.password.clone().unwrap_or_default())),
+            chain: Vec::new(),
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+/// Loads the proxies from the cache file that were validated less than `ttl` ago.
+/// A missing cache file yields no proxies; an unreadable one is reported and ignored.
+///
+/// # Arguments
+///
+/// * `path` - The path to the JSON cache file.
+/// * `ttl` - How long a validation stays fresh.
+///
+/// # Returns
+///
+/// The fresh cached proxies, in the order they were stored.
+pub fn load_proxy_cache(path: &str, ttl: Duration) -> Vec<Socks5Proxy> {
+    let cache = match read_proxy_cache(path) {
+        Ok(cache) => cache,
+        Err(message) => {
+            emit(Level::Warn, "proxy_cache_error", message.clone(), json!({"file": path, "error": message}));
+            return Vec::new();
+        }
+    };
+
+    let now = unix_now();
+    cache
+        .proxies
+        .iter()
+        .filter(|entry| now.saturating_sub(entry.validated_at) < ttl.as_secs())
+        .map(CachedProxy::proxy)
+        .collect()
+}
+
+/// Reads the cache file; a missing one is an empty cache. The error is a message for the user.
+fn read_proxy_cache(path: &str) -> Result<ProxyCache, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ProxyCache::default()),
+        Err(e) => return Err(format!("Could not read proxy cache {}: {}", path, e)),
+    };
+    serde_json::from_str(&contents).map_err(|e| format!("Ignoring invalid proxy cache {}: {}", path, e))
+}
+
+/// Merges the proxies validated in this run into `cached`: an entry with the host and port of one of them is
+/// replaced in place, with its new timestamp, and the others are appended. Entries older than `ttl` are dropped.
+fn merge_proxies(cached: Vec<CachedProxy>, fresh: Vec<CachedProxy>, now: u64, ttl: Duration) -> Vec<CachedProxy> {
+    let mut merged: Vec<CachedProxy> = cached.into_iter().filter(|entry| now.saturating_sub(entry.validated_at) < ttl.as_secs()).collect();
+    for entry in fresh {
+        match merged.iter_mut().find(|cached| cached.same_address(&entry)) {
+            Some(cached) => *cached = entry,
+            None => merged.push(entry),
+        }
+    }
+    merged
+}
+
+/// Writes the proxies that passed the check in this run to the cache file, replacing its contents, or with
+/// `CachePolicy::Append`, merging them into the proxies it holds that are fresher than `ttl`; see `merge_proxies`.
+///
+/// # Arguments
+///
+/// * `path` - The path to the JSON cache file.
+/// * `results` - The proxies that passed the check.
+/// * `policy` - Whether the cached proxies that were not checked again are kept.
+/// * `ttl` - How long a validation stays fresh.
+pub fn save_proxy_cache(path: &str, results: &[ProxyResult], policy: CachePolicy, ttl: Duration) -> io::Result<()> {
+    let now = unix_now();
+    // An unreadable cache was reported when it was loaded, and is overwritten.
+    let cached = read_proxy_cache(path).unwrap_or_default().proxies;
+    let fresh = results.iter().map(|result| CachedProxy::from_result(result, now).with_history_of(&cached)).collect();
+    let proxies = match policy {
+        CachePolicy::Append => merge_proxies(cached, fresh, now, ttl),
+        CachePolicy::Replace => fresh,
+    };
+    write_proxy_cache(path, proxies)
+}
+
+fn write_proxy_cache(path: &str, proxies: Vec<CachedProxy>) -> io::Result<()> {
+    let cache = ProxyCache { version: ProxyCache::format_version(), proxies };
+    let contents = serde_json::to_string_pretty(&cache).map_err(io::Error::other)?;
+    std::fs::write(path, contents)
+}
+
+/// Loads the average download speed of the past torrents through each cached proxy that has any, by
+/// `host:port`, in bytes per second, however long ago the proxy was validated. An unreadable cache has none;
+/// `load_proxy_cache` reports it.
+pub fn load_download_speeds(path: &str) -> HashMap<String, f64> {
+    let proxies = read_proxy_cache(path).map(|cache| cache.proxies).unwrap_or_default();
+    proxies.iter().filter_map(|entry| Some((entry.proxy().address(), entry.download_speed_bps? as f64))).collect()
+}
+
+/// Adds the speeds of the downloads of a batch to the history of the cached proxies they went through, so
+/// that later runs rank the proxies by it. Proxies that are not in the cache are left out.
+///
+/// # Arguments
+///
+/// * `path` - The path to the JSON cache file.
+/// * `downloads` - Each proxy, with the average speed of every torrent downloaded through it, in bytes per second.
+pub fn record_download_speeds(path: &str, downloads: &[(&Socks5Proxy, Vec<f64>)]) -> io::Result<()> {
+    let mut proxies = match read_proxy_cache(path) {
+        Ok(cache) => cache.proxies,
+        Err(message) => return Err(io::Error::other(message)),
+    };
+    let mut recorded = false;
+    for entry in &mut proxies {
+        let address = entry.proxy().address();
+        if let Some((_, speeds)) = downloads.iter().find(|(proxy, _)| proxy.chain.is_empty() && proxy.address() == address) {
+            entry.record_downloads(speeds.iter().sum(), speeds.len() as u32);
+            recorded = true;
+        }
+    }
+    if !recorded {
+        return Ok(());
+    }
+    write_proxy_cache(path, proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn appended_proxies_replace_those_with_the_same_address() {
+        let path = std::env::temp_dir().join(format!("batch_tor-cache-{}.json", std::process::id())).display().to_string();
+        let result = |address: &str| ProxyResult { proxy: Socks5Proxy::parse(address).unwrap(), status: StatusCode::OK, latency: Duration::from_millis(5), throughput: None, download_speed: None };
+        let ttl = Duration::from_secs(3600);
+        save_proxy_cache(&path, &[result("10.0.0.1:1080"), result("10.0.0.2:1080")], CachePolicy::Replace, ttl).unwrap();
+        save_proxy_cache(&path, &[result("http://10.0.0.2:1080"), result("10.0.0.3:1080")], CachePolicy::Append, ttl).unwrap();
+        let appended = load_proxy_cache(&path, ttl);
+        save_proxy_cache(&path, &[result("10.0.0.3:1080")], CachePolicy::Replace, ttl).unwrap();
+        let replaced = load_proxy_cache(&path, ttl);
+        let _ = std::fs::remove_file(&path);
+        let addresses = |proxies: &[Socks5Proxy]| proxies.iter().map(|proxy| format!("{}:{}:{}", proxy.kind.scheme(false), proxy.host, proxy.port)).collect::<Vec<_>>();
+        assert_eq!(addresses(&appended), ["socks5:10.0.0.1:1080", "http:10.0.0.2:1080", "socks5:10.0.0.3:1080"]);
+        assert_eq!(addresses(&replaced), ["socks5:10.0.0.3:1080"]);
+
+        let stale = CachedProxy::from_result(&result("10.0.0.4:1080"), 0);
+        assert!(merge_proxies(vec![stale], Vec::new(), unix_now(), ttl).is_empty());
+    }
+
+    #[test]
+    fn download_speeds_are_averaged_and_outlive_a_new_check() {
+        let path = std::env::temp_dir().join(format!("batch_tor-speeds-{}.json", std::process::id())).display().to_string();
+        let result = ProxyResult { proxy: Socks5Proxy::parse("10.0.0.1:1080").unwrap(), status: StatusCode::OK, latency: Duration::from_millis(5), throughput: None, download_speed: None };
+        let ttl = Duration::from_secs(3600);
+        save_proxy_cache(&path, std::slice::from_ref(&result), CachePolicy::Replace, ttl).unwrap();
+        let uncached = Socks5Proxy::parse("10.0.0.2:1080").unwrap();
+        record_download_speeds(&path, &[(&result.proxy, vec![100.0, 300.0]), (&uncached, vec![1e9])]).unwrap();
+        record_download_speeds(&path, &[(&result.proxy, vec![500.0])]).unwrap();
+        save_proxy_cache(&path, &[result], CachePolicy::Replace, ttl).unwrap();
+        let speeds = load_download_speeds(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(speeds, HashMap::from([("10.0.0.1:1080".to_string(), 300.0)]));
+    }
+}
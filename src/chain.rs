@@ -0,0 +1,196 @@
+//! The experimental `--proxy-chain` mode, which sends every connection through two SOCKS5 proxies in sequence.
+//!
+//! Downloaders only take a single proxy, so each chain is served by a relay: a SOCKS5 proxy without
+//! authentication, listening on 127.0.0.1, that opens every connection it is asked for through the hops of
+//! the chain. The relay stands for the chain everywhere a proxy is used: it is checked end to end like any
+//! other proxy, and it is what the downloader is pointed at.
+
+use crate::backend::Backend;
+use crate::log::{emit, Level};
+use crate::proxy::{ProxyKind, Socks5Proxy};
+use crate::BatchTorConfig;
+use serde_json::json;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_socks::{IntoTargetAddr, TargetAddr};
+
+/// How many proxies each chain goes through.
+pub const CHAIN_HOPS: usize = 2;
+
+/// How long opening a connection through all the hops of a chain may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connection to the next hop of a chain, or through the hops so far.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Groups the proxies into chains of `CHAIN_HOPS` consecutive proxies, in the order of the list. Only SOCKS5
+/// proxies can be chained; the others, and the proxies left over at the end of the list, are returned separately.
+pub fn group_chains(proxies: Vec<Socks5Proxy>) -> (Vec<Vec<Socks5Proxy>>, Vec<Socks5Proxy>) {
+    let (socks5, mut unused): (Vec<Socks5Proxy>, Vec<Socks5Proxy>) = proxies.into_iter().partition(|proxy| proxy.kind == ProxyKind::Socks5);
+    let chunks = socks5.chunks_exact(CHAIN_HOPS);
+    unused.extend_from_slice(chunks.remainder());
+    (chunks.map(<[Socks5Proxy]>::to_vec).collect(), unused)
+}
+
+/// Starts a relay for the chain `hops` and returns the proxy that reaches it, with `hops` as its `chain`.
+/// The relay runs until the program exits.
+pub async fn start_chain(hops: Vec<Socks5Proxy>) -> io::Result<Socks5Proxy> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let relay = Socks5Proxy { kind: ProxyKind::Socks5, host: Ipv4Addr::LOCALHOST.to_string(), port, auth: None, chain: hops.clone() };
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let hops = hops.clone();
+            tokio::spawn(async move {
+                // A failed connection only concerns the client that asked for it, which sees it as a SOCKS error.
+                let _ = relay_connection(client, &hops).await;
+            });
+        }
+    });
+    Ok(relay)
+}
+
+/// Replaces the proxies with chains of proxies, as `--proxy-chain` asks. With a backend that cannot use the relays,
+/// the proxies are returned as they are.
+pub(crate) async fn chain_proxies(config: &BatchTorConfig, proxies: Vec<Socks5Proxy>) -> Vec<Socks5Proxy> {
+    if config.backend != Backend::Transmission {
+        emit(
+            Level::Warn,
+            "proxy_chain_unsupported",
+            "aria2c cannot use SOCKS5 proxies, so --proxy-chain is ignored and each proxy is used on its own.".to_string(),
+            json!({"backend": "aria2"}),
+        );
+        return proxies;
+    }
+    let (chains, unused) = group_chains(proxies);
+    if !unused.is_empty() {
+        emit(
+            Level::Info,
+            "proxies_unchained",
+            format!("Not using {} proxies that cannot be chained: only pairs of SOCKS5 proxies are.", unused.len()),
+            json!({"count": unused.len(), "proxies": unused.iter().map(|proxy| proxy.to_string()).collect::<Vec<_>>()}),
+        );
+    }
+    let mut relays = Vec::with_capacity(chains.len());
+    for hops in chains {
+        match start_chain(hops).await {
+            Ok(relay) => relays.push(relay),
+            Err(e) => emit(Level::Warn, "proxy_chain_error", format!("Could not start a proxy chain relay: {}", e), json!({"error": e.to_string()})),
+        }
+    }
+    emit(
+        Level::Info,
+        "proxies_chained",
+        format!("Chained the proxies into {} chains of {} (experimental).", relays.len(), CHAIN_HOPS),
+        json!({"chains": relays.iter().map(|relay| relay.to_string()).collect::<Vec<_>>()}),
+    );
+    relays
+}
+
+/// Serves one SOCKS5 `CONNECT` request of a client of the relay, by opening the connection through `hops`.
+async fn relay_connection(mut client: TcpStream, hops: &[Socks5Proxy]) -> io::Result<()> {
+    // The greeting lists the authentication methods the client supports; the relay only accepts "none".
+    let mut header = [0; 2];
+    client.read_exact(&mut header).await?;
+    let mut methods = vec![0; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    if header[0] != 5 || !methods.contains(&0) {
+        client.write_all(&[5, 0xff]).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported SOCKS greeting"));
+    }
+    client.write_all(&[5, 0]).await?;
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let target = read_target(&mut client, request[3]).await?;
+    if request[1] != 1 {
+        client.write_all(&reply(7)).await?;  // Only CONNECT is supported, not BIND nor UDP ASSOCIATE.
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "unsupported SOCKS command"));
+    }
+
+    let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, connect_through(hops, target)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            client.write_all(&reply(5)).await?;
+            return Err(e);
+        }
+        Err(_) => {
+            client.write_all(&reply(4)).await?;
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+    };
+    client.write_all(&reply(0)).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Reads the destination of a SOCKS5 request, whose address type is `kind`.
+async fn read_target(client: &mut TcpStream, kind: u8) -> io::Result<TargetAddr<'static>> {
+    let target = match kind {
+        1 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip).await?;
+            TargetAddr::Ip(SocketAddr::from((Ipv4Addr::from(ip), client.read_u16().await?)))
+        }
+        3 => {
+            let mut name = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut name).await?;
+            let name = String::from_utf8(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "host name is not UTF-8"))?;
+            TargetAddr::Domain(name.into(), client.read_u16().await?)
+        }
+        4 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip).await?;
+            TargetAddr::Ip(SocketAddr::from((Ipv6Addr::from(ip), client.read_u16().await?)))
+        }
+        _ => {
+            client.write_all(&reply(8)).await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported SOCKS address type"));
+        }
+    };
+    Ok(target)
+}
+
+/// A SOCKS5 reply with the status `code`. The bound address is left unspecified, since clients do not use it.
+fn reply(code: u8) -> [u8; 10] {
+    [5, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+/// Opens a connection to `target` through every hop in turn: the first hop is asked to connect to the second,
+/// and so on, and the last one to the target.
+async fn connect_through(hops: &[Socks5Proxy], target: TargetAddr<'static>) -> io::Result<Box<dyn Stream>> {
+    let first = hops.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty proxy chain"))?;
+    let mut stream: Box<dyn Stream> = Box::new(TcpStream::connect((first.host.as_str(), first.port)).await?);
+    for (index, hop) in hops.iter().enumerate() {
+        let next = match hops.get(index + 1) {
+            Some(next) => (next.host.as_str(), next.port).into_target_addr().map_err(io::Error::other)?,
+            None => target.clone(),
+        };
+        let connected = match &hop.auth {
+            Some((user, pass)) => Socks5Stream::connect_with_password_and_socket(stream, next, user, pass).await,
+            None => Socks5Stream::connect_with_socket(stream, next).await,
+        };
+        stream = Box::new(connected.map_err(|e| io::Error::other(format!("{}: {}", hop, e)))?);
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pairs_of_socks5_proxies_are_chained() {
+        let proxies: Vec<Socks5Proxy> = ["10.0.0.1:1080", "http://10.0.0.2:3128", "10.0.0.3:1080", "10.0.0.4:1080"].iter().map(|line| Socks5Proxy::parse(line).unwrap()).collect();
+        let (chains, unused) = group_chains(proxies);
+        let chains: Vec<Vec<String>> = chains.iter().map(|hops| hops.iter().map(|hop| hop.to_string()).collect()).collect();
+        assert_eq!(chains, [["10.0.0.1:1080", "10.0.0.3:1080"]]);
+        assert_eq!(unused.iter().map(|proxy| proxy.to_string()).collect::<Vec<_>>(), ["http://10.0.0.2:3128", "10.0.0.4:1080"]);
+    }
+}
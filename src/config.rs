@@ -0,0 +1,486 @@
+//! Loading defaults for a batch from a `batch_tor.toml` config file.
+
+use crate::backend::Backend;
+use crate::cache::CachePolicy;
+use crate::download::{parse_log_sample, ExhaustedPolicy, ProgressFormat};
+use crate::files::parse_file_pattern;
+use crate::geoip::{parse_country_code, GeoUnknown};
+use crate::leak::LeakPolicy;
+use crate::log::{emit, set_json_output, set_verbosity, Level, Verbosity};
+use crate::peer_check::{parse_peer_address, TestProtocol};
+use crate::proxy::{ProxyFormat, Strategy};
+use crate::scrape::ScrapeUnknown;
+use crate::size::parse_byte_size;
+use crate::BatchTorConfig;
+use glob::Pattern;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// The config file looked for in the current directory when no `--config` path is given.
+pub const DEFAULT_CONFIG_FILE: &str = "batch_tor.toml";
+
+/// The contents of a config file. Every key matches the name of a CLI option with dashes
+/// replaced by underscores, e.g. `download_dir` for `--download-dir`; durations are in the same unit as on the command line.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    /// A single file or a list of files; `magnet_file` is accepted as well.
+    #[serde(default, alias = "magnet_file", deserialize_with = "one_or_many")]
+    pub magnet_files: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "regex")]
+    pub filter: Option<Regex>,
+    #[serde(default, deserialize_with = "regex")]
+    pub exclude: Option<Regex>,
+    pub limit: Option<u32>,
+    pub continue_on_missing_files: Option<bool>,
+    pub extra_trackers: Option<String>,
+    pub watch: Option<bool>,
+    pub socks_file: Option<String>,
+    pub proxy_url: Option<String>,
+    pub proxy_format: Option<ProxyFormat>,
+    pub proxy_blocklist: Option<String>,
+    /// A single country code or a list of them; `proxy_country` is accepted as well.
+    #[serde(default, alias = "proxy_country", deserialize_with = "one_or_many")]
+    pub proxy_countries: Option<Vec<String>>,
+    pub geoip_db: Option<String>,
+    pub geo_unknown: Option<GeoUnknown>,
+    pub download_dir: Option<String>,
+    pub max_concurrency: Option<u32>,
+    pub check_batch_size: Option<u32>,
+    pub check_urls: Option<Vec<String>>,
+    pub proxy_timeout: Option<u64>,
+    pub strategy: Option<Strategy>,
+    pub shuffle: Option<bool>,
+    pub shuffle_seed: Option<u64>,
+    pub proxy_sample: Option<u32>,
+    pub sample_fallback: Option<bool>,
+    pub proxy_order_file: Option<String>,
+    pub warmup_bytes: Option<u64>,
+    pub retry_timeouts: Option<bool>,
+    pub check_attempts: Option<u32>,
+    pub proxy_test_protocol: Option<TestProtocol>,
+    #[serde(default, deserialize_with = "peer_address")]
+    pub bt_test_peer: Option<String>,
+    pub interactive: Option<bool>,
+    pub confirm_threshold: Option<u32>,
+    pub yes: Option<bool>,
+    pub parallel_downloads: Option<u32>,
+    pub proxy_per_download: Option<bool>,
+    pub dry_run: Option<bool>,
+    pub verify_proxies_only: Option<bool>,
+    pub json: Option<bool>,
+    pub progress_format: Option<ProgressFormat>,
+    pub progress_interval: Option<u64>,
+    pub proxy_cache: Option<String>,
+    pub proxy_cache_ttl: Option<u64>,
+    pub proxy_cache_policy: Option<CachePolicy>,
+    pub max_retries: Option<u32>,
+    pub max_proxy_retries: Option<u32>,
+    pub download_limit: Option<u32>,
+    pub total_download_limit: Option<u32>,
+    pub upload_limit: Option<u32>,
+    pub disable_dht: Option<bool>,
+    pub disable_utp: Option<bool>,
+    pub disable_pex: Option<bool>,
+    pub allow_udp: Option<bool>,
+    pub seed_ratio: Option<f64>,
+    pub seed_time: Option<u64>,
+    pub log_dir: Option<String>,
+    /// A rate such as `"1/100"`, or just the number of lines per echoed line.
+    #[serde(default, deserialize_with = "log_sample")]
+    pub log_sample: Option<u64>,
+    #[serde(default, deserialize_with = "regex")]
+    pub log_keyword: Option<Regex>,
+    pub force: Option<bool>,
+    pub tor: Option<bool>,
+    pub no_proxy: Option<bool>,
+    pub proxy_chain: Option<bool>,
+    pub fallback_direct: Option<bool>,
+    pub leak_test: Option<bool>,
+    pub leak_policy: Option<LeakPolicy>,
+    pub ip_echo_url: Option<String>,
+    pub backend: Option<Backend>,
+    pub transmission_path: Option<String>,
+    pub aria2c_path: Option<String>,
+    pub skip_preflight: Option<bool>,
+    pub pause_on_metered: Option<bool>,
+    pub metered_check_command: Option<String>,
+    pub verbosity: Option<Verbosity>,
+    pub subdir_per_torrent: Option<bool>,
+    pub keep_incomplete: Option<bool>,
+    pub incomplete_dir: Option<String>,
+    /// A single glob pattern or a list of them.
+    #[serde(default, deserialize_with = "file_patterns")]
+    pub file_filter: Option<Vec<Pattern>>,
+    pub create_dir: Option<bool>,
+    pub journal: Option<String>,
+    pub on_complete: Option<String>,
+    pub notify_webhook: Option<String>,
+    pub notify_desktop: Option<bool>,
+    pub download_timeout: Option<u64>,
+    pub stall_timeout: Option<u64>,
+    pub recheck_before_download: Option<bool>,
+    pub max_downloads_per_proxy: Option<u32>,
+    pub exhausted_policy: Option<ExhaustedPolicy>,
+    pub size_preview: Option<bool>,
+    /// A size such as `"50GB"`, or a number of bytes.
+    #[serde(default, deserialize_with = "byte_size")]
+    pub max_total_size: Option<u64>,
+    pub min_seeders: Option<u64>,
+    pub scrape_unknown: Option<ScrapeUnknown>,
+    pub report: Option<String>,
+    pub metrics_port: Option<u16>,
+    /// Keys that are not recognized; they are reported with a warning and otherwise ignored.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Deserializes a string holding a regular expression.
+fn regex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes a value that may be given either as a single string or as a list of strings.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    }))
+}
+
+/// Deserializes `file_filter` glob patterns given either as a single string or as a list of strings.
+fn file_patterns<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Pattern>>, D::Error> {
+    let patterns = one_or_many(deserializer)?.unwrap_or_default();
+    patterns.iter().map(|pattern| parse_file_pattern(pattern)).collect::<Result<_, _>>().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes a `bt_test_peer` address, checked by `parse_peer_address`.
+fn peer_address<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    parse_peer_address(&String::deserialize(deserializer)?).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes a size given either as a string with a unit, parsed by `parse_byte_size`, or as a number of bytes.
+fn byte_size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(size) => parse_byte_size(&size).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Deserializes a `log_sample` rate given either as a string parsed by `parse_log_sample` or as a number of lines.
+fn log_sample<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Sample {
+        Lines(u64),
+        Text(String),
+    }
+    match Sample::deserialize(deserializer)? {
+        Sample::Lines(0) => Err(serde::de::Error::custom("log_sample must be at least 1")),
+        Sample::Lines(every) => Ok(Some(every)),
+        Sample::Text(rate) => parse_log_sample(&rate).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Expands the `${NAME}` references to environment variables in `text`, so that secrets such as proxy passwords
+/// and webhook tokens can be kept out of the file; `$${` stands for a literal `${`. Fails with a message naming
+/// the first variable that `lookup` does not find.
+fn expand_env_vars(text: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let Some((name, after)) = rest[start + 2..].split_once('}') else { return Err(format!("`${{` without a closing `}}` in `{}`", text)) };
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None => return Err(format!("the environment variable {} is not set", name)),
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Applies `expand_env_vars` to every string of `value`, including those in arrays and tables.
+fn expand_value(value: &mut toml::Value, lookup: &impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => *text = expand_env_vars(text, lookup)?,
+        toml::Value::Array(values) => values.iter_mut().try_for_each(|value| expand_value(value, lookup))?,
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, value)| expand_value(value, lookup))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The reasons a config file can fail to load.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    #[error("could not read config file {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    /// The config file is not valid TOML or a value has the wrong type.
+    #[error("invalid config file {}: {}", .0.display(), .1)]
+    Parse(PathBuf, #[source] toml::de::Error),
+    /// A value is out of range, e.g. a concurrency of zero.
+    #[error("invalid config file {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, String),
+}
+
+impl ConfigFile {
+    /// Reads and parses a config file.
+    pub fn load(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let mut table: toml::Table = toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        for (key, value) in table.iter_mut() {
+            expand_value(value, &|name| std::env::var(name).ok()).map_err(|message| ConfigError::Invalid(path.to_path_buf(), format!("{}: {}", key, message)))?;
+        }
+        let mut file: ConfigFile = table.try_into().map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        if let Some(countries) = &mut file.proxy_countries {
+            for country in countries.iter_mut() {
+                *country = parse_country_code(country).map_err(|message| ConfigError::Invalid(path.to_path_buf(), format!("proxy_country: {}", message)))?;
+            }
+        }
+        if file.check_attempts == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "check_attempts must be at least 1".to_string()));
+        }
+        if file.max_concurrency == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "max_concurrency must be at least 1".to_string()));
+        }
+        if file.min_seeders == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "min_seeders must be at least 1".to_string()));
+        }
+        if file.progress_interval == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "progress_interval must be at least 1".to_string()));
+        }
+        if file.limit == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "limit must be at least 1".to_string()));
+        }
+        if file.proxy_sample == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "proxy_sample must be at least 1".to_string()));
+        }
+        if file.check_batch_size == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "check_batch_size must be at least 1".to_string()));
+        }
+        if file.parallel_downloads == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "parallel_downloads must be at least 1".to_string()));
+        }
+        if file.proxy_timeout == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "proxy_timeout must be at least 1".to_string()));
+        }
+        if file.warmup_bytes == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "warmup_bytes must be at least 1".to_string()));
+        }
+        if file.download_timeout == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "download_timeout must be at least 1".to_string()));
+        }
+        if file.max_downloads_per_proxy == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "max_downloads_per_proxy must be at least 1".to_string()));
+        }
+        if file.stall_timeout == Some(0) {
+            return Err(ConfigError::Invalid(path.to_path_buf(), "stall_timeout must be at least 1".to_string()));
+        }
+        Ok(file)
+    }
+
+    /// The keys in the file that are not recognized.
+    pub fn unknown_keys(&self) -> impl Iterator<Item = &str> {
+        self.unknown.keys().map(String::as_str)
+    }
+
+    /// Copies every value set in the file into `config`, except for the options for which
+    /// `overridden` returns `true` because they were given on the command line.
+    pub fn apply(self, config: &mut BatchTorConfig, overridden: impl Fn(&str) -> bool) {
+        macro_rules! apply {
+            ($key:ident) => { apply!($key, |value| value) };
+            ($key:ident, $convert:expr) => {
+                if let Some(value) = self.$key {
+                    if !overridden(stringify!($key)) {
+                        config.$key = $convert(value);
+                    }
+                }
+            };
+        }
+        apply!(magnet_files);
+        if let Some(include) = self.filter.filter(|_| !overridden("filter")) {
+            config.filter.include = Some(include);
+        }
+        if let Some(exclude) = self.exclude.filter(|_| !overridden("exclude")) {
+            config.filter.exclude = Some(exclude);
+        }
+        apply!(limit, |value: u32| Some(value as usize));
+        apply!(continue_on_missing_files);
+        apply!(extra_trackers, Some);
+        apply!(watch);
+        apply!(socks_file);
+        if let Some(url) = self.proxy_url.filter(|_| !overridden("proxy_url") && !overridden("socks_file")) {
+            config.proxy_url = Some(url);  // A --socks-file on the command line takes precedence over a URL in the file.
+        }
+        apply!(proxy_format);
+        apply!(proxy_blocklist, Some);
+        apply!(proxy_countries);
+        apply!(geoip_db, Some);
+        apply!(geo_unknown);
+        apply!(download_dir);
+        apply!(max_concurrency, |value: u32| value as usize);
+        apply!(check_batch_size, |value: u32| Some(value as usize));
+        apply!(check_urls);
+        apply!(proxy_timeout, Duration::from_secs);
+        apply!(strategy);
+        apply!(shuffle);
+        apply!(shuffle_seed, Some);
+        apply!(proxy_sample, |value: u32| Some(value as usize));
+        apply!(sample_fallback);
+        apply!(proxy_order_file, Some);
+        apply!(warmup_bytes, Some);
+        apply!(retry_timeouts);
+        apply!(check_attempts);
+        apply!(proxy_test_protocol);
+        apply!(bt_test_peer);
+        apply!(interactive);
+        apply!(confirm_threshold, |value: u32| value as usize);
+        apply!(yes);
+        apply!(parallel_downloads, |value: u32| value as usize);
+        apply!(proxy_per_download);
+        apply!(dry_run);
+        apply!(verify_proxies_only);
+        apply!(json);
+        apply!(progress_format);
+        apply!(progress_interval, Duration::from_secs);
+        apply!(proxy_cache, Some);
+        apply!(proxy_cache_ttl, Duration::from_secs);
+        apply!(proxy_cache_policy);
+        apply!(max_retries);
+        apply!(max_proxy_retries);
+        apply!(download_limit, Some);
+        apply!(total_download_limit, Some);
+        apply!(upload_limit, Some);
+        apply!(disable_dht);
+        apply!(disable_utp);
+        apply!(disable_pex);
+        apply!(allow_udp);
+        apply!(seed_ratio, Some);
+        apply!(seed_time, |value| Some(Duration::from_secs(value)));
+        apply!(log_dir, Some);
+        apply!(log_sample, Some);
+        apply!(log_keyword, Some);
+        apply!(force);
+        apply!(tor);
+        apply!(no_proxy);
+        apply!(proxy_chain);
+        apply!(fallback_direct);
+        apply!(leak_test);
+        apply!(leak_policy);
+        apply!(ip_echo_url);
+        apply!(backend);
+        apply!(transmission_path, Some);
+        apply!(aria2c_path, Some);
+        apply!(skip_preflight);
+        apply!(pause_on_metered);
+        apply!(metered_check_command, Some);
+        apply!(verbosity);
+        apply!(subdir_per_torrent);
+        apply!(keep_incomplete);
+        apply!(incomplete_dir, Some);
+        apply!(file_filter);
+        apply!(create_dir);
+        apply!(journal, Some);
+        apply!(on_complete, Some);
+        apply!(notify_webhook, Some);
+        apply!(notify_desktop);
+        apply!(download_timeout, |minutes: u64| Some(Duration::from_secs(minutes * 60)));
+        apply!(stall_timeout, |minutes: u64| Some(Duration::from_secs(minutes * 60)));
+        apply!(recheck_before_download);
+        apply!(max_downloads_per_proxy, |max: u32| Some(max as usize));
+        apply!(exhausted_policy);
+        apply!(size_preview);
+        apply!(max_total_size, Some);
+        apply!(min_seeders, Some);
+        apply!(scrape_unknown);
+        apply!(report, Some);
+        apply!(metrics_port, Some);
+    }
+}
+
+/// Loads the config file and applies it to `config`, leaving the options given on the command line untouched.
+/// With no explicit `path`, `batch_tor.toml` in the current directory is used if it exists.
+/// Problems are reported through the diagnostic output, like `run` does.
+///
+/// # Arguments
+///
+/// * `config` - The configuration built from the command line.
+/// * `path` - The config file given with `--config`, if any; it must exist.
+/// * `overridden` - Returns whether the option with the given key was set on the command line.
+pub fn apply_config_file(
+    config: &mut BatchTorConfig,
+    path: Option<&Path>,
+    overridden: impl Fn(&str) -> bool,
+) -> Result<(), ConfigError> {
+    // Reports problems in the format and detail requested on the command line.
+    set_json_output(config.json);
+    set_verbosity(config.verbosity);
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Path::new(DEFAULT_CONFIG_FILE),
+        None => return Ok(()),
+    };
+    let file = match ConfigFile::load(path) {
+        Ok(file) => file,
+        Err(e) => {
+            emit(Level::Error, "config_error", format!("{}", e), json!({"file": path, "error": e.to_string()}));
+            return Err(e);
+        }
+    };
+    let unknown: Vec<String> = file.unknown_keys().map(str::to_string).collect();
+    file.apply(config, overridden);
+
+    // The config file may turn on JSON output, so it is applied before reporting anything about the file.
+    set_json_output(config.json);
+    set_verbosity(config.verbosity);
+    for key in unknown {
+        emit(
+            Level::Warn,
+            "config_unknown_key",
+            format!("Ignoring unknown key `{}` in config file {}", key, path.display()),
+            json!({"file": path, "key": key}),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_variables_are_expanded() {
+        let lookup = |name: &str| (name == "TOKEN").then(|| "s3cret".to_string());
+        assert_eq!(expand_env_vars("https://hooks.example/${TOKEN}?a=${TOKEN}", &lookup).as_deref(), Ok("https://hooks.example/s3cret?a=s3cret"));
+        assert_eq!(expand_env_vars("cost: $${TOKEN} $5", &lookup).as_deref(), Ok("cost: ${TOKEN} $5"));
+        assert_eq!(expand_env_vars("${MISSING}", &lookup), Err("the environment variable MISSING is not set".to_string()));
+        assert!(expand_env_vars("${TOKEN", &lookup).is_err());
+    }
+}
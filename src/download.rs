@@ -0,0 +1,1321 @@
+//! Downloading torrents with an external downloader through a proxy.
+
+use crate::backend::{Backend, Downloader, Progress};
+use crate::bandwidth::{BandwidthShare, ShareGuard};
+use crate::files;
+use crate::log::{self, emit, Level};
+use crate::metered;
+use crate::metrics;
+use crate::proxy::{check_proxy, find_valid_proxies, CheckOptions, ProxyPool, Socks5Proxy, Strategy};
+use crate::shutdown::{shutdown_requested, terminate, track_child, wait_for_shutdown};
+use crate::source::TorrentSource;
+use clap::ValueEnum;
+use glob::Pattern;
+use indicatif::ProgressBar;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+use tokio::process::Child;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::OwnedSemaphorePermit;
+
+/// The subdirectory of the download directory where `--keep-incomplete` keeps partial files, unless
+/// `--incomplete-dir` names another directory.
+pub const INCOMPLETE_SUBDIR: &str = ".incomplete";
+
+/// Settings shared by every download in a batch.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// The program that downloads the torrents.
+    pub backend: Backend,
+    /// The executable to run instead of looking up the backend's program on `PATH`, if any.
+    pub program: Option<PathBuf>,
+    /// The directory where the downloaded files will be saved.
+    pub download_dir: String,
+    /// The download speed limit in KB/s.
+    pub download_limit: Option<u32>,
+    /// The upload speed limit in KB/s.
+    pub upload_limit: Option<u32>,
+    /// The `--total-download-limit` shared by the downloads, which then run with their share as `download_limit`.
+    pub bandwidth: Option<Arc<BandwidthShare>>,
+    /// Stop seeding once the upload ratio reaches this value.
+    pub seed_ratio: Option<f64>,
+    /// Stop seeding once the torrent has been seeded for this long.
+    pub seed_time: Option<Duration>,
+    /// A directory where the output of every downloader run is saved, one file per torrent.
+    pub log_dir: Option<PathBuf>,
+    /// Which output lines are echoed; the `log_dir` transcript gets them all.
+    pub output: OutputFilter,
+    /// How long a single download attempt may run before it is stopped and counted as failed.
+    pub download_timeout: Option<Duration>,
+    /// How long a download may go without progress before it is stopped and resumed through the next proxy.
+    pub stall_timeout: Option<Duration>,
+    /// Save every torrent into a subdirectory of `download_dir` named after it; see `torrent_subdir`.
+    pub subdir_per_torrent: bool,
+    /// How proxies are checked again while the batch runs, and where replacements are looked for;
+    /// `None` without a proxy list.
+    pub recheck: Option<ProxyRecheck>,
+    /// Check the proxy of every attempt again before it starts; see `recheck_proxy`.
+    pub recheck_before_download: bool,
+    /// What a download does once every proxy of the pool served `--max-downloads-per-proxy` torrents.
+    pub exhausted_policy: ExhaustedPolicy,
+    /// How often a `progress` event is emitted for each running download with `--progress-format json-lines`;
+    /// `None` without it.
+    pub progress_events: Option<Duration>,
+    /// Which peer protocols the downloader may use.
+    pub peer_protocols: PeerProtocols,
+    /// Where partial files are kept with `--keep-incomplete`, so that the next attempt or run resumes them;
+    /// `None` to discard them.
+    pub incomplete_dir: Option<PathBuf>,
+    /// The `--file-filter` patterns of the files to download within each torrent; all of them if empty.
+    pub file_filter: Vec<Pattern>,
+}
+
+/// Which lines of the downloader's output are echoed as `transmission_output` events, set with `--log-sample`
+/// and `--log-keyword`, so that a chatty downloader doesn't flood the console.
+#[derive(Debug, Clone, Default)]
+pub struct OutputFilter {
+    /// Echo only one line out of this many.
+    pub sample: Option<u64>,
+    /// Always echo the lines that match; with a keyword and no sample, the other lines are not echoed at all.
+    pub keyword: Option<Regex>,
+}
+
+impl OutputFilter {
+    /// Whether to echo `line`, the `index`-th line of the attempt that could be echoed, counting from zero.
+    pub fn shows(&self, line: &str, index: u64) -> bool {
+        if self.keyword.as_ref().is_some_and(|keyword| keyword.is_match(line)) {
+            return true;
+        }
+        match self.sample {
+            Some(every) => index.is_multiple_of(every),
+            None => self.keyword.is_none(),
+        }
+    }
+}
+
+/// Which peer protocols the downloader may use, set with `--disable-dht`, `--disable-utp`, `--disable-pex`, and
+/// `--allow-udp`. Through a proxy, DHT and uTP are off unless `allow_udp` is set: both run over UDP, which the
+/// downloaders do not send through SOCKS5 proxies, so their traffic would go out directly and reveal the real address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerProtocols {
+    pub disable_dht: bool,
+    pub disable_utp: bool,
+    pub disable_pex: bool,
+    /// Keep DHT and uTP on through a proxy, unless they are disabled explicitly.
+    pub allow_udp: bool,
+}
+
+impl PeerProtocols {
+    /// Whether peers may be found through DHT, and on the local network, whose discovery also runs over UDP.
+    pub fn dht(&self, proxied: bool) -> bool {
+        !self.disable_dht && (!proxied || self.allow_udp)
+    }
+
+    /// Whether peers may be connected to over uTP instead of TCP.
+    pub fn utp(&self, proxied: bool) -> bool {
+        !self.disable_utp && (!proxied || self.allow_udp)
+    }
+
+    /// Whether peers may be learned from other peers. Peer exchange runs over the peer connections themselves,
+    /// so it stays on through a proxy.
+    pub fn pex(&self) -> bool {
+        !self.disable_pex
+    }
+}
+
+/// Parses a `--log-sample` rate: `1/N`, or just `N`, to echo one line out of `N`.
+pub fn parse_log_sample(value: &str) -> Result<u64, String> {
+    let every = value.trim().strip_prefix("1/").unwrap_or(value.trim());
+    match every.parse::<u64>() {
+        Ok(every) if every > 0 => Ok(every),
+        _ => Err(format!("`{}` is not a sampling rate such as 1/100", value)),
+    }
+}
+
+/// What `--exhausted-policy` does once every proxy of the pool served `--max-downloads-per-proxy` torrents.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExhaustedPolicy {
+    /// Fail the remaining torrents.
+    #[default]
+    Fail,
+    /// Check the rest of the proxy list for a proxy that has not served as many torrents, and add it to the pool.
+    Revalidate,
+}
+
+/// How `--progress-format` shows the progress of the running downloads.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressFormat {
+    /// One progress bar per download, unless `--json` or `-q` hides them.
+    #[default]
+    Bars,
+    /// A `progress` JSON event per download every `--progress-interval`, printed to stdout instead of the bars.
+    JsonLines,
+}
+
+/// How `download_with_retries` checks proxies again once the batch runs: the proxy of an attempt right
+/// before starting it with `--recheck-before-download`, so that a proxy that died since it was selected is
+/// replaced instead of stalling the download, new proxies once the pool is exhausted, and a replacement for a
+/// proxy that passed the check but fails the download.
+#[derive(Debug, Clone)]
+pub struct ProxyRecheck {
+    /// How the proxy is checked, normally as at startup.
+    pub options: CheckOptions,
+    /// Where a replacement is looked for once no other proxy of the pool passes, usually the whole proxy list.
+    pub candidates: Vec<Socks5Proxy>,
+    /// The maximum number of checks running at the same time while looking for a replacement.
+    pub max_concurrency: usize,
+    /// The proxies left out for failing a download, shared by every download of the run.
+    pub failed: Arc<FailedProxies>,
+}
+
+/// The proxies that passed the check but failed a download, which `--max-proxy-retries` leaves out for the
+/// rest of the run, and the proxies that completed one, which are never left out.
+#[derive(Debug, Default)]
+pub struct FailedProxies {
+    /// How many times a failed proxy may be replaced by a new search, in the whole run.
+    pub max_searches: u32,
+    searches: AtomicU32,
+    failed: Mutex<Vec<Socks5Proxy>>,
+    proven: Mutex<Vec<Socks5Proxy>>,
+}
+
+impl FailedProxies {
+    /// Returns an empty list that allows `max_searches` searches.
+    pub fn new(max_searches: u32) -> FailedProxies {
+        FailedProxies { max_searches, ..Default::default() }
+    }
+
+    /// Whether `proxy` was left out.
+    pub fn contains(&self, proxy: &Socks5Proxy) -> bool {
+        self.failed.lock().expect("Failed proxies poisoned").contains(proxy)
+    }
+
+    /// Records that a download through `proxy` completed.
+    fn prove(&self, proxy: &Socks5Proxy) {
+        let mut proven = self.proven.lock().expect("Failed proxies poisoned");
+        if !proven.contains(proxy) {
+            proven.push(proxy.clone());
+        }
+    }
+
+    /// Leaves `proxy` out and takes one of the searches, unless a download through it completed or no search is left.
+    /// Returns the number of the search, counting from one.
+    fn leave_out(&self, proxy: &Socks5Proxy) -> Option<u32> {
+        if self.proven.lock().expect("Failed proxies poisoned").contains(proxy) {
+            return None;
+        }
+        let search = self.searches.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |searches| (searches < self.max_searches).then_some(searches + 1)).ok()? + 1;
+        self.failed.lock().expect("Failed proxies poisoned").push(proxy.clone());
+        Some(search)
+    }
+}
+
+impl DownloadOptions {
+    /// The executable that runs `downloader`: `program` if set, or else the downloader's program, looked up on `PATH`.
+    pub fn executable(&self, downloader: &dyn Downloader) -> PathBuf {
+        self.program.clone().unwrap_or_else(|| PathBuf::from(downloader.program()))
+    }
+
+    /// The directory the downloader saves `source` into.
+    pub fn torrent_dir(&self, source: &TorrentSource) -> PathBuf {
+        torrent_dir(Path::new(&self.download_dir), source, self.subdir_per_torrent)
+    }
+
+    /// The patterns of the files to download within `source`: those of the `||want=` suffix of a magnet link,
+    /// or else `file_filter`. All of its files are downloaded if empty.
+    pub fn wanted_files<'a>(&'a self, source: &'a TorrentSource) -> &'a [Pattern] {
+        files::wanted_files(source, &self.file_filter)
+    }
+
+    fn has_speed_limits(&self) -> bool {
+        self.download_limit.is_some() || self.upload_limit.is_some()
+    }
+
+    /// Returns whether a seeding torrent has reached its target. With neither target set,
+    /// seeding stops as soon as the download completes.
+    fn seeding_done(&self, ratio: f64, seeded_for: Duration) -> bool {
+        match (self.seed_ratio, self.seed_time) {
+            (None, None) => true,
+            (seed_ratio, seed_time) => {
+                seed_ratio.is_some_and(|target| ratio >= target) || seed_time.is_some_and(|target| seeded_for >= target)
+            }
+        }
+    }
+}
+
+/// Per-download state handed to the downloader by the caller.
+#[derive(Debug, Default)]
+pub struct DownloadTask {
+    /// A download slot held by the caller; it is released as soon as the torrent starts seeding,
+    /// so seeding torrents don't hold up the rest of the batch.
+    pub slot: Option<OwnedSemaphorePermit>,
+    /// A progress bar from 0 to 100 that follows the download. While it is set, the downloader's
+    /// status lines update the bar instead of being echoed.
+    pub bar: Option<ProgressBar>,
+    /// How many times `download_with_retries` switched to the next proxy because the download stalled.
+    pub rotations: u32,
+    /// How many times `download_with_retries` replaced a proxy that failed its re-check before an attempt.
+    pub recheck_switches: u32,
+}
+
+/// Returns whether a line printed by the downloader reports an option it does not understand.
+fn is_unsupported_option_message(line: &str) -> bool {
+    let line = line.to_ascii_lowercase();
+    ["unrecognized option", "unknown option", "invalid option", "illegal option"]
+        .iter()
+        .any(|pattern| line.contains(pattern))
+}
+
+/// Returns whether a line printed by the downloader reports that it could not connect through its proxy, in the words
+/// of libcurl, which `transmission-cli` uses for trackers and web seeds, or of `aria2c`.
+fn is_proxy_error_message(line: &str) -> bool {
+    let line = line.to_ascii_lowercase();
+    ["connect to proxy", "resolve proxy", "proxy connect", "proxy authentication", "socks4 connect", "socks5 connect", "socks5 connection", "socks5 response", "socks5 request"]
+        .iter()
+        .any(|pattern| line.contains(pattern))
+}
+
+/// The reasons a single torrent download can fail.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    /// The downloader could not be started or waited on.
+    #[error("failed to run the downloader: {0}")]
+    Io(#[source] io::Error),
+    /// The downloader exited unsuccessfully.
+    #[error("the downloader exited with {0}")]
+    Exit(ExitStatus),
+    /// The downloader exited unsuccessfully after reporting that it could not connect through its proxy; carries its
+    /// exit status and the first such message.
+    #[error("the downloader exited with {0} after failing to connect through the proxy ({1})")]
+    Proxy(ExitStatus, String),
+    /// The downloader rejected the speed limit options; carries its error message.
+    #[error("the installed downloader does not support --download-limit/--upload-limit ({0})")]
+    UnsupportedSpeedLimit(String),
+    /// The downloader cannot use the proxy; carries the reason.
+    #[error("{0}")]
+    UnsupportedProxy(String),
+    /// The download was stopped, or never started, because a shutdown was requested.
+    #[error("interrupted")]
+    Interrupted,
+    /// The download did not finish within `--download-timeout`.
+    #[error("timed out after {} min", .0.as_secs() / 60)]
+    TimedOut(Duration),
+    /// The download made no progress for `--stall-timeout`.
+    #[error("made no progress for {} min", .0.as_secs() / 60)]
+    Stalled(Duration),
+    /// Every attempt allowed by `--max-retries` failed; carries the error of the last attempt.
+    #[error("gave up after {attempts} attempts: {last}")]
+    RetriesExhausted {
+        attempts: u32,
+        #[source]
+        last: Box<DownloadError>,
+    },
+    /// The files to download could not be selected; carries the reason.
+    #[error("{0}")]
+    FileSelection(String),
+    /// The downloader was stopped to run it again with its new share of `--total-download-limit`, carried here in KB/s;
+    /// `run_download` restarts it right away.
+    #[error("restarted at {0} KB/s")]
+    Reshared(u32),
+    /// Every proxy already served `--max-downloads-per-proxy` torrents, carried here.
+    #[error("no proxy left, every proxy already reached --max-downloads-per-proxy ({0})")]
+    ProxiesExhausted(usize),
+}
+
+impl DownloadError {
+    /// Whether the download, or its last attempt, failed by running out of time.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            DownloadError::TimedOut(_) => true,
+            DownloadError::RetriesExhausted { last, .. } => last.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
+/// What a successful download transferred, as far as the downloader's output tells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadOutcome {
+    /// The proxy the download went through, or `None` for a direct download.
+    pub proxy: Option<Socks5Proxy>,
+    /// The bytes downloaded by the attempt: the count printed by the downloader, or with `transmission-cli`,
+    /// which only prints its current rate, an estimate from the rates it printed. Data resumed from an earlier
+    /// attempt is not counted.
+    pub bytes: u64,
+    /// The average download speed of the attempt, in bytes per second.
+    pub avg_speed: f64,
+    /// How long the attempt took until the download finished, excluding seeding.
+    pub duration: Duration,
+    /// Whether the downloader reported the torrent as complete, by reaching 100% or seeding, rather than just exiting successfully.
+    pub success: bool,
+    /// Whether the downloader printed any rate or byte count; without one, `bytes` and `avg_speed` are zero.
+    pub stats_available: bool,
+}
+
+/// Accumulates the byte counts and rates of a downloader's progress lines into a `DownloadOutcome`.
+#[derive(Debug)]
+struct TransferStats {
+    started: Instant,
+    /// When the download finished, if it started seeding.
+    finished: Option<Instant>,
+    /// The largest byte count printed, if any.
+    downloaded: Option<u64>,
+    /// The bytes estimated from the printed rates, each one held until the next progress line.
+    estimated: f64,
+    /// The last printed rate, in bytes per second, and when it was printed.
+    last_rate: Option<(Instant, f64)>,
+    complete: bool,
+}
+
+impl TransferStats {
+    fn new() -> TransferStats {
+        TransferStats { started: Instant::now(), finished: None, downloaded: None, estimated: 0.0, last_rate: None, complete: false }
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        if self.finished.is_some() {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(bytes) = progress.downloaded {
+            self.downloaded = Some(self.downloaded.unwrap_or_default().max(bytes));
+        }
+        if let Some((since, rate)) = self.last_rate {
+            self.estimated += rate * now.duration_since(since).as_secs_f64();
+        }
+        if let Some(rate) = progress.rate_bytes_per_sec() {
+            self.last_rate = Some((now, rate));
+        }
+        self.complete |= progress.percent >= 100.0;
+    }
+
+    fn seeding(&mut self) {
+        self.complete = true;
+        self.finished.get_or_insert_with(Instant::now);
+    }
+
+    fn outcome(&self, proxy: Option<&Socks5Proxy>) -> DownloadOutcome {
+        let duration = self.finished.unwrap_or_else(Instant::now).duration_since(self.started);
+        let stats_available = self.downloaded.is_some() || self.last_rate.is_some();
+        let bytes = self.downloaded.unwrap_or(self.estimated as u64);
+        let avg_speed = if duration.is_zero() { 0.0 } else { bytes as f64 / duration.as_secs_f64() };
+        DownloadOutcome { proxy: proxy.cloned(), bytes, avg_speed, duration, success: self.complete, stats_available }
+    }
+}
+
+/// Emits the `progress` events of `--progress-format json-lines` for one download attempt: at most one per interval,
+/// and a last one at 100% once the download completes, after which the attempt emits no more.
+#[derive(Debug)]
+struct ProgressEvents {
+    interval: Option<Duration>,
+    /// When the last event was emitted, if any.
+    last: Option<Instant>,
+    done: bool,
+}
+
+impl ProgressEvents {
+    fn new(interval: Option<Duration>) -> ProgressEvents {
+        ProgressEvents { interval, last: None, done: false }
+    }
+
+    /// Returns the percentage to emit for a status line at `now`, if an event is due. `percent` is what the line
+    /// printed, if anything, and `seeding` whether it says the download finished.
+    fn due(&mut self, percent: Option<f64>, seeding: bool, now: Instant) -> Option<f64> {
+        let interval = self.interval?;
+        if self.done {
+            return None;
+        }
+        let complete = seeding || percent.is_some_and(|percent| percent >= 100.0);
+        if !complete && self.last.is_some_and(|last| now.duration_since(last) < interval) {
+            return None;
+        }
+        self.last = Some(now);
+        self.done = complete;
+        if complete { Some(100.0) } else { percent }
+    }
+
+    fn update(&mut self, source: &TorrentSource, progress: Option<&Progress>, seeding: bool) {
+        let Some(percent) = self.due(progress.map(|progress| progress.percent), seeding, Instant::now()) else { return };
+        let info_hash = match source {
+            TorrentSource::Magnet(info) => Some(info.info_hash.as_str()),
+            _ => None,
+        };
+        let down_rate = progress.and_then(Progress::rate_bytes_per_sec).map(|rate| rate as u64);
+        let peers = progress.and_then(|progress| progress.peers);
+        log::emit_progress(
+            format!("[{}] {:.1}%", source, percent),
+            json!({"infohash": info_hash, "magnet": source.to_string(), "percent": percent, "down_rate": down_rate, "peers": peers}),
+        );
+    }
+}
+
+/// Downloads a torrent using the first valid SOCKS5 proxy found, with the downloader selected by `options.backend`.
+/// It runs the downloader (`transmission-cli` by default) and logs both `stdout` and `stderr` to the console in real-time,
+/// prefixing every line with the torrent's name so the output of parallel downloads stays readable.
+///
+/// # Arguments
+///
+/// * `source` - The magnet link, `.torrent` file, or `.torrent` URL to be downloaded.
+/// * `options` - The download directory, speed limits, and seeding targets.
+/// * `proxy` - The SOCKS5 proxy to use for the download, or `None` to download directly.
+/// * `task` - The caller's download slot and progress bar.
+///
+/// # Returns
+///
+/// The `DownloadOutcome` if the downloader exited successfully or was stopped after reaching the seeding target,
+/// otherwise the `DownloadError` that occurred.
+/// If speed limits were requested and the downloader rejected them, the error says so explicitly.
+/// With `options.log_dir` set, the captured output is also appended to a per-torrent log file.
+pub async fn download_torrent(
+    source: &TorrentSource,
+    options: &DownloadOptions,
+    proxy: Option<&Socks5Proxy>,
+    task: &mut DownloadTask,
+) -> Result<DownloadOutcome, DownloadError> {
+    options.backend.downloader().download(source, options, proxy, task).await
+}
+
+/// Runs one download attempt with `downloader` and writes its log file; this is what `Downloader::download` does.
+/// With `options.bandwidth`, the downloader runs with its share of the total limit, and is run again whenever its
+/// share changes; see `BandwidthShare`. Each run is written to the log file.
+pub(crate) async fn run_download<D: Downloader + ?Sized>(
+    downloader: &D,
+    source: &TorrentSource,
+    options: &DownloadOptions,
+    proxy: Option<&Socks5Proxy>,
+    task: &mut DownloadTask,
+) -> Result<DownloadOutcome, DownloadError> {
+    loop {
+        if shutdown_requested() {
+            return Err(DownloadError::Interrupted);
+        }
+        let share = options.bandwidth.as_ref().map(|bandwidth| bandwidth.join(options.download_limit));
+        let shared;
+        let options = match &share {
+            Some(share) => {
+                shared = DownloadOptions { download_limit: Some(share.limit()), ..options.clone() };
+                &shared
+            }
+            None => options,
+        };
+        let mut transcript = Transcript::default();
+        let started = time::OffsetDateTime::now_utc();
+        let result = run_downloader(downloader, source, options, proxy, task, share.as_ref(), &mut transcript).await;
+        if let Some(log_dir) = &options.log_dir {
+            let path = log_dir.join(log_file_name(source));
+            if let Err(e) = write_download_log(&path, source, proxy, started, &transcript, &result) {
+                emit(
+                    Level::Warn,
+                    "download_log_error",
+                    format!("[{}] Could not write download log {}: {}", source, path.display(), e),
+                    json!({"magnet": source.to_string(), "file": path, "error": e.to_string()}),
+                );
+            }
+        }
+        if !matches!(result, Err(DownloadError::Reshared(_))) {
+            return result;
+        }
+    }
+}
+
+/// The longest subdirectory name `torrent_subdir` returns, in bytes; file systems commonly allow 255.
+const MAX_SUBDIR_LEN: usize = 200;
+
+/// Returns the directory a torrent is saved into: `download_dir` itself, or with `subdir_per_torrent`,
+/// the subdirectory of `download_dir` named by `torrent_subdir`.
+pub fn torrent_dir(download_dir: &Path, source: &TorrentSource, subdir_per_torrent: bool) -> PathBuf {
+    if subdir_per_torrent {
+        download_dir.join(torrent_subdir(source))
+    } else {
+        download_dir.to_path_buf()
+    }
+}
+
+/// Returns the name of the subdirectory a torrent is saved into with `--subdir-per-torrent`: the display
+/// name of a magnet link, or its info hash without one, or the file name of a `.torrent` file or URL.
+/// Path separators and control characters are replaced with `_`, leading dots are dropped so that the
+/// directory is neither hidden nor `..`, and names longer than `MAX_SUBDIR_LEN` bytes are shortened.
+pub fn torrent_subdir(source: &TorrentSource) -> String {
+    let name = match source {
+        TorrentSource::Magnet(info) => info.display_name.clone().unwrap_or_else(|| info.info_hash.clone()),
+        TorrentSource::File(path) => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+        TorrentSource::Url(url) => url.rsplit('/').next().unwrap_or_default().trim_end_matches(".torrent").to_string(),
+    };
+    let name: String = name.chars().map(|c| if matches!(c, '/' | '\\') || c.is_control() { '_' } else { c }).collect();
+    let mut name = name.trim().trim_start_matches('.').trim_start();
+    while name.len() > MAX_SUBDIR_LEN {
+        let mut end = MAX_SUBDIR_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = name[..end].trim_end();
+    }
+    if name.is_empty() { "torrent".to_string() } else { name.to_string() }
+}
+
+/// The output of a single downloader run, kept for the per-torrent log file.
+#[derive(Debug, Default)]
+struct Transcript {
+    /// Every output line, prefixed with the stream it came from.
+    output: String,
+    /// The exit status, once the downloader has exited.
+    exit_status: Option<ExitStatus>,
+}
+
+impl Transcript {
+    fn record(&mut self, stream: &str, line: &str) {
+        self.output.push_str(&format!("[{}] {}\n", stream, line));
+    }
+}
+
+/// Returns the log file name for a torrent: its info hash for magnet links, or a sanitized
+/// version of the file or URL name otherwise.
+fn log_file_name(source: &TorrentSource) -> String {
+    let name = match source {
+        TorrentSource::Magnet(info) => info.info_hash.clone(),
+        TorrentSource::File(path) => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+        TorrentSource::Url(url) => url.rsplit('/').next().unwrap_or_default().trim_end_matches(".torrent").to_string(),
+    };
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    let name = name.trim_start_matches('.');
+    format!("{}.log", if name.is_empty() { "torrent" } else { name })
+}
+
+/// Appends one attempt to a per-torrent log file: a header with the proxy and exit status,
+/// followed by everything the downloader printed.
+fn write_download_log(
+    path: &Path,
+    source: &TorrentSource,
+    proxy: Option<&Socks5Proxy>,
+    started: time::OffsetDateTime,
+    transcript: &Transcript,
+    result: &Result<DownloadOutcome, DownloadError>,
+) -> io::Result<()> {
+    let started = started
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default();
+    let exit_status = match transcript.exit_status {
+        Some(status) => status.code().map_or_else(|| status.to_string(), |code| code.to_string()),
+        None => "none".to_string(),
+    };
+    let outcome = match result {
+        Ok(outcome) if outcome.stats_available => format!("completed, {} bytes in {} s", outcome.bytes, outcome.duration.as_secs()),
+        Ok(_) => "completed".to_string(),
+        Err(e) => format!("failed: {}", e),
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "Torrent: {}", source)?;
+    writeln!(file, "Proxy: {}", proxy.map_or_else(|| "none".to_string(), |proxy| proxy.to_string()))?;
+    writeln!(file, "Started: {}", started)?;
+    writeln!(file, "Exit status: {}", exit_status)?;
+    writeln!(file, "Result: {}", outcome)?;
+    writeln!(file)?;
+    file.write_all(transcript.output.as_bytes())?;
+    writeln!(file)?;
+    Ok(())
+}
+
+/// Which of the downloader's output streams a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn name(self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Sends every non-empty line of one of the downloader's output streams to `lines`, tagged with the stream,
+/// until the stream closes or cannot be read. Downloaders redraw their status line with `\r`, so lines end
+/// at either `\r` or `\n`, and each one is sent as soon as it ends.
+async fn forward_lines(source: String, stream: OutputStream, output: impl AsyncRead + Unpin, lines: UnboundedSender<(OutputStream, String)>) {
+    let mut reader = tokio::io::BufReader::new(output);
+    let mut line = Vec::new();
+    // Sends the line gathered so far, returning `false` once the download is over and nobody is listening anymore.
+    let send = |line: &mut Vec<u8>| {
+        let text = String::from_utf8_lossy(line).trim_end().to_string();
+        line.clear();
+        text.is_empty() || lines.send((stream, text)).is_ok()
+    };
+    loop {
+        match reader.fill_buf().await {
+            Ok([]) => {
+                send(&mut line);
+                return;
+            }
+            Ok(buffer) => {
+                let (taken, ended) = match buffer.iter().position(|&byte| byte == b'\r' || byte == b'\n') {
+                    Some(end) => {
+                        line.extend_from_slice(&buffer[..end]);
+                        (end + 1, true)
+                    }
+                    None => {
+                        line.extend_from_slice(buffer);
+                        (buffer.len(), false)
+                    }
+                };
+                reader.consume(taken);
+                if ended && !send(&mut line) {
+                    return;
+                }
+            }
+            Err(err) => {
+                emit(
+                    Level::Warn,
+                    "transmission_output_error",
+                    format!("[{}] Error reading {}: {}", source, stream.name(), err),
+                    json!({"magnet": source, "stream": stream.name(), "error": err.to_string()}),
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Runs the downloader for a single download attempt, echoing its output and recording it in `transcript`.
+/// With `share`, the downloader is stopped with `DownloadError::Reshared` once its share of the total limit changes,
+/// and leaves the share once it seeds.
+async fn run_downloader<D: Downloader + ?Sized>(
+    downloader: &D,
+    source: &TorrentSource,
+    options: &DownloadOptions,
+    proxy: Option<&Socks5Proxy>,
+    task: &mut DownloadTask,
+    share: Option<&ShareGuard>,
+    transcript: &mut Transcript,
+) -> Result<DownloadOutcome, DownloadError> {
+    let message = match proxy {
+        Some(proxy) => format!("Starting download for {} via proxy {}", source, proxy),
+        None => format!("Starting download for {} without a proxy", source),
+    };
+    emit(Level::Info, "download_start", message, json!({"magnet": source.to_string(), "proxy": proxy.map(|proxy| proxy.to_string())}));
+
+    let mut command = downloader.command(source, options, proxy)?;
+    if options.subdir_per_torrent {
+        std::fs::create_dir_all(options.torrent_dir(source)).map_err(DownloadError::Io)?;
+    }
+    let mut child = command
+        .stderr(Stdio::piped())  // Captures the stderr stream.
+        .stdout(Stdio::piped())  // Captures the stdout stream.
+        .spawn()
+        .map_err(DownloadError::Io)?;
+    let _tracked = track_child(child.id());  // Lets a Ctrl-C send SIGTERM to the downloader.
+    let listed = metrics::track_download(source, proxy);  // Lists the download at `--metrics-port` until it ends.
+
+    // The first complaint about an unknown option, used to explain a failure when speed limits were set.
+    let mut unsupported_option: Option<String> = None;
+    // The first complaint about the proxy, which tells a proxy that cannot be used apart from other failures.
+    let mut proxy_error: Option<String> = None;
+
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stderr = child.stderr.take().expect("Failed to capture stderr");
+
+    // Both streams are read concurrently and their lines funneled into one channel, so that a downloader
+    // filling one pipe while batch_tor waits on the other cannot stall.
+    let (lines, mut output) = mpsc::unbounded_channel();
+    let readers = [
+        tokio::spawn(forward_lines(source.to_string(), OutputStream::Stdout, stdout, lines.clone())),
+        tokio::spawn(forward_lines(source.to_string(), OutputStream::Stderr, stderr, lines)),
+    ];
+
+    // transmission-cli seeds until it is stopped, so the downloader is stopped here once the seeding target is reached.
+    let mut seeding_since: Option<Instant> = None;
+    let mut stopped_seeding = false;
+
+    // When the download last made progress, in milliseconds since the attempt started, for `--stall-timeout`.
+    let attempt_started = Instant::now();
+    let last_progress = AtomicU64::new(0);
+    let mut best_percent = 0.0;
+    let mut stats = TransferStats::new();
+    let mut progress_events = ProgressEvents::new(options.progress_events);
+    let mut echoable = 0;  // The lines that `options.output` could have echoed so far.
+    let mut echo = |line: &str| {
+        echoable += 1;
+        options.output.shows(line, echoable - 1)
+    };
+
+    // Streams the output until the downloader closes both pipes, is stopped after seeding, or `--download-timeout` runs out.
+    let stream_output = async {
+        while let Some((stream, line)) = output.recv().await {
+            if unsupported_option.is_none() && is_unsupported_option_message(&line) {
+                unsupported_option = Some(line.clone());
+            }
+            if proxy_error.is_none() && proxy.is_some() && is_proxy_error_message(&line) {
+                proxy_error = Some(line.trim().to_string());
+            }
+            transcript.record(stream.name(), &line);
+            if stream == OutputStream::Stderr {
+                if !echo(&line) {
+                    continue;
+                }
+                emit(
+                    Level::Debug,
+                    "transmission_output",
+                    format!("[{}] {}", source, line),
+                    json!({"magnet": source.to_string(), "stream": "stderr", "line": line}),
+                );
+                continue;
+            }
+            let line = line.as_str();
+            let progress = downloader.progress(line);
+            let seeding = downloader.seeding_ratio(line);
+            if let Some(progress) = &progress {
+                listed.progress(progress);
+                stats.progress(progress);
+                if progress.percent > best_percent {
+                    best_percent = progress.percent;
+                    last_progress.store(attempt_started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                }
+            }
+            if progress.is_some() || seeding.is_some() {
+                progress_events.update(source, progress.as_ref(), seeding.is_some());
+            }
+            if let (Some(bar), Some(progress)) = (&task.bar, &progress) {
+                bar.set_position(progress.percent.clamp(0.0, 100.0) as u64);
+                bar.set_message(progress.rate.clone().unwrap_or_default());
+            }
+            // With a progress bar, status lines are shown by the bar rather than echoed.
+            if (task.bar.is_none() || (progress.is_none() && seeding.is_none())) && echo(line) {
+                emit(
+                    Level::Debug,
+                    "transmission_output",
+                    format!("[{}] {}", source, line),
+                    json!({"magnet": source.to_string(), "stream": "stdout", "line": line}),
+                );
+            }
+
+            let Some(ratio) = seeding else { continue };
+            last_progress.store(attempt_started.elapsed().as_millis() as u64, Ordering::Relaxed);  // Seeding never stalls.
+            stats.seeding();
+            let since = *seeding_since.get_or_insert_with(|| {
+                emit(
+                    Level::Info,
+                    "seeding_start",
+                    format!("[{}] Download finished, seeding", source),
+                    json!({"magnet": source.to_string()}),
+                );
+                Instant::now()
+            });
+            if let Some(bar) = &task.bar {
+                bar.set_position(100);
+                bar.set_message(format!("seeding, ratio {:.2}", ratio));
+            }
+            drop(task.slot.take());
+            if let Some(share) = share {
+                share.leave();  // Seeding only uploads, so the other downloads get the share.
+            }
+            if options.seeding_done(ratio, since.elapsed()) {
+                emit(
+                    Level::Info,
+                    "seeding_stop",
+                    format!("[{}] Stopping after seeding for {} s at ratio {:.2}", source, since.elapsed().as_secs(), ratio),
+                    json!({"magnet": source.to_string(), "ratio": ratio, "seeded_ms": since.elapsed().as_millis() as u64}),
+                );
+                child.start_kill().map_err(DownloadError::Io)?;
+                stopped_seeding = true;
+                break;
+            }
+        }
+        Ok::<(), DownloadError>(())
+    };
+    // Completes once the download has run for `--download-timeout`, not counting the time `--pause-on-metered` kept
+    // it stopped, which is checked every `PAUSE_POLL_INTERVAL`.
+    let timeout = async {
+        let Some(limit) = options.download_timeout else { return std::future::pending().await };
+        let (mut deadline, mut checked) = (attempt_started + limit, Instant::now());
+        loop {
+            let now = Instant::now();
+            if metered::paused() {
+                deadline += now - checked;
+            }
+            checked = now;
+            if now >= deadline {
+                return DownloadError::TimedOut(limit);
+            }
+            tokio::time::sleep_until(deadline.min(now + PAUSE_POLL_INTERVAL).into()).await;
+        }
+    };
+    // Completes once the download has gone `--stall-timeout` without progress.
+    let stall = async {
+        let Some(window) = options.stall_timeout else { return std::future::pending().await };
+        loop {
+            let deadline = attempt_started + Duration::from_millis(last_progress.load(Ordering::Relaxed)) + window;
+            if Instant::now() >= deadline && metered::paused() {
+                last_progress.store(attempt_started.elapsed().as_millis() as u64, Ordering::Relaxed);  // A paused download is not stalled.
+                continue;
+            }
+            if Instant::now() >= deadline {
+                return DownloadError::Stalled(window);
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    };
+    // Completes once the share of `--total-download-limit` of the download has changed.
+    let reshare = async {
+        match share {
+            Some(share) => DownloadError::Reshared(share.reshared().await),
+            None => std::future::pending().await,
+        }
+    };
+    let mut stopped = None;
+    tokio::select! {
+        result = stream_output => result?,
+        e = timeout => stopped = Some(e),
+        e = stall => stopped = Some(e),
+        e = reshare => stopped = Some(e),
+    }
+    if let Some(reason) = &stopped {
+        match reason {
+            _ if seeding_since.is_some() => {}
+            DownloadError::Reshared(limit) => emit(
+                Level::Info,
+                "download_reshared",
+                format!("[{}] Restarting the download at {} KB/s, its new share of --total-download-limit", source, limit),
+                json!({"magnet": source.to_string(), "download_limit_kbps": limit}),
+            ),
+            DownloadError::TimedOut(limit) => emit(
+                Level::Warn,
+                "download_timeout",
+                format!("[{}] Download did not finish within {} min; stopping it", source, limit.as_secs() / 60),
+                json!({"magnet": source.to_string(), "timeout_ms": limit.as_millis() as u64}),
+            ),
+            _ => emit(
+                Level::Warn,
+                "download_stalled",
+                format!("[{}] Download {}; stopping it", source, reason),
+                json!({"magnet": source.to_string(), "proxy": proxy.map(|proxy| proxy.to_string()), "percent": best_percent}),
+            ),
+        }
+        stop_child(&mut child).await;
+    }
+
+    let status = child.wait().await.map_err(DownloadError::Io)?;  // Waits for the downloader to finish.
+    for reader in readers {
+        reader.abort();  // A process the downloader started may still hold its pipes open.
+    }
+    transcript.exit_status = Some(status);
+    // Once seeding has started the data is complete, so exiting, or being stopped, while seeding is still a success.
+    if stopped_seeding || seeding_since.is_some() {
+        Ok(stats.outcome(proxy))
+    } else if let Some(reason) = stopped {
+        Err(reason)
+    } else if shutdown_requested() {
+        // Downloaders exit cleanly on SIGTERM, but the download did not finish.
+        Err(DownloadError::Interrupted)
+    } else if status.success() {
+        Ok(stats.outcome(proxy))
+    } else {
+        match unsupported_option {
+            Some(message) if options.has_speed_limits() => Err(DownloadError::UnsupportedSpeedLimit(message)),
+            _ => match proxy_error {
+                Some(message) => Err(DownloadError::Proxy(status, message)),
+                None => Err(DownloadError::Exit(status)),
+            },
+        }
+    }
+}
+
+/// How often a download with a `--download-timeout` checks whether `--pause-on-metered` paused it, which stops its clock.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a downloader is given to exit after SIGTERM before it is killed.
+pub const STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Stops a downloader: SIGTERM first so it can save its state, then SIGKILL if it has not exited in time.
+async fn stop_child(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        terminate(pid);
+    }
+    if tokio::time::timeout(STOP_GRACE_PERIOD, child.wait()).await.is_err() {
+        let _ = child.start_kill();
+    }
+}
+
+/// The delay before the first retry of a failed download; it doubles with every further attempt.
+pub const RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest delay between two download attempts.
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Downloads a torrent, retrying up to `max_retries` times if the downloader exits unsuccessfully, times out, or stalls.
+/// Each attempt uses the next proxy from the pool as it is when the attempt starts, so proxies added to or removed
+/// from the pool meanwhile take effect on the next attempt. Attempts are separated by an exponential backoff.
+/// A download that stalls is resumed right away through the next proxy instead; these rotations do not count
+/// against `max_retries`, but at most one per proxy in the pool is made, and they are counted in `task.rotations`.
+/// Data downloaded by a failed attempt stays in the download directory, so the next attempt resumes from it.
+/// With `options.recheck_before_download`, the proxy of every attempt is checked again first; see `recheck_proxy`.
+/// While `--pause-on-metered` pauses the downloads, the next attempt waits for them to resume.
+/// With a cap on the torrents each proxy of the pool may serve, the torrent fails with `DownloadError::ProxiesExhausted`
+/// once no proxy it may use is left, unless `options.exhausted_policy` finds another one; see `claim_proxy`.
+/// Errors starting the downloader itself are not retried, since another attempt would fail the same way,
+/// and nothing is retried once a shutdown has been requested.
+///
+/// # Arguments
+///
+/// * `source` - The magnet link, `.torrent` file, or `.torrent` URL to be downloaded.
+/// * `options` - The download directory, speed limits, and seeding targets.
+/// * `pool` - The valid proxies to choose from; when empty, the torrent is downloaded directly without a proxy.
+///   A pool may change while the download runs, but must not be emptied, which would switch to direct downloads.
+/// * `first` - The index in `pool` of the proxy used for the first attempt.
+/// * `max_retries` - How many times a failed download is retried.
+/// * `task` - The caller's download slot, released once the torrent starts seeding, and progress bar.
+///
+/// # Returns
+///
+/// The outcome of the attempt that succeeded, with its proxy (`None` without a pool), or `DownloadError::RetriesExhausted` if every attempt failed.
+pub async fn download_with_retries(
+    source: &TorrentSource,
+    options: &DownloadOptions,
+    pool: &ProxyPool,
+    first: usize,
+    max_retries: u32,
+    task: &mut DownloadTask,
+) -> Result<DownloadOutcome, DownloadError> {
+    let mut attempt = 0;
+    let mut used = Vec::new();  // The proxies the torrent went through, which count once against the cap.
+    loop {
+        metered::wait_while_paused().await;
+        if shutdown_requested() {
+            return Err(DownloadError::Interrupted);
+        }
+        let proxies = pool.snapshot();
+        let proxy = match proxies.is_empty() {
+            true => None,
+            false => Some(claim_proxy(source, options, pool, first + attempt as usize + task.rotations as usize, &mut used).await?),
+        };
+        let proxy = match (proxy, &options.recheck) {
+            (Some(proxy), Some(recheck)) if options.recheck_before_download => Some(recheck_proxy(source, recheck, pool, proxy, &mut used, task).await),
+            (proxy, _) => proxy,
+        };
+        let result = download_torrent(source, options, proxy.as_ref(), task).await;
+        if let (Some(proxy), Some(recheck)) = (&proxy, &options.recheck) {
+            match &result {
+                Ok(_) => recheck.failed.prove(proxy),
+                Err(e @ DownloadError::Proxy(..)) if !shutdown_requested() => replace_failed_proxy(source, recheck, pool, proxy, e, &mut used).await,
+                Err(_) => {}
+            }
+        }
+        match result {
+            Ok(outcome) => return Ok(outcome),
+            Err(DownloadError::Interrupted) => return Err(DownloadError::Interrupted),
+            Err(e @ DownloadError::Stalled(_)) if (task.rotations as usize) < proxies.len() && !shutdown_requested() => {
+                let proxy = proxy.expect("Rotations require a proxy pool");
+                task.rotations += 1;
+                let next = pool.peek(first + attempt as usize + task.rotations as usize, &used);
+                let shown = next.as_ref().map_or_else(|| "the next proxy".to_string(), |next| next.to_string());
+                emit(
+                    Level::Warn,
+                    "proxy_rotated",
+                    format!("[{}] {} via {}; resuming via {}", source, e, proxy, shown),
+                    json!({"magnet": source.to_string(), "proxy": proxy.to_string(), "next": next.map(|next| next.to_string()), "rotations": task.rotations}),
+                );
+            }
+            Err(e @ (DownloadError::Exit(_) | DownloadError::Proxy(..) | DownloadError::TimedOut(_) | DownloadError::Stalled(_))) if attempt < max_retries && !shutdown_requested() => {
+                let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempt.min(16)).min(RETRY_MAX_DELAY);
+                attempt += 1;
+                emit(
+                    Level::Warn,
+                    "download_retry",
+                    format!("[{}] Attempt {} failed: {}; retrying in {} s ({} of {})", source, attempt, e, delay.as_secs(), attempt, max_retries),
+                    json!({"magnet": source.to_string(), "proxy": proxy.as_ref().map(|proxy| proxy.to_string()), "attempt": attempt, "error": e.to_string(), "delay_ms": delay.as_millis() as u64}),
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = wait_for_shutdown() => return Err(DownloadError::Interrupted),
+                }
+            }
+            Err(e) if max_retries == 0 => return Err(e),
+            Err(e) => return Err(DownloadError::RetriesExhausted { attempts: attempt + 1, last: Box::new(e) }),
+        }
+    }
+}
+
+/// Picks the proxy at `index` in the pool for an attempt; see `ProxyPool::pick`. Once every proxy served as many
+/// torrents as the pool allows, `ExhaustedPolicy::Revalidate` checks the proxies of `options.recheck.candidates`
+/// that are not in the pool and adds the first to pass to it, one download at a time; otherwise, or if none
+/// passes, the torrent fails.
+async fn claim_proxy(source: &TorrentSource, options: &DownloadOptions, pool: &ProxyPool, index: usize, used: &mut Vec<Socks5Proxy>) -> Result<Socks5Proxy, DownloadError> {
+    if let Some(proxy) = pool.pick(index, used) {
+        return Ok(proxy);
+    }
+    let max_uses = pool.max_uses().unwrap_or(usize::MAX);
+    let recheck = match (&options.recheck, options.exhausted_policy) {
+        (Some(recheck), ExhaustedPolicy::Revalidate) => recheck,
+        _ => return Err(DownloadError::ProxiesExhausted(max_uses)),
+    };
+
+    let _refill = pool.lock_refill().await;
+    // Another download may have added a proxy while this one waited.
+    if let Some(proxy) = pool.pick(index, used) {
+        return Ok(proxy);
+    }
+    let current = pool.snapshot();
+    let candidates: Vec<Socks5Proxy> = recheck.candidates.iter().filter(|proxy| !current.contains(proxy) && pool.available(proxy, used)).cloned().collect();
+    if candidates.is_empty() {
+        return Err(DownloadError::ProxiesExhausted(max_uses));
+    }
+    emit(
+        Level::Info,
+        "proxies_exhausted",
+        format!("[{}] Every proxy reached --max-downloads-per-proxy ({}); checking {} more proxies", source, max_uses, candidates.len()),
+        json!({"magnet": source.to_string(), "max_downloads": max_uses, "candidates": candidates.len()}),
+    );
+    let bar = Arc::new(ProgressBar::hidden());
+    let found = find_valid_proxies(candidates, bar, recheck.max_concurrency, None, recheck.options.clone(), Strategy::First, 1).await.pop();
+    let Some(proxy) = found.map(|result| result.proxy) else {
+        return Err(DownloadError::ProxiesExhausted(max_uses));
+    };
+    let mut proxies = pool.snapshot();
+    proxies.push(proxy.clone());
+    pool.replace(proxies);
+    metrics::set_proxies_in_use(&pool.snapshot());
+    pool.claim(&proxy, used);
+    emit(Level::Info, "proxy_added", format!("[{}] Added proxy {} to the pool", source, proxy), json!({"magnet": source.to_string(), "proxy": proxy.to_string()}));
+    Ok(proxy)
+}
+
+/// Replaces `proxy`, which passed the check but could not be used by the downloader (`error`), for
+/// `--max-proxy-retries`: leaves it out of the pool for the rest of the run, and looks for the first proxy of
+/// `recheck.candidates` that is not in the pool, was not left out, and passes the check, to take its place, so that
+/// the next attempt goes through it. A proxy that completed a download in this run is kept, as is the last proxy of
+/// the pool when no replacement passes, since an empty pool would mean downloading without a proxy.
+async fn replace_failed_proxy(
+    source: &TorrentSource,
+    recheck: &ProxyRecheck,
+    pool: &ProxyPool,
+    proxy: &Socks5Proxy,
+    error: &DownloadError,
+    used: &mut Vec<Socks5Proxy>,
+) {
+    let _refill = pool.lock_refill().await;
+    if recheck.failed.contains(proxy) {
+        return;  // Another download already left it out.
+    }
+    let Some(search) = recheck.failed.leave_out(proxy) else { return };
+    let current: Vec<Socks5Proxy> = pool.snapshot().into_iter().filter(|other| other != proxy).collect();
+    let candidates: Vec<Socks5Proxy> =
+        recheck.candidates.iter().filter(|other| *other != proxy && !current.contains(other) && !recheck.failed.contains(other) && pool.available(other, used)).cloned().collect();
+    emit(
+        Level::Warn,
+        "proxy_blacklisted",
+        format!(
+            "[{}] The download via {} failed ({}); leaving the proxy out and checking {} more proxies ({} of --max-proxy-retries {})",
+            source,
+            proxy,
+            error,
+            candidates.len(),
+            search,
+            recheck.failed.max_searches,
+        ),
+        json!({"magnet": source.to_string(), "proxy": proxy.to_string(), "error": error.to_string(), "candidates": candidates.len(), "search": search}),
+    );
+    let bar = Arc::new(ProgressBar::hidden());
+    let found = find_valid_proxies(candidates, bar, recheck.max_concurrency, None, recheck.options.clone(), Strategy::First, 1).await.pop();
+    let Some(next) = found.map(|result| result.proxy) else {
+        if !current.is_empty() {
+            pool.replace(current);
+            metrics::set_proxies_in_use(&pool.snapshot());
+            return;
+        }
+        emit(
+            Level::Warn,
+            "proxy_search_failed",
+            format!("[{}] No other proxy passes the check; keeping {}", source, proxy),
+            json!({"magnet": source.to_string(), "proxy": proxy.to_string()}),
+        );
+        return;
+    };
+    let mut proxies = current;
+    proxies.push(next.clone());
+    pool.replace(proxies);
+    metrics::set_proxies_in_use(&pool.snapshot());
+    pool.claim(&next, used);
+    emit(Level::Info, "proxy_added", format!("[{}] Added proxy {} to the pool", source, next), json!({"magnet": source.to_string(), "proxy": next.to_string()}));
+}
+
+/// Checks the proxy picked for an attempt again. If it fails, the first other proxy of the pool, or else of
+/// `recheck.candidates`, that the torrent may use and that passes the check takes its place in the pool, and the
+/// switch is counted in `task.recheck_switches`. If none passes, the attempt goes ahead through the proxy picked,
+/// and fails or is retried as usual.
+async fn recheck_proxy(
+    source: &TorrentSource,
+    recheck: &ProxyRecheck,
+    pool: &ProxyPool,
+    proxy: Socks5Proxy,
+    used: &mut Vec<Socks5Proxy>,
+    task: &mut DownloadTask,
+) -> Socks5Proxy {
+    if check_proxy(proxy.clone(), &recheck.options).await.is_some() {
+        return proxy;
+    }
+
+    let current = pool.snapshot();
+    let mut candidates: Vec<Socks5Proxy> = current.iter().filter(|other| **other != proxy).cloned().collect();
+    candidates.extend(recheck.candidates.iter().filter(|other| **other != proxy && !current.contains(other)).cloned());
+    candidates.retain(|other| pool.available(other, used));
+    let bar = Arc::new(ProgressBar::hidden());
+    let found = find_valid_proxies(candidates, bar, recheck.max_concurrency, None, recheck.options.clone(), Strategy::First, 1).await.pop();
+    let Some(next) = found.map(|result| result.proxy) else {
+        emit(
+            Level::Warn,
+            "proxy_recheck_failed",
+            format!("[{}] Proxy {} failed its re-check and no other proxy passes; trying it anyway", source, proxy),
+            json!({"magnet": source.to_string(), "proxy": proxy.to_string()}),
+        );
+        return proxy;
+    };
+
+    // The pool is read again, since other downloads or a reload may have changed it during the search.
+    let mut proxies: Vec<Socks5Proxy> = pool.snapshot().into_iter().filter(|other| *other != proxy).collect();
+    if !proxies.contains(&next) {
+        proxies.push(next.clone());
+    }
+    pool.replace(proxies);
+    metrics::set_proxies_in_use(&pool.snapshot());
+    pool.claim(&next, used);
+    task.recheck_switches += 1;
+    emit(
+        Level::Warn,
+        "proxy_switched",
+        format!("[{}] Proxy {} failed its re-check; switching to {}", source, proxy, next),
+        json!({"magnet": source.to_string(), "proxy": proxy.to_string(), "next": next.to_string(), "switches": task.recheck_switches}),
+    );
+    next
+}
+
+/// Returns where a torrent was already downloaded to, if its content is complete in `download_dir`.
+/// Only magnet links with a display name can be matched, since the name is what transmission-cli saves
+/// the content under. Content is complete when it exists and transmission-cli left no `.part` files in it.
+pub fn find_completed_download(source: &TorrentSource, download_dir: &Path) -> Option<PathBuf> {
+    let TorrentSource::Magnet(info) = source else { return None };
+    let name = info.display_name.as_deref()?;
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+    let path = download_dir.join(name);
+    if download_dir.join(format!("{}.part", name)).exists() {
+        return None;
+    }
+    if path.is_file() || (path.is_dir() && !contains_partial_files(&path)) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Returns whether a directory contains any `.part` file, at any depth. Unreadable entries count as partial.
+fn contains_partial_files(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else { return true };
+    entries.into_iter().any(|entry| {
+        let Ok(entry) = entry else { return true };
+        let path = entry.path();
+        if path.is_dir() {
+            contains_partial_files(&path)
+        } else {
+            path.extension().is_some_and(|extension| extension == "part")
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subdir(line: &str) -> String {
+        torrent_subdir(&TorrentSource::parse(line).unwrap().unwrap())
+    }
+
+    #[test]
+    fn sampled_output_keeps_keyword_lines() {
+        assert_eq!(parse_log_sample("1/100"), Ok(100));
+        assert!(parse_log_sample("2/100").is_err());
+        assert!(parse_log_sample("1/0").is_err());
+
+        let filter = OutputFilter { sample: Some(3), keyword: Some(Regex::new("(?i)error").unwrap()) };
+        let shown: Vec<_> = (0..7).filter(|&index| filter.shows(if index == 4 { "Tracker error" } else { "Progress" }, index)).collect();
+        assert_eq!(shown, [0, 3, 4, 6]);
+        let keyword_only = OutputFilter { sample: None, ..filter };
+        assert!(!keyword_only.shows("Progress", 0));
+        assert!(OutputFilter::default().shows("Progress", 1));
+    }
+
+    #[test]
+    fn subdirectories_are_safe_names() {
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(subdir(&format!("{}&dn=Some%20Album%20%2F%201999", magnet)), "Some Album _ 1999");
+        assert_eq!(subdir(&format!("{}&dn=..%2F..%2Fetc", magnet)), "_.._etc");
+        assert_eq!(subdir(magnet), "0123456789abcdef0123456789abcdef01234567");
+        assert_eq!(subdir(&format!("{}&dn={}", magnet, "é".repeat(150))).len(), MAX_SUBDIR_LEN);
+    }
+
+    #[tokio::test]
+    async fn output_lines_end_at_carriage_returns_too() {
+        let (lines, mut output) = mpsc::unbounded_channel();
+        let stdout: &[u8] = b"Progress: 1.0%\rProgress: 2.0%\r\nlog line\n\npartial";
+        forward_lines("test".to_string(), OutputStream::Stdout, stdout, lines).await;
+        let mut received = Vec::new();
+        while let Some((_, line)) = output.recv().await {
+            received.push(line);
+        }
+        assert_eq!(received, ["Progress: 1.0%", "Progress: 2.0%", "log line", "partial"]);
+    }
+
+    #[test]
+    fn progress_events_are_spaced_and_end_at_completion() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut events = ProgressEvents::new(Some(Duration::from_secs(2)));
+        assert_eq!(events.due(Some(10.0), false, at(0)), Some(10.0));
+        assert_eq!(events.due(Some(20.0), false, at(1)), None);
+        assert_eq!(events.due(Some(30.0), false, at(2)), Some(30.0));
+        assert_eq!(events.due(None, true, at(2)), Some(100.0));
+        assert_eq!(events.due(Some(100.0), false, at(9)), None);
+        assert_eq!(ProgressEvents::new(None).due(Some(10.0), false, at(0)), None);
+    }
+
+    #[test]
+    fn errors_keep_their_messages_and_causes() {
+        let error = DownloadError::RetriesExhausted { attempts: 3, last: Box::new(DownloadError::TimedOut(Duration::from_secs(600))) };
+        assert_eq!(error.to_string(), "gave up after 3 attempts: timed out after 10 min");
+        let cause = std::error::Error::source(&error).map(|cause| cause.to_string());
+        assert_eq!(cause.as_deref(), Some("timed out after 10 min"));
+    }
+
+    #[test]
+    fn proxies_that_completed_a_download_are_never_left_out() {
+        let failed = FailedProxies::new(2);
+        let (a, b, c) = (Socks5Proxy::parse("10.0.0.1:1080").unwrap(), Socks5Proxy::parse("10.0.0.2:1080").unwrap(), Socks5Proxy::parse("10.0.0.3:1080").unwrap());
+        failed.prove(&a);
+        assert_eq!((failed.leave_out(&a), failed.leave_out(&b), failed.leave_out(&c)), (None, Some(1), Some(2)));
+        assert_eq!(failed.leave_out(&Socks5Proxy::parse("10.0.0.4:1080").unwrap()), None);
+        assert!(!failed.contains(&a) && failed.contains(&b) && failed.contains(&c));
+        assert!(is_proxy_error_message("[tracker] Couldn't connect to proxy 127.0.0.1:1080"));
+        assert!(is_proxy_error_message("errorCode=1 Proxy connection failed"));
+        assert!(!is_proxy_error_message("No space left on device"));
+    }
+}
@@ -0,0 +1,83 @@
+//! The `--file-filter` and `||want=` selection of the files downloaded within a torrent.
+
+use crate::size::Value;
+use crate::source::TorrentSource;
+use glob::Pattern;
+
+/// The separator of the `||want=` suffix of a magnet link, e.g. `magnet:?xt=... ||want=*.mkv,*.srt`.
+const WANTED_FILES_PREFIX: &str = "||want=";
+
+/// Parses a `--file-filter` glob pattern such as `*.mkv`. A `*` also matches the `/` between directories.
+pub fn parse_file_pattern(pattern: &str) -> Result<Pattern, String> {
+    Pattern::new(pattern).map_err(|e| format!("`{}` is not a glob pattern: {}", pattern, e))
+}
+
+/// Splits the `||want=` suffix off a line of a magnet file, returning the rest of the line and the comma-separated
+/// patterns of the suffix, or an error naming the first pattern that is not a glob pattern.
+pub fn split_wanted_files(line: &str) -> Result<(&str, Vec<Pattern>), String> {
+    let Some((rest, wanted)) = line.split_once(WANTED_FILES_PREFIX) else { return Ok((line, Vec::new())) };
+    let patterns = wanted.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()).map(parse_file_pattern).collect::<Result<_, _>>()?;
+    Ok((rest.trim_end(), patterns))
+}
+
+/// The patterns of the files to download within `source`: those of the `||want=` suffix of a magnet link, or else
+/// `file_filter`. All of its files are downloaded if empty.
+pub fn wanted_files<'a>(source: &'a TorrentSource, file_filter: &'a [Pattern]) -> &'a [Pattern] {
+    match source {
+        TorrentSource::Magnet(info) if !info.wanted_files.is_empty() => &info.wanted_files,
+        _ => file_filter,
+    }
+}
+
+/// The paths of the files of a `.torrent` file, in the order the downloaders number them: the `name` of a
+/// single-file torrent, or the `path` of every entry of its `files` list, joined with `/`. `None` if the file
+/// list cannot be read, e.g. for a torrent that only has a BitTorrent v2 `file tree`.
+pub fn torrent_files(torrent: &[u8]) -> Option<Vec<String>> {
+    let (value, _) = Value::parse(torrent, 0)?;
+    let info = value.get(b"info")?;
+    let text = |value: &Value| match value {
+        Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    };
+    match info.get(b"files") {
+        Some(Value::List(files)) => files
+            .iter()
+            .map(|file| match file.get(b"path") {
+                Some(Value::List(parts)) => parts.iter().map(text).collect::<Option<Vec<_>>>().map(|parts| parts.join("/")),
+                _ => None,
+            })
+            .collect(),
+        Some(_) => None,
+        None => info.get(b"name").and_then(text).map(|name| vec![name]),
+    }
+}
+
+/// Returns the files among `files` that match any of `patterns`, each with its 1-based index, as `aria2c
+/// --select-file` expects.
+pub fn select_files<'a>(files: &'a [String], patterns: &[Pattern]) -> Vec<(usize, &'a str)> {
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| patterns.iter().any(|pattern| pattern.matches(file)))
+        .map(|(index, file)| (index + 1, file.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_files_are_numbered_from_one() {
+        let multi = b"d4:infod5:filesld6:lengthi100e4:pathl3:Sub5:a.mkveed6:lengthi23e4:pathl5:b.nfoeed6:lengthi5e4:pathl5:c.srteee4:name1:deee";
+        let files = torrent_files(multi).unwrap();
+        assert_eq!(files, ["Sub/a.mkv", "b.nfo", "c.srt"]);
+        let (line, patterns) = split_wanted_files("magnet:?xt=urn:btih:abc ||want=*.mkv, *.srt").unwrap();
+        assert_eq!(line, "magnet:?xt=urn:btih:abc");
+        assert_eq!(select_files(&files, &patterns), [(1, "Sub/a.mkv"), (3, "c.srt")]);
+
+        assert_eq!(torrent_files(b"d4:infod6:lengthi1e4:name5:a.isoee").unwrap(), ["a.iso"]);
+        assert!(split_wanted_files("magnet:?xt=urn:btih:abc ||want=[").is_err());
+        assert_eq!(split_wanted_files("a.torrent").unwrap(), ("a.torrent", Vec::new()));
+    }
+}
@@ -0,0 +1,122 @@
+//! The `--proxy-country` filter, which looks up the country of each proxy in a MaxMind GeoLite2 database.
+
+use crate::log::{emit, Level};
+use crate::proxy::Socks5Proxy;
+use clap::ValueEnum;
+use maxminddb::{geoip2, MaxMindDbError, Reader};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// What `--geo-unknown` does with proxies whose country cannot be determined.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoUnknown {
+    /// Keep them.
+    Allow,
+    /// Skip them.
+    #[default]
+    Deny,
+}
+
+/// Parses a two-letter ISO 3166-1 country code such as `DE`, in either case, into upper case.
+pub fn parse_country_code(code: &str) -> Result<String, String> {
+    let code = code.trim();
+    if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(code.to_ascii_uppercase())
+    } else {
+        Err(format!("`{}` is not a two-letter country code", code))
+    }
+}
+
+/// The reasons the country filter cannot be set up.
+#[derive(Debug, Error)]
+pub enum GeoIpError {
+    /// `--proxy-country` was given without `--geoip-db`.
+    #[error("--proxy-country requires a --geoip-db database")]
+    MissingDatabase,
+    /// The database could not be read.
+    #[error("could not open GeoIP database {}: {}", .0.display(), .1)]
+    Open(PathBuf, #[source] MaxMindDbError),
+}
+
+/// The countries proxies may be in, and what to do with those whose country is unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CountryPolicy {
+    /// Upper-case two-letter country codes.
+    pub countries: Vec<String>,
+    pub unknown: GeoUnknown,
+}
+
+impl CountryPolicy {
+    /// Whether a proxy in `country`, or in an unknown country for `None`, may be used.
+    pub fn allows(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(country) => self.countries.iter().any(|allowed| allowed == country),
+            None => self.unknown == GeoUnknown::Allow,
+        }
+    }
+}
+
+/// A GeoLite2 (or GeoIP2) Country or City database together with the countries to keep.
+#[derive(Debug)]
+pub struct GeoFilter {
+    reader: Reader<Vec<u8>>,
+    policy: CountryPolicy,
+}
+
+impl GeoFilter {
+    /// Opens the `.mmdb` database at `path`.
+    pub fn open(path: &Path, policy: CountryPolicy) -> Result<GeoFilter, GeoIpError> {
+        let reader = Reader::open_readfile(path).map_err(|e| GeoIpError::Open(path.to_path_buf(), e))?;
+        Ok(GeoFilter { reader, policy })
+    }
+
+    /// The country code of the proxy's address, if the database knows it. Proxies given by host name have no country,
+    /// since they are not resolved.
+    pub fn country(&self, proxy: &Socks5Proxy) -> Option<String> {
+        let address: IpAddr = proxy.host.parse().ok()?;
+        let record = self.reader.lookup(address).ok()?.decode::<geoip2::Country>().ok()??;
+        record.country.iso_code.or(record.registered_country.iso_code).map(str::to_string)
+    }
+
+    /// Removes the proxies outside the allowed countries from the list, reporting the country of each proxy in verbose mode.
+    pub fn filter(&self, proxies: &mut Vec<Socks5Proxy>) {
+        proxies.retain(|proxy| {
+            let country = self.country(proxy);
+            let allowed = self.policy.allows(country.as_deref());
+            let shown = country.as_deref().unwrap_or("unknown country");
+            emit(
+                Level::Debug,
+                "proxy_country",
+                if allowed { format!("Proxy {} is in {}", proxy, shown) } else { format!("Skipping proxy {} in {}", proxy, shown) },
+                json!({"proxy": proxy.to_string(), "country": country, "allowed": allowed}),
+            );
+            allowed
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn country_codes_are_two_letters() {
+        assert_eq!(parse_country_code("de").as_deref(), Ok("DE"));
+        assert!(parse_country_code("DEU").is_err());
+        assert!(parse_country_code("D1").is_err());
+    }
+
+    #[test]
+    fn unknown_countries_follow_the_policy() {
+        let mut policy = CountryPolicy { countries: vec!["DE".to_string(), "NL".to_string()], unknown: GeoUnknown::Deny };
+        assert!(policy.allows(Some("NL")));
+        assert!(!policy.allows(Some("US")));
+        assert!(!policy.allows(None));
+        policy.unknown = GeoUnknown::Allow;
+        assert!(policy.allows(None));
+    }
+}
@@ -0,0 +1,100 @@
+//! The `--on-complete` command, run after every torrent of the batch finishes.
+
+use crate::download::DownloadError;
+use crate::log::{emit, Level};
+use crate::source::TorrentSource;
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use thiserror::Error;
+use tokio::process::Command;
+
+/// Why the `--on-complete` command failed.
+#[derive(Debug, Error)]
+pub enum HookError {
+    /// The shell could not be started or waited on.
+    #[error("could not run the command: {0}")]
+    Io(#[source] io::Error),
+    /// The command exited unsuccessfully; carries what it printed to stderr.
+    #[error("the command exited with {}{}", .0, colon_prefixed(.1))]
+    Exit(ExitStatus, String),
+}
+
+/// `": text"`, or nothing if `text` is empty, for appending an optional detail to a message.
+fn colon_prefixed(text: &str) -> String {
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", text)
+    }
+}
+
+/// Runs `command` with `sh -c` for a torrent that finished, with the torrent described by environment variables:
+///
+/// * `BATCH_TOR_MAGNET` - The magnet link as it appeared in the input file, or the `.torrent` path or URL.
+/// * `BATCH_TOR_INFOHASH` - The info hash of a magnet link, and empty for `.torrent` files.
+/// * `BATCH_TOR_DIR` - The directory the torrent was saved into.
+/// * `BATCH_TOR_STATUS` - `downloaded`, or `failed` if every attempt failed.
+///
+/// The command's output is not echoed, so that it does not break the progress bars; its stdout is reported at debug level.
+pub async fn run_hook(command: &str, source: &TorrentSource, dir: &Path, downloaded: bool) -> Result<(), HookError> {
+    let (link, info_hash) = match source {
+        TorrentSource::Magnet(info) => (info.link.clone(), info.info_hash.as_str()),
+        TorrentSource::File(path) => (path.display().to_string(), ""),
+        TorrentSource::Url(url) => (url.clone(), ""),
+    };
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("BATCH_TOR_MAGNET", link)
+        .env("BATCH_TOR_INFOHASH", info_hash)
+        .env("BATCH_TOR_DIR", dir)
+        .env("BATCH_TOR_STATUS", if downloaded { "downloaded" } else { "failed" })
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(HookError::Io)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        emit(Level::Debug, "hook_output", format!("[{}] --on-complete printed: {}", source, stdout.trim_end()), json!({"magnet": source.to_string(), "output": stdout}));
+    }
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(HookError::Exit(output.status, String::from_utf8_lossy(&output.stderr).trim().to_string()))
+    }
+}
+
+/// Runs the `--on-complete` command for the `result` of a torrent, and reports a failure without stopping the batch.
+/// Interrupted downloads did not finish, so the command is not run for them.
+pub(crate) async fn on_complete(command: &str, source: &TorrentSource, dir: &Path, result: &Result<impl Sized, DownloadError>) {
+    if matches!(result, Err(DownloadError::Interrupted)) {
+        return;
+    }
+    if let Err(e) = run_hook(command, source, dir, result.is_ok()).await {
+        emit(
+            Level::Warn,
+            "hook_failed",
+            format!("[{}] The --on-complete command failed: {}", source, e),
+            json!({"magnet": source.to_string(), "command": command, "error": e.to_string()}),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_torrent_is_described_by_environment_variables() {
+        let source = TorrentSource::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=A").unwrap().unwrap();
+        let check = r#"test "$BATCH_TOR_INFOHASH" = 0123456789abcdef0123456789abcdef01234567 && test "$BATCH_TOR_DIR" = /tmp/A \
+            && test "$BATCH_TOR_STATUS" = failed && test "${BATCH_TOR_MAGNET#magnet:}" != "$BATCH_TOR_MAGNET""#;
+        assert!(run_hook(check, &source, Path::new("/tmp/A"), false).await.is_ok());
+
+        let failure = run_hook("echo oops >&2; exit 3", &source, Path::new("/tmp"), true).await.unwrap_err();
+        assert!(matches!(&failure, HookError::Exit(status, stderr) if status.code() == Some(3) && stderr == "oops"), "{}", failure);
+    }
+}
@@ -0,0 +1,169 @@
+//! The `--journal` of finished torrents, used to resume an interrupted batch.
+
+use crate::download::{DownloadError, DownloadOutcome};
+use crate::log::{emit, Level};
+use crate::source::TorrentSource;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The outcome of a torrent, as recorded in the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalStatus {
+    /// The torrent was downloaded.
+    Downloaded,
+    /// Every attempt to download the torrent failed.
+    Failed,
+}
+
+/// One line of the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// The info hash of a magnet link, or the path or URL of a `.torrent` file.
+    pub torrent: String,
+    pub status: JournalStatus,
+    /// The proxy the torrent was downloaded through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Why the download failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the torrent finished, in seconds since the Unix epoch.
+    pub finished_at: u64,
+}
+
+/// Returns the key a torrent is recorded under: its info hash for magnet links, so that the same torrent
+/// is recognized even if its link changes, or its path or URL otherwise.
+pub fn journal_key(source: &TorrentSource) -> String {
+    match source {
+        TorrentSource::Magnet(info) => info.info_hash.clone(),
+        TorrentSource::File(path) => path.display().to_string(),
+        TorrentSource::Url(url) => url.clone(),
+    }
+}
+
+/// Reads the keys of the torrents a journal records as downloaded. A missing journal is empty, and lines
+/// that cannot be parsed, such as one cut short by a crash, are skipped with a warning.
+pub fn read_downloaded(path: &Path) -> io::Result<HashSet<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut downloaded = HashSet::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) if entry.status == JournalStatus::Downloaded => {
+                downloaded.insert(entry.torrent);
+            }
+            Ok(_) => {}
+            Err(e) => emit(
+                Level::Warn,
+                "journal_skipped",
+                format!("Skipping line {} of journal {}: {}", index + 1, path.display(), e),
+                json!({"file": path, "line": index + 1, "error": e.to_string()}),
+            ),
+        }
+    }
+    Ok(downloaded)
+}
+
+/// An open journal that finished torrents are appended to, one JSON object per line.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal for appending, creating it if needed; with `reset`, its previous entries are discarded.
+    pub fn open(path: &Path, reset: bool) -> io::Result<Journal> {
+        let mut options = OpenOptions::new();
+        if reset {
+            options.write(true).create(true).truncate(true);
+        } else {
+            options.read(true).append(true).create(true);
+        }
+        let mut file = options.open(path)?;
+        // A line cut short by a crash is terminated, so that the next entry starts on a line of its own.
+        if !reset && file.metadata()?.len() > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+        Ok(Journal { path: path.to_path_buf(), file: Mutex::new(file) })
+    }
+
+    /// Appends the outcome of a torrent. Interrupted downloads are not recorded, since they did not fail for good.
+    /// A failure to write is reported but does not stop the batch.
+    pub fn record(&self, source: &TorrentSource, result: &Result<DownloadOutcome, DownloadError>) {
+        let (status, proxy, error) = match result {
+            Ok(outcome) => (JournalStatus::Downloaded, outcome.proxy.as_ref().map(|proxy| proxy.to_string()), None),
+            Err(DownloadError::Interrupted) => return,
+            Err(e) => (JournalStatus::Failed, None, Some(e.to_string())),
+        };
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+        let entry = JournalEntry { torrent: journal_key(source), status, proxy, error, finished_at };
+
+        // The line is written with a single call, so a crash cannot interleave it with another entry.
+        let mut line = serde_json::to_string(&entry).expect("Journal entries always serialize");
+        line.push('\n');
+        let mut file = self.file.lock().expect("Journal lock poisoned");
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|()| file.sync_data()) {
+            emit(
+                Level::Warn,
+                "journal_error",
+                format!("Could not write to journal {}: {}", self.path.display(), e),
+                json!({"file": self.path, "error": e.to_string()}),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Socks5Proxy;
+    use std::time::Duration;
+
+    fn magnet(hash: &str) -> TorrentSource {
+        TorrentSource::parse(&format!("magnet:?xt=urn:btih:{}", hash)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn downloaded_entries_survive_a_cut_off_line() {
+        let path = std::env::temp_dir().join(format!("batch_tor-journal-{}.jsonl", std::process::id()));
+        let (done, failed, later) =
+            (magnet("0123456789abcdef0123456789abcdef01234567"), magnet("1123456789abcdef0123456789abcdef01234567"), magnet("2123456789abcdef0123456789abcdef01234567"));
+        let outcome = |proxy| DownloadOutcome { proxy, bytes: 0, avg_speed: 0.0, duration: Duration::ZERO, success: true, stats_available: false };
+        let proxy = Socks5Proxy::parse("127.0.0.1:1080").unwrap();
+
+        let journal = Journal::open(&path, true).unwrap();
+        journal.record(&done, &Ok(outcome(Some(proxy))));
+        journal.record(&failed, &Err(DownloadError::Exit(std::os::unix::process::ExitStatusExt::from_raw(256))));
+        journal.record(&later, &Err(DownloadError::Interrupted));
+        drop(journal);
+
+        // Simulates a crash in the middle of writing an entry.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"torrent\":\"21").unwrap();
+        Journal::open(&path, false).unwrap().record(&later, &Ok(outcome(None)));
+
+        let downloaded = read_downloaded(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(downloaded, HashSet::from([journal_key(&done), journal_key(&later)]));
+    }
+}
@@ -0,0 +1,108 @@
+//! The `--leak-test`, which checks that the selected proxies hide the IP address of this machine.
+
+use crate::log::{emit, Level};
+use crate::proxy::{error_chain, Socks5Proxy};
+use clap::ValueEnum;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The IP-echo service queried by `--leak-test` unless `--ip-echo-url` names another one. It answers with the
+/// address the request came from, as plain text.
+pub const DEFAULT_IP_ECHO_URL: &str = "https://api.ipify.org";
+
+/// What `--leak-policy` does when a proxy shows the IP address of this machine.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeakPolicy {
+    /// Warn, and download through the proxy anyway.
+    Warn,
+    /// Stop before downloading anything.
+    #[default]
+    Abort,
+}
+
+/// Why the IP address seen by the IP-echo service could not be learned.
+#[derive(Debug, Error)]
+pub enum LeakError {
+    /// The service could not be reached.
+    #[error("{}", error_chain(.0))]
+    Http(#[source] reqwest::Error),
+    /// The service answered with an error status.
+    #[error("the IP-echo service answered {0}")]
+    Status(reqwest::StatusCode),
+    /// The answer was not an IP address; carries the start of it.
+    #[error("the IP-echo service answered `{0}`, not an IP address")]
+    Invalid(String),
+}
+
+/// Parses the answer of an IP-echo service: an IP address, alone on its line.
+pub fn parse_echoed_ip(body: &str) -> Result<IpAddr, LeakError> {
+    let body = body.trim();
+    body.parse().map_err(|_| LeakError::Invalid(body.chars().take(40).collect()))
+}
+
+/// Asks the IP-echo service at `url` which address the request came from, through `proxy`, or directly without one.
+pub async fn echoed_ip(url: &str, proxy: Option<&Socks5Proxy>, timeout: Duration) -> Result<IpAddr, LeakError> {
+    let mut builder = Client::builder().timeout(timeout);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.url(true)).map_err(LeakError::Http)?);
+    }
+    let response = builder.build().map_err(LeakError::Http)?.get(url).send().await.map_err(LeakError::Http)?;
+    if !response.status().is_success() {
+        return Err(LeakError::Status(response.status()));
+    }
+    parse_echoed_ip(&response.text().await.map_err(LeakError::Http)?)
+}
+
+/// Runs the `--leak-test` for every proxy in `proxies`: learns the public address of this machine, then the one
+/// seen through each proxy, and returns the proxies that show the same address. A test that cannot compare the
+/// addresses, because one of them could not be learned, is reported with a warning and passes.
+pub(crate) async fn find_leaks(url: &str, proxies: &[Socks5Proxy], timeout: Duration) -> Vec<Socks5Proxy> {
+    let own = match echoed_ip(url, None, timeout).await {
+        Ok(own) => own,
+        Err(e) => {
+            emit(
+                Level::Warn,
+                "leak_test_inconclusive",
+                format!("Leak test skipped: could not learn the IP address of this machine from {}: {}", url, e),
+                json!({"url": url, "error": e.to_string()}),
+            );
+            return Vec::new();
+        }
+    };
+    let mut leaks = Vec::new();
+    for proxy in proxies {
+        match echoed_ip(url, Some(proxy), timeout).await {
+            Ok(seen) if seen == own => leaks.push(proxy.clone()),
+            Ok(seen) => emit(
+                Level::Info,
+                "leak_test_passed",
+                format!("Leak test passed: proxy {} shows {}, not the address of this machine.", proxy, seen),
+                json!({"proxy": proxy.to_string(), "ip": seen.to_string()}),
+            ),
+            Err(e) => emit(
+                Level::Warn,
+                "leak_test_inconclusive",
+                format!("Leak test inconclusive for proxy {}: could not learn the address it shows from {}: {}", proxy, url, e),
+                json!({"proxy": proxy.to_string(), "url": url, "error": e.to_string()}),
+            ),
+        }
+    }
+    leaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echoed_addresses_are_parsed() {
+        assert_eq!(parse_echoed_ip("203.0.113.7\n").unwrap(), "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_echoed_ip(" 2001:db8::1 ").unwrap(), "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_echoed_ip("<html>blocked</html>").unwrap_err().to_string(), "the IP-echo service answered `<html>blocked</html>`, not an IP address");
+    }
+}
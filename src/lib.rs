@@ -0,0 +1,1607 @@
+//! BatchTor downloads batches of torrents through SOCKS5 (or HTTP/SOCKS4) proxies.
+//!
+//! The library exposes the individual building blocks — reading inputs, checking proxies,
+//! and downloading torrents with `transmission-cli` — as well as `run`, which drives a whole
+//! batch from a `BatchTorConfig` exactly like the `batch_tor` binary does.
+
+pub mod backend;
+pub mod bandwidth;
+pub mod blocklist;
+pub mod cache;
+pub mod chain;
+pub mod config;
+pub mod download;
+pub mod files;
+pub mod geoip;
+pub mod hook;
+pub mod journal;
+pub mod leak;
+pub mod log;
+pub mod magnet;
+pub mod metalink;
+pub mod metered;
+pub mod metrics;
+pub mod notification;
+pub mod peer_check;
+pub mod preflight;
+pub mod proxy;
+pub mod proxy_source;
+mod prompt;
+mod reload;
+pub mod report;
+pub mod scrape;
+mod selftest;
+pub mod shutdown;
+pub mod size;
+pub mod source;
+pub mod tor;
+pub mod trackers;
+pub mod verify;
+pub mod watch;
+
+pub use backend::{Aria2, Backend, Downloader, Progress, Transmission};
+pub use bandwidth::{BandwidthShare, ShareGuard};
+pub use blocklist::ProxyBlocklist;
+pub use cache::CachePolicy;
+pub use config::{apply_config_file, ConfigError, ConfigFile};
+pub use download::{
+    download_torrent, download_with_retries, find_completed_download, parse_log_sample, torrent_dir, torrent_subdir, DownloadError, DownloadOptions, DownloadOutcome,
+    DownloadTask, ExhaustedPolicy, FailedProxies, OutputFilter, PeerProtocols, ProgressFormat, ProxyRecheck, INCOMPLETE_SUBDIR,
+};
+pub use files::{parse_file_pattern, select_files, split_wanted_files, torrent_files};
+pub use geoip::{parse_country_code, CountryPolicy, GeoFilter, GeoIpError, GeoUnknown};
+pub use hook::{run_hook, HookError};
+pub use journal::{Journal, JournalEntry, JournalStatus};
+pub use leak::{echoed_ip, parse_echoed_ip, LeakError, LeakPolicy, DEFAULT_IP_ECHO_URL};
+pub use log::Verbosity;
+pub use magnet::{canonicalize_magnet, normalize_peer, normalize_tracker, normalize_web_seed, validate_magnet, MagnetError, MagnetInfo};
+pub use metalink::{parse_metalink, MetalinkFile};
+pub use metered::{is_metered, parse_nm_metered, MeteredCheck, MeteredError};
+pub use metrics::MetricsServer;
+pub use notification::{notification_payload, send_webhook, NotifyError};
+pub use peer_check::{connect_to_peer, parse_peer_address, TestProtocol, DEFAULT_BT_TEST_PEER};
+pub use preflight::{check_download_dir, find_in_path, missing_programs, DownloadDirError, MissingProgram};
+pub use proxy::{
+    check_proxy, check_proxy_detailed, extract_socks_proxies, find_valid_proxies, find_valid_proxies_with, parse_proxy_list, read_proxy_file, sample_proxies, shuffle_proxies,
+    CheckError, CheckOptions, FailureKind, ProxyCheck, ProxyChecker, ProxyFormat, ProxyKind, ProxyPool, ProxyRecord, ProxyResult, Socks5Proxy, Strategy, PROXY_CHECK_TIMEOUT,
+};
+pub use proxy_source::{FileProxySource, HttpProxySource, ProxySource, ProxySourceError};
+pub use report::BatchReport;
+pub use scrape::{parse_scrape_response, scrape_seeders, scrape_url, ScrapeError, ScrapeUnknown};
+pub use selftest::SELF_TEST_MAGNET;
+pub use size::{content_length, estimate_sizes, parse_byte_size, torrent_size, SizeEstimate};
+pub use source::{expand_input_patterns, extract_magnet_links, SourceList, TorrentFilter, TorrentSource, STDIN_FILENAME};
+pub use tor::{Tor, TorError};
+pub use trackers::{add_trackers, read_tracker_list};
+
+use crate::cache::{load_download_speeds, load_proxy_cache, record_download_speeds, save_proxy_cache};
+use crate::log::{emit, Level};
+use crate::metered::MeteredWatch;
+use crate::proxy::format_throughput;
+use crate::report::{disk_usage, format_bytes};
+use crate::shutdown::shutdown_requested;
+use crate::watch::MagnetWatcher;
+use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+/// How long downloading the proxy list from `--proxy-url` may take.
+const PROXY_LIST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The paths and options that drive a batch run.
+/// `Default` yields the same values as the command-line defaults.
+#[derive(Debug, Clone)]
+pub struct BatchTorConfig {
+    /// The files containing magnet links and `.torrent` sources, one per line; glob patterns are expanded.
+    pub magnet_files: Vec<String>,
+    /// Which of the listed torrents are downloaded, by name.
+    pub filter: TorrentFilter,
+    /// Only the first this many torrents of the list are processed.
+    pub limit: Option<usize>,
+    /// Skip magnet files that do not exist instead of stopping, as long as one of them can be read.
+    pub continue_on_missing_files: bool,
+    /// A file of tracker URLs added to every magnet link, if any.
+    pub extra_trackers: Option<String>,
+    /// The file containing proxies, one per line.
+    pub socks_file: String,
+    /// A URL the proxy list is downloaded from instead of `socks_file`, if any.
+    pub proxy_url: Option<String>,
+    /// The format of the proxy list.
+    pub proxy_format: ProxyFormat,
+    /// A file of IP addresses and CIDR ranges of proxies that must not be used, if any.
+    pub proxy_blocklist: Option<String>,
+    /// The upper-case two-letter codes of the countries proxies must be in; empty to allow any country.
+    pub proxy_countries: Vec<String>,
+    /// The MaxMind GeoLite2 database `proxy_countries` are looked up in.
+    pub geoip_db: Option<String>,
+    /// Whether proxies whose country cannot be determined are kept when filtering by country.
+    pub geo_unknown: GeoUnknown,
+    /// The directory where the downloaded torrents will be saved.
+    pub download_dir: String,
+    /// The maximum number of proxies checked at the same time.
+    pub max_concurrency: usize,
+    /// The number of proxies checked per wave; without it, every proxy is checked at once.
+    pub check_batch_size: Option<usize>,
+    /// The URLs every proxy must be able to reach.
+    pub check_urls: Vec<String>,
+    /// The maximum time a single proxy check request may take.
+    pub proxy_timeout: Duration,
+    /// How the proxy used for downloading is chosen.
+    pub strategy: Strategy,
+    /// Check the proxies in random order.
+    pub shuffle: bool,
+    /// The seed of the `shuffle` order, for reproducible runs.
+    pub shuffle_seed: Option<u64>,
+    /// Check only a random sample of this many proxies of the list, if set.
+    pub proxy_sample: Option<usize>,
+    /// Check the rest of the list when the `proxy_sample` does not yield enough valid proxies.
+    pub sample_fallback: bool,
+    /// A `--verify-proxies-only` report whose ranking decides the order in which the proxies are checked, if any.
+    pub proxy_order_file: Option<String>,
+    /// How many bytes each valid proxy downloads to measure its throughput, if any.
+    pub warmup_bytes: Option<u64>,
+    /// Check a proxy once more if it failed with a timeout.
+    pub retry_timeouts: bool,
+    /// How many times a proxy is checked before it fails.
+    pub check_attempts: u32,
+    /// What the proxy check tests: the check URLs only, or also a connection to `bt_test_peer`.
+    pub proxy_test_protocol: TestProtocol,
+    /// The `host:port` of the BitTorrent peer that `TestProtocol::Bittorrent` connects to.
+    pub bt_test_peer: String,
+    /// Let the user choose among the working proxies when running in a terminal.
+    pub interactive: bool,
+    /// The maximum number of torrents downloaded at the same time.
+    pub parallel_downloads: usize,
+    /// Look for one distinct valid proxy per parallel download.
+    pub proxy_per_download: bool,
+    /// Check the inputs and proxies without downloading.
+    pub dry_run: bool,
+    /// Check every proxy and report them ranked, instead of downloading.
+    pub verify_proxies_only: bool,
+    /// Instead of the batch, download this magnet link into a temporary directory to test the setup.
+    pub self_test: Option<String>,
+    /// Keep downloading the torrents added to the magnet files until a shutdown is requested.
+    pub watch: bool,
+    /// Ask for confirmation before downloading more torrents than this.
+    pub confirm_threshold: usize,
+    /// Download without asking for confirmation, however many torrents there are.
+    pub yes: bool,
+    /// Emit diagnostics as newline-delimited JSON.
+    pub json: bool,
+    /// How the progress of the running downloads is shown.
+    pub progress_format: ProgressFormat,
+    /// How often a `progress` event is emitted for each download with `ProgressFormat::JsonLines`.
+    pub progress_interval: Duration,
+    /// The JSON file where validated proxies are cached between runs, if any.
+    pub proxy_cache: Option<String>,
+    /// How long a cached proxy validation stays fresh.
+    pub proxy_cache_ttl: Duration,
+    /// Whether the proxies that pass the check are merged into the `proxy_cache` or replace it.
+    pub proxy_cache_policy: CachePolicy,
+    /// How many times a failed download is retried.
+    pub max_retries: u32,
+    /// How many times in the run a proxy that the downloader cannot connect through is left out and replaced by a new search.
+    pub max_proxy_retries: u32,
+    /// The download speed limit in KB/s, if any.
+    pub download_limit: Option<u32>,
+    /// The download speed limit of all the downloads together in KB/s, if any.
+    pub total_download_limit: Option<u32>,
+    /// The upload speed limit in KB/s, if any.
+    pub upload_limit: Option<u32>,
+    /// Never look for peers through DHT.
+    pub disable_dht: bool,
+    /// Never connect to peers over uTP.
+    pub disable_utp: bool,
+    /// Never learn peers through peer exchange.
+    pub disable_pex: bool,
+    /// Keep DHT and uTP, which bypass the proxy, on when downloading through one.
+    pub allow_udp: bool,
+    /// Stop seeding once the upload ratio reaches this value.
+    pub seed_ratio: Option<f64>,
+    /// Stop seeding after this long. With neither seeding target set, torrents stop once downloaded.
+    pub seed_time: Option<Duration>,
+    /// A directory where each torrent's transmission-cli output is saved.
+    pub log_dir: Option<String>,
+    /// Echo only one line of the downloader's output out of this many.
+    pub log_sample: Option<u64>,
+    /// Always echo the downloader's output lines that match; without `log_sample`, echo only those.
+    pub log_keyword: Option<Regex>,
+    /// Download torrents again even if their content is already complete in `download_dir`.
+    pub force: bool,
+    /// Download through Tor instead of the proxy list, launching tor if it is not running.
+    pub tor: bool,
+    /// Download directly, without reading or checking the proxy list.
+    pub no_proxy: bool,
+    /// Chain pairs of proxies from the list and download through the chains; see `chain`.
+    pub proxy_chain: bool,
+    /// Download without a proxy if none of the proxies can be used, instead of stopping.
+    pub fallback_direct: bool,
+    /// Check that the selected proxies hide the IP address of this machine before downloading; see `leak`.
+    pub leak_test: bool,
+    /// What the `leak_test` does with a proxy that shows the IP address of this machine.
+    pub leak_policy: LeakPolicy,
+    /// The IP-echo service queried by the `leak_test`.
+    pub ip_echo_url: String,
+    /// The program that downloads the torrents.
+    pub backend: Backend,
+    /// The `transmission-cli` executable to run instead of the one on `PATH`, if any.
+    pub transmission_path: Option<String>,
+    /// The `aria2c` executable to run instead of the one on `PATH`, if any.
+    pub aria2c_path: Option<String>,
+    /// Start even if the external programs the batch needs are not found on `PATH`.
+    pub skip_preflight: bool,
+    /// Pause the downloads while the network connection is metered; see `metered`.
+    pub pause_on_metered: bool,
+    /// The shell command that tells `pause_on_metered` whether the connection is metered, instead of NetworkManager.
+    pub metered_check_command: Option<String>,
+    /// How much detail is reported.
+    pub verbosity: Verbosity,
+    /// Save every torrent into its own subdirectory of `download_dir`, named after the torrent.
+    pub subdir_per_torrent: bool,
+    /// Keep the partial files of unfinished downloads, so that the next attempt or run resumes them.
+    pub keep_incomplete: bool,
+    /// Where the partial files are kept with `keep_incomplete`; `.incomplete` in `download_dir` by default.
+    pub incomplete_dir: Option<String>,
+    /// The patterns of the files to download within each torrent, unless its magnet link has a `||want=` suffix;
+    /// all of them if empty.
+    pub file_filter: Vec<glob::Pattern>,
+    /// Create `download_dir` if it does not exist.
+    pub create_dir: bool,
+    /// A file where finished torrents are recorded, so that a later run skips those already downloaded.
+    pub journal: Option<String>,
+    /// Discard the entries of `journal` and start over.
+    pub reset_journal: bool,
+    /// A shell command run after each torrent finishes; see `hook::run_hook`.
+    pub on_complete: Option<String>,
+    /// A URL the final report is POSTed to as JSON when the batch finishes.
+    pub notify_webhook: Option<String>,
+    /// Show a desktop notification when the batch finishes.
+    pub notify_desktop: bool,
+    /// How long a single download attempt may run before it is stopped and counted as failed.
+    pub download_timeout: Option<Duration>,
+    /// How long a download may go without progress before it is resumed through the next proxy.
+    pub stall_timeout: Option<Duration>,
+    /// Check the proxy of every download attempt again right before it starts, and switch to another one if it fails.
+    pub recheck_before_download: bool,
+    /// How many torrents each proxy may serve, if that is limited.
+    pub max_downloads_per_proxy: Option<usize>,
+    /// What happens once every proxy served `max_downloads_per_proxy` torrents.
+    pub exhausted_policy: ExhaustedPolicy,
+    /// Report the size of every torrent and their total before the proxies are checked.
+    pub size_preview: bool,
+    /// Stop before downloading anything if the torrents whose size is known take more bytes than this.
+    pub max_total_size: Option<u64>,
+    /// Skip the torrents whose trackers report fewer seeders than this, if set.
+    pub min_seeders: Option<u64>,
+    /// What happens with the torrents whose seeders cannot be counted when `min_seeders` is set.
+    pub scrape_unknown: ScrapeUnknown,
+    /// A file where the final report is written as JSON, if any.
+    pub report: Option<String>,
+    /// The port of the HTTP server that reports the progress of the batch at `/status`, if any.
+    pub metrics_port: Option<u16>,
+}
+
+impl Default for BatchTorConfig {
+    fn default() -> Self {
+        BatchTorConfig {
+            magnet_files: vec!["magnet_links.txt".to_string()],
+            filter: TorrentFilter::default(),
+            limit: None,
+            continue_on_missing_files: false,
+            extra_trackers: None,
+            socks_file: "socks.txt".to_string(),
+            proxy_url: None,
+            proxy_format: ProxyFormat::Auto,
+            proxy_blocklist: None,
+            proxy_countries: Vec::new(),
+            geoip_db: None,
+            geo_unknown: GeoUnknown::Deny,
+            download_dir: "/home/hombre/Torrents".to_string(),
+            max_concurrency: 50,
+            check_batch_size: None,
+            check_urls: vec!["https://rutracker.org".to_string()],
+            proxy_timeout: PROXY_CHECK_TIMEOUT,
+            strategy: Strategy::First,
+            shuffle: false,
+            shuffle_seed: None,
+            proxy_sample: None,
+            sample_fallback: false,
+            proxy_order_file: None,
+            warmup_bytes: None,
+            retry_timeouts: false,
+            check_attempts: 1,
+            proxy_test_protocol: TestProtocol::Http,
+            bt_test_peer: DEFAULT_BT_TEST_PEER.to_string(),
+            interactive: false,
+            parallel_downloads: 1,
+            proxy_per_download: false,
+            dry_run: false,
+            verify_proxies_only: false,
+            self_test: None,
+            watch: false,
+            confirm_threshold: 100,
+            yes: false,
+            json: false,
+            progress_format: ProgressFormat::Bars,
+            progress_interval: Duration::from_secs(1),
+            proxy_cache: None,
+            proxy_cache_ttl: Duration::from_secs(3600),
+            proxy_cache_policy: CachePolicy::Replace,
+            max_retries: 0,
+            max_proxy_retries: 0,
+            download_limit: None,
+            total_download_limit: None,
+            upload_limit: None,
+            disable_dht: false,
+            disable_utp: false,
+            disable_pex: false,
+            allow_udp: false,
+            seed_ratio: None,
+            seed_time: None,
+            log_dir: None,
+            log_sample: None,
+            log_keyword: None,
+            force: false,
+            tor: false,
+            no_proxy: false,
+            fallback_direct: false,
+            leak_test: false,
+            leak_policy: LeakPolicy::Abort,
+            ip_echo_url: DEFAULT_IP_ECHO_URL.to_string(),
+            proxy_chain: false,
+            backend: Backend::Transmission,
+            transmission_path: None,
+            aria2c_path: None,
+            skip_preflight: false,
+            pause_on_metered: false,
+            metered_check_command: None,
+            verbosity: Verbosity::Normal,
+            subdir_per_torrent: false,
+            keep_incomplete: false,
+            incomplete_dir: None,
+            file_filter: Vec::new(),
+            create_dir: false,
+            journal: None,
+            reset_journal: false,
+            on_complete: None,
+            notify_webhook: None,
+            notify_desktop: false,
+            download_timeout: None,
+            stall_timeout: None,
+            recheck_before_download: false,
+            max_downloads_per_proxy: None,
+            exhausted_policy: ExhaustedPolicy::Fail,
+            size_preview: false,
+            max_total_size: None,
+            min_seeders: None,
+            scrape_unknown: ScrapeUnknown::Download,
+            report: None,
+            metrics_port: None,
+        }
+    }
+}
+
+impl BatchTorConfig {
+    /// Whether progress bars are drawn: not in JSON mode, where the only output is the event stream, nor with `-q`,
+    /// nor with `--progress-format json-lines`, whose events replace them.
+    fn shows_progress(&self) -> bool {
+        !self.json && self.verbosity > Verbosity::Quiet && self.progress_format == ProgressFormat::Bars
+    }
+
+    /// Where the proxy list is read from: `proxy_url` if set, or `socks_file`.
+    fn proxy_source(&self) -> Box<dyn ProxySource> {
+        match &self.proxy_url {
+            Some(url) => Box::new(HttpProxySource { url: url.clone(), format: self.proxy_format, timeout: PROXY_LIST_TIMEOUT }),
+            None => Box::new(FileProxySource { path: self.socks_file.clone(), format: self.proxy_format }),
+        }
+    }
+
+    /// The executable of the selected backend given with `--transmission-path` or `--aria2c-path`, if any.
+    pub fn downloader_path(&self) -> Option<&str> {
+        match self.backend {
+            Backend::Transmission => self.transmission_path.as_deref(),
+            Backend::Aria2 => self.aria2c_path.as_deref(),
+        }
+    }
+
+    /// What a proxy must do to pass the check.
+    fn check_options(&self) -> CheckOptions {
+        CheckOptions {
+            check_urls: self.check_urls.clone(),
+            timeout: self.proxy_timeout,
+            warmup_bytes: self.warmup_bytes,
+            retry_timeouts: self.retry_timeouts,
+            attempts: self.check_attempts,
+            download_speeds: Arc::new(self.proxy_cache().map(|path| load_download_speeds(path)).unwrap_or_default()),
+            bt_test_peer: (self.proxy_test_protocol == TestProtocol::Bittorrent).then(|| self.bt_test_peer.clone()),
+        }
+    }
+
+    /// How `pause_on_metered` learns whether the connection is metered.
+    fn metered_check(&self) -> MeteredCheck {
+        match &self.metered_check_command {
+            Some(command) => MeteredCheck::Command(command.clone()),
+            None => MeteredCheck::NetworkManager,
+        }
+    }
+
+    /// The `proxy_cache`, unless proxy chains are used: their relays change on every run, so they are not cached.
+    fn proxy_cache(&self) -> Option<&String> {
+        self.proxy_cache.as_ref().filter(|_| !self.proxy_chain)
+    }
+}
+
+/// The reasons `run` can end unsuccessfully. The details have already been reported through
+/// the diagnostic output by the time `run` returns.
+#[derive(Debug, Error)]
+pub enum RunError {
+    /// External programs the batch needs are not installed.
+    #[error("missing required programs: {}", program_names(.0))]
+    MissingPrograms(Vec<MissingProgram>),
+    /// The download directory is missing or not writable.
+    #[error(transparent)]
+    DownloadDir(DownloadDirError),
+    /// Both the magnet links and the proxies were to be read from standard input.
+    #[error("magnet links and proxies cannot both be read from standard input")]
+    BothFromStdin,
+    /// The magnet links file could not be read.
+    #[error("could not read magnet file: {0}")]
+    MagnetFile(#[source] io::Error),
+    /// The proxy list could not be read or downloaded.
+    #[error("could not read proxy list: {0}")]
+    ProxyList(#[source] ProxySourceError),
+    /// The `--journal` file could not be read or opened.
+    #[error("could not use journal: {0}")]
+    Journal(#[source] io::Error),
+    /// The `--extra-trackers` file could not be read.
+    #[error("could not read extra trackers: {0}")]
+    Trackers(#[source] io::Error),
+    /// The `--proxy-blocklist` file could not be read.
+    #[error("could not read proxy blocklist: {0}")]
+    Blocklist(#[source] io::Error),
+    /// The `--geoip-db` database for `--proxy-country` is missing or could not be read.
+    #[error(transparent)]
+    GeoIp(GeoIpError),
+    /// The magnet files listed no usable torrents.
+    #[error("no torrents to download")]
+    NoTorrents,
+    /// The torrents take more space than `--max-total-size`.
+    #[error("the torrents take at least {total} bytes, more than --max-total-size ({limit} bytes)")]
+    TooLarge { total: u64, limit: u64 },
+    /// The proxy list was empty, or none of its proxies passed the check.
+    #[error("no valid proxies found")]
+    NoValidProxies,
+    /// Some of the torrents could not be downloaded.
+    #[error("{failed} of {total} torrents failed to download")]
+    DownloadsFailed { failed: usize, total: usize },
+    /// The batch was stopped early because a shutdown was requested.
+    #[error("interrupted")]
+    Interrupted,
+    /// Tor mode was requested but tor could not be used.
+    #[error(transparent)]
+    Tor(TorError),
+    /// The `--metrics-port` server could not be started.
+    #[error("could not start the metrics server: {0}")]
+    Metrics(#[source] io::Error),
+    /// The batch was larger than `--confirm-threshold`, and the user declined it or could not be asked.
+    #[error("the batch was not confirmed")]
+    NotConfirmed,
+    /// The magnet files could not be watched for `--watch`.
+    #[error("could not watch the magnet files: {0}")]
+    Watch(#[source] notify::Error),
+    /// The `--leak-test` found proxies that show the IP address of this machine, with `--leak-policy abort`.
+    #[error("the leak test found proxies that show the IP address of this machine: {}", proxy_names(.0))]
+    Leak(Vec<Socks5Proxy>),
+}
+
+/// The proxies of `RunError::Leak`, for its message.
+fn proxy_names(proxies: &[Socks5Proxy]) -> String {
+    proxies.iter().map(|proxy| proxy.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// The names of the missing programs, for `RunError::MissingPrograms`.
+fn program_names(missing: &[MissingProgram]) -> String {
+    missing.iter().map(|program| program.program).collect::<Vec<_>>().join(", ")
+}
+
+impl RunError {
+    /// The exit status of the `batch_tor` binary for this error, so scripts can branch on the outcome:
+    ///
+    /// * `1` - The batch could not start: a missing program, an unusable download directory or journal,
+    ///   a Tor failure, a metrics port that is already in use, a large batch that was not confirmed, or magnet files
+    ///   that cannot be watched.
+    /// * `3` - Some torrents failed to download.
+    /// * `4` - No valid proxy was found.
+    /// * `5` - The inputs were empty or could not be read, or `--proxy-country` was given without a database.
+    /// * `6` - The `--leak-test` found a proxy that shows the IP address of this machine.
+    /// * `130` - The batch was interrupted with Ctrl-C.
+    ///
+    /// A successful run exits with `0`, and invalid command-line arguments with `2`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            RunError::MissingPrograms(_) | RunError::DownloadDir(_) | RunError::Journal(_) | RunError::Tor(_) | RunError::Metrics(_) | RunError::NotConfirmed | RunError::Watch(_) => 1,
+            RunError::DownloadsFailed { .. } => 3,
+            RunError::NoValidProxies => 4,
+            RunError::BothFromStdin | RunError::MagnetFile(_) | RunError::ProxyList(_) | RunError::Trackers(_) | RunError::Blocklist(_) | RunError::GeoIp(_) | RunError::NoTorrents | RunError::TooLarge { .. } => 5,
+            RunError::Leak(_) => 6,
+            RunError::Interrupted => 130,
+        }
+    }
+}
+
+/// Finds a single valid proxy from the list according to the given strategy.
+/// This is `find_valid_proxies` with a count of one.
+///
+/// # Returns
+///
+/// An `Option<ProxyResult>` containing the selected proxy, or `None` if no valid proxies are found.
+pub async fn find_valid_proxy(
+    proxies: Vec<Socks5Proxy>,
+    bar: Arc<ProgressBar>,
+    max_concurrency: usize,
+    batch_size: Option<usize>,
+    options: CheckOptions,
+    strategy: Strategy,
+) -> Option<ProxyResult> {
+    find_valid_proxies(proxies, bar, max_concurrency, batch_size, options, strategy, 1).await.pop()
+}
+
+/// Runs a whole batch: extracts magnet links and proxies, finds valid proxies, and downloads every torrent.
+/// Each magnet link is downloaded in a separate asynchronous task, with at most `parallel_downloads`
+/// running at the same time. A failed download does not abort the batch; a summary is reported at the end.
+///
+/// # Arguments
+///
+/// * `config` - The paths and options for the batch.
+///
+/// # Returns
+///
+/// `Ok(())` if the batch ran to completion (even if some downloads failed), or the `RunError` that stopped it.
+/// With `config.metrics_port` set, the progress of the batch is served over HTTP until it ends.
+pub async fn run(config: &BatchTorConfig) -> Result<(), RunError> {
+    log::set_json_output(config.json);
+    log::set_verbosity(config.verbosity);
+
+    let server = match config.metrics_port {
+        Some(port) => match MetricsServer::start(port).await {
+            Ok(server) => {
+                let address = server.address();
+                emit(Level::Info, "metrics_listening", format!("Serving status at http://{}/status", address), json!({"address": address}));
+                Some(server)
+            }
+            Err(e) => {
+                emit(Level::Error, "metrics_error", format!("Could not listen on port {}: {}", port, e), json!({"port": port, "error": e.to_string()}));
+                return Err(RunError::Metrics(e));
+            }
+        },
+        None => None,
+    };
+    let result = if config.verify_proxies_only {
+        verify::run_verify(config).await
+    } else if let Some(magnet) = &config.self_test {
+        selftest::run_self_test(config, magnet).await
+    } else {
+        run_batch(config).await
+    };
+    metrics::finished();
+    if let Some(server) = server {
+        server.stop().await;
+    }
+    result
+}
+
+/// Runs the batch for `run`, once the metrics server is up.
+async fn run_batch(config: &BatchTorConfig) -> Result<(), RunError> {
+    let started = Instant::now();
+
+    // Checks for the external programs first, so a missing one is reported before any work is done.
+    if !config.skip_preflight {
+        let missing = preflight::preflight(config);
+        if !missing.is_empty() {
+            return Err(RunError::MissingPrograms(missing));
+        }
+    }
+
+    // Checks the download directory up front rather than letting every download fail on it after the proxy checks.
+    if let Err(e) = check_download_dir(Path::new(&config.download_dir), config.create_dir) {
+        emit(Level::Error, "download_dir_error", format!("Cannot use download directory: {}", e), json!({"dir": config.download_dir, "error": e.to_string()}));
+        return Err(RunError::DownloadDir(e));
+    }
+
+    // Standard input can only be read once, so at most one of the lists may come from it.
+    if config.magnet_files.iter().any(|file| file == STDIN_FILENAME) && config.proxy_url.is_none() && config.socks_file == STDIN_FILENAME {
+        emit(
+            Level::Error,
+            "input_error",
+            "Cannot read both --magnet-file and --socks-file from standard input.".to_string(),
+            json!({"file": STDIN_FILENAME, "error": "both inputs read from standard input"}),
+        );
+        return Err(RunError::BothFromStdin);
+    }
+
+    // Pauses the downloads while the connection is metered, until the batch ends.
+    let _metered = match config.pause_on_metered {
+        true => Some(MeteredWatch::start(config.metered_check()).await),
+        false => None,
+    };
+
+    // Extracts magnet links and `.torrent` sources from every magnet file, deduplicated across the files.
+    let magnet_files = expand_input_patterns(&config.magnet_files);
+    let mut sources = SourceList::new();
+    let mut missing = 0;
+    for file in &magnet_files {
+        match sources.read_file(file) {
+            Ok(count) if magnet_files.len() > 1 => {
+                emit(Level::Info, "input_read", format!("Read {} torrents from {}", count, file), json!({"file": file, "count": count}));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound && config.continue_on_missing_files => {
+                emit(Level::Warn, "input_missing", format!("Skipping missing magnet file {}", file), json!({"file": file, "error": e.to_string()}));
+                missing += 1;
+            }
+            Err(e) => {
+                emit(
+                    Level::Error,
+                    "input_error",
+                    format!("Could not read magnet file {}: {}", file, e),
+                    json!({"file": file, "error": e.to_string()}),
+                );
+                return Err(RunError::MagnetFile(e));
+            }
+        }
+    }
+    if missing > 0 && missing == magnet_files.len() {
+        emit(Level::Error, "input_error", "None of the magnet files exist.".to_string(), json!({"files": magnet_files}));
+        return Err(RunError::MagnetFile(io::Error::new(io::ErrorKind::NotFound, "none of the magnet files exist")));
+    }
+    let mut magnet_links = sources.into_sources();
+
+    // Adds the `--extra-trackers` to every magnet link, which helps find peers for links with few or dead trackers.
+    let extra_trackers = match &config.extra_trackers {
+        Some(path) => match trackers::read_tracker_list(path) {
+            Ok(extra) => extra,
+            Err(e) => {
+                emit(Level::Error, "input_error", format!("Could not read extra trackers {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+                return Err(RunError::Trackers(e));
+            }
+        },
+        None => Vec::new(),
+    };
+    if !extra_trackers.is_empty() {
+        emit(
+            Level::Info,
+            "trackers_added",
+            format!("Adding {} extra trackers to every magnet link.", extra_trackers.len()),
+            json!({"count": extra_trackers.len(), "trackers": extra_trackers}),
+        );
+        trackers::add_trackers(&mut magnet_links, &extra_trackers);
+    }
+
+    // With `--watch`, the files are watched from now on, so that torrents added while the first ones download are
+    // found too; the torrents listed so far are known already.
+    let watcher = match config.watch && !config.dry_run {
+        true if config.magnet_files.iter().any(|file| file == STDIN_FILENAME) => {
+            emit(Level::Error, "input_error", "Cannot --watch standard input.".to_string(), json!({"file": STDIN_FILENAME}));
+            return Err(RunError::MagnetFile(io::Error::new(io::ErrorKind::InvalidInput, "standard input cannot be watched")));
+        }
+        true => {
+            let seen = magnet_links.iter().map(journal::journal_key).collect();
+            match MagnetWatcher::start(&config.magnet_files, config.filter.clone(), extra_trackers, seen) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    emit(Level::Error, "watch_error", format!("Cannot watch the magnet files: {}", e), json!({"files": magnet_files, "error": e.to_string()}));
+                    return Err(RunError::Watch(e));
+                }
+            }
+        }
+        false => None,
+    };
+
+    if magnet_links.is_empty() && watcher.is_none() {
+        emit(Level::Error, "no_torrents", "No magnet links or torrent files found.".to_string(), json!({"files": magnet_files}));
+        return Err(RunError::NoTorrents);
+    }
+
+    // Keeps only the torrents selected by `--filter` and `--exclude`.
+    if !config.filter.is_empty() {
+        let listed = magnet_links.len();
+        magnet_links.retain(|link| config.filter.keeps(link));
+        emit(
+            Level::Info,
+            "torrents_filtered",
+            format!("{} of {} torrents match the filter.", magnet_links.len(), listed),
+            json!({"matched": magnet_links.len(), "total": listed}),
+        );
+        if magnet_links.is_empty() && watcher.is_none() {
+            emit(Level::Error, "no_torrents", "No torrents match --filter and --exclude.".to_string(), json!({"files": magnet_files}));
+            return Err(RunError::NoTorrents);
+        }
+    }
+
+    // Keeps only the first `--limit` torrents of the list.
+    if let Some(limit) = config.limit.filter(|&limit| limit < magnet_links.len()) {
+        emit(
+            Level::Info,
+            "torrents_limited",
+            format!("Processing {} of {} magnets (limited).", limit, magnet_links.len()),
+            json!({"limit": limit, "total": magnet_links.len()}),
+        );
+        magnet_links.truncate(limit);
+    }
+
+    // Torrents the journal records as downloaded by a previous run are skipped, unless it is being reset.
+    let journaled = match &config.journal {
+        Some(path) if !config.reset_journal => match journal::read_downloaded(Path::new(path)) {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                emit(Level::Error, "journal_error", format!("Could not read journal {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+                return Err(RunError::Journal(e));
+            }
+        },
+        _ => HashSet::new(),
+    };
+
+    // Skips torrents whose content is already complete in the download directory, unless `--force` is given.
+    let (skipped, magnet_links): (Vec<_>, Vec<_>) = magnet_links.into_iter().partition(|link| {
+        if config.force {
+            return false;
+        }
+        if journaled.contains(&journal::journal_key(link)) {
+            emit(
+                Level::Info,
+                "download_skipped",
+                format!("[{}] Already downloaded according to the journal, skipping", link),
+                json!({"magnet": link.to_string(), "journal": config.journal}),
+            );
+            return true;
+        }
+        let dir = torrent_dir(Path::new(&config.download_dir), link, config.subdir_per_torrent);
+        let Some(path) = find_completed_download(link, &dir) else { return false };
+        emit(
+            Level::Info,
+            "download_skipped",
+            format!("[{}] Already downloaded to {}, skipping", link, path.display()),
+            json!({"magnet": link.to_string(), "path": path}),
+        );
+        true
+    });
+    if magnet_links.is_empty() && !config.dry_run && watcher.is_none() {
+        return finish_report(config, BatchReport { skipped, ..Default::default() }, started).await;
+    }
+
+    // Stops before any proxy is checked if the torrents would take more than `--max-total-size`.
+    if config.size_preview || config.max_total_size.is_some() {
+        let estimate = estimate_sizes(&magnet_links, &config.file_filter, config.size_preview);
+        if let Some(limit) = config.max_total_size.filter(|&limit| estimate.known_bytes > limit) {
+            emit(
+                Level::Error,
+                "size_limit",
+                format!(
+                    "The torrents take at least {}, more than --max-total-size ({}).",
+                    format_bytes(estimate.known_bytes),
+                    format_bytes(limit),
+                ),
+                json!({"bytes": estimate.known_bytes, "limit": limit, "unknown": estimate.unknown}),
+            );
+            return Err(RunError::TooLarge { total: estimate.known_bytes, limit });
+        }
+        if config.max_total_size.is_some() && estimate.unknown > 0 {
+            emit(
+                Level::Warn,
+                "size_unknown",
+                format!("The size of {} torrents is unknown, so --max-total-size only covers the other {}.", estimate.unknown, estimate.known),
+                json!({"unknown": estimate.unknown, "known": estimate.known}),
+            );
+        }
+    }
+
+    // In Tor mode, tor's SOCKS port is the only proxy, so the proxy list is not read or checked.
+    if config.tor {
+        let tor = match Tor::start().await {
+            Ok(tor) => tor,
+            Err(e) => {
+                emit(Level::Error, "tor_error", format!("Could not set up Tor: {}", e), json!({"error": e.to_string()}));
+                return Err(RunError::Tor(e));
+            }
+        };
+        check_leaks(config, &[tor.proxy()]).await?;
+        if config.dry_run {
+            emit(
+                Level::Info,
+                "dry_run",
+                format!("Dry run: parsed {} torrents.\nDry run: would download via Tor at {}.", magnet_links.len(), tor.proxy()),
+                json!({"torrents": magnet_links.len(), "proxies": 1, "proxy": tor.proxy().to_string()}),
+            );
+            return Ok(());
+        }
+        let proxy = tor.proxy();
+        confirm_batch(config, &magnet_links, &format!("via Tor at {}", proxy)).await?;
+        let route = Route { pool: Arc::new(ProxyPool::new(vec![proxy])), tor: Some(Arc::new(tor)), recheck: None, journal: open_journal(config)? };
+        return download_rounds(config, &route, magnet_links, skipped, started, watcher).await;
+    }
+
+    // With `--no-proxy`, the torrents are downloaded directly, so the proxy list is not read or checked either.
+    if config.no_proxy {
+        if config.dry_run {
+            emit(
+                Level::Info,
+                "dry_run",
+                format!("Dry run: parsed {} torrents.\nDry run: would download without a proxy.", magnet_links.len()),
+                json!({"torrents": magnet_links.len(), "proxies": 0, "proxy": null}),
+            );
+            return Ok(());
+        }
+        return download_direct(config, magnet_links, skipped, started, watcher).await;
+    }
+
+    // Extracts SOCKS5 proxies from the specified file.
+    let (source, mut proxies, filters) = load_proxies(config).await?;
+    if config.shuffle {
+        shuffle_proxies(&mut proxies, config.shuffle_seed);
+    }
+    if let Some(path) = &config.proxy_order_file {
+        apply_proxy_order(path, &mut proxies);
+    }
+    if config.proxy_chain {
+        proxies = chain::chain_proxies(config, proxies).await;
+    }
+    if proxies.is_empty() && config.fallback_direct {
+        return fall_back_to_direct(config, "No proxies found", magnet_links, skipped, started, watcher).await;
+    }
+    if proxies.is_empty() {
+        emit(Level::Error, "no_proxies", "No proxies found.".to_string(), json!({"file": source.to_string()}));
+        return Err(RunError::NoValidProxies);
+    }
+    let proxy_count = proxies.len();
+    // Proxies that fail their `--recheck-before-download` check, or that served `--max-downloads-per-proxy`
+    // torrents, are replaced from the whole list.
+    // A proxy that passed the check but fails a download is replaced from it too, `--max-proxy-retries` times in the run.
+    let recheck = ProxyRecheck {
+        options: config.check_options(),
+        candidates: proxies.clone(),
+        max_concurrency: config.max_concurrency,
+        failed: Arc::new(FailedProxies::new(config.max_proxy_retries)),
+    };
+
+    // Sets up a progress bar to track the proxy-checking process.
+    // Its length grows by each list of proxies as its checks start; with `--check-batch-size`, a second bar below it
+    // shows the current wave. Checks finish out of order, but the rate over the whole phase still gives a fair estimate.
+    let bar = proxy_check_bar(config);
+
+    // With `--proxy-per-download`, look for one proxy per download slot; otherwise a single proxy serves every download.
+    // With `--max-downloads-per-proxy`, look for enough proxies to serve every torrent, too.
+    let wanted = if config.proxy_per_download { config.parallel_downloads } else { 1 };
+    let wanted = match config.max_downloads_per_proxy {
+        Some(max) => wanted.max(magnet_links.len().div_ceil(max)),
+        None => wanted,
+    };
+
+    // With `--interactive`, every proxy is checked so that the user can choose among all the working ones.
+    let interactive = config.interactive && !config.json && prompt::can_prompt();
+    if config.interactive && !interactive {
+        emit(
+            Level::Warn,
+            "interactive_unavailable",
+            "Not running in a terminal; choosing the proxy automatically.".to_string(),
+            json!({}),
+        );
+    }
+    let (strategy, search) = if interactive { (Strategy::Fastest, usize::MAX) } else { (config.strategy, wanted) };
+
+    // Proxies validated recently by a previous run, or that torrents were downloaded through, are checked first,
+    // those that downloaded the fastest ahead; the full list is only swept if they do not yield enough valid proxies.
+    let proxy_cache = config.proxy_cache();
+    let cached = match proxy_cache {
+        Some(path) => load_proxy_cache(path, config.proxy_cache_ttl),
+        None => Vec::new(),
+    };
+    let check_options = config.check_options();
+    let speed = |proxy: &Socks5Proxy| check_options.download_speeds.get(&proxy.address()).copied();
+    let (mut cached, rest): (Vec<Socks5Proxy>, Vec<Socks5Proxy>) = proxies.into_iter().partition(|proxy| cached.contains(proxy) || speed(proxy).is_some());
+    cached.sort_by(|a, b| speed(b).unwrap_or(-1.0).total_cmp(&speed(a).unwrap_or(-1.0)));  // A stable sort, so proxies without history keep their order.
+
+    // With `--proxy-sample`, only a random sample of the rest is checked, and the others only with `--sample-fallback`
+    // if the sample does not yield enough valid proxies.
+    let (rest, unsampled) = match config.proxy_sample {
+        Some(size) if size < rest.len() => {
+            emit(
+                Level::Info,
+                "proxy_sample",
+                format!("Checking a random sample of {} of the {} proxies.", size, rest.len()),
+                json!({"sample": size, "proxies": rest.len(), "fallback": config.sample_fallback}),
+            );
+            sample_proxies(rest, size, config.shuffle_seed)
+        }
+        _ => (rest, Vec::new()),
+    };
+
+    // Runs the race to find the valid proxies.
+    let mut valid_proxies = Vec::new();
+    if !cached.is_empty() {
+        emit(
+            Level::Info,
+            "proxy_cache_hit",
+            format!("Checking {} cached proxies first.", cached.len()),
+            json!({"count": cached.len()}),
+        );
+        valid_proxies = find_valid_proxies(
+            cached,
+            Arc::clone(&bar),
+            config.max_concurrency,
+            config.check_batch_size,
+            check_options.clone(),
+            strategy,
+            search,
+        )
+        .await;
+    }
+    let from_cache = valid_proxies.len();
+    if valid_proxies.len() < search {
+        let more = find_valid_proxies(
+            rest,
+            Arc::clone(&bar),
+            config.max_concurrency,
+            config.check_batch_size,
+            check_options,
+            strategy,
+            search - valid_proxies.len(),
+        )
+        .await;
+        valid_proxies.extend(more);
+    }
+    if !unsampled.is_empty() {
+        let from_sample = valid_proxies.len() - from_cache;
+        let missing = search.saturating_sub(valid_proxies.len());
+        valid_proxies.extend(check_unsampled(config, unsampled, &bar, strategy, from_sample, missing).await);
+    }
+    bar.finish();  // Leaves the bar with its final tally once the valid proxies are found or all proxies are checked.
+    log::set_progress(None);
+
+    if let Some(path) = proxy_cache {
+        if let Err(e) = save_proxy_cache(path, &valid_proxies, config.proxy_cache_policy, config.proxy_cache_ttl) {
+            emit(Level::Warn, "proxy_cache_error", format!("Could not write proxy cache {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+        }
+    }
+    if interactive && !shutdown_requested() {
+        valid_proxies.sort_by(ProxyResult::rank);
+        valid_proxies = prompt::choose_proxies(valid_proxies, wanted).await;
+    }
+    if shutdown_requested() {
+        return Err(RunError::Interrupted);
+    }
+
+    if valid_proxies.is_empty() && config.fallback_direct {
+        return fall_back_to_direct(config, "No valid proxies found", magnet_links, skipped, started, watcher).await;
+    }
+    let selected: Vec<Socks5Proxy> = valid_proxies.iter().map(|result| result.proxy.clone()).collect();
+    check_leaks(config, &selected).await?;
+
+    // A dry run stops here: report what was parsed and which proxy would have been used.
+    if config.dry_run {
+        let selected = valid_proxies.first().map(|result| result.proxy.to_string());
+        emit(
+            Level::Info,
+            "dry_run",
+            match &selected {
+                Some(proxy) => format!("Dry run: parsed {} torrents and {} proxies.\nDry run: would download via proxy {}.", magnet_links.len(), proxy_count, proxy),
+                None => format!("Dry run: parsed {} torrents and {} proxies.\nDry run: no valid proxies found.", magnet_links.len(), proxy_count),
+            },
+            json!({"torrents": magnet_links.len(), "proxies": proxy_count, "proxy": selected}),
+        );
+        return if selected.is_some() { Ok(()) } else { Err(RunError::NoValidProxies) };
+    }
+
+    if valid_proxies.is_empty() {
+        emit(Level::Error, "no_valid_proxies", "No valid proxies found.".to_string(), json!({"proxies": proxy_count}));
+        return Err(RunError::NoValidProxies);
+    }
+    for result in &valid_proxies {
+        let mut details = vec![format!("HTTP {}", result.status), format!("{} ms", result.latency.as_millis())];
+        details.extend(result.throughput.map(format_throughput));
+        details.extend(result.download_speed.map(|speed| format!("{} in past downloads", format_throughput(speed))));
+        emit(
+            Level::Info,
+            "proxy_selected",
+            format!("Selected proxy {} ({})", result.proxy, details.join(", ")),
+            json!({
+                "proxy": result.proxy.to_string(),
+                "status": result.status.as_u16(),
+                "latency_ms": result.latency.as_millis() as u64,
+                "throughput_bps": result.throughput.map(|speed| speed as u64),
+                "download_speed_bps": result.download_speed.map(|speed| speed as u64),
+            }),
+        );
+    }
+    if valid_proxies.len() < wanted {
+        emit(
+            Level::Warn,
+            "proxy_shortage",
+            match config.max_downloads_per_proxy {
+                Some(max) => format!(
+                    "Only {} of {} proxies passed the check; with --max-downloads-per-proxy {}, they can serve {} of the {} torrents.",
+                    valid_proxies.len(),
+                    wanted,
+                    max,
+                    (valid_proxies.len() * max).min(magnet_links.len()),
+                    magnet_links.len(),
+                ),
+                None => format!("Only {} of {} proxies passed the check; downloads will share them round-robin.", valid_proxies.len(), wanted),
+            },
+            json!({"found": valid_proxies.len(), "wanted": wanted}),
+        );
+    }
+    let via = match valid_proxies.as_slice() {
+        [only] => format!("via proxy {}", only.proxy),
+        [first, rest @ ..] => format!("via proxy {} and {} more", first.proxy, rest.len()),
+        [] => unreachable!("the batch stops above without a valid proxy"),
+    };
+    confirm_batch(config, &magnet_links, &via).await?;
+    let pool = Arc::new(ProxyPool::with_max_uses(valid_proxies.into_iter().map(|result| result.proxy).collect(), config.max_downloads_per_proxy));
+    let route = Route { pool: Arc::clone(&pool), tor: None, recheck: Some(recheck), journal: open_journal(config)? };
+    // While the torrents download, a SIGHUP reloads the proxy list into the pool.
+    tokio::select! {
+        result = download_rounds(config, &route, magnet_links, skipped, started, watcher) => result,
+        never = reload::reload_on_hangup(config, source.as_ref(), &pool, &filters) => match never {},
+    }
+}
+
+/// Reports whether the `--proxy-sample` yielded the valid proxies, `from_sample` of them, and, with `--sample-fallback`,
+/// looks for the `missing` ones among the `unsampled` proxies of the list.
+async fn check_unsampled(
+    config: &BatchTorConfig,
+    unsampled: Vec<Socks5Proxy>,
+    bar: &Arc<ProgressBar>,
+    strategy: Strategy,
+    from_sample: usize,
+    missing: usize,
+) -> Vec<ProxyResult> {
+    let unchecked = unsampled.len();
+    if shutdown_requested() {
+        return Vec::new();
+    }
+    if missing == 0 {
+        let message = match from_sample {
+            0 => format!("The cached proxies sufficed; the sample and the other {} proxies were not checked.", unchecked),
+            _ => format!("Found {} valid proxies in the sample; the other {} proxies were not checked.", from_sample, unchecked),
+        };
+        emit(Level::Info, "proxy_sample_result", message, json!({"found_by": if from_sample == 0 { "cache" } else { "sample" }, "found": from_sample, "unchecked": unchecked}));
+        return Vec::new();
+    }
+    if !config.sample_fallback {
+        emit(
+            Level::Warn,
+            "proxy_sample_result",
+            format!("The sample yielded {} valid proxies; not checking the other {} proxies without --sample-fallback.", from_sample, unchecked),
+            json!({"found_by": if from_sample == 0 { None } else { Some("sample") }, "found": from_sample, "unchecked": unchecked}),
+        );
+        return Vec::new();
+    }
+    emit(
+        Level::Info,
+        "proxy_sample_fallback",
+        format!("The sample yielded {} valid proxies; checking the other {} proxies (--sample-fallback).", from_sample, unchecked),
+        json!({"found": from_sample, "proxies": unchecked}),
+    );
+    let found = find_valid_proxies(unsampled, Arc::clone(bar), config.max_concurrency, config.check_batch_size, config.check_options(), strategy, missing).await;
+    emit(
+        Level::Info,
+        "proxy_sample_result",
+        format!("The rest of the list yielded {} more valid proxies.", found.len()),
+        json!({"found_by": if found.is_empty() { None } else { Some("fallback") }, "found": from_sample + found.len(), "unchecked": 0}),
+    );
+    found
+}
+
+/// Reorders `proxies` by the ranking of a `--verify-proxies-only` report at `path`, for `--proxy-order-file`.
+/// A ranking that cannot be read leaves the order as it is, with a warning.
+fn apply_proxy_order(path: &str, proxies: &mut Vec<Socks5Proxy>) {
+    let ranking = match verify::read_proxy_order(Path::new(path)) {
+        Ok(ranking) => ranking,
+        Err(e) => {
+            emit(Level::Warn, "proxy_order_error", format!("Could not read proxy ranking {}, keeping the order of the list: {}", path, e), json!({"file": path, "error": e.to_string()}));
+            return;
+        }
+    };
+    let missing = verify::order_proxies(proxies, &ranking);
+    emit(
+        Level::Info,
+        "proxy_order",
+        format!("Checking the {} ranked proxies of {} first, fastest first.", ranking.len() - missing, path),
+        json!({"file": path, "ranked": ranking.len() - missing, "missing": missing}),
+    );
+    if missing > 0 {
+        emit(
+            Level::Debug,
+            "proxy_order_missing",
+            format!("{} proxies of the ranking {} are no longer in the proxy list.", missing, path),
+            json!({"file": path, "missing": missing}),
+        );
+    }
+}
+
+/// Runs the `--leak-test` on the `proxies` selected for the batch, before anything is downloaded through them,
+/// and applies the `--leak-policy` to those that show the IP address of this machine.
+async fn check_leaks(config: &BatchTorConfig, proxies: &[Socks5Proxy]) -> Result<(), RunError> {
+    if !config.leak_test || proxies.is_empty() {
+        return Ok(());
+    }
+    let leaks = leak::find_leaks(&config.ip_echo_url, proxies, config.proxy_timeout).await;
+    if leaks.is_empty() {
+        return Ok(());
+    }
+    let names = proxy_names(&leaks);
+    let fields = |policy| json!({"proxies": leaks.iter().map(|proxy| proxy.to_string()).collect::<Vec<_>>(), "policy": policy});
+    match config.leak_policy {
+        LeakPolicy::Warn => {
+            emit(
+                Level::Warn,
+                "leak_detected",
+                format!("Leak test failed: these proxies show the IP address of this machine, so trackers and peers will see it: {}. Downloading anyway because of --leak-policy warn.", names),
+                fields("warn"),
+            );
+            Ok(())
+        }
+        LeakPolicy::Abort => {
+            emit(Level::Error, "leak_detected", format!("Leak test failed: these proxies show the IP address of this machine: {}. Nothing was downloaded.", names), fields("abort"));
+            Err(RunError::Leak(leaks))
+        }
+    }
+}
+
+/// Downloads the torrents without a proxy, as `--no-proxy` asks.
+async fn download_direct(
+    config: &BatchTorConfig,
+    magnet_links: Vec<TorrentSource>,
+    skipped: Vec<TorrentSource>,
+    started: Instant,
+    watcher: Option<MagnetWatcher>,
+) -> Result<(), RunError> {
+    confirm_batch(config, &magnet_links, "without a proxy").await?;
+    let route = Route { pool: Arc::new(ProxyPool::default()), tor: None, recheck: None, journal: open_journal(config)? };
+    download_rounds(config, &route, magnet_links, skipped, started, watcher).await
+}
+
+/// Warns that no proxy can be used, giving the `reason`, and downloads the torrents without a proxy, as
+/// `--fallback-direct` asks. A dry run only reports that it would.
+async fn fall_back_to_direct(
+    config: &BatchTorConfig,
+    reason: &str,
+    magnet_links: Vec<TorrentSource>,
+    skipped: Vec<TorrentSource>,
+    started: Instant,
+    watcher: Option<MagnetWatcher>,
+) -> Result<(), RunError> {
+    if config.dry_run {
+        emit(
+            Level::Info,
+            "dry_run",
+            format!("Dry run: parsed {} torrents.\nDry run: {}; would download without a proxy (--fallback-direct).", magnet_links.len(), reason.to_lowercase()),
+            json!({"torrents": magnet_links.len(), "proxy": null, "fallback_direct": true}),
+        );
+        return Ok(());
+    }
+    emit(
+        Level::Warn,
+        "fallback_direct",
+        format!("{}; downloading without a proxy because of --fallback-direct, so trackers and peers will see your own IP address.", reason),
+        json!({"reason": reason}),
+    );
+    download_direct(config, magnet_links, skipped, started, watcher).await
+}
+
+/// How the torrents of a batch are downloaded, the same for every round of `--watch`.
+struct Route {
+    pool: Arc<ProxyPool>,
+    tor: Option<Arc<Tor>>,
+    recheck: Option<ProxyRecheck>,
+    journal: Option<Arc<Journal>>,
+}
+
+/// Downloads the torrents and reports the batch. With a `watcher`, the torrents added to the magnet files are then
+/// downloaded as they appear, each round with a report of its own, until a shutdown is requested.
+async fn download_rounds(
+    config: &BatchTorConfig,
+    route: &Route,
+    magnet_links: Vec<TorrentSource>,
+    skipped: Vec<TorrentSource>,
+    started: Instant,
+    watcher: Option<MagnetWatcher>,
+) -> Result<(), RunError> {
+    let download = |torrents, skipped| download_all(config, torrents, Arc::clone(&route.pool), skipped, route.tor.clone(), route.recheck.clone(), route.journal.clone());
+    let report = download(magnet_links, skipped).await;
+    let Some(mut watcher) = watcher else { return finish_report(config, report, started).await };
+    // A round with failed downloads does not end the watch; its report lists them.
+    let _ = finish_report(config, report, started).await;
+
+    emit(
+        Level::Info,
+        "watch_started",
+        "Watching the magnet files for new torrents; press Ctrl-C to stop.".to_string(),
+        json!({"files": config.magnet_files}),
+    );
+    while !shutdown_requested() {
+        let Some(torrents) = watcher.next_torrents().await else { break };
+        let started = Instant::now();
+        let report = download(torrents, Vec::new()).await;
+        let _ = finish_report(config, report, started).await;
+    }
+    Err(RunError::Interrupted)
+}
+
+/// Asks for confirmation before downloading more than `--confirm-threshold` torrents, unless `--yes` was given.
+/// Without a terminal to ask on, the batch is abandoned instead. `via` says how the torrents are about to be downloaded, e.g. "via proxy 127.0.0.1:1080".
+async fn confirm_batch(config: &BatchTorConfig, torrents: &[TorrentSource], via: &str) -> Result<(), RunError> {
+    if config.yes || torrents.len() <= config.confirm_threshold {
+        return Ok(());
+    }
+    if config.json || !prompt::can_prompt() {
+        emit(
+            Level::Error,
+            "confirmation_required",
+            format!(
+                "About to download {} torrents, more than --confirm-threshold ({}); pass --yes to download them without a terminal.",
+                torrents.len(),
+                config.confirm_threshold,
+            ),
+            json!({"torrents": torrents.len(), "threshold": config.confirm_threshold}),
+        );
+        return Err(RunError::NotConfirmed);
+    }
+
+    let estimate = estimate_sizes(torrents, &config.file_filter, false);
+    let size = match (estimate.known, estimate.unknown) {
+        (0, _) => String::new(),
+        (_, 0) => format!(" (~{})", format_bytes(estimate.known_bytes)),
+        _ => format!(" (at least ~{})", format_bytes(estimate.known_bytes)),
+    };
+    if prompt::confirm(&format!("About to download {} torrents{} {} — continue? [y/N] ", torrents.len(), size, via)).await {
+        Ok(())
+    } else if shutdown_requested() {
+        Err(RunError::Interrupted)
+    } else {
+        emit(Level::Info, "batch_declined", "Not downloading the batch.".to_string(), json!({"torrents": torrents.len()}));
+        Err(RunError::NotConfirmed)
+    }
+}
+
+/// Reads the proxy list and removes the proxies that must not be used, returning the list's source and the filters
+/// applied, so that a reloaded list can be filtered the same way.
+pub(crate) async fn load_proxies(config: &BatchTorConfig) -> Result<(Box<dyn ProxySource>, Vec<Socks5Proxy>, ProxyFilters), RunError> {
+    let source = config.proxy_source();
+    let mut proxies = match source.fetch().await {
+        Ok(proxies) => proxies,
+        Err(e) => {
+            emit(Level::Error, "input_error", format!("Could not read proxy list {}: {}", source, e), json!({"file": source.to_string(), "error": e.to_string()}));
+            return Err(RunError::ProxyList(e));
+        }
+    };
+    let blocklist = match &config.proxy_blocklist {
+        Some(path) => match ProxyBlocklist::load(path) {
+            Ok(blocklist) => Some(blocklist),
+            Err(e) => {
+                emit(Level::Error, "input_error", format!("Could not read proxy blocklist {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+                return Err(RunError::Blocklist(e));
+            }
+        },
+        None => None,
+    };
+    let geo = match open_geo_filter(config) {
+        Ok(geo) => geo,
+        Err(e) => {
+            emit(Level::Error, "input_error", format!("Cannot filter proxies by country: {}", e), json!({"file": config.geoip_db, "error": e.to_string()}));
+            return Err(RunError::GeoIp(e));
+        }
+    };
+    let filters = ProxyFilters { blocklist, geo };
+    remove_unusable_proxies(config, &filters, &mut proxies);
+    Ok((source, proxies, filters))
+}
+
+/// The progress bar of the proxy checks, hidden in JSON mode so that the only output is the event stream.
+/// It starts empty: its length grows as the checks start. Once shown, messages print above it, and the bar of the
+/// current wave of `--check-batch-size` below it, until `log::set_progress(None)`.
+pub(crate) fn proxy_check_bar(config: &BatchTorConfig) -> Arc<ProgressBar> {
+    let bar = Arc::new(if config.shows_progress() {
+        let progress = MultiProgress::new();
+        log::set_progress(Some(progress.clone()));
+        progress.add(ProgressBar::new(0))
+    } else {
+        ProgressBar::hidden()
+    });
+    bar.set_style(
+        ProgressStyle::with_template("Checking proxies [{bar:30}] {pos}/{len} ({rate}, {elapsed} elapsed, ETA {eta}) {msg}")
+            .expect("Invalid progress template")
+            .with_key("rate", |state: &ProgressState, out: &mut dyn fmt::Write| {
+                let _ = out.write_fmt(format_args!("{:.1}/s", state.per_sec()));
+            }),
+    );
+    bar
+}
+
+/// Opens the `--geoip-db` database if proxies are to be filtered by country.
+fn open_geo_filter(config: &BatchTorConfig) -> Result<Option<GeoFilter>, GeoIpError> {
+    if config.proxy_countries.is_empty() {
+        return Ok(None);
+    }
+    let path = config.geoip_db.as_ref().ok_or(GeoIpError::MissingDatabase)?;
+    let policy = CountryPolicy { countries: config.proxy_countries.clone(), unknown: config.geo_unknown };
+    GeoFilter::open(Path::new(path), policy).map(Some)
+}
+
+/// The rules that exclude proxies from a freshly read proxy list before they are checked.
+pub(crate) struct ProxyFilters {
+    blocklist: Option<ProxyBlocklist>,
+    geo: Option<GeoFilter>,
+}
+
+/// Removes the proxies on the `--proxy-blocklist`, those outside the `--proxy-country` list, and those the backend cannot use.
+pub(crate) fn remove_unusable_proxies(config: &BatchTorConfig, filters: &ProxyFilters, proxies: &mut Vec<Socks5Proxy>) {
+    if let Some(blocklist) = &filters.blocklist {
+        let before = proxies.len();
+        blocklist.filter(proxies);
+        if proxies.len() < before {
+            emit(
+                Level::Info,
+                "proxies_blocked",
+                format!("Excluded {} blocklisted proxies.", before - proxies.len()),
+                json!({"count": before - proxies.len(), "file": config.proxy_blocklist}),
+            );
+        }
+    }
+    if let Some(geo) = &filters.geo {
+        let before = proxies.len();
+        geo.filter(proxies);
+        if proxies.len() < before {
+            emit(
+                Level::Info,
+                "proxies_geo_filtered",
+                format!("Excluded {} proxies outside {}.", before - proxies.len(), config.proxy_countries.join(", ")),
+                json!({"count": before - proxies.len(), "countries": config.proxy_countries}),
+            );
+        }
+    }
+    if config.backend == Backend::Aria2 {
+        // aria2c can only use HTTP proxies, so there is no point in checking the others.
+        let before = proxies.len();
+        proxies.retain(|proxy| proxy.kind == ProxyKind::Http);
+        if proxies.len() < before {
+            emit(
+                Level::Warn,
+                "proxies_unsupported",
+                format!("Ignoring {} non-HTTP proxies, which aria2c cannot use.", before - proxies.len()),
+                json!({"count": before - proxies.len(), "backend": "aria2"}),
+            );
+        }
+    }
+}
+
+/// Downloads every torrent through the proxies of the pool and returns the report of the batch.
+/// Each torrent is downloaded in a separate asynchronous task, bounded by `parallel_downloads`.
+/// With `tor` set, tor is asked for new circuits before every torrent but the first, `recheck` tells how proxies are
+/// checked again and replaced while the downloads run, and with `journal` set, every torrent that finishes or fails
+/// for good is recorded in it. The `--on-complete` command runs for each of those torrents once it is recorded.
+async fn download_all(
+    config: &BatchTorConfig,
+    magnet_links: Vec<TorrentSource>,
+    pool: Arc<ProxyPool>,
+    skipped: Vec<TorrentSource>,
+    tor: Option<Arc<Tor>>,
+    recheck: Option<ProxyRecheck>,
+    journal: Option<Arc<Journal>>,
+) -> BatchReport {
+    // With `--min-seeders`, the torrents whose trackers report too few seeders are skipped, asking the trackers
+    // through the proxy the first torrent would use.
+    let (magnet_links, low_seeders) = match config.min_seeders {
+        Some(min) => scrape::filter_by_seeders(config, min, pool.peek(0, &[]).as_ref(), magnet_links).await,
+        None => (magnet_links, Vec::new()),
+    };
+    let used_before = disk_usage(Path::new(&config.download_dir));
+    metrics::set_proxies_in_use(&pool.snapshot());
+    let not_downloaded = skipped.len() + low_seeders.len();
+    metrics::torrents_queued(magnet_links.len() + not_downloaded, not_downloaded);
+    // Downloads each torrent in a separate asynchronous task, bounded by `--parallel-downloads`.
+    // A torrent gives its slot back once it starts seeding, so seeding doesn't count against the limit.
+    // Proxies are assigned round-robin, so concurrent downloads use distinct proxies whenever enough passed.
+    let options = Arc::new(DownloadOptions {
+        backend: config.backend,
+        program: config.downloader_path().map(PathBuf::from),
+        download_dir: config.download_dir.clone(),
+        download_limit: config.download_limit,
+        upload_limit: config.upload_limit,
+        bandwidth: config.total_download_limit.map(|total| Arc::new(BandwidthShare::new(total))),
+        seed_ratio: config.seed_ratio,
+        seed_time: config.seed_time,
+        log_dir: config.log_dir.as_ref().map(PathBuf::from),
+        output: OutputFilter { sample: config.log_sample, keyword: config.log_keyword.clone() },
+        download_timeout: config.download_timeout,
+        stall_timeout: config.stall_timeout,
+        subdir_per_torrent: config.subdir_per_torrent,
+        incomplete_dir: config.keep_incomplete.then(|| match &config.incomplete_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&config.download_dir).join(INCOMPLETE_SUBDIR),
+        }),
+        file_filter: config.file_filter.clone(),
+        recheck,
+        recheck_before_download: config.recheck_before_download,
+        exhausted_policy: config.exhausted_policy,
+        progress_events: (config.progress_format == ProgressFormat::JsonLines).then_some(config.progress_interval),
+        peer_protocols: PeerProtocols {
+            disable_dht: config.disable_dht,
+            disable_utp: config.disable_utp,
+            disable_pex: config.disable_pex,
+            allow_udp: config.allow_udp,
+        },
+    });
+    let slots = Arc::new(Semaphore::new(config.parallel_downloads));
+
+    // In human-readable mode, one bar per active download sits above an overall bar; messages print above them.
+    let progress = config.shows_progress().then(MultiProgress::new);
+    let overall = match &progress {
+        Some(progress) => {
+            log::set_progress(Some(progress.clone()));
+            let overall = progress.add(ProgressBar::new(magnet_links.len() as u64));
+            overall.set_style(ProgressStyle::with_template("[{bar:30}] {pos}/{len} torrents complete").expect("Invalid progress template"));
+            overall
+        }
+        None => ProgressBar::hidden(),
+    };
+
+    let mut tasks = vec![];
+    for (index, link) in magnet_links.into_iter().enumerate() {
+        let options = Arc::clone(&options);
+        let pool = Arc::clone(&pool);
+        let max_retries = config.max_retries;
+        let slots = Arc::clone(&slots);
+        let tor = tor.clone();
+        let progress = progress.clone();
+        let overall = overall.clone();
+        let journal = journal.clone();
+        let on_complete = config.on_complete.clone();
+        tasks.push(tokio::spawn(async move {
+            let slot = slots.acquire_owned().await.expect("Download semaphore closed");
+            if shutdown_requested() {
+                return (link, Err(DownloadError::Interrupted), 0, 0);
+            }
+            let bar = progress.map(|progress| {
+                let bar = progress.insert_before(&overall, ProgressBar::new(100));
+                bar.set_style(
+                    ProgressStyle::with_template("{prefix} [{bar:30}] {pos:>3}% {msg} ETA {eta}").expect("Invalid progress template"),
+                );
+                bar.set_prefix(link.to_string());
+                bar
+            });
+            // Every torrent after the first asks tor for new circuits, so torrents don't share an exit node.
+            if let Some(tor) = tor.filter(|_| index > 0) {
+                match tor.new_identity().await {
+                    Ok(()) => emit(Level::Debug, "tor_newnym", format!("[{}] Requested new Tor circuits", link), json!({"magnet": link.to_string()})),
+                    Err(e) => emit(
+                        Level::Warn,
+                        "tor_newnym_error",
+                        format!("[{}] Could not request new Tor circuits: {}", link, e),
+                        json!({"magnet": link.to_string(), "error": e.to_string()}),
+                    ),
+                }
+            }
+            let mut task = DownloadTask { slot: Some(slot), bar: bar.clone(), rotations: 0, recheck_switches: 0 };
+            let result = download_with_retries(&link, &options, &pool, index, max_retries, &mut task).await;
+            drop(task.slot.take());
+            if let Some(bar) = bar {
+                bar.finish_and_clear();
+            }
+            overall.inc(1);
+            metrics::torrent_finished(result.as_ref().ok());
+            if let Some(journal) = &journal {
+                journal.record(&link, &result);
+            }
+            match &result {
+                Ok(_) => emit(
+                    Level::Info,
+                    "download_complete",
+                    format!("[{}] Download complete", link),
+                    json!({"magnet": link.to_string()}),
+                ),
+                Err(e) => emit(
+                    Level::Error,
+                    "download_failed",
+                    format!("[{}] Download failed: {}", link, e),
+                    json!({"magnet": link.to_string(), "error": e.to_string()}),
+                ),
+            }
+            if let Some(command) = &on_complete {
+                hook::on_complete(command, &link, &options.torrent_dir(&link), &result).await;
+            }
+            (link, result, task.rotations, task.recheck_switches)
+        }));
+    }
+
+    // Wait for all download tasks to complete and collect their results.
+    let mut report = BatchReport { skipped, low_seeders, ..Default::default() };
+    for task in tasks {
+        let (link, result, rotations, recheck_switches) = task.await.expect("Download task panicked");
+        report.record(link, result, rotations);
+        report.recheck_switches += recheck_switches;
+    }
+    overall.finish_and_clear();
+    log::set_progress(None);
+    report.bytes_downloaded = disk_usage(Path::new(&config.download_dir)).saturating_sub(used_before);
+    report
+}
+
+/// Opens the `--journal` for recording the torrents about to be downloaded, if one was given.
+fn open_journal(config: &BatchTorConfig) -> Result<Option<Arc<Journal>>, RunError> {
+    let Some(path) = &config.journal else { return Ok(None) };
+    match Journal::open(Path::new(path), config.reset_journal) {
+        Ok(journal) => Ok(Some(Arc::new(journal))),
+        Err(e) => {
+            emit(Level::Error, "journal_error", format!("Could not open journal {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+            Err(RunError::Journal(e))
+        }
+    }
+}
+
+/// Prints the final report of a batch and, with `--report`, writes it to a file.
+/// Returns whether every torrent was downloaded or skipped, unless the batch was interrupted.
+async fn finish_report(config: &BatchTorConfig, mut report: BatchReport, started: Instant) -> Result<(), RunError> {
+    report.elapsed = started.elapsed();
+    report.print();
+    if let Some(path) = config.proxy_cache() {
+        if let Err(e) = record_download_speeds(path, &report.proxy_speeds()) {
+            emit(Level::Warn, "proxy_cache_error", format!("Could not write proxy cache {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+        }
+    }
+    if let Some(path) = &config.report {
+        if let Err(e) = report.write(Path::new(path)) {
+            emit(Level::Warn, "report_error", format!("Could not write report {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+        }
+    }
+    notification::notify_finished(config, &report, shutdown_requested()).await;
+    if shutdown_requested() {
+        Err(RunError::Interrupted)
+    } else if !report.failed.is_empty() {
+        Err(RunError::DownloadsFailed { failed: report.failed.len(), total: report.total() })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_match_the_readme() {
+        let io_error = || io::Error::other("test");
+        let errors = [
+            (RunError::MissingPrograms(Vec::new()), 1),
+            (RunError::DownloadDir(DownloadDirError::Missing(PathBuf::from("downloads"))), 1),
+            (RunError::Journal(io_error()), 1),
+            (RunError::Tor(TorError::NotInstalled), 1),
+            (RunError::Metrics(io_error()), 1),
+            (RunError::NotConfirmed, 1),
+            (RunError::Watch(notify::Error::generic("test")), 1),
+            (RunError::DownloadsFailed { failed: 1, total: 2 }, 3),
+            (RunError::NoValidProxies, 4),
+            (RunError::BothFromStdin, 5),
+            (RunError::MagnetFile(io_error()), 5),
+            (RunError::ProxyList(ProxySourceError::Io(io_error())), 5),
+            (RunError::Trackers(io_error()), 5),
+            (RunError::Blocklist(io_error()), 5),
+            (RunError::GeoIp(GeoIpError::MissingDatabase), 5),
+            (RunError::NoTorrents, 5),
+            (RunError::TooLarge { total: 2, limit: 1 }, 5),
+            (RunError::Leak(Vec::new()), 6),
+            (RunError::Interrupted, 130),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Diagnostic output shared by every part of the crate, in human-readable or JSON form.
+
+use indicatif::MultiProgress;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// Whether diagnostics are emitted as newline-delimited JSON instead of human-readable text.
+/// Set once from `--json` at startup via `set_json_output`.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Switches all diagnostics to newline-delimited JSON (`true`) or human-readable text (`false`).
+pub fn set_json_output(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether diagnostics are emitted as JSON, for events that only make sense to a program reading the stream.
+pub(crate) fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// How much detail is shown, set with `-q` and `-v`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// Only errors.
+    Quiet,
+    /// High-level events such as the proxy found and downloads started or finished, plus warnings.
+    #[default]
+    Normal,
+    /// Also every proxy check result and the full downloader output (`-v`).
+    Verbose,
+    /// Everything, including when each proxy check starts (`-vv`).
+    Debug,
+}
+
+impl Verbosity {
+    /// The verbosity for the number of `-v` flags given, or `Quiet` with `-q`.
+    pub fn from_flags(quiet: bool, verbose: u8) -> Verbosity {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+
+    /// Whether events of the given level are shown at this verbosity.
+    fn shows(self, level: Level) -> bool {
+        let needed = match level {
+            Level::Error => Verbosity::Quiet,
+            Level::Warn | Level::Info => Verbosity::Normal,
+            Level::Debug => Verbosity::Verbose,
+            Level::Trace => Verbosity::Debug,
+        };
+        self >= needed
+    }
+}
+
+/// The current verbosity, stored as its discriminant. Set from `-q`/`-v` at startup via `set_verbosity`.
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Sets how much detail is emitted, in both human-readable and JSON mode.
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    }
+}
+
+/// The progress bars currently on screen, if any. Human-readable messages are printed above them.
+static PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Routes human-readable output around the given progress bars while they are shown, or stops doing so with `None`.
+pub(crate) fn set_progress(progress: Option<MultiProgress>) {
+    *PROGRESS.lock().expect("Progress lock poisoned") = progress;
+}
+
+/// The progress bars currently on screen, for a bar to be added among them.
+pub(crate) fn progress_bars() -> Option<MultiProgress> {
+    PROGRESS.lock().expect("Progress lock poisoned").clone()
+}
+
+/// The severity of a diagnostic event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+    /// Detail shown with `-v`, such as proxy check results and downloader output.
+    Debug,
+    /// Fine-grained detail shown with `-vv`.
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// Emits a diagnostic event. All program output goes through this function.
+/// Events below the current verbosity are dropped in both modes.
+/// In the default human-readable mode only `message` is printed, to stderr for errors and warnings
+/// and to stdout otherwise. With `--json`, a single JSON object is printed to stdout instead, carrying
+/// a `timestamp`, the `level`, the `event` name, the `message`, and the event-specific `fields`.
+///
+/// # Arguments
+///
+/// * `level` - The severity of the event.
+/// * `event` - A stable, machine-readable event name such as `proxy_check` or `download_start`.
+/// * `message` - The human-readable description of the event.
+/// * `fields` - A JSON object with event-specific fields such as `proxy`, `magnet`, or `latency_ms`.
+pub(crate) fn emit(level: Level, event: &str, message: String, fields: Value) {
+    if !verbosity().shows(level) {
+        return;
+    }
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        print_record(json_record(level, event, message, fields));
+    } else {
+        let print = || {
+            if matches!(level, Level::Error | Level::Warn) {
+                let mut stderr = io::stderr().lock();
+                let _ = writeln!(stderr, "{}", message);
+                let _ = stderr.flush();
+            } else {
+                let mut stdout = io::stdout().lock();
+                let _ = writeln!(stdout, "{}", message);
+                let _ = stdout.flush();  // Flushes so the message is printed immediately.
+            }
+        };
+        // Hides the progress bars while printing, so messages don't tear through them.
+        let progress = PROGRESS.lock().expect("Progress lock poisoned").clone();
+        match progress {
+            Some(progress) => progress.suspend(print),
+            None => print(),
+        }
+    }
+}
+
+/// Emits a `progress` event of `--progress-format json-lines`: a JSON object in the shape of the `--json` events,
+/// printed to stdout whatever the verbosity and whether or not `--json` is given, for a frontend to draw its own bars.
+pub(crate) fn emit_progress(message: String, fields: Value) {
+    print_record(json_record(Level::Info, "progress", message, fields));
+}
+
+/// The JSON object of an event: its `timestamp`, `level`, `event` name and `message`, followed by its `fields`.
+fn json_record(level: Level, event: &str, message: String, fields: Value) -> Value {
+    let timestamp = time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default();
+    let mut record = json!({
+        "timestamp": timestamp,
+        "level": level.as_str(),
+        "event": event,
+        "message": message,
+    });
+    if let (Some(record), Value::Object(fields)) = (record.as_object_mut(), fields) {
+        record.extend(fields);
+    }
+    record
+}
+
+fn print_record(record: Value) {
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", record);
+    let _ = stdout.flush();  // Flushes so wrapper scripts receive each event immediately.
+}
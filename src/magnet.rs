@@ -0,0 +1,349 @@
+//! Parsing and validation of magnet links.
+
+use glob::Pattern;
+use thiserror::Error;
+
+/// The parsed contents of a magnet link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetInfo {
+    /// The magnet link exactly as it appeared in the input file.
+    pub link: String,
+    /// The BitTorrent info hash as 40 lowercase hex characters, even if the link used base32.
+    pub info_hash: String,
+    /// The display name (`dn`), if present.
+    pub display_name: Option<String>,
+    /// The tracker URLs (`tr`), in the order they appear in the link.
+    pub trackers: Vec<String>,
+    /// The total size of the content in bytes (`xl`), if the link gives it.
+    pub exact_length: Option<u64>,
+    /// The web seed URLs (`ws`), which serve the content over HTTP when peers are hard to reach.
+    pub web_seeds: Vec<String>,
+    /// The URLs of the `.torrent` file (`as`, acceptable source), which a client can fetch the metadata from.
+    pub sources: Vec<String>,
+    /// The peer addresses (`x.pe`) a client can connect to directly, as given in the link, well-formed or not.
+    pub peers: Vec<String>,
+    /// The patterns of the files to download, from the `||want=` suffix of its line in the input file; all of
+    /// them if empty.
+    pub wanted_files: Vec<Pattern>,
+}
+
+/// The reasons a magnet link can be rejected by `validate_magnet`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MagnetError {
+    /// The link does not start with `magnet:?`.
+    #[error("link does not start with magnet:?")]
+    NotAMagnet,
+    /// The link has no `xt=urn:btih:` parameter.
+    #[error("missing xt=urn:btih: info hash")]
+    MissingInfoHash,
+    /// The `btih` value is neither 40 hex nor 32 base32 characters.
+    #[error("invalid info hash {0}")]
+    InvalidInfoHash(String),
+}
+
+/// Decodes `%XX` escapes and `+` (as a space) in a magnet query value.
+/// Malformed escapes are kept verbatim.
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).expect("hex digits are ASCII");
+                decoded.push(u8::from_str_radix(hex, 16).expect("hex digits parse as a byte"));
+                i += 3;
+                continue;
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes a value for use in a magnet query, escaping everything except RFC 3986 unreserved characters.
+pub(crate) fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Normalizes a `btih` value to 40 lowercase hex characters.
+/// Accepts either 40 hex characters or the 32-character base32 form.
+pub(crate) fn normalize_info_hash(hash: &str) -> Option<String> {
+    if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Some(hash.to_ascii_lowercase());
+    }
+    if hash.len() != 32 {
+        return None;
+    }
+
+    // Base32 (RFC 4648): every character carries 5 bits, so 32 characters decode to 20 bytes.
+    let mut hex = String::with_capacity(40);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in hash.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            hex.push_str(&format!("{:02x}", (buffer >> bits) & 0xff));
+        }
+    }
+    Some(hex)
+}
+
+/// Parses a magnet link and checks that it carries a valid BitTorrent info hash.
+///
+/// # Arguments
+///
+/// * `link` - The magnet link to validate.
+///
+/// # Returns
+///
+/// The parsed `MagnetInfo`, or a `MagnetError` describing why the link was rejected.
+pub fn validate_magnet(link: &str) -> Result<MagnetInfo, MagnetError> {
+    let query = link.strip_prefix("magnet:?").ok_or(MagnetError::NotAMagnet)?;
+
+    let mut info_hash = None;
+    let mut display_name = None;
+    let mut trackers = Vec::new();
+    let mut exact_length = None;
+    let mut web_seeds = Vec::new();
+    let mut sources = Vec::new();
+    let mut peers = Vec::new();
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "xt" if info_hash.is_none() => {
+                if let Some(hash) = percent_decode(value).strip_prefix("urn:btih:") {
+                    let normalized = normalize_info_hash(hash).ok_or_else(|| MagnetError::InvalidInfoHash(hash.to_string()))?;
+                    info_hash = Some(normalized);
+                }
+            }
+            "dn" if display_name.is_none() => display_name = Some(percent_decode(value)),
+            "tr" => trackers.push(percent_decode(value)),
+            "xl" if exact_length.is_none() => exact_length = value.parse().ok(),
+            "ws" => web_seeds.push(percent_decode(value)),
+            "as" => sources.push(percent_decode(value)),
+            "x.pe" => peers.push(percent_decode(value)),
+            _ => {}
+        }
+    }
+
+    Ok(MagnetInfo {
+        link: link.to_string(),
+        info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+        display_name,
+        trackers,
+        exact_length,
+        web_seeds,
+        sources,
+        peers,
+        wanted_files: Vec::new(),
+    })
+}
+
+/// The tracker URL schemes BitTorrent clients announce to.
+const TRACKER_SCHEMES: [&str; 5] = ["http", "https", "udp", "ws", "wss"];
+
+/// The URL schemes of web seeds and acceptable sources.
+const WEB_SCHEMES: [&str; 2] = ["http", "https"];
+
+/// Normalizes a tracker URL by lowercasing its scheme and host, or returns `None` if it is obviously invalid:
+/// an unknown scheme, a missing host, a port that is not a number, or whitespace in the URL.
+pub fn normalize_tracker(url: &str) -> Option<String> {
+    normalize_url(url, &TRACKER_SCHEMES)
+}
+
+/// Normalizes a web seed (`ws`) or acceptable source (`as`) URL like `normalize_tracker`, but only accepts
+/// `http` and `https` URLs.
+pub fn normalize_web_seed(url: &str) -> Option<String> {
+    normalize_url(url, &WEB_SCHEMES)
+}
+
+/// Lowercases the scheme and host of a URL whose scheme is one of `schemes`, or returns `None` if it is obviously invalid.
+fn normalize_url(url: &str, schemes: &[&str]) -> Option<String> {
+    let url = url.trim();
+    if url.chars().any(char::is_whitespace) {
+        return None;
+    }
+    let (scheme, rest) = url.split_once("://")?;
+    let scheme = scheme.to_ascii_lowercase();
+    if !schemes.contains(&scheme.as_str()) {
+        return None;
+    }
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let (userinfo, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => (Some(userinfo), host_port),
+        None => (None, authority),
+    };
+    // The port follows the last colon, unless that colon is inside the brackets of an IPv6 address.
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (host_port, None),
+    };
+    if host.is_empty() || port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return None;
+    }
+
+    let mut normalized = format!("{}://", scheme);
+    if let Some(userinfo) = userinfo {
+        normalized.push_str(userinfo);
+        normalized.push('@');
+    }
+    normalized.push_str(&host.to_ascii_lowercase());
+    if let Some(port) = port {
+        normalized.push(':');
+        normalized.push_str(port);
+    }
+    normalized.push_str(path);
+    Some(normalized)
+}
+
+/// Normalizes a peer address (`x.pe`) given as `host:port`, with an IPv6 address in brackets, by lowercasing its
+/// host, or returns `None` if it is malformed: a missing host, a port that is not a number from 1 to 65535, an
+/// unbracketed IPv6 address, or a host with characters no host name or address has.
+pub fn normalize_peer(address: &str) -> Option<String> {
+    let (host, port) = address.trim().rsplit_once(':')?;
+    if port.parse::<u16>().ok()? == 0 {
+        return None;
+    }
+    let valid = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(ipv6) => ipv6.parse::<std::net::Ipv6Addr>().is_ok(),
+        None => !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'),
+    };
+    valid.then(|| format!("{}:{}", host.to_ascii_lowercase(), port))
+}
+
+/// Appends `key=url` for each URL that `normalize` accepts, skipping duplicates.
+fn push_urls(link: &mut String, key: &str, urls: &[String], normalize: fn(&str) -> Option<String>) {
+    let mut pushed: Vec<String> = Vec::new();
+    for url in urls.iter().filter_map(|url| normalize(url)) {
+        if !pushed.contains(&url) {
+            link.push_str(&format!("&{}=", key));
+            link.push_str(&percent_encode(&url));
+            pushed.push(url);
+        }
+    }
+}
+
+/// Rebuilds a magnet link from its info hash, display name, trackers, web seeds, acceptable sources, and peer
+/// addresses, with the trackers normalized by `normalize_tracker`, the other URLs by `normalize_web_seed`, and the
+/// peers by `normalize_peer`, invalid ones dropped, and duplicates removed. Other parameters of the original link, such as a v2 hash (`xt=urn:btmh:`), are kept
+/// as they were.
+pub fn canonicalize_magnet(info: &MagnetInfo) -> String {
+    let mut link = format!("magnet:?xt=urn:btih:{}", info.info_hash);
+    if let Some(name) = &info.display_name {
+        link.push_str("&dn=");
+        link.push_str(&percent_encode(name));
+    }
+    push_urls(&mut link, "tr", &info.trackers, normalize_tracker);
+    push_urls(&mut link, "ws", &info.web_seeds, normalize_web_seed);
+    push_urls(&mut link, "as", &info.sources, normalize_web_seed);
+    push_urls(&mut link, "x.pe", &info.peers, normalize_peer);
+    let query = info.link.strip_prefix("magnet:?").unwrap_or_default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let info_hash = key == "xt" && percent_decode(value).starts_with("urn:btih:");
+        if !info_hash && !matches!(key, "dn" | "tr" | "ws" | "as" | "x.pe") {
+            link.push('&');
+            link.push_str(pair);
+        }
+    }
+    link
+}
+
+impl MagnetInfo {
+    /// Adds the trackers of `other` that this magnet does not list yet, appending them to the link as `tr` parameters.
+    /// Trackers are compared after `normalize_tracker`, so differences in the case of the scheme or host don't count.
+    pub fn merge_trackers(&mut self, other: &MagnetInfo) {
+        self.add_trackers(&other.trackers);
+    }
+
+    /// Adds the `trackers` this magnet does not list yet, compared like `merge_trackers` does.
+    pub fn add_trackers(&mut self, trackers: &[String]) {
+        for tracker in trackers {
+            let normalized = normalize_tracker(tracker);
+            let known = self.trackers.iter().any(|known| known == tracker || (normalized.is_some() && normalize_tracker(known) == normalized));
+            if !known {
+                self.link.push_str("&tr=");
+                self.link.push_str(&percent_encode(tracker));
+                self.trackers.push(tracker.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn trackers_are_normalized_or_dropped() {
+        assert_eq!(normalize_tracker("UDP://Tracker.Example.ORG:1337/announce").as_deref(), Some("udp://tracker.example.org:1337/announce"));
+        assert_eq!(normalize_tracker("http://[2001:DB8::1]/announce?Key=A").as_deref(), Some("http://[2001:db8::1]/announce?Key=A"));
+        assert_eq!(normalize_tracker("ftp://tracker.example.org/announce"), None);
+        assert_eq!(normalize_tracker("udp://:1337/announce"), None);
+        assert_eq!(normalize_tracker("udp://tracker.example.org:port"), None);
+        assert_eq!(normalize_tracker("not a tracker"), None);
+    }
+
+    #[test]
+    fn canonical_links_list_each_tracker_once() {
+        let link = format!(
+            "magnet:?xt=urn:btih:{}&tr=udp%3A%2F%2FA.example%3A80&dn=Some+Name&tr=udp%3A%2F%2Fa.example%3A80&tr=bogus&ws=http%3A%2F%2FSeed.example%2Ffiles&xs=x",
+            HASH.to_ascii_uppercase(),
+        );
+        let info = validate_magnet(&link).unwrap();
+        assert_eq!(
+            canonicalize_magnet(&info),
+            format!("magnet:?xt=urn:btih:{}&dn=Some%20Name&tr=udp%3A%2F%2Fa.example%3A80&ws=http%3A%2F%2Fseed.example%2Ffiles&xs=x", HASH),
+        );
+    }
+
+    #[test]
+    fn web_seeds_must_be_http() {
+        let link = format!(
+            "magnet:?xt=urn:btih:{}&ws=https%3A%2F%2Fseed.example%2Fa&ws=ftp%3A%2F%2Fseed.example%2Fa&ws=https%3A%2F%2FSEED.example%2Fa&as=http%3A%2F%2Fsite.example%2Fa.torrent&as=bogus",
+            HASH,
+        );
+        let info = validate_magnet(&link).unwrap();
+        assert_eq!(info.web_seeds.len(), 3);
+        assert_eq!(
+            canonicalize_magnet(&info),
+            format!("magnet:?xt=urn:btih:{}&ws=https%3A%2F%2Fseed.example%2Fa&as=http%3A%2F%2Fsite.example%2Fa.torrent", HASH),
+        );
+    }
+
+    #[test]
+    fn malformed_peers_are_dropped() {
+        let link = format!("magnet:?xt=urn:btih:{}&x.pe=Peer.example%3A6881&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413&x.pe=10.0.0.1&x.pe=2001%3Adb8%3A%3A1%3A80&x.pe=h%3A0", HASH);
+        let info = validate_magnet(&link).unwrap();
+        assert_eq!(info.peers.len(), 5);
+        assert_eq!(canonicalize_magnet(&info), format!("magnet:?xt=urn:btih:{}&x.pe=peer.example%3A6881&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413", HASH));
+    }
+
+    #[test]
+    fn merged_trackers_skip_case_variants() {
+        let mut first = validate_magnet(&format!("magnet:?xt=urn:btih:{}&tr=udp%3A%2F%2Fa.example%3A80", HASH)).unwrap();
+        let second = validate_magnet(&format!("magnet:?xt=urn:btih:{}&tr=UDP%3A%2F%2FA.example%3A80&tr=udp%3A%2F%2Fb.example%3A80", HASH)).unwrap();
+        first.merge_trackers(&second);
+        assert_eq!(first.trackers, ["udp://a.example:80", "udp://b.example:80"]);
+    }
+}
@@ -1,269 +1,741 @@
-use clap::Parser;
-use indicatif::ProgressBar;
-use std::fs::File;
-use std::io::{self, BufRead, Write};
-use std::process::{Command, Stdio};
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::task;
-
-/// The `Args` struct defines the CLI interface for the program using the `clap` library.
-/// It allows users to specify paths to the magnet links file, the SOCKS5 proxies file,
-/// and the directory where the downloaded torrents will be saved.
+use batch_tor::{apply_config_file, parse_byte_size, parse_country_code, parse_file_pattern, parse_log_sample, parse_peer_address, shutdown, Backend, BatchTorConfig, CachePolicy, ExhaustedPolicy, GeoUnknown, LeakPolicy, ProgressFormat, ProxyFormat, RunError, ScrapeUnknown, Strategy, TestProtocol, TorrentFilter, Verbosity, DEFAULT_BT_TEST_PEER, DEFAULT_IP_ECHO_URL, SELF_TEST_MAGNET};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use glob::Pattern;
+use regex::Regex;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// The `Args` struct defines the CLI interface for the program using the `clap` library: the options shared by
+/// every subcommand, and the subcommand that says what to do. Without a subcommand, the arguments are those of
+/// `download`, so that `batch_tor -m links.txt` keeps working.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The file containing magnet links. Each line is expected to be a valid magnet link.
-    #[arg(short, long, default_value = "magnet_links.txt")]
-    magnet_file: String,
+    #[command(flatten)]
+    shared: SharedArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// What batch_tor does with the proxies.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check every proxy of the list, without downloading anything, and print them ranked by latency.
+    Check(CheckArgs),
+    /// Download the torrents of the magnet files through a proxy that passes the check. This is the default.
+    Download(Box<DownloadArgs>),
+    /// Check that the setup works end to end: check the proxies, make sure the selected one hides the IP address of
+    /// this machine, and download a small, well-seeded test torrent into a temporary directory that is removed afterwards.
+    Verify(Box<VerifyArgs>),
+}
+
+impl Command {
+    /// The config keys that the subcommand itself sets, and that the config file therefore leaves alone.
+    fn implied_keys(&self) -> &'static [&'static str] {
+        match self {
+            Command::Check(_) => &["verify_proxies_only"],
+            Command::Download(_) => &[],
+            Command::Verify(_) => &["verify_proxies_only", "dry_run", "leak_test"],
+        }
+    }
+}
+
+/// The options of every subcommand: where the proxies come from and how they are checked, and how the
+/// diagnostics are shown. They may be given before or after the subcommand.
+#[derive(clap::Args, Debug)]
+struct SharedArgs {
+    /// A TOML file with default values for the options below; options given on the command line take precedence.
+    /// Defaults to `batch_tor.toml` in the current directory, if it exists.
+    #[arg(global = true, long)]
+    config: Option<String>,
 
     /// The file containing SOCKS5 proxies in the format `IP:PORT`. Each line represents one proxy.
-    #[arg(short, long, default_value = "socks.txt")]
+    /// Use `-` to read the proxies from standard input.
+    #[arg(global = true, short, long, default_value = "socks.txt")]
     socks_file: String,
 
+    /// Download the proxy list from this URL instead of reading --socks-file, e.g. from a proxy provider's API.
+    /// The response must be in one of the formats of the file.
+    #[arg(global = true, long, value_name = "URL", conflicts_with = "socks_file")]
+    proxy_url: Option<String>,
+
+    /// The format of the proxy list. `auto` reads `.json` and `.csv` lists as such, and others as text.
+    #[arg(global = true, long, value_enum, default_value_t = ProxyFormat::Auto)]
+    proxy_format: ProxyFormat,
+
+    /// A file of proxy IP addresses and CIDR ranges, one per line, that are never used even if they pass the check.
+    #[arg(global = true, long)]
+    proxy_blocklist: Option<String>,
+
+    /// Only check proxies located in this country, given as a two-letter code such as `DE`. Can be given
+    /// multiple times or as a comma-separated list. Requires --geoip-db.
+    #[arg(global = true, long = "proxy-country", value_name = "CODE", value_delimiter = ',', value_parser = parse_country_code, requires = "geoip_db")]
+    proxy_countries: Vec<String>,
+
+    /// A MaxMind GeoLite2 Country or City database (`.mmdb`) used to look up the country of each proxy.
+    #[arg(global = true, long, value_name = "FILE")]
+    geoip_db: Option<String>,
+
+    /// Whether --proxy-country keeps the proxies whose country cannot be determined, such as those given by host name.
+    #[arg(global = true, long, value_enum, default_value_t = GeoUnknown::Deny)]
+    geo_unknown: GeoUnknown,
+
+    /// The maximum number of proxies checked at the same time.
+    #[arg(global = true, long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrency: u32,
+
+    /// A URL that must be reachable through a proxy for it to be considered valid.
+    /// Can be given multiple times, in which case a proxy must pass every URL.
+    #[arg(global = true, long = "check-url", default_value = "https://rutracker.org")]
+    check_urls: Vec<String>,
+
+    /// How long, in seconds, a proxy check request may take before the proxy is considered dead.
+    #[arg(global = true, long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    proxy_timeout: u64,
+
+    /// Additionally download this many bytes of the first check URL through each proxy that passes
+    /// the check, and rank proxies by the measured throughput for --strategy fastest.
+    #[arg(global = true, long, value_parser = clap::value_parser!(u64).range(1..))]
+    warmup_bytes: Option<u64>,
+
+    /// Check a proxy a second time if it failed with a timeout, which may be transient, rather than
+    /// a refused connection.
+    #[arg(global = true, long)]
+    retry_timeouts: bool,
+
+    /// Check each proxy up to this many times, a second apart, before declaring it dead; it passes on any attempt.
+    /// Raises the pass rate of proxies on lossy links without accepting dead ones.
+    #[arg(global = true, long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    check_attempts: u32,
+
+    /// What the proxy check tests. `bittorrent` also opens a TCP connection to --bt-test-peer through each proxy
+    /// that passes the HTTP check, which is slower, but tells the proxies that block BitTorrent apart.
+    #[arg(global = true, long, value_enum, default_value_t = TestProtocol::Http)]
+    proxy_test_protocol: TestProtocol,
+
+    /// The host:port that --proxy-test-protocol bittorrent connects to through each proxy.
+    #[arg(global = true, long, value_name = "HOST:PORT", default_value = DEFAULT_BT_TEST_PEER, value_parser = parse_peer_address)]
+    bt_test_peer: String,
+
+    /// Emit diagnostics as newline-delimited JSON objects instead of human-readable text.
+    #[arg(global = true, long)]
+    json: bool,
+
+    /// Serve the progress of the batch as JSON at http://<host>:<port>/status while it runs.
+    #[arg(global = true, long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+
+    /// Show more detail: every proxy check result and the full downloader output. Repeat (`-vv`) for even more.
+    #[arg(global = true, short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only show errors.
+    #[arg(global = true, short, long)]
+    quiet: bool,
+}
+
+/// How `download` and `verify` choose the proxy to download through.
+#[derive(clap::Args, Debug)]
+struct SelectionArgs {
+    /// Check the proxies in waves of this many, and stop after the first wave that yields a valid proxy.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    check_batch_size: Option<u32>,
+
+    /// How the proxy used for downloading is chosen among the proxies that pass the check.
+    #[arg(long, value_enum, default_value_t = Strategy::First)]
+    strategy: Strategy,
+
+    /// Check the proxies in random order, so that users sharing a list spread the load across it.
+    #[arg(long)]
+    shuffle: bool,
+
+    /// Seed the --shuffle order so that it is the same on every run.
+    #[arg(long, requires = "shuffle")]
+    shuffle_seed: Option<u64>,
+
+    /// Check only a random sample of this many proxies of the list, stopping as soon as enough of them pass.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    proxy_sample: Option<u32>,
+
+    /// Check the rest of the list when the --proxy-sample does not yield enough valid proxies.
+    #[arg(long, requires = "proxy_sample")]
+    sample_fallback: bool,
+
+    /// A report written by `check --report`: the proxies it ranks are checked first, fastest
+    /// first, and the others after them.
+    #[arg(long, value_name = "FILE")]
+    proxy_order_file: Option<String>,
+
+    /// Check every proxy and choose the one to use from a numbered list. Falls back to --strategy
+    /// when not running in a terminal.
+    #[arg(long)]
+    interactive: bool,
+
+    /// A JSON file where proxies that pass the check are cached between runs.
+    /// Recently validated proxies from the cache are checked before the rest of the list.
+    #[arg(long)]
+    proxy_cache: Option<String>,
+
+    /// How long, in seconds, a cached proxy validation stays fresh.
+    #[arg(long, default_value_t = 3600)]
+    proxy_cache_ttl: u64,
+
+    /// How the proxies that pass the check update the --proxy-cache: `append` merges them into the cached proxies,
+    /// keeping those that were not checked again in this run, and `replace` keeps only them.
+    #[arg(long, value_enum, default_value_t = CachePolicy::Replace)]
+    proxy_cache_policy: CachePolicy,
+
+    /// Download through Tor instead of the proxy list. Uses the tor already running on 127.0.0.1:9051
+    /// or launches one, and requests new circuits before each torrent.
+    #[arg(long)]
+    tor: bool,
+
+    /// Download directly, without any proxy. The proxy list is neither read nor checked.
+    #[arg(long, conflicts_with = "tor")]
+    no_proxy: bool,
+
+    /// Experimental: send every connection through two SOCKS5 proxies in sequence. Consecutive pairs of proxies
+    /// from the list are chained, and each chain is checked end to end.
+    #[arg(long, conflicts_with_all = ["tor", "no_proxy"])]
+    proxy_chain: bool,
+
+    /// What the --leak-test does when a proxy shows the address of this machine: `warn` and download anyway,
+    /// or `abort` the batch.
+    #[arg(long, value_enum, default_value_t = LeakPolicy::Abort)]
+    leak_policy: LeakPolicy,
+
+    /// The IP-echo service queried by --leak-test, which must answer with the caller's IP address as plain text.
+    #[arg(long, value_name = "URL", default_value = DEFAULT_IP_ECHO_URL)]
+    ip_echo_url: String,
+}
+
+/// How `download` and `verify` run the downloader.
+#[derive(clap::Args, Debug)]
+struct DownloaderArgs {
+    /// How to show the progress of the running downloads: `bars`, or `json-lines` to print a `progress` JSON
+    /// event per download to stdout every `--progress-interval`, for a frontend to draw its own progress bars.
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Bars)]
+    progress_format: ProgressFormat,
+
+    /// How often, in seconds, a `progress` event is printed for each download with `--progress-format json-lines`.
+    #[arg(long, value_name = "SECS", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    progress_interval: u64,
+
+    /// Limit the download speed of each torrent, in KB/s. No limit is applied when unset.
+    #[arg(long)]
+    download_limit: Option<u32>,
+
+    /// Limit the download speed of all the torrents together, in KB/s. The total is divided equally among the active
+    /// downloads, and each downloader is restarted with its new share when downloads start or finish.
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u32).range(1..))]
+    total_download_limit: Option<u32>,
+
+    /// Limit the upload speed of each torrent, in KB/s. No limit is applied when unset.
+    #[arg(long)]
+    upload_limit: Option<u32>,
+
+    /// Never look for peers through DHT. Through a proxy, DHT is off anyway unless --allow-udp is given.
+    #[arg(long)]
+    disable_dht: bool,
+
+    /// Never connect to peers over uTP. Through a proxy, uTP is off anyway unless --allow-udp is given.
+    #[arg(long)]
+    disable_utp: bool,
+
+    /// Never learn peers from other peers through peer exchange.
+    #[arg(long)]
+    disable_pex: bool,
+
+    /// Keep DHT and uTP on when downloading through a proxy, although their UDP traffic does not go through it
+    /// and reveals your IP address.
+    #[arg(long)]
+    allow_udp: bool,
+
+    /// A directory where the transmission-cli output of each torrent is saved, in a file named after
+    /// its info hash. Each attempt is appended with the proxy used and the exit status.
+    #[arg(long)]
+    log_dir: Option<String>,
+
+    /// With -v, echo only one line of the downloader's output out of N, e.g. 1/100. The --log-dir
+    /// transcript still gets every line.
+    #[arg(long, value_name = "1/N", value_parser = parse_log_sample)]
+    log_sample: Option<u64>,
+
+    /// With -v, always echo the downloader's output lines that match this regular expression and,
+    /// without --log-sample, only those.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    log_keyword: Option<Regex>,
+
+    /// Stop a download that has not finished after this many minutes and count it as failed
+    /// (or retry it with --max-retries).
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    download_timeout: Option<u64>,
+
+    /// Stop a download whose progress has not advanced for this many minutes and resume it through the next proxy.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout: Option<u64>,
+
+    /// The program used to download the torrents. aria2c only supports HTTP proxies.
+    #[arg(long, value_enum, default_value_t = Backend::Transmission)]
+    backend: Backend,
+
+    /// Run this transmission-cli executable instead of the one on PATH, e.g. one installed under another name.
+    #[arg(long, value_name = "PATH")]
+    transmission_path: Option<String>,
+
+    /// Run this aria2c executable instead of the one on PATH.
+    #[arg(long, value_name = "PATH")]
+    aria2c_path: Option<String>,
+
+    /// Start without checking that transmission-cli (or aria2c) and, with --tor, tor are installed.
+    #[arg(long)]
+    skip_preflight: bool,
+
+    /// Pause the downloads while the network connection is metered, as NetworkManager reports it, and resume
+    /// them once it is not.
+    #[arg(long, alias = "pause-on-metered-network")]
+    pause_on_metered: bool,
+
+    /// A shell command that tells --pause-on-metered whether the connection is metered, instead of asking
+    /// NetworkManager: exit status 0 means metered, 1 means not metered.
+    #[arg(long, value_name = "COMMAND", requires = "pause_on_metered")]
+    metered_check_command: Option<String>,
+}
+
+/// The options of `batch_tor check`.
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// A file where the ranking is written, as CSV if the file name ends in .csv, and as JSON otherwise.
+    #[arg(long)]
+    report: Option<String>,
+}
+
+/// The options of `batch_tor download`.
+#[derive(clap::Args, Debug)]
+struct DownloadArgs {
+    /// The file containing magnet links. Each line is expected to be a valid magnet link.
+    /// Use `-` to read the links from standard input. Can be given multiple times, and accepts
+    /// glob patterns such as `lists/*.txt`; duplicates across files are downloaded once.
+    #[arg(short, long = "magnet-file", default_value = "magnet_links.txt")]
+    magnet_files: Vec<String>,
+
+    /// Skip magnet files that do not exist, with a warning, instead of stopping. At least one must exist.
+    #[arg(long)]
+    continue_on_missing_files: bool,
+
+    /// A file of tracker URLs (udp://, http:// or https://), one per line, added to every magnet link
+    /// that does not list them yet.
+    #[arg(long, value_name = "FILE")]
+    extra_trackers: Option<String>,
+
+    /// After downloading the listed torrents, keep watching the magnet files and download the torrents
+    /// added to them, until Ctrl-C.
+    #[arg(long)]
+    watch: bool,
+
+    /// Only download the torrents whose display name matches this regular expression.
+    /// Magnet links without a display name are kept.
+    #[arg(long, value_parser = Regex::new)]
+    filter: Option<Regex>,
+
+    /// Skip the torrents whose display name (or, without one, info hash) matches this regular expression.
+    #[arg(long, value_parser = Regex::new)]
+    exclude: Option<Regex>,
+
+    /// Only process the first N torrents of the list, after duplicates and filtered torrents are dropped.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    limit: Option<u32>,
+
     /// The directory where the downloaded torrents will be saved.
     #[arg(short, long, default_value = "/home/hombre/Torrents")]
     download_dir: String,
+
+    /// Save every torrent into its own subdirectory of the download directory, named after its display name.
+    #[arg(long)]
+    subdir_per_torrent: bool,
+
+    /// Keep the partial files of unfinished downloads instead of discarding them, so that running the batch
+    /// again resumes them.
+    #[arg(long, alias = "resume-incomplete")]
+    keep_incomplete: bool,
+
+    /// Where --keep-incomplete keeps the partial files; `.incomplete` in the download directory by default.
+    #[arg(long, value_name = "DIR", requires = "keep_incomplete")]
+    incomplete_dir: Option<String>,
+
+    /// Download only the files of each torrent that match this glob pattern, such as `*.mkv`; may be repeated.
+    /// A magnet link may name its own patterns instead, after ` ||want=`. Needs --backend aria2.
+    #[arg(long, value_name = "GLOB", value_parser = parse_file_pattern)]
+    file_filter: Vec<Pattern>,
+
+    /// Create the download directory if it does not exist.
+    #[arg(long)]
+    create_dir: bool,
+
+    /// Ask for confirmation before downloading more than this many torrents. Without a terminal,
+    /// such a batch is abandoned unless --yes is given.
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    confirm_threshold: u32,
+
+    /// Download without asking for confirmation, however many torrents there are.
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// The maximum number of torrents downloaded at the same time.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    parallel_downloads: u32,
+
+    /// Look for one distinct valid proxy per parallel download instead of a single shared proxy.
+    #[arg(long)]
+    proxy_per_download: bool,
+
+    /// Parse the inputs and check the proxies, but skip downloading. Exits non-zero unless
+    /// at least one torrent was parsed and a valid proxy was found.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Check every proxy of the list, without downloading anything, and print them ranked by latency.
+    /// With --report, the ranking is written as CSV if the file name ends in .csv, and as JSON otherwise.
+    #[arg(long, hide = true)]
+    verify_proxies_only: bool,
+
+    /// Check that the setup works end to end: check the proxies and download a small, well-seeded test torrent,
+    /// or the given magnet link, into a temporary directory that is removed afterwards. The magnet files are not read.
+    #[arg(long, hide = true, value_name = "MAGNET", num_args = 0..=1, default_missing_value = SELF_TEST_MAGNET, conflicts_with_all = ["verify_proxies_only", "dry_run", "watch"])]
+    self_test: Option<String>,
+
+    /// How many times a download is retried with the next valid proxy if transmission-cli fails.
+    #[arg(long, default_value_t = 0)]
+    max_retries: u32,
+
+    /// How many times in the run a proxy that passed the check but that the downloader cannot connect through is left
+    /// out, and the rest of the list searched for another one; 0, the default, keeps the proxies that were selected.
+    #[arg(long, default_value_t = 0)]
+    max_proxy_retries: u32,
+
+    /// Stop seeding a completed torrent once its upload ratio reaches this value.
+    #[arg(long)]
+    seed_ratio: Option<f64>,
+
+    /// Stop seeding a completed torrent after this many seconds.
+    /// With neither --seed-ratio nor --seed-time, torrents stop as soon as they finish downloading.
+    #[arg(long)]
+    seed_time: Option<u64>,
+
+    /// Check the proxy again right before each download attempt, and switch to another valid proxy if it fails.
+    #[arg(long)]
+    recheck_before_download: bool,
+
+    /// Send at most this many torrents through each proxy, then move on to the next valid proxy.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_downloads_per_proxy: Option<u32>,
+
+    /// What to do once every proxy served --max-downloads-per-proxy torrents.
+    #[arg(long, value_enum, default_value_t = ExhaustedPolicy::Fail)]
+    exhausted_policy: ExhaustedPolicy,
+
+    /// Print the size of every torrent and their total before checking the proxies. Sizes are known for
+    /// magnet links with an `xl` parameter and local .torrent files; with --file-filter or ||want=, only the
+    /// selected files count, and only local .torrent files have a known size.
+    #[arg(long)]
+    size_preview: bool,
+
+    /// Stop before downloading anything if the torrents take more than this, e.g. 50GB or 1.5TiB.
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    max_total_size: Option<u64>,
+
+    /// Ask the trackers of each magnet link how many seeders it has, and skip the torrents with fewer than N.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    min_seeders: Option<u64>,
+
+    /// What --min-seeders does with the torrents whose trackers cannot be scraped, and with .torrent files.
+    #[arg(long, value_enum, default_value_t = ScrapeUnknown::Download)]
+    scrape_unknown: ScrapeUnknown,
+
+    /// A file where the final report of the batch is written as JSON.
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Download every torrent, even those whose content is already complete in the download directory
+    /// or that the journal records as downloaded.
+    #[arg(long)]
+    force: bool,
+
+    /// A file where every torrent that finishes or fails is recorded as a JSON line. Torrents it records
+    /// as downloaded are skipped, so an interrupted batch can be resumed by running it again.
+    #[arg(long)]
+    journal: Option<String>,
+
+    /// Discard the entries of --journal and start the batch over.
+    #[arg(long, requires = "journal")]
+    reset_journal: bool,
+
+    /// A shell command run after each torrent finishes, e.g. to move or extract it. The torrent is described by
+    /// BATCH_TOR_MAGNET, BATCH_TOR_INFOHASH, BATCH_TOR_DIR and BATCH_TOR_STATUS (downloaded or failed).
+    #[arg(long, value_name = "COMMAND")]
+    on_complete: Option<String>,
+
+    /// POST the final report as JSON to this URL when the batch finishes, e.g. to get pinged after an overnight run.
+    #[arg(long, value_name = "URL")]
+    notify_webhook: Option<String>,
+
+    /// Show a desktop notification when the batch finishes.
+    #[arg(long)]
+    notify_desktop: bool,
+
+    /// If no proxy passes the check, download directly, without a proxy, after a warning, instead of stopping.
+    #[arg(long, conflicts_with_all = ["tor", "no_proxy"])]
+    fallback_direct: bool,
+
+    /// Before downloading, ask an IP-echo service which address it sees through each selected proxy, and compare
+    /// it with the address of this machine, asked directly, to catch proxies that do not hide it.
+    #[arg(long, conflicts_with = "no_proxy")]
+    leak_test: bool,
+
+    #[command(flatten)]
+    selection: SelectionArgs,
+
+    #[command(flatten)]
+    downloader: DownloaderArgs,
 }
 
-/// Reads the given file and extracts magnet links.
-/// The function expects each line in the file to represent a valid magnet link starting with "magnet:".
-///
-/// # Arguments
-///
-/// * `filename` - The path to the file containing magnet links.
-///
-/// # Returns
-///
-/// A vector of magnet links extracted from the file.
-fn extract_magnet_links(filename: &str) -> Vec<String> {
-    let file = File::open(filename).expect("Failed to open magnet links file");
-    let reader = io::BufReader::new(file);
-
-    let mut links = Vec::new();
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line from file");
-        if line.starts_with("magnet:") {
-            links.push(line);
-        }
-    }
-    links
+/// The options of `batch_tor verify`.
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// The magnet link of the test torrent; Sintel, the open movie of the Blender Foundation, by default.
+    #[arg(default_value = SELF_TEST_MAGNET, hide_default_value = true)]
+    magnet: String,
+
+    /// Only check the proxies and run the leak test through the selected one, without downloading the test torrent.
+    #[arg(long)]
+    leak_only: bool,
+
+    #[command(flatten)]
+    selection: SelectionArgs,
+
+    #[command(flatten)]
+    downloader: DownloaderArgs,
 }
 
-/// Reads the list of SOCKS5 proxies from a file.
-/// The function expects each line to be in the format `IP:PORT`, with each line representing one proxy.
-///
-/// # Arguments
-///
-/// * `filename` - The path to the file containing proxy addresses.
-///
-/// # Returns
-///
-/// A vector of SOCKS5 proxy addresses.
-fn extract_socks_proxies(filename: &str) -> Vec<String> {
-    let file = File::open(filename).expect("Failed to open proxy list file");
-    let reader = io::BufReader::new(file);
-
-    let mut proxies = Vec::new();
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line from file");
-        if !line.trim().is_empty() {
-            proxies.push(line);
-        }
+impl SharedArgs {
+    /// Sets the options of this group in `config`.
+    fn apply(self, config: &mut BatchTorConfig) {
+        config.socks_file = self.socks_file;
+        config.proxy_url = self.proxy_url;
+        config.proxy_format = self.proxy_format;
+        config.proxy_blocklist = self.proxy_blocklist;
+        config.proxy_countries = self.proxy_countries;
+        config.geoip_db = self.geoip_db;
+        config.geo_unknown = self.geo_unknown;
+        config.max_concurrency = self.max_concurrency as usize;
+        config.check_urls = self.check_urls;
+        config.proxy_timeout = Duration::from_secs(self.proxy_timeout);
+        config.warmup_bytes = self.warmup_bytes;
+        config.retry_timeouts = self.retry_timeouts;
+        config.check_attempts = self.check_attempts;
+        config.proxy_test_protocol = self.proxy_test_protocol;
+        config.bt_test_peer = self.bt_test_peer;
+        config.json = self.json;
+        config.verbosity = Verbosity::from_flags(self.quiet, self.verbose);
+        config.metrics_port = self.metrics_port;
     }
-    proxies
 }
 
-/// Asynchronously checks if a SOCKS5 proxy is functional using `curl`.
-/// It attempts to establish a connection to `rutracker.org` through the proxy.
-/// The function returns `Some(proxy)` if the proxy is valid, otherwise returns `None`.
-///
-/// # Arguments
-///
-/// * `proxy` - A `String` representing the SOCKS5 proxy in the format `IP:PORT`.
-///
-/// # Returns
-///
-/// An `Option<String>`, where `Some(proxy)` indicates a valid proxy and `None` indicates a failure.
-async fn check_proxy_with_curl(proxy: String) -> Option<String> {
-    println!("Checking proxy via curl: {}", proxy);
-
-    let status = Command::new("curl")
-        .arg("-x")
-        .arg(format!("socks5h://{}", proxy))
-        .arg("https://rutracker.org")
-        .arg("--max-time")
-        .arg("10")  // Sets a 10-second timeout for the connection attempt
-        .stdout(Stdio::null())  // Suppresses the output from curl
-        .stderr(Stdio::null())  // Suppresses any error messages from curl
-        .status();
-
-    match status {
-        Ok(status) => {
-            if status.success() {
-                println!("Proxy {} passed curl check.", proxy);
-                Some(proxy)
-            } else {
-                println!("Proxy {} failed curl check.", proxy);
-                None
-            }
-        }
-        Err(e) => {
-            println!("Error while checking proxy {}: {:?}", proxy, e);
-            None
-        }
+impl SelectionArgs {
+    /// Sets the options of this group in `config`.
+    fn apply(self, config: &mut BatchTorConfig) {
+        config.check_batch_size = self.check_batch_size.map(|size| size as usize);
+        config.strategy = self.strategy;
+        config.shuffle = self.shuffle;
+        config.shuffle_seed = self.shuffle_seed;
+        config.proxy_sample = self.proxy_sample.map(|size| size as usize);
+        config.sample_fallback = self.sample_fallback;
+        config.proxy_order_file = self.proxy_order_file;
+        config.interactive = self.interactive;
+        config.proxy_cache = self.proxy_cache;
+        config.proxy_cache_ttl = Duration::from_secs(self.proxy_cache_ttl);
+        config.proxy_cache_policy = self.proxy_cache_policy;
+        config.tor = self.tor;
+        config.no_proxy = self.no_proxy;
+        config.leak_policy = self.leak_policy;
+        config.ip_echo_url = self.ip_echo_url;
+        config.proxy_chain = self.proxy_chain;
     }
 }
 
-/// Runs a race to find the first valid SOCKS5 proxy from the list.
-/// Once a valid proxy is found, the remaining tasks are canceled to save resources.
-/// If no valid proxy is found, the function returns `None`.
-///
-/// # Arguments
-///
-/// * `proxies` - A vector of proxies to be checked.
-/// * `bar` - A reference to a progress bar (indicatif) to track the progress of the proxy checks.
-///
-/// # Returns
-///
-/// An `Option<String>` containing the first valid proxy, or `None` if no valid proxies are found.
-async fn find_valid_proxy(proxies: Vec<String>, bar: Arc<ProgressBar>) -> Option<String> {
-    let (tx, mut rx) = mpsc::channel(1);  // A channel to send the result back to the main thread.
-    let mut tasks = vec![];
-
-    // Spawn a task for each proxy to check it concurrently.
-    for proxy in proxies {
-        let tx = tx.clone();
-        let bar = Arc::clone(&bar);
-        let proxy_clone = proxy.clone();
-
-        let handle = task::spawn(async move {
-            let result = check_proxy_with_curl(proxy_clone).await;
-            if result.is_some() {
-                let _ = tx.send(result).await;  // Send the first valid proxy to the main thread.
-            }
-            bar.inc(1);  // Update the progress bar.
-        });
-
-        tasks.push(handle);
+impl DownloaderArgs {
+    /// Sets the options of this group in `config`.
+    fn apply(self, config: &mut BatchTorConfig) {
+        config.progress_format = self.progress_format;
+        config.progress_interval = Duration::from_secs(self.progress_interval);
+        config.download_limit = self.download_limit;
+        config.total_download_limit = self.total_download_limit;
+        config.upload_limit = self.upload_limit;
+        config.disable_dht = self.disable_dht;
+        config.disable_utp = self.disable_utp;
+        config.disable_pex = self.disable_pex;
+        config.allow_udp = self.allow_udp;
+        config.log_dir = self.log_dir;
+        config.log_sample = self.log_sample;
+        config.log_keyword = self.log_keyword;
+        config.backend = self.backend;
+        config.transmission_path = self.transmission_path;
+        config.aria2c_path = self.aria2c_path;
+        config.skip_preflight = self.skip_preflight;
+        config.pause_on_metered = self.pause_on_metered;
+        config.metered_check_command = self.metered_check_command;
+        config.download_timeout = self.download_timeout.map(|minutes| Duration::from_secs(minutes * 60));
+        config.stall_timeout = self.stall_timeout.map(|minutes| Duration::from_secs(minutes * 60));
     }
+}
 
-    // Wait for the first successful proxy.
-    let result = rx.recv().await;
-
-    // Cancel all remaining tasks.
-    for handle in tasks {
-        handle.abort();
+impl DownloadArgs {
+    /// Sets the options of this group in `config`.
+    fn apply(self, config: &mut BatchTorConfig) {
+        self.selection.apply(config);
+        self.downloader.apply(config);
+        config.magnet_files = self.magnet_files;
+        config.filter = TorrentFilter { include: self.filter, exclude: self.exclude };
+        config.limit = self.limit.map(|limit| limit as usize);
+        config.continue_on_missing_files = self.continue_on_missing_files;
+        config.extra_trackers = self.extra_trackers;
+        config.watch = self.watch;
+        config.download_dir = self.download_dir;
+        config.confirm_threshold = self.confirm_threshold as usize;
+        config.yes = self.yes;
+        config.parallel_downloads = self.parallel_downloads as usize;
+        config.proxy_per_download = self.proxy_per_download;
+        config.dry_run = self.dry_run;
+        config.verify_proxies_only = self.verify_proxies_only;
+        config.self_test = self.self_test;
+        config.max_retries = self.max_retries;
+        config.max_proxy_retries = self.max_proxy_retries;
+        config.seed_ratio = self.seed_ratio;
+        config.seed_time = self.seed_time.map(Duration::from_secs);
+        config.force = self.force;
+        config.fallback_direct = self.fallback_direct;
+        config.leak_test = self.leak_test;
+        config.subdir_per_torrent = self.subdir_per_torrent;
+        config.keep_incomplete = self.keep_incomplete;
+        config.incomplete_dir = self.incomplete_dir;
+        config.file_filter = self.file_filter;
+        config.create_dir = self.create_dir;
+        config.journal = self.journal;
+        config.reset_journal = self.reset_journal;
+        config.on_complete = self.on_complete;
+        config.notify_webhook = self.notify_webhook;
+        config.notify_desktop = self.notify_desktop;
+        config.recheck_before_download = self.recheck_before_download;
+        config.max_downloads_per_proxy = self.max_downloads_per_proxy.map(|max| max as usize);
+        config.exhausted_policy = self.exhausted_policy;
+        config.size_preview = self.size_preview;
+        config.max_total_size = self.max_total_size;
+        config.min_seeders = self.min_seeders;
+        config.scrape_unknown = self.scrape_unknown;
+        config.report = self.report;
     }
-
-    result.flatten()
 }
 
-/// Downloads a torrent file using the first valid SOCKS5 proxy found.
-/// It runs `transmission-cli` to download the torrent and logs both `stdout` and `stderr` to the console in real-time.
-///
-/// # Arguments
-///
-/// * `magnet_link` - The magnet link of the torrent to be downloaded.
-/// * `download_dir` - The directory where the downloaded files will be saved.
-/// * `proxy` - The SOCKS5 proxy to use for the download.
-fn download_torrent_via_socks(magnet_link: &str, download_dir: &str, proxy: &str) {
-    println!("Starting download for magnet link: {}", magnet_link);
-    io::stdout().flush().unwrap();  // Flushes the stdout buffer to ensure the message is printed immediately.
-
-    println!("Using proxy: {} for download", proxy);
-
-    let mut child = Command::new("transmission-cli")
-        .arg(magnet_link)
-        .arg("-w")
-        .arg(download_dir)
-        .arg("--no-incomplete")  // Ensures that incomplete downloads are not kept.
-        .arg("--debug")  // Enables debug logging for transmission-cli.
-        .env("ALL_PROXY", format!("socks5://{}", proxy))  // Sets the proxy environment variable for transmission-cli.
-        .stderr(Stdio::piped())  // Captures the stderr stream.
-        .stdout(Stdio::piped())  // Captures the stdout stream.
-        .spawn()
-        .expect("Failed to start transmission-cli");
-
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-    let stdout_reader = io::BufReader::new(stdout);
-    let stderr_reader = io::BufReader::new(stderr);
-
-    // Read stdout and print in real-time.
-    for line in stdout_reader.lines() {
-        match line {
-            Ok(line) => {
-                println!("{}", line);
-                io::stdout().flush().unwrap();  // Flushes after each line to ensure immediate output.
+impl From<Args> for BatchTorConfig {
+    fn from(args: Args) -> Self {
+        let mut config = BatchTorConfig::default();
+        args.shared.apply(&mut config);
+        match args.command {
+            Command::Check(check) => {
+                config.verify_proxies_only = true;
+                config.report = check.report;
+            }
+            Command::Download(download) => download.apply(&mut config),
+            Command::Verify(verify) => {
+                verify.selection.apply(&mut config);
+                verify.downloader.apply(&mut config);
+                config.self_test = Some(verify.magnet);
+                config.dry_run = verify.leak_only;
+                // There is nothing to test without a proxy.
+                config.leak_test = !config.no_proxy;
             }
-            Err(err) => eprintln!("Error reading stdout: {}", err),
         }
+        config
     }
+}
 
-    // Read stderr and print in real-time.
-    for line in stderr_reader.lines() {
-        match line {
-            Ok(line) => {
-                eprintln!("{}", line);
-                io::stderr().flush().unwrap();  // Flushes after each line to ensure immediate output.
-            }
-            Err(err) => eprintln!("Error reading stderr: {}", err),
-        }
+/// Parses the command line. Arguments that do not start with a subcommand, such as `batch_tor -m links.txt`,
+/// are parsed as those of `download`.
+fn parse_command_line() -> ArgMatches {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let command = Args::command();
+    let error = match command.clone().try_get_matches_from(&argv) {
+        Ok(matches) => return matches,
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => e.exit(),
+        Err(e) => e,
+    };
+    let names_subcommand = argv.iter().skip(1).any(|arg| arg == "help" || command.get_subcommands().any(|sub| arg == sub.get_name()));
+    if names_subcommand {
+        error.exit();
     }
+    let mut argv = argv;
+    argv.insert(argv.len().min(1), OsString::from("download"));
+    command.try_get_matches_from(argv).unwrap_or_else(|e| e.exit())
+}
 
-    child.wait().expect("Failed to wait for transmission-cli to finish");  // Waits for the transmission-cli process to finish.
+/// Returns whether the argument `id` was given on the command line, or through its environment variable,
+/// before or after the subcommand.
+fn given_on_command_line(matches: &ArgMatches, id: &str) -> bool {
+    let given = matches.ids().any(|known| known == id) && matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+    given || matches.subcommand().is_some_and(|(_, matches)| given_on_command_line(matches, id))
 }
 
-/// The main entry point of the program. It parses the CLI arguments, extracts magnet links and proxies,
-/// runs the race to find a valid proxy, and starts the torrent download using the first valid proxy found.
-/// Each magnet link is downloaded in a separate asynchronous task.
+/// Handles Ctrl-C: the first press requests a graceful shutdown, which stops new downloads and sends SIGTERM
+/// to the running transmission-cli processes; a second press kills them and exits immediately.
+async fn handle_ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    shutdown::request_shutdown();
+    if tokio::signal::ctrl_c().await.is_ok() {
+        shutdown::force_shutdown();
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+}
+
+/// The exit code used when the run is stopped by Ctrl-C, following the shell convention of 128 + SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// The main entry point of the program. It parses the CLI arguments and hands them to `batch_tor::run`,
+/// which extracts magnet links and proxies, runs the race to find a valid proxy, and downloads the torrents.
+/// The exit status tells how the batch ended, as listed in `RunError::exit_code`; an unreadable config file exits with 1.
 ///
 /// # Asynchronous Execution
 ///
 /// The function is marked with `#[tokio::main]` to run asynchronous tasks using the `tokio` runtime.
 #[tokio::main]
-async fn main() {
-    let args = Args::parse();  // Parses CLI arguments using `clap`.
-
-    // Extracts magnet links from the specified file.
-    let magnet_links = extract_magnet_links(&args.magnet_file);
-    if magnet_links.is_empty() {
-        println!("No magnet links found.");
-        return;
-    }
-
-    // Extracts SOCKS5 proxies from the specified file.
-    let proxies = extract_socks_proxies(&args.socks_file);
-    if proxies.is_empty() {
-        println!("No proxies found.");
-        return;
+async fn main() -> ExitCode {
+    let matches = parse_command_line();  // Parses CLI arguments using `clap`.
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config_path = args.shared.config.clone();
+    let implied_keys = args.command.implied_keys();
+    let mut config = BatchTorConfig::from(args);
+
+    // Values from the config file only fill in options that were not given on the command line.
+    let overridden = |key: &str| {
+        // The `verbosity` config key is set on the command line by `-v` and `-q`.
+        let ids: &[&str] = if key == "verbosity" { &["verbose", "quiet"] } else { &[key] };
+        implied_keys.contains(&key) || ids.iter().any(|id| given_on_command_line(&matches, id))
+    };
+    if apply_config_file(&mut config, config_path.as_deref().map(Path::new), overridden).is_err() {
+        return ExitCode::FAILURE;
     }
+    tokio::spawn(handle_ctrl_c());
 
-    // Sets up a progress bar to track the proxy-checking process.
-    let bar = Arc::new(ProgressBar::new(proxies.len() as u64));
-
-    // Runs the race to find the first valid proxy.
-    if let Some(valid_proxy) = find_valid_proxy(proxies, Arc::clone(&bar)).await {
-        bar.finish();  // Finishes the progress bar once a valid proxy is found or all proxies are checked.
-        
-        // Downloads each torrent in a separate asynchronous task.
-        let mut tasks = vec![];
-        for link in magnet_links {
-            let download_dir = args.download_dir.clone();
-            let valid_proxy = valid_proxy.clone();
-            tasks.push(tokio::spawn(async move {
-                download_torrent_via_socks(&link, &download_dir, &valid_proxy);
-            }));
-        }
-
-        // Wait for all download tasks to complete.
-        for task in tasks {
-            task.await.unwrap();
-        }
-
-    } else {
-        println!("No valid proxies found.");
+    match batch_tor::run(&config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        // Exits right away rather than waiting for a pending `--interactive` prompt to be answered.
+        Err(RunError::Interrupted) => std::process::exit(INTERRUPTED_EXIT_CODE),
+        Err(e) => ExitCode::from(e.exit_code()),
     }
 }
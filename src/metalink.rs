@@ -0,0 +1,117 @@
+//! Reading the torrents of a Metalink 4 (`.meta4`, RFC 5854) list, as exported by download managers.
+
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// One `<file>` of a Metalink.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetalinkFile {
+    /// The `name` attribute of the file, if any.
+    pub name: Option<String>,
+    /// The size of the file in bytes, from `<size>`, if given.
+    pub size: Option<u64>,
+    /// The magnet links and `.torrent` URLs of the file, from its `<metaurl>` and `<url>` elements, in that order.
+    pub links: Vec<String>,
+}
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).expect("Invalid Metalink pattern"))
+}
+
+/// Parses the `<file>` elements of a Metalink document. Only what batch_tor needs is read: the magnet links,
+/// the `<metaurl mediatype="torrent">` URLs, the `<url>` URLs that end in `.torrent`, and the size. Other URLs,
+/// which serve the file itself over HTTP or FTP, are left out.
+pub fn parse_metalink(document: &str) -> Vec<MetalinkFile> {
+    static FILE: OnceLock<Regex> = OnceLock::new();
+    static NAME: OnceLock<Regex> = OnceLock::new();
+    static SIZE: OnceLock<Regex> = OnceLock::new();
+    static URL: OnceLock<Regex> = OnceLock::new();
+    let file = regex(&FILE, r"(?s)<file\b([^>]*)>(.*?)</file\s*>");
+    let name = regex(&NAME, r#"\bname\s*=\s*(?:"([^"]*)"|'([^']*)')"#);
+    let size = regex(&SIZE, r"<size\s*>\s*(\d+)\s*</size\s*>");
+    let url = regex(&URL, r"(?s)<(metaurl|url)\b([^>]*)>(.*?)</(?:metaurl|url)\s*>");
+
+    file.captures_iter(document)
+        .map(|file| {
+            let (attributes, body) = (&file[1], &file[2]);
+            let name = name.captures(attributes).and_then(|name| name.get(1).or(name.get(2))).map(|name| decode_text(name.as_str()));
+            let size = size.captures(body).and_then(|size| size[1].parse().ok());
+            let mut urls: Vec<(bool, String)> = url
+                .captures_iter(body)
+                .filter_map(|url| {
+                    let (meta, link) = (&url[1] == "metaurl", decode_text(&url[3]));
+                    let wanted = link.starts_with("magnet:") || link.ends_with(".torrent") || (meta && says_torrent(&url[2]));
+                    wanted.then_some((meta, link))
+                })
+                .collect();
+            urls.sort_by_key(|(meta, _)| !*meta);  // A stable sort: the metaurls first, each kind in document order.
+            MetalinkFile { name, size, links: urls.into_iter().map(|(_, link)| link).collect() }
+        })
+        .collect()
+}
+
+/// Whether the attributes of a `<metaurl>` give it the torrent media type.
+fn says_torrent(attributes: &str) -> bool {
+    attributes.split_whitespace().any(|attribute| matches!(attribute.trim_end_matches('/'), r#"mediatype="torrent""# | "mediatype='torrent'"))
+}
+
+/// Returns the text of an element or attribute with its CDATA section unwrapped, its character references and
+/// XML entities decoded, and surrounding whitespace trimmed.
+fn decode_text(text: &str) -> String {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix("<![CDATA[").and_then(|inner| inner.strip_suffix("]]>")) {
+        return inner.trim().to_string();
+    }
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find(';').map(|end| start + end) else { break };
+        let entity = &rest[start + 1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        match character {
+            Some(character) => decoded.push(character),
+            None => decoded.push_str(&rest[start..=end]),  // Not an entity; kept as written.
+        }
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrent_links_and_sizes_are_read() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="Sintel.mkv">
+                <size>129241752</size>
+                <url priority="1">https://example.com/Sintel.mkv</url>
+                <url>magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10&amp;dn=Sintel</url>
+                <metaurl mediatype="torrent">https://example.com/get?id=1</metaurl>
+              </file>
+              <file name='notes &amp; more.txt'><url>ftp://example.com/notes.txt</url></file>
+              <file name="c.iso"><url><![CDATA[https://example.com/c.iso.torrent]]></url></file>
+            </metalink>"#;
+        let files = parse_metalink(document);
+        assert_eq!(files.len(), 3);
+        assert_eq!((files[0].name.as_deref(), files[0].size), (Some("Sintel.mkv"), Some(129241752)));
+        assert_eq!(files[0].links, ["https://example.com/get?id=1", "magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10&dn=Sintel"]);
+        assert_eq!((files[1].name.as_deref(), files[1].links.len()), (Some("notes & more.txt"), 0));
+        assert_eq!(files[2].links, ["https://example.com/c.iso.torrent"]);
+        assert_eq!(decode_text("a&#38;b&#x26;c&unknown;"), "a&b&c&unknown;");
+    }
+}
@@ -0,0 +1,193 @@
+//! `--pause-on-metered`: pause the downloads while the network connection is metered, and resume them once it is not.
+
+use crate::log::{emit, Level};
+use crate::shutdown::{shutdown_requested, signal_children, wait_for_shutdown};
+use serde_json::json;
+use std::pin::pin;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How often the connection is checked again.
+const METERED_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the downloads are paused because the connection is metered.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Wakes tasks waiting in `wait_while_paused` when the downloads resume.
+static RESUMED: OnceLock<Notify> = OnceLock::new();
+
+/// How `--pause-on-metered` learns whether the connection is metered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeteredCheck {
+    /// Ask NetworkManager over D-Bus, with `busctl`.
+    NetworkManager,
+    /// Run the `--metered-check-command` through `sh -c`: exit status 0 means metered, 1 means not metered.
+    Command(String),
+}
+
+/// Why the connection could not be checked.
+#[derive(Debug, Error)]
+pub enum MeteredError {
+    /// The check could not be run.
+    #[error("could not run {0}: {1}")]
+    Io(String, #[source] std::io::Error),
+    /// The check answered something else than metered or not metered; carries what it answered.
+    #[error("{0}")]
+    Unexpected(String),
+}
+
+/// Parses the answer of `busctl get-property` for the `Metered` property of NetworkManager, such as `u 1`:
+/// `NM_METERED_YES` (1) and `NM_METERED_GUESS_YES` (3) are metered, `NM_METERED_UNKNOWN` (0), `NM_METERED_NO` (2),
+/// and `NM_METERED_GUESS_NO` (4) are not.
+pub fn parse_nm_metered(answer: &str) -> Option<bool> {
+    match answer.trim().strip_prefix("u ")?.trim() {
+        "1" | "3" => Some(true),
+        "0" | "2" | "4" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns whether the connection is metered, according to `check`.
+pub async fn is_metered(check: &MeteredCheck) -> Result<bool, MeteredError> {
+    match check {
+        MeteredCheck::NetworkManager => {
+            let output = Command::new("busctl")
+                .args(["--system", "get-property", "org.freedesktop.NetworkManager", "/org/freedesktop/NetworkManager", "org.freedesktop.NetworkManager", "Metered"])
+                .stdin(Stdio::null())
+                .output()
+                .await
+                .map_err(|e| MeteredError::Io("busctl".to_string(), e))?;
+            if !output.status.success() {
+                return Err(MeteredError::Unexpected(format!("NetworkManager did not answer: {}", String::from_utf8_lossy(&output.stderr).trim())));
+            }
+            let answer = String::from_utf8_lossy(&output.stdout);
+            parse_nm_metered(&answer).ok_or_else(|| MeteredError::Unexpected(format!("NetworkManager answered `{}`", answer.trim())))
+        }
+        MeteredCheck::Command(command) => {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .await
+                .map_err(|e| MeteredError::Io(command.clone(), e))?;
+            match status.code() {
+                Some(0) => Ok(true),
+                Some(1) => Ok(false),
+                _ => Err(MeteredError::Unexpected(format!("{} exited with {}", command, status))),
+            }
+        }
+    }
+}
+
+/// Returns whether the downloads are paused.
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Completes once the downloads are not paused, or a shutdown has been requested.
+pub(crate) async fn wait_while_paused() {
+    let mut resumed = pin!(RESUMED.get_or_init(Notify::new).notified());
+    resumed.as_mut().enable();  // Registers before checking the flag, so a resume in between isn't missed.
+    if paused() && !shutdown_requested() {
+        tokio::select! {
+            _ = resumed => {}
+            _ = wait_for_shutdown() => {}
+        }
+    }
+}
+
+/// Pauses or resumes the downloads: no new attempt starts while they are paused, and the running downloaders are
+/// stopped with SIGSTOP, then continued with SIGCONT.
+fn set_paused(metered: bool) {
+    if PAUSED.swap(metered, Ordering::SeqCst) == metered {
+        return;
+    }
+    if metered {
+        emit(
+            Level::Warn,
+            "metered_pause",
+            "The connection is metered; pausing the downloads until it is not (--pause-on-metered).".to_string(),
+            json!({"paused": true}),
+        );
+        signal_children(libc::SIGSTOP);
+    } else {
+        emit(Level::Info, "metered_resume", "The connection is no longer metered; resuming the downloads.".to_string(), json!({"paused": false}));
+        signal_children(libc::SIGCONT);
+        RESUMED.get_or_init(Notify::new).notify_waiters();
+    }
+}
+
+/// Checks the connection with `check` every `METERED_CHECK_INTERVAL` while it is alive, pausing the downloads while
+/// it is metered. Dropping it resumes them.
+pub(crate) struct MeteredWatch {
+    task: JoinHandle<()>,
+}
+
+impl MeteredWatch {
+    /// Checks the connection once, so that a batch started on a metered connection starts paused, then keeps
+    /// checking it in the background. A check that fails is reported, and leaves the downloads as they are.
+    pub(crate) async fn start(check: MeteredCheck) -> MeteredWatch {
+        let mut failing = false;
+        update(&check, &mut failing).await;
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(METERED_CHECK_INTERVAL).await;
+                update(&check, &mut failing).await;
+            }
+        });
+        MeteredWatch { task }
+    }
+}
+
+/// Checks the connection and pauses or resumes the downloads. Only the first of consecutive failures is reported.
+async fn update(check: &MeteredCheck, failing: &mut bool) {
+    match is_metered(check).await {
+        Ok(metered) => {
+            *failing = false;
+            set_paused(metered);
+        }
+        Err(e) if !*failing => {
+            *failing = true;
+            emit(
+                Level::Warn,
+                "metered_check_error",
+                format!("Could not check whether the connection is metered: {}", e),
+                json!({"error": e.to_string()}),
+            );
+        }
+        Err(_) => {}
+    }
+}
+
+impl Drop for MeteredWatch {
+    fn drop(&mut self) {
+        self.task.abort();
+        if PAUSED.swap(false, Ordering::SeqCst) {
+            signal_children(libc::SIGCONT);
+            RESUMED.get_or_init(Notify::new).notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metered_answers_are_understood() {
+        assert_eq!((parse_nm_metered("u 1\n"), parse_nm_metered("u 3"), parse_nm_metered("u 4")), (Some(true), Some(true), Some(false)));
+        assert_eq!(parse_nm_metered("u 0"), Some(false));
+        assert_eq!(parse_nm_metered("s \"yes\""), None);
+        assert!(is_metered(&MeteredCheck::Command("exit 0".to_string())).await.unwrap());
+        assert!(!is_metered(&MeteredCheck::Command("exit 1".to_string())).await.unwrap());
+        assert!(is_metered(&MeteredCheck::Command("exit 7".to_string())).await.is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! The `--metrics-port` HTTP endpoint that reports the progress of a running batch as JSON at `/status`.
+
+use crate::backend::Progress;
+use crate::download::DownloadOutcome;
+use crate::proxy::Socks5Proxy;
+use crate::source::TorrentSource;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How long the server may take to finish the requests in flight once the batch is over.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The status of the batch, updated as it runs. It is recorded whether or not the server runs,
+/// since keeping it is cheaper than checking.
+static STATUS: Mutex<Status> = Mutex::new(Status::new());
+
+/// The stage the batch is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Starting,
+    CheckingProxies,
+    Downloading,
+    Finished,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Starting => "starting",
+            Phase::CheckingProxies => "checking_proxies",
+            Phase::Downloading => "downloading",
+            Phase::Finished => "finished",
+        }
+    }
+}
+
+/// A download that is running, keyed by `TrackedDownload::id` in `Status::downloads`.
+#[derive(Debug)]
+struct ActiveDownload {
+    torrent: String,
+    proxy: Option<String>,
+    percent: f64,
+    rate: Option<String>,
+    downloaded: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Status {
+    started: Option<Instant>,
+    phase: Phase,
+    proxies_total: usize,
+    proxies_passed: usize,
+    proxies_failed: usize,
+    proxies_in_use: Vec<String>,
+    torrents_total: usize,
+    torrents_completed: usize,
+    torrents_failed: usize,
+    torrents_skipped: usize,
+    bytes_downloaded: u64,
+    next_download: u64,
+    downloads: BTreeMap<u64, ActiveDownload>,
+}
+
+impl Status {
+    const fn new() -> Status {
+        Status {
+            started: None,
+            phase: Phase::Starting,
+            proxies_total: 0,
+            proxies_passed: 0,
+            proxies_failed: 0,
+            proxies_in_use: Vec::new(),
+            torrents_total: 0,
+            torrents_completed: 0,
+            torrents_failed: 0,
+            torrents_skipped: 0,
+            bytes_downloaded: 0,
+            next_download: 0,
+            downloads: BTreeMap::new(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let finished = self.torrents_completed + self.torrents_failed + self.torrents_skipped;
+        json!({
+            "phase": self.phase.name(),
+            "elapsed_ms": self.started.map(|started| started.elapsed().as_millis() as u64).unwrap_or_default(),
+            "proxies": {
+                "total": self.proxies_total,
+                "checked": self.proxies_passed + self.proxies_failed,
+                "passed": self.proxies_passed,
+                "failed": self.proxies_failed,
+                "in_use": self.proxies_in_use,
+            },
+            "torrents": {
+                "total": self.torrents_total,
+                "completed": self.torrents_completed,
+                "failed": self.torrents_failed,
+                "skipped": self.torrents_skipped,
+                "remaining": self.torrents_total.saturating_sub(finished),
+                "bytes_downloaded": self.bytes_downloaded,
+            },
+            "downloads": self.downloads.values().map(|download| json!({
+                "magnet": download.torrent,
+                "proxy": download.proxy,
+                "percent": download.percent,
+                "rate": download.rate,
+                "bytes": download.downloaded,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn update(change: impl FnOnce(&mut Status)) {
+    change(&mut STATUS.lock().expect("Batch status poisoned"));
+}
+
+/// Returns the current status of the batch, as served at `/status`.
+pub fn status() -> Value {
+    STATUS.lock().expect("Batch status poisoned").to_json()
+}
+
+/// Counts proxies that are about to be checked. Proxies checked once the downloads started, after
+/// a reload of the list, leave the batch in the downloading phase.
+pub(crate) fn proxies_queued(count: usize) {
+    update(|status| {
+        if status.phase == Phase::Starting {
+            status.phase = Phase::CheckingProxies;
+        }
+        status.proxies_total += count;
+    });
+}
+
+/// Counts a finished proxy check.
+pub(crate) fn proxy_checked(passed: bool) {
+    update(|status| {
+        if passed {
+            status.proxies_passed += 1;
+        } else {
+            status.proxies_failed += 1;
+        }
+    });
+}
+
+/// Records the proxies the downloads go through.
+pub(crate) fn set_proxies_in_use(proxies: &[Socks5Proxy]) {
+    update(|status| status.proxies_in_use = proxies.iter().map(|proxy| proxy.to_string()).collect());
+}
+
+/// Records the torrents of the batch, including those skipped because they were already downloaded.
+pub(crate) fn torrents_queued(total: usize, skipped: usize) {
+    update(|status| {
+        status.phase = Phase::Downloading;
+        status.torrents_total = total;
+        status.torrents_skipped = skipped;
+    });
+}
+
+/// Counts a torrent whose download succeeded, with its outcome, or failed for good.
+pub(crate) fn torrent_finished(outcome: Option<&DownloadOutcome>) {
+    update(|status| {
+        if let Some(outcome) = outcome {
+            status.torrents_completed += 1;
+            status.bytes_downloaded += outcome.bytes;
+        } else {
+            status.torrents_failed += 1;
+        }
+    });
+}
+
+/// Records that the batch is over.
+pub(crate) fn finished() {
+    update(|status| {
+        status.phase = Phase::Finished;
+        status.downloads.clear();
+    });
+}
+
+/// Keeps a running download listed in the status until it is dropped.
+#[derive(Debug)]
+pub(crate) struct TrackedDownload {
+    id: u64,
+}
+
+/// Lists a download attempt in the status.
+pub(crate) fn track_download(source: &TorrentSource, proxy: Option<&Socks5Proxy>) -> TrackedDownload {
+    let mut status = STATUS.lock().expect("Batch status poisoned");
+    let id = status.next_download;
+    status.next_download += 1;
+    status.downloads.insert(id, ActiveDownload { torrent: source.to_string(), proxy: proxy.map(|proxy| proxy.to_string()), percent: 0.0, rate: None, downloaded: None });
+    TrackedDownload { id }
+}
+
+impl TrackedDownload {
+    /// Records the progress reported by the downloader.
+    pub(crate) fn progress(&self, progress: &Progress) {
+        update(|status| {
+            if let Some(download) = status.downloads.get_mut(&self.id) {
+                download.percent = progress.percent.clamp(0.0, 100.0);
+                download.rate = progress.rate.clone();
+                download.downloaded = progress.downloaded.or(download.downloaded);
+            }
+        });
+    }
+}
+
+impl Drop for TrackedDownload {
+    fn drop(&mut self) {
+        update(|status| {
+            status.downloads.remove(&self.id);
+        });
+    }
+}
+
+/// The running `/status` server; `stop` shuts it down.
+#[derive(Debug)]
+pub struct MetricsServer {
+    address: SocketAddr,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl MetricsServer {
+    /// Starts serving the status of the batch at `http://<host>:<port>/status` on every interface.
+    pub async fn start(port: u16) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let address = listener.local_addr()?;
+        update(|status| {
+            status.started.get_or_insert_with(Instant::now);
+        });
+
+        let app = Router::new().route("/status", get(|| async { Json(status()) }));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        Ok(MetricsServer { address, stop, task })
+    }
+
+    /// The address the server listens on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stops accepting connections and waits briefly for the requests in flight to finish.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let mut task = self.task;
+        if tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_torrents_exclude_finished_and_skipped() {
+        let mut status = Status::new();
+        status.torrents_total = 5;
+        (status.torrents_completed, status.torrents_failed, status.torrents_skipped) = (1, 1, 2);
+        status.downloads.insert(0, ActiveDownload { torrent: "A".to_string(), proxy: Some("10.0.0.1:1080".to_string()), percent: 45.3, rate: None, downloaded: None });
+        let json = status.to_json();
+        assert_eq!(json["torrents"]["remaining"], 1);
+        assert_eq!(json["downloads"][0]["percent"], 45.3);
+    }
+}
@@ -0,0 +1,117 @@
+//! The `--notify-webhook` and `--notify-desktop` notifications sent when a batch finishes.
+
+use crate::log::{emit, Level};
+use crate::proxy::error_chain;
+use crate::report::{format_bytes, BatchReport};
+use crate::BatchTorConfig;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+use thiserror::Error;
+
+/// How long the webhook may take to accept the notification.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a notification could not be sent.
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    /// The webhook could not be reached.
+    #[error("{}", error_chain(.0))]
+    Http(#[source] reqwest::Error),
+    /// The webhook answered with an error status.
+    #[error("the webhook answered {0}")]
+    Status(reqwest::StatusCode),
+    /// The desktop notification could not be shown; carries the reason.
+    #[error("{0}")]
+    Desktop(String),
+}
+
+/// Returns the body POSTed to the webhook: the counts of the batch, whether it was `interrupted`, and the full
+/// report under `report`, in the shape written by `--report`.
+pub fn notification_payload(report: &BatchReport, interrupted: bool) -> Value {
+    let status = if interrupted {
+        "interrupted"
+    } else if report.failed.is_empty() {
+        "succeeded"
+    } else {
+        "failed"
+    };
+    json!({
+        "event": "batch_finished",
+        "status": status,
+        "total": report.total(),
+        "succeeded": report.succeeded.len(),
+        "failed": report.failed.len(),
+        "skipped": report.skipped.len(),
+        "report": report.to_json(),
+    })
+}
+
+/// POSTs `payload` to `url` as JSON.
+pub async fn send_webhook(url: &str, payload: &Value) -> Result<(), NotifyError> {
+    let client = Client::builder().timeout(WEBHOOK_TIMEOUT).build().map_err(NotifyError::Http)?;
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(NotifyError::Http)?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(NotifyError::Status(response.status()))
+    }
+}
+
+/// Shows a desktop notification with the outcome of the batch.
+pub async fn show_desktop_notification(report: &BatchReport) -> Result<(), NotifyError> {
+    let mut body = format!("Downloaded {} of {} torrents ({}).", report.succeeded.len(), report.total(), format_bytes(report.bytes_downloaded));
+    if !report.failed.is_empty() {
+        body.push_str(&format!(" {} failed.", report.failed.len()));
+    }
+    // Talking to the notification daemon blocks, so it runs off the async threads.
+    tokio::task::spawn_blocking(move || notify_rust::Notification::new().appname("batch_tor").summary("batch_tor finished").body(&body).show().map(drop))
+        .await
+        .map_err(|e| NotifyError::Desktop(e.to_string()))?
+        .map_err(|e| NotifyError::Desktop(e.to_string()))
+}
+
+/// Sends the notifications asked for by `--notify-webhook` and `--notify-desktop`. A notification that cannot be
+/// sent is reported with a warning; it never changes the outcome of the batch.
+pub(crate) async fn notify_finished(config: &BatchTorConfig, report: &BatchReport, interrupted: bool) {
+    if let Some(url) = &config.notify_webhook {
+        match send_webhook(url, &notification_payload(report, interrupted)).await {
+            Ok(()) => emit(Level::Debug, "notification_sent", format!("Sent the batch report to {}", url), json!({"url": url})),
+            Err(e) => emit(
+                Level::Warn,
+                "notification_error",
+                format!("Could not send the batch report to {}: {}", url, e),
+                json!({"url": url, "error": e.to_string()}),
+            ),
+        }
+    }
+    if config.notify_desktop {
+        if let Err(e) = show_desktop_notification(report).await {
+            emit(Level::Warn, "notification_error", format!("Could not show a desktop notification: {}", e), json!({"desktop": true, "error": e.to_string()}));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::DownloadError;
+    use crate::source::TorrentSource;
+
+    #[test]
+    fn payload_counts_the_outcomes() {
+        let magnet = |hash: char| TorrentSource::parse(&format!("magnet:?xt=urn:btih:{}", hash.to_string().repeat(40))).unwrap().unwrap();
+        let report = BatchReport { failed: vec![(magnet('1'), DownloadError::Interrupted)], skipped: vec![magnet('2'), magnet('3')], ..Default::default() };
+        let payload = notification_payload(&report, false);
+        assert_eq!((payload["status"].as_str(), payload["total"].as_u64(), payload["failed"].as_u64()), (Some("failed"), Some(3), Some(1)));
+        assert_eq!(payload["report"]["skipped"].as_array().map(Vec::len), Some(2));
+        assert_eq!(notification_payload(&BatchReport::default(), true)["status"], "interrupted");
+    }
+}
@@ -0,0 +1,125 @@
+//! `--proxy-test-protocol bittorrent`: checking that a proxy lets plain TCP connections through to BitTorrent ports,
+//! which many proxies that pass an HTTPS check refuse.
+
+use crate::magnet::normalize_peer;
+use crate::proxy::{ProxyKind, Socks5Proxy};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::{Socks4Stream, Socks5Stream};
+
+/// The peer connected to by `--proxy-test-protocol bittorrent` unless `--bt-test-peer` names another: the TCP port of
+/// the opentrackr.org tracker, which is always up and, like BitTorrent peers, not on a web port.
+pub const DEFAULT_BT_TEST_PEER: &str = "tracker.opentrackr.org:1337";
+
+/// The longest answer to an HTTP `CONNECT` request that is read.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+/// What the proxy check tests, set with `--proxy-test-protocol`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestProtocol {
+    /// Only the requests to the check URLs.
+    #[default]
+    Http,
+    /// The requests to the check URLs, then a TCP connection to `--bt-test-peer` through the proxy.
+    Bittorrent,
+}
+
+/// Parses a `--bt-test-peer` address, `host:port` with an IPv6 address in brackets.
+pub fn parse_peer_address(address: &str) -> Result<String, String> {
+    normalize_peer(address).ok_or_else(|| format!("`{}` is not an address such as host:6881", address))
+}
+
+/// Opens a TCP connection to `peer`, a `host:port` address, through `proxy`, and closes it again. The host name is
+/// resolved by the proxy.
+pub async fn connect_to_peer(proxy: &Socks5Proxy, peer: &str) -> io::Result<()> {
+    let (host, port) = peer.rsplit_once(':').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port: u16 = port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+    let socket = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    match proxy.kind {
+        ProxyKind::Socks5 => {
+            let connected = match &proxy.auth {
+                Some((user, pass)) => Socks5Stream::connect_with_password_and_socket(socket, (host, port), user, pass).await,
+                None => Socks5Stream::connect_with_socket(socket, (host, port)).await,
+            };
+            connected.map(drop).map_err(io::Error::other)
+        }
+        ProxyKind::Socks4 => {
+            let connected = match &proxy.auth {
+                Some((user, _)) => Socks4Stream::connect_with_userid_and_socket(socket, (host, port), user).await,
+                None => Socks4Stream::connect_with_socket(socket, (host, port)).await,
+            };
+            connected.map(drop).map_err(io::Error::other)
+        }
+        ProxyKind::Http => http_connect(socket, peer, proxy.auth.as_ref()).await,
+    }
+}
+
+/// Asks an HTTP proxy to open a tunnel to `peer` with a `CONNECT` request, and checks that it agreed.
+async fn http_connect(mut socket: TcpStream, peer: &str, auth: Option<&(String, String)>) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", peer, peer);
+    if let Some((user, pass)) = auth {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64(format!("{}:{}", user, pass).as_bytes())));
+    }
+    request.push_str("\r\n");
+    socket.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = socket.read(&mut buffer).await?;
+        if read == 0 || response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the proxy closed the connection"));
+        }
+        response.extend_from_slice(&buffer[..read]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    if connect_succeeded(status) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("the proxy answered {}", status.trim())))
+    }
+}
+
+/// Whether the status line of the answer to a `CONNECT` request, e.g. `HTTP/1.1 200 Connection established`, is a success.
+fn connect_succeeded(status: &str) -> bool {
+    let mut parts = status.split_whitespace();
+    parts.next().is_some_and(|version| version.starts_with("HTTP/")) && parts.next().is_some_and(|code| code.starts_with('2'))
+}
+
+/// Encodes `bytes` in standard base64 with padding, for the `Proxy-Authorization` header.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (index, &byte)| triple | u32::from(byte) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(triple >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_answers_and_credentials_are_understood() {
+        assert!(connect_succeeded("HTTP/1.1 200 Connection established\r"));
+        assert!(!connect_succeeded("HTTP/1.0 403 Forbidden"));
+        assert!(!connect_succeeded("SSH-2.0-OpenSSH_9.6"));
+        assert_eq!((base64(b"user:pass"), base64(b"ab"), base64(b"a")), ("dXNlcjpwYXNz".to_string(), "YWI=".to_string(), "YQ==".to_string()));
+        assert_eq!(parse_peer_address("Tracker.Example:1337").as_deref(), Ok("tracker.example:1337"));
+        assert!(parse_peer_address("tracker.example").is_err());
+    }
+}
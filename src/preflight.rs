@@ -0,0 +1,151 @@
+//! Checking that the external programs a batch needs are installed before it starts.
+
+use crate::log::{emit, Level};
+use crate::tor::{TOR_CONTROL_PORT, TOR_HOST};
+use crate::BatchTorConfig;
+use serde_json::json;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// An external program that is needed but could not be found on `PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingProgram {
+    /// The name of the program.
+    pub program: &'static str,
+    /// The Debian/Ubuntu package that provides it.
+    pub package: &'static str,
+}
+
+/// Returns the full path of `program` if an executable with that name is on `PATH`, like `which`.
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join(program)).find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Returns whether something is listening on tor's control port, in which case tor need not be installed.
+fn tor_is_running() -> bool {
+    (TOR_HOST, TOR_CONTROL_PORT)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .is_some_and(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok())
+}
+
+/// Whether `program` can be run: a path to an executable file, or, without a `/`, the name of one on `PATH`.
+pub fn is_runnable(program: &str) -> bool {
+    if program.contains('/') {
+        is_executable(Path::new(program))
+    } else {
+        find_in_path(program).is_some()
+    }
+}
+
+/// Lists the external programs `config` needs that are not installed: the downloader selected by
+/// `backend`, at its `--transmission-path` or `--aria2c-path` if given, and `tor` with `tor` set unless
+/// a tor is already running.
+pub fn missing_programs(config: &BatchTorConfig) -> Vec<MissingProgram> {
+    let downloader = config.backend.downloader();
+    let mut missing = Vec::new();
+    if !is_runnable(config.downloader_path().unwrap_or(downloader.program())) {
+        missing.push(MissingProgram { program: downloader.program(), package: downloader.package() });
+    }
+    if config.tor && !tor_is_running() && find_in_path("tor").is_none() {
+        missing.push(MissingProgram { program: "tor", package: "tor" });
+    }
+    missing
+}
+
+/// Reports every missing program with a hint on how to install it.
+///
+/// # Returns
+///
+/// The missing programs; the batch should not start unless this is empty.
+pub(crate) fn preflight(config: &BatchTorConfig) -> Vec<MissingProgram> {
+    let missing = missing_programs(config);
+    for program in &missing {
+        if let Some(path) = config.downloader_path().filter(|_| program.program == config.backend.downloader().program()) {
+            emit(
+                Level::Error,
+                "program_missing",
+                format!("{} is not an executable {}", path, program.program),
+                json!({"program": program.program, "path": path}),
+            );
+            continue;
+        }
+        emit(
+            Level::Error,
+            "program_missing",
+            format!("{} not found; install it via apt install {}", program.program, program.package),
+            json!({"program": program.program, "package": program.package}),
+        );
+    }
+    missing
+}
+
+/// The reasons the download directory cannot be used.
+#[derive(Debug, Error)]
+pub enum DownloadDirError {
+    /// The directory does not exist and `--create-dir` was not given.
+    #[error("download directory {} does not exist; pass --create-dir to create it", .0.display())]
+    Missing(PathBuf),
+    /// The path exists but is not a directory.
+    #[error("download directory {} is not a directory", .0.display())]
+    NotADirectory(PathBuf),
+    /// The directory could not be created.
+    #[error("could not create download directory {}: {}", .0.display(), .1)]
+    Create(PathBuf, #[source] io::Error),
+    /// A file could not be written to the directory.
+    #[error("download directory {} is not writable: {}", .0.display(), .1)]
+    NotWritable(PathBuf, #[source] io::Error),
+}
+
+/// Checks that `dir` exists, creating it first if `create` is set, and that files can be written to it,
+/// by creating and removing a temporary file.
+pub fn check_download_dir(dir: &Path, create: bool) -> Result<(), DownloadDirError> {
+    if !dir.exists() {
+        if !create {
+            return Err(DownloadDirError::Missing(dir.to_path_buf()));
+        }
+        fs::create_dir_all(dir).map_err(|e| DownloadDirError::Create(dir.to_path_buf(), e))?;
+    }
+    if !dir.is_dir() {
+        return Err(DownloadDirError::NotADirectory(dir.to_path_buf()));
+    }
+    let probe = dir.join(format!(".batch_tor-write-test-{}", std::process::id()));
+    let written = OpenOptions::new().write(true).create_new(true).open(&probe);
+    let _ = fs::remove_file(&probe);
+    written.map(drop).map_err(|e| DownloadDirError::NotWritable(dir.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_dirs_are_checked_and_created() {
+        let root = std::env::temp_dir().join(format!("batch_tor-download-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let missing = root.join("missing");
+        let created = root.join("created").join("nested");
+        let file = root.join("file");
+        fs::write(&file, "").unwrap();
+
+        let results = (check_download_dir(&missing, false), check_download_dir(&created, true), check_download_dir(&file, true));
+        let created_is_dir = created.is_dir();
+        let _ = fs::remove_dir_all(&root);
+        assert!(matches!(results.0, Err(DownloadDirError::Missing(path)) if path == missing));
+        assert!(!missing.exists());
+        assert!(results.1.is_ok() && created_is_dir);
+        assert!(matches!(results.2, Err(DownloadDirError::NotADirectory(path)) if path == file));
+    }
+}
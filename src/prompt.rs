@@ -0,0 +1,123 @@
+//! Prompting the user: picking the download proxy by hand with `--interactive`, and confirming large batches.
+
+use crate::proxy::{format_throughput, ProxyResult};
+use crate::shutdown::wait_for_shutdown;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Whether the user can be prompted: both standard input and standard error must be a terminal.
+pub(crate) fn can_prompt() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Lists the working proxies, best first, and asks the user to pick up to `wanted` of them by number.
+/// Entering nothing, or closing standard input, keeps the first `wanted` proxies of the list. The prompt is
+/// repeated until the answer is valid, and abandoned with no proxies if a shutdown is requested meanwhile.
+pub(crate) async fn choose_proxies(mut results: Vec<ProxyResult>, wanted: usize) -> Vec<ProxyResult> {
+    if results.len() <= 1 {
+        return results;
+    }
+    let mut stderr = io::stderr().lock();
+    let _ = writeln!(stderr, "Working proxies:");
+    for (number, result) in results.iter().enumerate() {
+        let speed = result.throughput.map(|speed| format!(", {}", format_throughput(speed))).unwrap_or_default();
+        let _ = writeln!(stderr, "  {:>3}. {} ({} ms{})", number + 1, result.proxy, result.latency.as_millis(), speed);
+    }
+    drop(stderr);
+
+    let wanted = wanted.min(results.len());
+    loop {
+        {
+            let mut stderr = io::stderr().lock();
+            let _ = match wanted {
+                1 => write!(stderr, "Proxy to use [1-{}, Enter for 1]: ", results.len()),
+                _ => write!(stderr, "Proxies to use, up to {} separated by spaces [1-{}, Enter for 1-{}]: ", wanted, results.len(), wanted),
+            };
+            let _ = stderr.flush();
+        }
+        let line = tokio::select! {
+            line = tokio::task::spawn_blocking(read_answer) => line.ok().flatten(),
+            _ = wait_for_shutdown() => return Vec::new(),
+        };
+        let Some(line) = line.filter(|line| !line.trim().is_empty()) else {
+            results.truncate(wanted);
+            return results;
+        };
+        match parse_choice(&line, results.len(), wanted) {
+            Some(numbers) => return numbers.into_iter().map(|number| results[number - 1].clone()).collect(),
+            None => {
+                let mut stderr = io::stderr().lock();
+                let _ = writeln!(stderr, "Please enter up to {} distinct numbers between 1 and {}.", wanted, results.len());
+            }
+        }
+    }
+}
+
+/// Asks a yes-or-no question, which defaults to no. Closing standard input or requesting a shutdown answers no.
+pub(crate) async fn confirm(question: &str) -> bool {
+    {
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "{}", question);
+        let _ = stderr.flush();
+    }
+    let line = tokio::select! {
+        line = tokio::task::spawn_blocking(read_answer) => line.ok().flatten(),
+        _ = wait_for_shutdown() => return false,
+    };
+    line.is_some_and(|line| is_yes(&line))
+}
+
+/// Whether an answer to `confirm` is a yes.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Reads one line from standard input, or `None` at end of input.
+fn read_answer() -> Option<String> {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+/// Parses an answer of up to `wanted` distinct numbers between 1 and `count`, separated by spaces or commas.
+fn parse_choice(line: &str, count: usize, wanted: usize) -> Option<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for word in line.split([' ', ',', '\t']).map(str::trim).filter(|word| !word.is_empty()) {
+        let number: usize = word.parse().ok()?;
+        if number == 0 || number > count || numbers.contains(&number) {
+            return None;
+        }
+        numbers.push(number);
+    }
+    (!numbers.is_empty() && numbers.len() <= wanted).then_some(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choices_are_numbers_within_the_list() {
+        assert_eq!(parse_choice("2\n", 3, 1), Some(vec![2]));
+        assert_eq!(parse_choice("3, 1", 3, 2), Some(vec![3, 1]));
+        assert_eq!(parse_choice("0", 3, 1), None);
+        assert_eq!(parse_choice("4", 3, 1), None);
+        assert_eq!(parse_choice("one", 3, 1), None);
+    }
+
+    #[test]
+    fn only_yes_confirms() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no"));
+    }
+
+    #[test]
+    fn choices_are_distinct_and_at_most_wanted() {
+        assert_eq!(parse_choice("1 1", 3, 2), None);
+        assert_eq!(parse_choice("1 2", 3, 1), None);
+        assert_eq!(parse_choice(" , ", 3, 1), None);
+    }
+}
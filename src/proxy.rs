@@ -0,0 +1,1223 @@
+//! Reading proxy lists and checking which proxies work.
+
+use crate::log::{self, emit, json_output, Level};
+use crate::metrics;
+use crate::peer_check::connect_to_peer;
+use crate::source::open_input;
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, BufRead};
+use std::net::Ipv6Addr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+/// The strategy used by `find_valid_proxies` to pick proxies.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Use the first proxy that passes the check and cancel the remaining checks.
+    First,
+    /// Wait for all checks to complete and use the proxy with the lowest latency.
+    Fastest,
+}
+
+/// The protocol spoken by a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Http,
+    Socks4,
+    Socks5,
+}
+
+impl ProxyKind {
+    /// Returns the URL scheme for this kind of proxy. With `remote_dns`, the SOCKS variants that
+    /// resolve host names on the proxy (`socks4a`, `socks5h`) are used so that lookups do not leak.
+    pub fn scheme(self, remote_dns: bool) -> &'static str {
+        match (self, remote_dns) {
+            (ProxyKind::Http, _) => "http",
+            (ProxyKind::Socks4, false) => "socks4",
+            (ProxyKind::Socks4, true) => "socks4a",
+            (ProxyKind::Socks5, false) => "socks5",
+            (ProxyKind::Socks5, true) => "socks5h",
+        }
+    }
+}
+
+/// The reasons a proxy line can be rejected by `Socks5Proxy::parse`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProxyParseError {
+    /// The line is not in the format `[scheme://][user:pass@]IP:PORT`, with IPv6 addresses in brackets.
+    #[error("invalid proxy, expected [scheme://][user:pass@]IP:PORT, with an IPv6 address in brackets")]
+    Malformed,
+    /// The line uses a scheme that transmission-cli cannot tunnel through.
+    #[error("unsupported proxy scheme {0}://, transmission-cli can only use http://, socks4:// and socks5:// proxies")]
+    UnsupportedScheme(String),
+}
+
+/// A proxy endpoint, optionally protected by a username and password.
+/// Despite the name, the proxy may also be an HTTP or SOCKS4 proxy, as recorded in `kind`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Socks5Proxy {
+    pub kind: ProxyKind,
+    /// The IP address or host name, without the brackets of an IPv6 address.
+    pub host: String,
+    pub port: u16,
+    /// The `(username, password)` pair, if the proxy requires authentication.
+    pub auth: Option<(String, String)>,
+    /// For the local relay of a `--proxy-chain`, the proxies it forwards every connection through, in order;
+    /// empty for a plain proxy. See `chain::start_chain`.
+    pub chain: Vec<Socks5Proxy>,
+}
+
+impl Socks5Proxy {
+    /// Parses a proxy in the format `IP:PORT` or `user:pass@IP:PORT`, optionally prefixed with
+    /// `http://`, `socks4://`, or `socks5://`. Proxies without a scheme are treated as SOCKS5.
+    /// IPv6 addresses are written in brackets, e.g. `[2001:db8::1]:1080`.
+    pub fn parse(line: &str) -> Result<Socks5Proxy, ProxyParseError> {
+        let (kind, rest) = match line.split_once("://") {
+            Some((scheme, rest)) => {
+                let kind = match scheme.to_ascii_lowercase().as_str() {
+                    "http" => ProxyKind::Http,
+                    "socks4" => ProxyKind::Socks4,
+                    "socks5" => ProxyKind::Socks5,
+                    _ => return Err(ProxyParseError::UnsupportedScheme(scheme.to_string())),
+                };
+                (kind, rest)
+            }
+            None => (ProxyKind::Socks5, line),
+        };
+
+        let (auth, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let (user, pass) = credentials.split_once(':').ok_or(ProxyParseError::Malformed)?;
+                if user.is_empty() {
+                    return Err(ProxyParseError::Malformed);
+                }
+                (Some((user.to_string(), pass.to_string())), address)
+            }
+            None => (None, rest),
+        };
+
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed.split_once("]:").ok_or(ProxyParseError::Malformed)?;
+                host.parse::<Ipv6Addr>().map_err(|_| ProxyParseError::Malformed)?;
+                (host, port)
+            }
+            None => {
+                let (host, port) = address.rsplit_once(':').ok_or(ProxyParseError::Malformed)?;
+                // Without brackets, the port of an IPv6 address cannot be told apart from its last group.
+                if host.is_empty() || host.contains(':') {
+                    return Err(ProxyParseError::Malformed);
+                }
+                (host, port)
+            }
+        };
+        let port = port.parse().map_err(|_| ProxyParseError::Malformed)?;
+
+        Ok(Socks5Proxy { kind, host: host.to_string(), port, auth, chain: Vec::new() })
+    }
+
+    /// Formats the proxy as a URL for its kind, including credentials if present.
+    /// See `ProxyKind::scheme` for the meaning of `remote_dns`.
+    pub fn url(&self, remote_dns: bool) -> String {
+        let scheme = self.kind.scheme(remote_dns);
+        match &self.auth {
+            Some((user, pass)) => format!("{}://{}:{}@{}", scheme, user, pass, self.address()),
+            None => format!("{}://{}", scheme, self.address()),
+        }
+    }
+
+    /// Formats the host and port as `IP:PORT`, with an IPv6 address in brackets.
+    pub(crate) fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Displays the proxy as `IP:PORT`, prefixed with the scheme for non-SOCKS5 proxies,
+/// leaving out credentials so they never end up in logs. A chain is displayed as its hops, e.g. `IP:PORT -> IP:PORT`.
+impl fmt::Display for Socks5Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.chain.is_empty() {
+            let hops: Vec<String> = self.chain.iter().map(|hop| hop.to_string()).collect();
+            return write!(f, "{}", hops.join(" -> "));
+        }
+        match self.kind {
+            ProxyKind::Socks5 => write!(f, "{}", self.address()),
+            kind => write!(f, "{}://{}", kind.scheme(false), self.address()),
+        }
+    }
+}
+
+/// The format of a proxy list, set with `--proxy-format`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyFormat {
+    /// Guess from the extension of the file or URL: `.json` and `.csv` lists are read as such, others as text.
+    #[default]
+    Auto,
+    /// One proxy per line, as accepted by `Socks5Proxy::parse`.
+    Text,
+    /// An array of proxies, each either a string like a line of a text list or an object with the
+    /// fields of a `ProxyRecord`.
+    Json,
+    /// A header row naming the columns of a `ProxyRecord`, then one proxy per row.
+    Csv,
+}
+
+impl ProxyFormat {
+    /// The format a list named `name`, a path or URL, is in according to its extension, if it has a known one.
+    pub fn from_extension(name: &str) -> Option<ProxyFormat> {
+        let path = name.split(['?', '#']).next().unwrap_or(name);
+        let (_, extension) = path.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "json" => Some(ProxyFormat::Json),
+            "csv" => Some(ProxyFormat::Csv),
+            "txt" => Some(ProxyFormat::Text),
+            _ => None,
+        }
+    }
+
+    /// The format of a list named `name`: this one, or for `Auto`, the one its extension suggests, or text.
+    pub fn resolve(self, name: &str) -> ProxyFormat {
+        match self {
+            ProxyFormat::Auto => ProxyFormat::from_extension(name).unwrap_or(ProxyFormat::Text),
+            format => format,
+        }
+    }
+}
+
+/// A proxy in a JSON or CSV list. Field and column names are matched without regard to case, and the
+/// common alternatives given as aliases are accepted too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ProxyRecord {
+    /// The IP address or host name; IPv6 addresses may be in brackets.
+    #[serde(alias = "ip", alias = "address")]
+    pub host: String,
+    /// The port, as a number or a string.
+    #[serde(deserialize_with = "port")]
+    pub port: u16,
+    #[serde(default, alias = "user", alias = "login")]
+    pub username: Option<String>,
+    #[serde(default, alias = "pass")]
+    pub password: Option<String>,
+    /// `http`, `socks4`, or `socks5`, the default.
+    #[serde(default, alias = "type", alias = "scheme")]
+    pub protocol: Option<String>,
+}
+
+/// Deserializes a port written as either a number or a string, as CSV columns and many JSON exports do.
+fn port<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u64),
+        Text(String),
+    }
+    let port = match Port::deserialize(deserializer)? {
+        Port::Number(port) => port.to_string(),
+        Port::Text(port) => port,
+    };
+    port.trim().parse().map_err(|_| serde::de::Error::custom(format!("invalid port `{}`", port)))
+}
+
+impl TryFrom<ProxyRecord> for Socks5Proxy {
+    type Error = ProxyParseError;
+
+    fn try_from(record: ProxyRecord) -> Result<Socks5Proxy, ProxyParseError> {
+        let kind = match record.protocol.as_deref().map(str::trim).filter(|protocol| !protocol.is_empty()) {
+            None => ProxyKind::Socks5,
+            Some(protocol) => match protocol.to_ascii_lowercase().as_str() {
+                "http" => ProxyKind::Http,
+                "socks4" => ProxyKind::Socks4,
+                "socks5" => ProxyKind::Socks5,
+                _ => return Err(ProxyParseError::UnsupportedScheme(protocol.to_string())),
+            },
+        };
+        let host = record.host.trim();
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() || host.contains(char::is_whitespace) || (host.contains(':') && host.parse::<Ipv6Addr>().is_err()) {
+            return Err(ProxyParseError::Malformed);
+        }
+        let auth = match record.username.filter(|username| !username.is_empty()) {
+            Some(username) => Some((username, record.password.unwrap_or_default())),
+            None => None,
+        };
+        Ok(Socks5Proxy { kind, host: host.to_string(), port: record.port, auth, chain: Vec::new() })
+    }
+}
+
+/// Reads the list of proxies from a file.
+/// The function expects each line to be in the format `IP:PORT` or `user:pass@IP:PORT`, optionally
+/// prefixed with `http://`, `socks4://`, or `socks5://`, with each line representing one proxy.
+/// Files ending in `.json` or `.csv` are read in those formats instead; see `ProxyFormat`.
+/// Malformed lines and unsupported schemes are skipped with a warning.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the file containing proxy addresses, or `-` to read them from standard input.
+///
+/// # Returns
+///
+/// A vector of proxies, or the I/O error that prevented reading the file.
+pub fn extract_socks_proxies(filename: &str) -> io::Result<Vec<Socks5Proxy>> {
+    read_proxy_file(filename, ProxyFormat::Auto)
+}
+
+/// Reads the list of proxies from a file, or from standard input for `-`, in the given format.
+pub fn read_proxy_file(filename: &str, format: ProxyFormat) -> io::Result<Vec<Socks5Proxy>> {
+    parse_proxy_list(open_input(filename)?, filename, format)
+}
+
+/// Parses a proxy list in the given format; `filename` names the list in warnings, and for `ProxyFormat::Auto`
+/// its extension tells the format. Malformed entries are skipped with a warning naming their line or index,
+/// while a JSON list that is not an array, or a CSV list whose header has no host or port column, is an error.
+pub fn parse_proxy_list(reader: impl BufRead, filename: &str, format: ProxyFormat) -> io::Result<Vec<Socks5Proxy>> {
+    match format.resolve(filename) {
+        ProxyFormat::Json => parse_json_proxies(reader, filename),
+        ProxyFormat::Csv => parse_csv_proxies(reader, filename),
+        _ => parse_text_proxies(reader, filename),
+    }
+}
+
+/// Warns that the entry at `position` (`line` or `entry`) `number` of the list is skipped.
+fn skip_entry(filename: &str, position: &str, number: usize, error: &str) {
+    emit(
+        Level::Warn,
+        "input_skipped",
+        format!("Skipping {} {} of {}: {}", position, number, filename, error),
+        json!({"file": filename, position: number, "reason": "invalid_proxy", "error": error}),
+    );
+}
+
+fn parse_json_proxies(reader: impl BufRead, filename: &str) -> io::Result<Vec<Socks5Proxy>> {
+    let entries: Vec<serde_json::Value> = serde_json::from_reader(reader)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("expected a JSON array of proxies: {}", e)))?;
+    let mut proxies = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let proxy = match entry {
+            serde_json::Value::String(line) => Socks5Proxy::parse(line.trim()).map_err(|e| e.to_string()),
+            entry => {
+                // Field names are matched without regard to case, like CSV columns.
+                let entry = match entry {
+                    serde_json::Value::Object(fields) => serde_json::Value::Object(fields.into_iter().map(|(name, value)| (name.to_ascii_lowercase(), value)).collect()),
+                    entry => entry,
+                };
+                serde_json::from_value::<ProxyRecord>(entry).map_err(|e| e.to_string()).and_then(|record| Socks5Proxy::try_from(record).map_err(|e| e.to_string()))
+            }
+        };
+        match proxy {
+            Ok(proxy) => proxies.push(proxy),
+            Err(e) => skip_entry(filename, "entry", index + 1, &e),
+        }
+    }
+    Ok(proxies)
+}
+
+fn parse_csv_proxies(reader: impl BufRead, filename: &str) -> io::Result<Vec<Socks5Proxy>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).flexible(true).from_reader(reader);
+    let headers: csv::StringRecord = reader.headers().map_err(io::Error::from)?.iter().map(str::to_ascii_lowercase).collect();
+    let has_column = |names: &[&str]| headers.iter().any(|header| names.contains(&header));
+    if !has_column(&["host", "ip", "address"]) || !has_column(&["port"]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the CSV header must name a host (or ip) and a port column"));
+    }
+    reader.set_headers(headers.clone());
+
+    let mut proxies = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(io::Error::from)?;
+        let line = record.position().map(|position| position.line() as usize).unwrap_or_default();
+        if record.iter().all(str::is_empty) {
+            continue;
+        }
+        let proxy = record
+            .deserialize::<ProxyRecord>(Some(&headers))
+            .map_err(|e| match e.kind() {
+                csv::ErrorKind::Deserialize { err, .. } => err.to_string(),  // Without the position, which the warning gives.
+                _ => e.to_string(),
+            })
+            .and_then(|record| Socks5Proxy::try_from(record).map_err(|e| e.to_string()));
+        match proxy {
+            Ok(proxy) => proxies.push(proxy),
+            Err(e) => skip_entry(filename, "line", line, &e),
+        }
+    }
+    Ok(proxies)
+}
+
+fn parse_text_proxies(reader: impl BufRead, filename: &str) -> io::Result<Vec<Socks5Proxy>> {
+    let mut proxies = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Socks5Proxy::parse(line) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(e) => skip_entry(filename, "line", index + 1, &e.to_string()),
+        }
+    }
+    Ok(proxies)
+}
+
+/// The proxies downloads are spread over. It can be changed while the batch runs, for instance when the
+/// proxy list is reloaded on SIGHUP; downloads pick their proxy from the pool as it is at each attempt.
+/// With a `max_uses` cap, set by `--max-downloads-per-proxy`, the pool counts how many torrents went through
+/// each proxy, and `pick` passes over the proxies that reached the cap.
+#[derive(Debug, Default)]
+pub struct ProxyPool {
+    proxies: RwLock<Vec<Socks5Proxy>>,
+    max_uses: Option<usize>,
+    /// How many torrents went through each proxy, kept across changes to the pool.
+    uses: Mutex<HashMap<Socks5Proxy, usize>>,
+    /// Held while a download looks for proxies to add once every proxy reached the cap, so that
+    /// concurrent downloads don't all search at once.
+    refill: tokio::sync::Mutex<()>,
+}
+
+impl ProxyPool {
+    pub fn new(proxies: Vec<Socks5Proxy>) -> ProxyPool {
+        ProxyPool::with_max_uses(proxies, None)
+    }
+
+    /// A pool in which each proxy serves at most `max_uses` torrents, if set.
+    pub fn with_max_uses(proxies: Vec<Socks5Proxy>, max_uses: Option<usize>) -> ProxyPool {
+        ProxyPool { proxies: RwLock::new(proxies), max_uses, ..Default::default() }
+    }
+
+    /// How many torrents each proxy may serve, if that is limited.
+    pub fn max_uses(&self) -> Option<usize> {
+        self.max_uses
+    }
+
+    /// Whether a torrent that already went through the proxies in `used` may use `proxy`: it is one of them,
+    /// or the proxy has not reached the cap yet.
+    pub fn available(&self, proxy: &Socks5Proxy, used: &[Socks5Proxy]) -> bool {
+        let uses = self.uses.lock().expect("Proxy pool poisoned");
+        Self::may_use(self.max_uses, &uses, proxy, used)
+    }
+
+    fn may_use(max_uses: Option<usize>, uses: &HashMap<Socks5Proxy, usize>, proxy: &Socks5Proxy, used: &[Socks5Proxy]) -> bool {
+        used.contains(proxy) || max_uses.is_none_or(|max_uses| uses.get(proxy).copied().unwrap_or_default() < max_uses)
+    }
+
+    /// The proxy `pick` would return, without counting a use.
+    pub fn peek(&self, index: usize, used: &[Socks5Proxy]) -> Option<Socks5Proxy> {
+        let uses = self.uses.lock().expect("Proxy pool poisoned");
+        self.find(&uses, index, used)
+    }
+
+    fn find(&self, uses: &HashMap<Socks5Proxy, usize>, index: usize, used: &[Socks5Proxy]) -> Option<Socks5Proxy> {
+        let proxies = self.proxies.read().expect("Proxy pool poisoned");
+        (0..proxies.len()).map(|offset| &proxies[(index + offset) % proxies.len()]).find(|proxy| Self::may_use(self.max_uses, uses, proxy, used)).cloned()
+    }
+
+    /// Picks the proxy at `index` in the pool, round-robin, or the next one from there that the torrent may
+    /// still use, and counts a use of it unless the torrent, which went through the proxies in `used`, used it
+    /// before. Returns `None` if the pool is empty or every proxy reached the cap.
+    pub fn pick(&self, index: usize, used: &mut Vec<Socks5Proxy>) -> Option<Socks5Proxy> {
+        let mut uses = self.uses.lock().expect("Proxy pool poisoned");
+        let proxy = self.find(&uses, index, used)?;
+        Self::count_use(&mut uses, &proxy, used);
+        Some(proxy)
+    }
+
+    /// Counts a use of `proxy` by a torrent that went through the proxies in `used`, unless it is one of them.
+    pub fn claim(&self, proxy: &Socks5Proxy, used: &mut Vec<Socks5Proxy>) {
+        Self::count_use(&mut self.uses.lock().expect("Proxy pool poisoned"), proxy, used);
+    }
+
+    fn count_use(uses: &mut HashMap<Socks5Proxy, usize>, proxy: &Socks5Proxy, used: &mut Vec<Socks5Proxy>) {
+        if !used.contains(proxy) {
+            *uses.entry(proxy.clone()).or_default() += 1;
+            used.push(proxy.clone());
+        }
+    }
+
+    /// Waits until no other download is adding proxies to the pool, and keeps the others waiting until the guard is dropped.
+    pub(crate) async fn lock_refill(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.refill.lock().await
+    }
+
+    /// The proxies currently in the pool.
+    pub fn snapshot(&self) -> Vec<Socks5Proxy> {
+        self.proxies.read().expect("Proxy pool poisoned").clone()
+    }
+
+    /// Replaces the proxies in the pool.
+    pub fn replace(&self, proxies: Vec<Socks5Proxy>) {
+        *self.proxies.write().expect("Proxy pool poisoned") = proxies;
+    }
+}
+
+/// Randomizes the order in which proxies are checked, so that users sharing a list don't all start with
+/// the same proxies. The same `seed` always gives the same order; without one, the order differs on every run.
+pub fn shuffle_proxies(proxies: &mut [Socks5Proxy], seed: Option<u64>) {
+    proxies.shuffle(&mut seeded_rng(seed));
+}
+
+/// Splits `proxies` into a random sample of `size` proxies, checked by `--proxy-sample` before the others, and the
+/// rest of the list. Both keep the order of the list. The same `seed` always picks the same sample.
+pub fn sample_proxies(proxies: Vec<Socks5Proxy>, size: usize, seed: Option<u64>) -> (Vec<Socks5Proxy>, Vec<Socks5Proxy>) {
+    let picked: HashSet<usize> = rand::seq::index::sample(&mut seeded_rng(seed), proxies.len(), size.min(proxies.len())).into_iter().collect();
+    let (sample, rest): (Vec<_>, Vec<_>) = proxies.into_iter().enumerate().partition(|(index, _)| picked.contains(index));
+    (sample.into_iter().map(|(_, proxy)| proxy).collect(), rest.into_iter().map(|(_, proxy)| proxy).collect())
+}
+
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// The default maximum time a single check request may take before the proxy is considered dead.
+pub const PROXY_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How each proxy is checked.
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// The URLs that must be reachable through the proxy.
+    pub check_urls: Vec<String>,
+    /// The maximum time a single check request may take, set with `--proxy-timeout`.
+    pub timeout: Duration,
+    /// How many bytes to download to measure the throughput, if any.
+    pub warmup_bytes: Option<u64>,
+    /// Check a proxy once more if it failed with a timeout, which may be transient, set with `--retry-timeouts`.
+    pub retry_timeouts: bool,
+    /// How many times a proxy is checked before it fails, set with `--check-attempts`; at least 1.
+    pub attempts: u32,
+    /// The average download speed of the past torrents through each proxy, by `host:port`, in bytes per second,
+    /// from the `--proxy-cache`; a proxy that passes the check carries its own in `ProxyResult::download_speed`.
+    pub download_speeds: Arc<HashMap<String, f64>>,
+    /// The `host:port` of a BitTorrent peer that a proxy passing the check must also connect to, with
+    /// `--proxy-test-protocol bittorrent`; see `peer_check::connect_to_peer`.
+    pub bt_test_peer: Option<String>,
+}
+
+impl CheckOptions {
+    /// The longest a whole check may take: every check request and the connection to the `bt_test_peer`, plus the
+    /// warmup download.
+    fn total_timeout(&self) -> Duration {
+        let requests = self.timeout.saturating_mul((self.check_urls.len().max(1) + usize::from(self.bt_test_peer.is_some())) as u32);
+        match self.warmup_bytes {
+            Some(_) => requests + WARMUP_TIMEOUT,
+            None => requests,
+        }
+    }
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            check_urls: vec!["https://rutracker.org".to_string()],
+            timeout: PROXY_CHECK_TIMEOUT,
+            warmup_bytes: None,
+            retry_timeouts: false,
+            attempts: 1,
+            download_speeds: Arc::default(),
+            bt_test_peer: None,
+        }
+    }
+}
+
+/// The maximum time the `--warmup-bytes` download may take before the proxy is considered too slow.
+pub const WARMUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before checking a proxy again after a failed attempt, so that a brief outage can pass.
+pub const CHECK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The kind of failure that made a proxy fail its check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// A request, or the whole check, took too long. This may be transient, unlike the other kinds.
+    Timeout,
+    /// The connection to the proxy, or through it to the check URL, could not be established, e.g. because it was refused.
+    Connect,
+    /// The proxy's address or credentials could not be used to set up a client.
+    Invalid,
+    /// The proxy passed the HTTP check, but could not connect to the BitTorrent peer of `--proxy-test-protocol bittorrent`.
+    BtBlocked,
+    /// Any other failure, such as a broken response.
+    Other,
+}
+
+impl FailureKind {
+    /// The name of the kind in JSON output, e.g. `"timeout"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Timeout => "timeout",
+            FailureKind::Connect => "connect",
+            FailureKind::Invalid => "invalid",
+            FailureKind::BtBlocked => "bt_blocked",
+            FailureKind::Other => "other",
+        }
+    }
+
+    /// Classifies a failed request. A timeout anywhere in the error's sources, such as a SOCKS handshake that timed out,
+    /// counts as a timeout.
+    fn of(error: &reqwest::Error) -> FailureKind {
+        let mut source: Option<&dyn std::error::Error> = Some(error);
+        while let Some(cause) = source {
+            if cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) {
+                return FailureKind::Timeout;
+            }
+            source = cause.source();
+        }
+        if error.is_timeout() {
+            FailureKind::Timeout
+        } else if error.is_connect() {
+            FailureKind::Connect
+        } else {
+            FailureKind::Other
+        }
+    }
+}
+
+/// Why a proxy failed its check.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message}")]
+pub struct CheckError {
+    pub kind: FailureKind,
+    /// The error, followed by its causes.
+    pub message: String,
+}
+
+impl CheckError {
+    fn new(kind: FailureKind, message: String) -> CheckError {
+        CheckError { kind, message }
+    }
+}
+
+/// The result of a successful proxy health check.
+#[derive(Debug, Clone)]
+pub struct ProxyResult {
+    /// The proxy that passed the check.
+    pub proxy: Socks5Proxy,
+    /// The HTTP status returned by the last check URL.
+    pub status: StatusCode,
+    /// The total round-trip time of all check requests.
+    pub latency: Duration,
+    /// The download speed measured by the warmup probe, in bytes per second, if one was run.
+    pub throughput: Option<f64>,
+    /// The average download speed of the past torrents through the proxy, in bytes per second, if the
+    /// `--proxy-cache` has any.
+    pub download_speed: Option<f64>,
+}
+
+impl ProxyResult {
+    /// Orders results from best to worst: by the download speed of past torrents, then, for results without
+    /// one, by measured throughput, then by latency for results without either.
+    pub(crate) fn rank(&self, other: &ProxyResult) -> std::cmp::Ordering {
+        match (self.download_speed, other.download_speed) {
+            (Some(a), Some(b)) => return b.total_cmp(&a),
+            (Some(_), None) => return std::cmp::Ordering::Less,
+            (None, Some(_)) => return std::cmp::Ordering::Greater,
+            (None, None) => {}
+        }
+        match (self.throughput, other.throughput) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => self.latency.cmp(&other.latency),
+        }
+    }
+}
+
+/// Formats a download speed in bytes per second as MB/s.
+pub(crate) fn format_throughput(bytes_per_sec: f64) -> String {
+    format!("{:.2} MB/s", bytes_per_sec / 1_000_000.0)
+}
+
+/// Downloads up to `bytes` bytes of `url` through the client and returns the measured speed in bytes per second.
+/// The body may be shorter than `bytes`, in which case the speed is measured over what was received.
+async fn measure_throughput(client: &Client, url: &str, bytes: u64) -> Result<f64, reqwest::Error> {
+    let started = Instant::now();
+    let mut response = client.get(url).timeout(WARMUP_TIMEOUT).send().await?.error_for_status()?;
+    let mut received = 0;
+    while received < bytes {
+        match response.chunk().await? {
+            Some(chunk) => received += chunk.len() as u64,
+            None => break,
+        }
+    }
+    Ok(received as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON))
+}
+
+/// Asynchronously checks if a SOCKS5 proxy is functional using an in-process HTTP client.
+/// It sends a request to every check URL through the proxy, and the proxy is only considered
+/// valid if all of them respond. The first URL that fails is logged.
+/// Each request is capped at `options.timeout`, and the whole check at the sum of its requests' limits, so a
+/// proxy that stalls the connection cannot hold up a check slot. With `options.warmup_bytes`, a proxy that passes
+/// additionally downloads that many bytes of the first check URL to measure its throughput, and fails if the
+/// download errors or takes longer than `WARMUP_TIMEOUT`.
+///
+/// # Arguments
+///
+/// * `proxy` - The SOCKS5 proxy to check, including its credentials if any.
+/// * `options` - The check URLs, the request timeout, and the warmup size.
+///
+/// # Returns
+///
+/// An `Option<ProxyResult>`, where `Some` carries the HTTP status and latency of a valid proxy
+/// and `None` indicates a failure.
+pub async fn check_proxy(proxy: Socks5Proxy, options: &CheckOptions) -> Option<ProxyResult> {
+    check_proxy_detailed(proxy, options).await.ok()
+}
+
+/// Checks a proxy like `check_proxy`, but returns why a proxy failed the check instead of discarding it.
+/// A proxy that fails is checked again, after `CHECK_RETRY_DELAY`, until it passes or `options.attempts` attempts failed,
+/// and with `options.retry_timeouts`, once more if the last attempt timed out. An invalid proxy is not checked again.
+/// In JSON mode, every finished check is reported with a `proxy_checked` event whatever the verbosity, so that
+/// a supervising program sees each proxy checked, including those checked before the `first` strategy found its winners.
+pub async fn check_proxy_detailed(proxy: Socks5Proxy, options: &CheckOptions) -> Result<ProxyResult, CheckError> {
+    let started = Instant::now();
+    let (mut attempt, mut allowed, mut timeout_retry) = (1, options.attempts.max(1), options.retry_timeouts);
+    let mut result = check_within_limit(proxy.clone(), options).await;
+    while let Err(e) = &result {
+        if e.kind == FailureKind::Invalid {
+            break;
+        }
+        if attempt == allowed {
+            // With `retry_timeouts`, a last attempt that timed out earns one more.
+            if !(timeout_retry && e.kind == FailureKind::Timeout) {
+                break;
+            }
+            timeout_retry = false;
+            allowed += 1;
+        }
+        emit(
+            Level::Debug,
+            "proxy_check_retry",
+            format!("Checking proxy {} again ({} of {}) after a {} failure", proxy, attempt + 1, allowed, e.kind.name()),
+            json!({"proxy": proxy.to_string(), "attempt": attempt + 1, "attempts": allowed, "error": e.message, "failure": e.kind.name()}),
+        );
+        tokio::time::sleep(CHECK_RETRY_DELAY).await;
+        attempt += 1;
+        result = check_within_limit(proxy.clone(), options).await;
+    }
+    if attempt > 1 && result.is_ok() {
+        emit(
+            Level::Debug,
+            "proxy_check_passed_on_retry",
+            format!("Proxy {} passed on attempt {} of {}", proxy, attempt, allowed),
+            json!({"proxy": proxy.to_string(), "attempt": attempt, "attempts": allowed}),
+        );
+    }
+    if json_output() {
+        let (latency, error) = match &result {
+            Ok(result) => (result.latency, None),
+            Err(e) => (started.elapsed(), Some(e)),
+        };
+        emit(
+            Level::Info,
+            "proxy_checked",
+            match error {
+                Some(e) => format!("Proxy {} failed the check: {}", proxy, e),
+                None => format!("Proxy {} passed the check", proxy),
+            },
+            json!({
+                "proxy": proxy.to_string(),
+                "ok": result.is_ok(),
+                "latency_ms": latency.as_millis() as u64,
+                "status": result.as_ref().ok().map(|result| result.status.as_u16()),
+                "error": error.map(|e| e.message.as_str()),
+                "failure": error.map(|e| e.kind.name()),
+                "attempts": attempt,
+            }),
+        );
+    }
+    result
+}
+
+/// Runs `run_check` within the time limit of the whole check.
+async fn check_within_limit(proxy: Socks5Proxy, options: &CheckOptions) -> Result<ProxyResult, CheckError> {
+    let limit = options.total_timeout();
+    match tokio::time::timeout(limit, run_check(proxy.clone(), options)).await {
+        Ok(result) => result,
+        Err(_) => {
+            emit(
+                Level::Debug,
+                "proxy_check",
+                format!("Proxy {} failed check: timed out after {} s", proxy, limit.as_secs()),
+                json!({"proxy": proxy.to_string(), "ok": false, "error": "timed out", "failure": FailureKind::Timeout.name()}),
+            );
+            Err(CheckError::new(FailureKind::Timeout, format!("timed out after {} s", limit.as_secs())))
+        }
+    }
+}
+
+/// Formats an error followed by its sources, since reqwest's own message doesn't say why a request failed.
+pub(crate) fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Runs the requests of `check_proxy`, without the overall time limit.
+async fn run_check(proxy: Socks5Proxy, options: &CheckOptions) -> Result<ProxyResult, CheckError> {
+    emit(Level::Trace, "proxy_check_start", format!("Checking proxy: {}", proxy), json!({"proxy": proxy.to_string()}));
+
+    let client = match reqwest::Proxy::all(proxy.url(true))
+        .and_then(|socks| Client::builder().proxy(socks).timeout(options.timeout).build())
+    {
+        Ok(client) => client,
+        Err(e) => {
+            emit(
+                Level::Debug,
+                "proxy_check",
+                format!("Invalid proxy {}: {}", proxy, e),
+                json!({"proxy": proxy.to_string(), "ok": false, "error": e.to_string(), "failure": FailureKind::Invalid.name()}),
+            );
+            return Err(CheckError::new(FailureKind::Invalid, format!("invalid proxy: {}", e)));
+        }
+    };
+
+    let started = Instant::now();
+    let mut status = None;
+    for url in &options.check_urls {
+        match client.get(url).send().await {
+            Ok(response) => status = Some(response.status()),
+            Err(e) => {
+                let kind = FailureKind::of(&e);
+                emit(
+                    Level::Debug,
+                    "proxy_check",
+                    format!("Proxy {} failed check for {} ({}): {}", proxy, url, kind.name(), e),
+                    json!({"proxy": proxy.to_string(), "ok": false, "url": url, "error": e.to_string(), "failure": kind.name()}),
+                );
+                return Err(CheckError::new(kind, error_chain(&e)));
+            }
+        }
+    }
+    let latency = started.elapsed();
+
+    let status = status.ok_or_else(|| CheckError::new(FailureKind::Other, "no check URL".to_string()))?;
+
+    // Many proxies only let web traffic through, which no BitTorrent peer uses.
+    if let Some(peer) = &options.bt_test_peer {
+        let error = match tokio::time::timeout(options.timeout, connect_to_peer(&proxy, peer)).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("timed out after {} s", options.timeout.as_secs())),
+        };
+        if let Some(e) = error {
+            emit(
+                Level::Debug,
+                "proxy_check",
+                format!("Proxy {} passed the HTTP check ({}) but could not connect to BitTorrent peer {}: {}", proxy, status, peer, e),
+                json!({"proxy": proxy.to_string(), "ok": false, "peer": peer, "error": e, "failure": FailureKind::BtBlocked.name()}),
+            );
+            return Err(CheckError::new(FailureKind::BtBlocked, format!("HTTP ok ({}) but BitTorrent blocked: cannot connect to {}: {}", status, peer, e)));
+        }
+    }
+
+    // The check requests are small, so a proxy that passes them may still be slow for bulk transfer.
+    let mut throughput = None;
+    if let (Some(bytes), Some(url)) = (options.warmup_bytes, options.check_urls.first()) {
+        match measure_throughput(&client, url, bytes).await {
+            Ok(speed) => throughput = Some(speed),
+            Err(e) => {
+                let kind = FailureKind::of(&e);
+                emit(
+                    Level::Debug,
+                    "proxy_check",
+                    format!("Proxy {} failed the warmup download from {} ({}): {}", proxy, url, kind.name(), e),
+                    json!({"proxy": proxy.to_string(), "ok": false, "url": url, "error": e.to_string(), "failure": kind.name()}),
+                );
+                return Err(CheckError::new(kind, format!("warmup download failed: {}", error_chain(&e))));
+            }
+        }
+    }
+
+    emit(
+        Level::Debug,
+        "proxy_check",
+        match throughput {
+            Some(speed) => format!("Proxy {} passed check ({}, {} ms, {}).", proxy, status, latency.as_millis(), format_throughput(speed)),
+            None => format!("Proxy {} passed check ({}, {} ms).", proxy, status, latency.as_millis()),
+        },
+        json!({
+            "proxy": proxy.to_string(),
+            "ok": true,
+            "status": status.as_u16(),
+            "latency_ms": latency.as_millis() as u64,
+            "throughput_bps": throughput.map(|speed| speed as u64),
+        }),
+    );
+    let download_speed = options.download_speeds.get(&proxy.address()).copied();
+    Ok(ProxyResult { proxy, status, latency, throughput, download_speed })
+}
+
+/// The future returned by a `ProxyChecker`; resolves to `Some` for a proxy that passed the check.
+pub type ProxyCheck = Pin<Box<dyn Future<Output = Option<ProxyResult>> + Send>>;
+
+/// Checks a single proxy. `find_valid_proxies` uses `check_proxy`; other checkers can be passed to
+/// `find_valid_proxies_with`, e.g. to test the selection logic without a network.
+pub type ProxyChecker = Arc<dyn Fn(Socks5Proxy) -> ProxyCheck + Send + Sync>;
+
+/// Finds up to `count` distinct valid SOCKS5 proxies from the list according to the given strategy.
+/// At most `max_concurrency` checks run at the same time; the rest wait for a free slot.
+/// With `Strategy::First` the checks race, and once `count` valid proxies are found the remaining checks
+/// are canceled, closing their connections, before the search returns. With `Strategy::Fastest` every proxy is checked and the `count`
+/// proxies with the highest warmup throughput win, or those with the lowest latency without a warmup.
+/// With a `batch_size`, the proxies are checked in waves of that many, and no further wave is started
+/// once a wave yields enough valid proxies; `Strategy::Fastest` then only compares the proxies checked so far.
+/// The length of `bar` grows by the number of `proxies`. With more than one wave, and `bar` on screen among the
+/// progress bars of `log`, the progress of the current wave is shown on a second bar below it.
+/// If no valid proxy is found, the returned vector is empty.
+///
+/// # Arguments
+///
+/// * `proxies` - A vector of proxies to be checked.
+/// * `bar` - A reference to a progress bar (indicatif) to track the progress of the proxy checks.
+/// * `max_concurrency` - The maximum number of proxy checks running simultaneously.
+/// * `batch_size` - The number of proxies checked per wave, or `None` to check them all at once.
+/// * `options` - The URLs every proxy must be able to reach, the request timeout, and the warmup size.
+/// * `strategy` - Whether to take the first valid proxies or the fastest ones.
+/// * `count` - The maximum number of distinct proxies to return.
+///
+/// # Returns
+///
+/// A `Vec<ProxyResult>` with at most `count` proxies; sorted fastest first for `Strategy::Fastest`,
+/// and in the order they passed the check for `Strategy::First`.
+pub async fn find_valid_proxies(
+    proxies: Vec<Socks5Proxy>,
+    bar: Arc<ProgressBar>,
+    max_concurrency: usize,
+    batch_size: Option<usize>,
+    options: CheckOptions,
+    strategy: Strategy,
+    count: usize,
+) -> Vec<ProxyResult> {
+    let options = Arc::new(options);
+    let checker: ProxyChecker = Arc::new(move |proxy| {
+        let options = Arc::clone(&options);
+        Box::pin(async move { check_proxy(proxy, &options).await })
+    });
+    find_valid_proxies_with(proxies, bar, max_concurrency, batch_size, strategy, count, checker).await
+}
+
+/// Finds up to `count` distinct valid proxies like `find_valid_proxies`, but checks each proxy with `checker`
+/// instead of making requests to the check URLs.
+pub async fn find_valid_proxies_with(
+    mut proxies: Vec<Socks5Proxy>,
+    bar: Arc<ProgressBar>,
+    max_concurrency: usize,
+    batch_size: Option<usize>,
+    strategy: Strategy,
+    count: usize,
+    checker: ProxyChecker,
+) -> Vec<ProxyResult> {
+    let batch_size = batch_size.unwrap_or(proxies.len()).max(1);
+    let waves = proxies.len().div_ceil(batch_size);
+    let search = Search {
+        bar: Arc::clone(&bar),
+        semaphore: Arc::new(Semaphore::new(max_concurrency)),  // Caps the number of concurrent checks.
+        tally: Arc::new((AtomicUsize::new(0), AtomicUsize::new(0))),  // How many checks passed and failed so far.
+        wave_bar: if waves > 1 { wave_bar(&bar) } else { ProgressBar::hidden() },
+        checker,
+        strategy,
+        count,
+    };
+    bar.inc_length(proxies.len() as u64);
+
+    let mut selected: Vec<ProxyResult> = Vec::new();
+    let mut wave = 0;
+    while !proxies.is_empty() && selected.len() < count {
+        let rest = proxies.split_off(batch_size.min(proxies.len()));
+        let batch = std::mem::replace(&mut proxies, rest);
+        wave += 1;
+        if waves > 1 {
+            emit(
+                Level::Trace,
+                "proxy_check_wave",
+                format!("Checking wave {} of {}: {} proxies", wave, waves, batch.len()),
+                json!({"wave": wave, "waves": waves, "proxies": batch.len()}),
+            );
+            search.wave_bar.reset();
+            search.wave_bar.set_length(batch.len() as u64);
+            search.wave_bar.set_prefix(format!("{}/{}", wave, waves));
+        }
+        metrics::proxies_queued(batch.len());
+        search.check_wave(batch, &mut selected).await;
+    }
+    search.wave_bar.finish_and_clear();
+
+    if strategy == Strategy::Fastest {
+        selected.sort_by(ProxyResult::rank);
+        selected.truncate(count);
+    }
+    bar.set_message(match selected.as_slice() {
+        [] => format!("no valid proxy; {} failed", search.tally.1.load(Ordering::Relaxed)),
+        [only] => format!("Found: {}", only.proxy),
+        [first, rest @ ..] => format!("Found: {} and {} more", first.proxy, rest.len()),
+    });
+    selected
+}
+
+/// The bar of the current wave, shown below `bar` when `bar` is on screen among the progress bars of `log`.
+fn wave_bar(bar: &ProgressBar) -> ProgressBar {
+    match log::progress_bars() {
+        Some(progress) if !bar.is_hidden() => {
+            let wave_bar = progress.add(ProgressBar::new(0));
+            wave_bar.set_style(ProgressStyle::with_template("  Wave {prefix:>7} [{bar:30}] {pos}/{len}").expect("Invalid progress template"));
+            wave_bar
+        }
+        _ => ProgressBar::hidden(),
+    }
+}
+
+/// The state shared by the waves of one proxy search.
+struct Search {
+    bar: Arc<ProgressBar>,
+    /// The progress of the current wave, hidden unless there are several.
+    wave_bar: ProgressBar,
+    semaphore: Arc<Semaphore>,
+    tally: Arc<(AtomicUsize, AtomicUsize)>,
+    checker: ProxyChecker,
+    strategy: Strategy,
+    count: usize,
+}
+
+impl Search {
+    /// Checks a wave of proxies concurrently, adding the distinct valid ones to `selected`.
+    /// With `Strategy::First` the wave ends as soon as `selected` holds `count` proxies.
+    /// The checks still running when it ends are canceled, and so are all of them if the wave itself is dropped.
+    async fn check_wave(&self, proxies: Vec<Socks5Proxy>, selected: &mut Vec<ProxyResult>) {
+        let (tx, mut rx) = mpsc::channel(1);  // A channel to send the results back to the main thread.
+        let mut tasks = JoinSet::new();  // Aborts the tasks left when dropped.
+
+        // Spawn a task for each proxy to check it concurrently.
+        for proxy in proxies {
+            let tx = tx.clone();
+            let bar = Arc::clone(&self.bar);
+            let wave_bar = self.wave_bar.clone();
+            let semaphore = Arc::clone(&self.semaphore);
+            let checker = Arc::clone(&self.checker);
+            let tally = Arc::clone(&self.tally);
+
+            tasks.spawn(async move {
+                // Wait for a free slot before checking; the permit is released when the check ends.
+                let _permit = semaphore.acquire_owned().await.expect("Proxy check semaphore closed");
+                let (passed, failed) = (tally.0.load(Ordering::Relaxed), tally.1.load(Ordering::Relaxed));
+                bar.set_message(format!("{} passed, {} failed; checking {}", passed, failed, proxy));
+                let result = checker(proxy).await;
+                metrics::proxy_checked(result.is_some());
+                match result {
+                    Some(result) => {
+                        tally.0.fetch_add(1, Ordering::Relaxed);
+                        let _ = tx.send(result).await;  // Send the valid proxy to the main thread.
+                    }
+                    None => {
+                        tally.1.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                bar.inc(1);  // Update the progress bars.
+                wave_bar.inc(1);
+            });
+        }
+        drop(tx);  // Only the tasks hold senders now, so `recv` returns `None` once every check has finished.
+
+        while let Some(result) = rx.recv().await {
+            // The same proxy may be listed more than once; only keep distinct endpoints.
+            if selected.iter().any(|other| other.proxy == result.proxy) {
+                continue;
+            }
+            selected.push(result);
+            // Stop as soon as enough proxies passed, unless every proxy has to be measured.
+            if self.strategy == Strategy::First && selected.len() == self.count {
+                break;
+            }
+        }
+
+        // Cancel all remaining checks and wait until they are gone, so that none of them holds a connection,
+        // or updates the progress bar, once the search has moved on.
+        tasks.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A checker that fails every proxy on port 0 and passes the others after `port` milliseconds,
+    /// so a proxy's port doubles as its latency.
+    fn fake_checker() -> ProxyChecker {
+        Arc::new(|proxy: Socks5Proxy| {
+            Box::pin(async move {
+                if proxy.port == 0 {
+                    return None;
+                }
+                let latency = Duration::from_millis(proxy.port.into());
+                tokio::time::sleep(latency).await;
+                Some(ProxyResult { proxy, status: StatusCode::OK, latency, throughput: None, download_speed: None })
+            })
+        })
+    }
+
+    fn proxies(lines: &[&str]) -> Vec<Socks5Proxy> {
+        lines.iter().map(|line| Socks5Proxy::parse(line).unwrap()).collect()
+    }
+
+    async fn find(lines: &[&str], strategy: Strategy, count: usize) -> Vec<String> {
+        let bar = Arc::new(ProgressBar::hidden());
+        let found = find_valid_proxies_with(proxies(lines), bar, 10, None, strategy, count, fake_checker()).await;
+        found.iter().map(|result| result.proxy.to_string()).collect()
+    }
+
+    #[test]
+    fn json_and_csv_lists_are_read_by_extension() {
+        let json = r#"[{"Host": "10.0.0.1", "port": "1080", "user": "u", "pass": "p"}, "http://10.0.0.2:3128", {"host": "10.0.0.3", "port": 70000}, {"ip": "10.0.0.4", "port": 1080, "type": "socks4"}]"#;
+        let read = parse_proxy_list(json.as_bytes(), "proxies.json", ProxyFormat::Auto).unwrap();
+        assert_eq!(read, proxies(&["u:p@10.0.0.1:1080", "http://10.0.0.2:3128", "socks4://10.0.0.4:1080"]));
+
+        let csv = "IP,Port,Protocol,Country\n10.0.0.1,1080,socks5,DE\n10.0.0.2,,http,NL\n[2001:db8::1],1080,,US\n";
+        let read = parse_proxy_list(csv.as_bytes(), "https://provider.example/list.csv?key=1", ProxyFormat::Auto).unwrap();
+        assert_eq!(read, proxies(&["10.0.0.1:1080", "[2001:db8::1]:1080"]));
+
+        assert!(parse_proxy_list("10.0.0.1:1080".as_bytes(), "proxies.json", ProxyFormat::Auto).is_err());
+        assert_eq!(parse_proxy_list("10.0.0.1:1080".as_bytes(), "proxies.json", ProxyFormat::Text).unwrap(), proxies(&["10.0.0.1:1080"]));
+    }
+
+    #[test]
+    fn capped_proxies_are_passed_over() {
+        let pool = ProxyPool::with_max_uses(proxies(&["10.0.0.1:1080", "10.0.0.2:1080"]), Some(2));
+        let pick = |index: usize, used: &mut Vec<Socks5Proxy>| pool.pick(index, used).map(|proxy| proxy.to_string());
+        let (mut first, mut second, mut third) = (Vec::new(), Vec::new(), Vec::new());
+        assert_eq!(pick(0, &mut first).as_deref(), Some("10.0.0.1:1080"));
+        assert_eq!(pick(0, &mut second).as_deref(), Some("10.0.0.1:1080"));
+        assert_eq!(pick(0, &mut third).as_deref(), Some("10.0.0.2:1080"));
+        // A torrent going back to a proxy it used does not count again, even once the proxy reached the cap.
+        assert_eq!(pick(0, &mut first).as_deref(), Some("10.0.0.1:1080"));
+        assert_eq!(pick(1, &mut first).as_deref(), Some("10.0.0.2:1080"));
+        assert_eq!(pick(0, &mut Vec::new()), None);
+    }
+
+    #[tokio::test]
+    async fn first_valid_proxy_wins() {
+        let found = find(&["10.0.0.1:0", "10.0.0.2:300", "10.0.0.3:10", "10.0.0.4:0"], Strategy::First, 1).await;
+        assert_eq!(found, ["10.0.0.3:10"]);
+    }
+
+    #[tokio::test]
+    async fn remaining_checks_are_canceled_once_a_winner_is_found() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&finished);
+        let checker: ProxyChecker = Arc::new(move |proxy: Socks5Proxy| {
+            let (check, finished) = (fake_checker()(proxy), Arc::clone(&counted));
+            Box::pin(async move {
+                let result = check.await;
+                finished.fetch_add(1, Ordering::Relaxed);
+                result
+            })
+        });
+        let bar = Arc::new(ProgressBar::hidden());
+        let found = find_valid_proxies_with(proxies(&["10.0.0.1:10", "10.0.0.2:300", "10.0.0.3:300"]), Arc::clone(&bar), 10, None, Strategy::First, 1, checker).await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!((finished.load(Ordering::Relaxed), bar.position()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn fastest_proxies_are_sorted_by_latency() {
+        let found = find(&["10.0.0.1:200", "10.0.0.2:0", "10.0.0.3:10", "10.0.0.4:100"], Strategy::Fastest, 2).await;
+        assert_eq!(found, ["10.0.0.3:10", "10.0.0.4:100"]);
+    }
+
+    #[tokio::test]
+    async fn fastest_prefers_measured_throughput_over_latency() {
+        let checker: ProxyChecker = Arc::new(|proxy: Socks5Proxy| {
+            Box::pin(async move {
+                // The higher the port, the slower the check but the faster the warmup download.
+                let throughput = Some(f64::from(proxy.port) * 1000.0);
+                Some(ProxyResult { latency: Duration::from_millis(proxy.port.into()), proxy, status: StatusCode::OK, throughput, download_speed: None })
+            })
+        });
+        let list = proxies(&["10.0.0.1:10", "10.0.0.2:30", "10.0.0.3:20"]);
+        let found = find_valid_proxies_with(list, Arc::new(ProgressBar::hidden()), 10, None, Strategy::Fastest, 2, checker).await;
+        let found: Vec<String> = found.iter().map(|result| result.proxy.to_string()).collect();
+        assert_eq!(found, ["10.0.0.2:30", "10.0.0.3:20"]);
+    }
+
+    #[tokio::test]
+    async fn waves_stop_once_enough_proxies_pass() {
+        let bar = Arc::new(ProgressBar::with_draw_target(Some(0), indicatif::ProgressDrawTarget::hidden()));
+        let list = proxies(&["10.0.0.1:0", "10.0.0.2:0", "10.0.0.3:50", "10.0.0.4:10", "10.0.0.5:1", "10.0.0.6:1"]);
+        let found = find_valid_proxies_with(list, Arc::clone(&bar), 10, Some(2), Strategy::Fastest, 1, fake_checker()).await;
+        let found: Vec<String> = found.iter().map(|result| result.proxy.to_string()).collect();
+        // The faster proxies of the third wave are never checked.
+        assert_eq!(found, ["10.0.0.4:10"]);
+        assert_eq!((bar.position(), bar.length()), (4, Some(6)));
+    }
+
+    #[tokio::test]
+    async fn duplicate_proxies_are_returned_once() {
+        let found = find(&["10.0.0.1:10", "10.0.0.1:10", "10.0.0.2:200"], Strategy::First, 2).await;
+        assert_eq!(found, ["10.0.0.1:10", "10.0.0.2:200"]);
+    }
+
+    #[tokio::test]
+    async fn all_failing_proxies_return_nothing() {
+        assert!(find(&["10.0.0.1:0", "10.0.0.2:0"], Strategy::First, 1).await.is_empty());
+        assert!(find(&["10.0.0.1:0", "10.0.0.2:0"], Strategy::Fastest, 1).await.is_empty());
+    }
+
+    #[test]
+    fn ipv6_proxies_are_bracketed() {
+        let proxy = Socks5Proxy::parse("user:pass@[2001:db8::1]:1080").unwrap();
+        assert_eq!((proxy.host.as_str(), proxy.port), ("2001:db8::1", 1080));
+        assert_eq!(proxy.url(true), "socks5h://user:pass@[2001:db8::1]:1080");
+        assert_eq!(proxy.to_string(), "[2001:db8::1]:1080");
+        assert!(Socks5Proxy::parse("2001:db8::1:1080").is_err());
+        assert!(Socks5Proxy::parse("[2001:db8::1]1080").is_err());
+        assert!(Socks5Proxy::parse("[not-an-address]:1080").is_err());
+    }
+
+    #[test]
+    fn seeded_shuffle_is_reproducible() {
+        let lines: Vec<String> = (1..=20).map(|port| format!("10.0.0.1:{}", port)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let (mut first, mut second) = (proxies(&lines), proxies(&lines));
+        shuffle_proxies(&mut first, Some(7));
+        shuffle_proxies(&mut second, Some(7));
+        assert_eq!(first, second);
+        assert_ne!(first, proxies(&lines));
+
+        let (sample, rest) = sample_proxies(proxies(&lines), 5, Some(7));
+        assert_eq!((sample.len(), rest.len()), (5, 15));
+        assert_eq!(sample, sample_proxies(proxies(&lines), 5, Some(7)).0);
+        assert!(sample.windows(2).all(|pair| pair[0].port < pair[1].port));
+        assert_eq!(sample_proxies(proxies(&lines), 50, None).0.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn empty_input_returns_nothing() {
+        assert!(find(&[], Strategy::First, 1).await.is_empty());
+        assert!(find(&[], Strategy::Fastest, 1).await.is_empty());
+    }
+}
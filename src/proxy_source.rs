@@ -0,0 +1,93 @@
+//! Where the proxy list comes from: a file with `--socks-file`, or a proxy provider's URL with `--proxy-url`.
+
+use crate::proxy::{parse_proxy_list, read_proxy_file, ProxyFormat, Socks5Proxy};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The reasons a `ProxySource` can fail to fetch its list.
+#[derive(Debug, Error)]
+pub enum ProxySourceError {
+    /// The list could not be read.
+    #[error(transparent)]
+    Io(io::Error),
+    /// The list could not be downloaded, or the server answered with an error status.
+    #[error(transparent)]
+    Http(reqwest::Error),
+}
+
+/// A list of proxies, fetched when the batch starts and again whenever it is reloaded on SIGHUP.
+/// Its `Display` names the list in messages, e.g. the path of the file.
+pub trait ProxySource: fmt::Display + Send + Sync {
+    /// Fetches the current list. Malformed entries are skipped with a warning, like in a proxy file.
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = Result<Vec<Socks5Proxy>, ProxySourceError>> + Send + '_>>;
+}
+
+/// Reads the proxies from a file, or from standard input for `-`.
+#[derive(Debug, Clone)]
+pub struct FileProxySource {
+    pub path: String,
+    /// The format of the file; `ProxyFormat::Auto` goes by its extension.
+    pub format: ProxyFormat,
+}
+
+impl fmt::Display for FileProxySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl ProxySource for FileProxySource {
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = Result<Vec<Socks5Proxy>, ProxySourceError>> + Send + '_>> {
+        Box::pin(async move { read_proxy_file(&self.path, self.format).map_err(ProxySourceError::Io) })
+    }
+}
+
+/// Downloads the proxies from a URL that serves them in one of the formats of a proxy file,
+/// as proxy providers' APIs commonly do.
+#[derive(Debug, Clone)]
+pub struct HttpProxySource {
+    pub url: String,
+    /// The format of the list; `ProxyFormat::Auto` goes by the extension of the URL, or else the `Content-Type`
+    /// of the response.
+    pub format: ProxyFormat,
+    /// How long the download may take.
+    pub timeout: Duration,
+}
+
+impl fmt::Display for HttpProxySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl ProxySource for HttpProxySource {
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = Result<Vec<Socks5Proxy>, ProxySourceError>> + Send + '_>> {
+        Box::pin(async move {
+            let client = Client::builder().timeout(self.timeout).build().map_err(ProxySourceError::Http)?;
+            let response = client.get(&self.url).send().await.and_then(|response| response.error_for_status()).map_err(ProxySourceError::Http)?;
+            let format = match (self.format, ProxyFormat::from_extension(&self.url)) {
+                (ProxyFormat::Auto, None) => content_type_format(&response),
+                (ProxyFormat::Auto, Some(format)) => format,
+                (format, _) => format,
+            };
+            let body = response.text().await.map_err(ProxySourceError::Http)?;
+            parse_proxy_list(body.as_bytes(), &self.url, format).map_err(ProxySourceError::Io)
+        })
+    }
+}
+
+/// The format the `Content-Type` of a response announces, or text.
+fn content_type_format(response: &Response) -> ProxyFormat {
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    match content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "application/json" => ProxyFormat::Json,
+        "text/csv" => ProxyFormat::Csv,
+        _ => ProxyFormat::Text,
+    }
+}
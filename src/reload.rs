@@ -0,0 +1,98 @@
+//! Reloading the `--socks-file` or `--proxy-url` list on SIGHUP, so that a long batch picks up proxies added to the list meanwhile.
+
+use crate::log::{emit, Level};
+use crate::metrics;
+use crate::proxy::{find_valid_proxies, ProxyPool, Socks5Proxy, Strategy};
+use crate::proxy_source::ProxySource;
+use crate::source::STDIN_FILENAME;
+use crate::{remove_unusable_proxies, BatchTorConfig, ProxyFilters};
+use indicatif::ProgressBar;
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Reloads the proxy list into `pool` every time the process receives SIGHUP. Never completes; the caller
+/// drops it once the downloads are over.
+pub(crate) async fn reload_on_hangup(config: &BatchTorConfig, source: &dyn ProxySource, pool: &ProxyPool, filters: &ProxyFilters) -> Infallible {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            emit(Level::Warn, "proxy_reload_error", format!("Cannot reload the proxy list on SIGHUP: {}", e), json!({"error": e.to_string()}));
+            return std::future::pending().await;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload(config, source, pool, filters).await;
+    }
+    std::future::pending().await
+}
+
+/// Reads the proxy list again: proxies no longer listed leave the pool, and new ones join it once they pass
+/// the check. If that would leave the pool empty, it is kept as it is.
+async fn reload(config: &BatchTorConfig, source: &dyn ProxySource, pool: &ProxyPool, filters: &ProxyFilters) {
+    let file = source.to_string();
+    if config.proxy_chain {
+        emit(
+            Level::Warn,
+            "proxy_reload_error",
+            "Cannot reload the proxy list with --proxy-chain.".to_string(),
+            json!({"file": file, "error": "proxy chains are not reloaded"}),
+        );
+        return;
+    }
+    if config.proxy_url.is_none() && file == STDIN_FILENAME {
+        emit(
+            Level::Warn,
+            "proxy_reload_error",
+            "Cannot reload the proxy list from standard input.".to_string(),
+            json!({"file": file, "error": "proxy list read from standard input"}),
+        );
+        return;
+    }
+    let mut listed = match source.fetch().await {
+        Ok(listed) => listed,
+        Err(e) => {
+            emit(Level::Warn, "proxy_reload_error", format!("Could not reload proxy list {}: {}", file, e), json!({"file": file, "error": e.to_string()}));
+            return;
+        }
+    };
+    remove_unusable_proxies(config, filters, &mut listed);
+
+    let current = pool.snapshot();
+    let (mut proxies, removed): (Vec<Socks5Proxy>, Vec<Socks5Proxy>) = current.iter().cloned().partition(|proxy| listed.contains(proxy));
+    let mut new: Vec<Socks5Proxy> = Vec::new();
+    for proxy in listed {
+        if !current.contains(&proxy) && !new.contains(&proxy) {
+            new.push(proxy);
+        }
+    }
+    let checked = new.len();
+    if !new.is_empty() {
+        let bar = Arc::new(ProgressBar::hidden());
+        let passed = find_valid_proxies(new, bar, config.max_concurrency, config.check_batch_size, config.check_options(), Strategy::First, usize::MAX).await;
+        proxies.extend(passed.into_iter().map(|result| result.proxy));
+    }
+    let added = proxies.len() + removed.len() - current.len();
+
+    if proxies.is_empty() {
+        emit(
+            Level::Warn,
+            "proxy_reload_error",
+            format!("No proxy in {} passes the check; keeping the current proxies.", file),
+            json!({"file": file, "error": "no valid proxies"}),
+        );
+        return;
+    }
+    pool.replace(proxies);
+    metrics::set_proxies_in_use(&pool.snapshot());
+    emit(
+        Level::Info,
+        "proxies_reloaded",
+        match checked - added {
+            0 => format!("Reloaded proxy list {}: {} added, {} removed.", file, added, removed.len()),
+            failed => format!("Reloaded proxy list {}: {} added, {} removed; {} new proxies failed the check.", file, added, removed.len(), failed),
+        },
+        json!({"file": file, "added": added, "removed": removed.len(), "failed": checked - added}),
+    );
+}
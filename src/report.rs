@@ -0,0 +1,243 @@
+//! The summary of a batch, accumulated as downloads finish and printed at the end of the run.
+
+use crate::download::{DownloadError, DownloadOutcome};
+use crate::log::{emit, Level};
+use crate::proxy::Socks5Proxy;
+use crate::source::TorrentSource;
+use serde_json::{json, Value};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// The outcome of every torrent in a batch, with the totals shown at the end of the run.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// The torrents that were downloaded, with the outcome of the successful attempt, including its proxy.
+    pub succeeded: Vec<(TorrentSource, DownloadOutcome)>,
+    /// The torrents that failed or were interrupted, with the error of their last attempt.
+    pub failed: Vec<(TorrentSource, DownloadError)>,
+    /// The torrents that were skipped because they were already downloaded.
+    pub skipped: Vec<TorrentSource>,
+    /// The torrents that were skipped by `--min-seeders`, with the number of seeders their trackers reported, if any.
+    pub low_seeders: Vec<(TorrentSource, Option<u64>)>,
+    /// The torrents that were resumed through another proxy after stalling, with how many times.
+    pub rotations: Vec<(TorrentSource, u32)>,
+    /// How many times a proxy failed its `--recheck-before-download` check and was replaced.
+    pub recheck_switches: u32,
+    /// How long the whole run took.
+    pub elapsed: Duration,
+    /// How much the download directory grew during the batch, in bytes.
+    pub bytes_downloaded: u64,
+}
+
+impl BatchReport {
+    /// Records the outcome of one download, and how many times it switched proxies after stalling.
+    pub fn record(&mut self, source: TorrentSource, result: Result<DownloadOutcome, DownloadError>, rotations: u32) {
+        if rotations > 0 {
+            self.rotations.push((source.clone(), rotations));
+        }
+        match result {
+            Ok(outcome) => self.succeeded.push((source, outcome)),
+            Err(e) => self.failed.push((source, e)),
+        }
+    }
+
+    /// The number of torrents in the batch, including the skipped ones.
+    pub fn total(&self) -> usize {
+        self.succeeded.len() + self.failed.len() + self.skipped.len() + self.low_seeders.len()
+    }
+
+    /// The number of torrents whose last attempt ran out of `--download-timeout`.
+    pub fn timed_out(&self) -> usize {
+        self.failed.iter().filter(|(_, e)| e.is_timeout()).count()
+    }
+
+    /// The distinct proxies that successful downloads went through, in the order they were first used.
+    pub fn proxies_used(&self) -> Vec<&Socks5Proxy> {
+        let mut proxies: Vec<&Socks5Proxy> = Vec::new();
+        for proxy in self.succeeded.iter().filter_map(|(_, outcome)| outcome.proxy.as_ref()) {
+            if !proxies.contains(&proxy) {
+                proxies.push(proxy);
+            }
+        }
+        proxies
+    }
+
+    /// The proxies that successful downloads went through, each with the average speed of every such download
+    /// whose downloader reported stats, in bytes per second.
+    pub fn proxy_speeds(&self) -> Vec<(&Socks5Proxy, Vec<f64>)> {
+        let mut speeds: Vec<(&Socks5Proxy, Vec<f64>)> = Vec::new();
+        for (proxy, outcome) in self.succeeded.iter().filter(|(_, outcome)| outcome.stats_available).filter_map(|(_, outcome)| Some((outcome.proxy.as_ref()?, outcome))) {
+            match speeds.iter_mut().find(|(listed, _)| *listed == proxy) {
+                Some((_, proxy_speeds)) => proxy_speeds.push(outcome.avg_speed),
+                None => speeds.push((proxy, vec![outcome.avg_speed])),
+            }
+        }
+        speeds
+    }
+
+    /// The average download speed of the successful downloads whose downloader reported stats, in bytes per second.
+    pub fn average_speed(&self) -> Option<f64> {
+        let (bytes, seconds) = self
+            .succeeded
+            .iter()
+            .filter(|(_, outcome)| outcome.stats_available)
+            .fold((0, 0.0), |(bytes, seconds), (_, outcome)| (bytes + outcome.bytes, seconds + outcome.duration.as_secs_f64()));
+        (seconds > 0.0).then(|| bytes as f64 / seconds)
+    }
+
+    /// The report as a single JSON object, as emitted in `--json` mode and written by `--report`.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "total": self.total(),
+            "succeeded": self.succeeded.len(),
+            "timed_out": self.timed_out(),
+            "proxies": self.proxies_used().iter().map(|proxy| proxy.to_string()).collect::<Vec<_>>(),
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "bytes_downloaded": self.bytes_downloaded,
+            "avg_speed_bps": self.average_speed().map(|speed| speed as u64),
+            "downloaded": self.succeeded.iter().map(|(source, outcome)| json!({
+                "magnet": source.to_string(),
+                "proxy": outcome.proxy.as_ref().map(|proxy| proxy.to_string()),
+                "bytes": outcome.bytes,
+                "avg_speed_bps": outcome.avg_speed as u64,
+                "duration_ms": outcome.duration.as_millis() as u64,
+                "complete": outcome.success,
+                "stats_available": outcome.stats_available,
+            })).collect::<Vec<_>>(),
+            "skipped": self.skipped.iter().map(|source| source.to_string()).collect::<Vec<_>>(),
+            "low_seeders": self.low_seeders.iter().map(|(source, seeders)| json!({"magnet": source.to_string(), "seeders": seeders})).collect::<Vec<_>>(),
+            "failed": self.failed.iter().map(|(source, e)| json!({"magnet": source.to_string(), "error": e.to_string()})).collect::<Vec<_>>(),
+            "rotations": self.rotations.iter().map(|(source, count)| json!({"magnet": source.to_string(), "count": count})).collect::<Vec<_>>(),
+            "recheck_switches": self.recheck_switches,
+        })
+    }
+
+    /// Emits the report as the `summary` event: a short recap in human-readable mode, or the full report in JSON mode.
+    pub fn print(&self) {
+        let mut message = format!(
+            "Downloaded {} of {} torrents ({}) in {}.",
+            self.succeeded.len(),
+            self.total(),
+            format_bytes(self.bytes_downloaded),
+            format_elapsed(self.elapsed),
+        );
+        if !self.skipped.is_empty() {
+            message.push_str(&format!(" Skipped {} already downloaded.", self.skipped.len()));
+        }
+        if !self.low_seeders.is_empty() {
+            message.push_str(&format!(" Skipped {} by --min-seeders.", self.low_seeders.len()));
+        }
+        if !self.failed.is_empty() {
+            message.push_str(&format!(" {} failed", self.failed.len()));
+            match self.timed_out() {
+                0 => message.push('.'),
+                timed_out => message.push_str(&format!(", {} of them timed out.", timed_out)),
+            }
+        }
+        if let Some(speed) = self.average_speed() {
+            message.push_str(&format!("\n  Average download speed: {}/s", format_bytes(speed as u64)));
+        }
+        let proxies = self.proxies_used();
+        if !proxies.is_empty() {
+            let proxies: Vec<String> = proxies.iter().map(|proxy| proxy.to_string()).collect();
+            message.push_str(&format!("\n  Proxies used: {}", proxies.join(", ")));
+        }
+        if !self.rotations.is_empty() {
+            let rotations: Vec<String> = self.rotations.iter().map(|(source, count)| format!("{} ({})", source, count)).collect();
+            message.push_str(&format!("\n  Proxy rotations after stalls: {}", rotations.join(", ")));
+        }
+        if self.recheck_switches > 0 {
+            message.push_str(&format!("\n  Proxy switches after failed re-checks: {}", self.recheck_switches));
+        }
+        for (source, seeders) in &self.low_seeders {
+            match seeders {
+                Some(seeders) => message.push_str(&format!("\n  Skipped by --min-seeders: {} ({} seeders)", source, seeders)),
+                None => message.push_str(&format!("\n  Skipped by --min-seeders: {} (seeders unknown)", source)),
+            }
+        }
+        for (source, e) in &self.failed {
+            message.push_str(&format!("\n  Failed: {}: {}", source, e));
+        }
+        emit(Level::Info, "summary", message, self.to_json());
+    }
+
+    /// Writes the report to `path` as a JSON document.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut contents = serde_json::to_string_pretty(&self.to_json()).map_err(io::Error::other)?;
+        contents.push('\n');
+        std::fs::write(path, contents)
+    }
+}
+
+/// Returns the total size of the files under `path`, in bytes. Symbolic links are not followed,
+/// and entries that cannot be read count as empty.
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else { return 0 };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    let Ok(entries) = std::fs::read_dir(path) else { return 0 };
+    entries.flatten().map(|entry| disk_usage(&entry.path())).sum()
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 GiB`.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration to the second, e.g. `1h 02m 03s` or `45s`.
+pub(crate) fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn magnet(hash: &str) -> TorrentSource {
+        TorrentSource::parse(&format!("magnet:?xt=urn:btih:{}", hash)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn sizes_and_durations_are_formatted() {
+        assert_eq!((format_bytes(0), format_bytes(1023), format_bytes(1024)), ("0 B".to_string(), "1023 B".to_string(), "1.0 KiB".to_string()));
+        assert_eq!((format_bytes(1536 * 1024), format_bytes(3 << 30)), ("1.5 MiB".to_string(), "3.0 GiB".to_string()));
+        assert_eq!(format_bytes((1 << 30) - 1), "1024.0 MiB");  // Rounded, but still below the next unit.
+        assert_eq!((format_elapsed(Duration::from_millis(59_999)), format_elapsed(Duration::from_secs(60))), ("59s".to_string(), "1m 00s".to_string()));
+        assert_eq!((format_elapsed(Duration::from_secs(3599)), format_elapsed(Duration::from_secs(3723))), ("59m 59s".to_string(), "1h 02m 03s".to_string()));
+    }
+
+    #[test]
+    fn json_report_counts_every_status() {
+        let outcome = DownloadOutcome { proxy: None, bytes: 2048, avg_speed: 1024.0, duration: Duration::from_secs(2), success: true, stats_available: true };
+        let mut report = BatchReport { elapsed: Duration::from_secs(5), bytes_downloaded: 2048, ..BatchReport::default() };
+        report.record(magnet("0123456789abcdef0123456789abcdef01234567"), Ok(outcome), 1);
+        report.record(magnet("1123456789abcdef0123456789abcdef01234567"), Err(DownloadError::TimedOut(Duration::from_secs(60))), 0);
+        report.record(magnet("2123456789abcdef0123456789abcdef01234567"), Err(DownloadError::Interrupted), 0);
+        report.skipped.push(magnet("3123456789abcdef0123456789abcdef01234567"));
+        report.low_seeders.push((magnet("4123456789abcdef0123456789abcdef01234567"), Some(0)));
+
+        let json = report.to_json();
+        assert_eq!((json["total"].as_u64(), json["succeeded"].as_u64(), json["timed_out"].as_u64()), (Some(5), Some(1), Some(1)));
+        let count = |key: &str| json[key].as_array().map(Vec::len);
+        assert_eq!((count("downloaded"), count("failed"), count("skipped"), count("low_seeders"), count("rotations")), (Some(1), Some(2), Some(1), Some(1), Some(1)));
+        assert_eq!((json["elapsed_ms"].as_u64(), json["avg_speed_bps"].as_u64()), (Some(5000), Some(1024)));
+    }
+}
@@ -0,0 +1,276 @@
+//! The `--min-seeders` guard, which asks the trackers of each magnet link how many seeders it has before it is downloaded.
+//!
+//! HTTP trackers are scraped as described in BEP 48, through the proxy the torrents are downloaded with, and UDP
+//! trackers with the protocol of BEP 15. A UDP scrape cannot go through the proxy, so UDP trackers are only
+//! asked when the torrents are downloaded without one.
+
+use crate::log::{emit, Level};
+use crate::magnet::{normalize_tracker, MagnetInfo};
+use crate::proxy::{error_chain, Socks5Proxy};
+use crate::size::Value;
+use crate::source::TorrentSource;
+use crate::BatchTorConfig;
+use clap::ValueEnum;
+use rand::Rng;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// How long a tracker may take to answer a scrape.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The magic number that starts a BEP 15 connect request.
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+/// What `--scrape-unknown` does with torrents whose number of seeders cannot be found out.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrapeUnknown {
+    /// Skip them.
+    Skip,
+    /// Download them anyway.
+    #[default]
+    Download,
+}
+
+/// Why a tracker could not be scraped.
+#[derive(Debug, Error)]
+pub enum ScrapeError {
+    /// The tracker does not support scraping, or cannot be reached the way the torrents are downloaded.
+    #[error("{0}")]
+    Unsupported(&'static str),
+    /// The HTTP request failed.
+    #[error("{}", error_chain(.0))]
+    Http(#[source] reqwest::Error),
+    /// The UDP exchange failed.
+    #[error("{0}")]
+    Io(#[source] io::Error),
+    /// The tracker did not answer within `SCRAPE_TIMEOUT`.
+    #[error("timed out")]
+    TimedOut,
+    /// The tracker sent an error or an answer that could not be understood.
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// The URL of the scrape convention of BEP 48 for an HTTP announce URL: the `announce` that starts its last path
+/// segment is replaced with `scrape`. Returns `None` if the tracker does not follow the convention.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let slash = announce.rfind('/')?;
+    let segment = &announce[slash + 1..];
+    segment.starts_with("announce").then(|| format!("{}scrape{}", &announce[..slash + 1], &segment["announce".len()..]))
+}
+
+/// Asks the trackers of a magnet link how many seeders it has, through `proxy` if given, and returns the
+/// highest count any of them reported. Returns `None` if none of them could be scraped.
+pub async fn scrape_seeders(info: &MagnetInfo, proxy: Option<&Socks5Proxy>) -> Option<u64> {
+    let hash = info_hash_bytes(&info.info_hash)?;
+    let mut builder = Client::builder().timeout(SCRAPE_TIMEOUT);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.url(true)).ok()?);
+    }
+    let client = builder.build().ok()?;
+
+    let mut trackers: Vec<String> = Vec::new();
+    for tracker in info.trackers.iter().filter_map(|tracker| normalize_tracker(tracker)) {
+        if !trackers.contains(&tracker) {
+            trackers.push(tracker);
+        }
+    }
+    let mut tasks = JoinSet::new();
+    for tracker in trackers {
+        let client = client.clone();
+        let direct = proxy.is_none();
+        tasks.spawn(async move {
+            let result = scrape_tracker(&client, &tracker, &hash, direct).await;
+            (tracker, result)
+        });
+    }
+    let name = info.display_name.as_deref().unwrap_or(&info.info_hash);
+    let mut seeders: Option<u64> = None;
+    while let Some(Ok((tracker, result))) = tasks.join_next().await {
+        match result {
+            Ok(count) => seeders = Some(seeders.map_or(count, |seeders| seeders.max(count))),
+            Err(e) => emit(
+                Level::Debug,
+                "scrape_error",
+                format!("[{}] Could not scrape {}: {}", name, tracker, e),
+                json!({"magnet": name, "tracker": tracker, "error": e.to_string()}),
+            ),
+        }
+    }
+    seeders
+}
+
+/// Scrapes one tracker for the torrent with the info hash `hash`. UDP trackers are only scraped if `direct`.
+async fn scrape_tracker(client: &Client, tracker: &str, hash: &[u8; 20], direct: bool) -> Result<u64, ScrapeError> {
+    if let Some(address) = tracker.strip_prefix("udp://") {
+        if !direct {
+            return Err(ScrapeError::Unsupported("UDP trackers cannot be scraped through a proxy"));
+        }
+        let address = address.split(['/', '?']).next().unwrap_or(address);
+        return tokio::time::timeout(SCRAPE_TIMEOUT, scrape_udp(address, hash)).await.map_err(|_| ScrapeError::TimedOut)?;
+    }
+    if !tracker.starts_with("http://") && !tracker.starts_with("https://") {
+        return Err(ScrapeError::Unsupported("only HTTP and UDP trackers can be scraped"));
+    }
+    let url = scrape_url(tracker).ok_or(ScrapeError::Unsupported("the tracker does not support scraping"))?;
+    scrape_http(client, &url, hash).await
+}
+
+/// Scrapes an HTTP tracker at its scrape `url`, as described in BEP 48.
+async fn scrape_http(client: &Client, url: &str, hash: &[u8; 20]) -> Result<u64, ScrapeError> {
+    let encoded: String = hash.iter().map(|byte| format!("%{:02X}", byte)).collect();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let response = client.get(format!("{}{}info_hash={}", url, separator, encoded)).send().await.map_err(ScrapeError::Http)?;
+    let response = response.error_for_status().map_err(ScrapeError::Http)?;
+    let body = response.bytes().await.map_err(ScrapeError::Http)?;
+    parse_scrape_response(&body, hash)
+}
+
+/// Reads the number of seeders (`complete`) of the torrent `hash` from a bencoded scrape response.
+pub fn parse_scrape_response(body: &[u8], hash: &[u8; 20]) -> Result<u64, ScrapeError> {
+    let (response, _) = Value::parse(body, 0).ok_or_else(|| ScrapeError::Invalid("the response is not bencoded".to_string()))?;
+    if let Some(Value::Bytes(reason)) = response.get(b"failure reason") {
+        return Err(ScrapeError::Invalid(String::from_utf8_lossy(reason).into_owned()));
+    }
+    match response.get(b"files").and_then(|files| files.get(hash)).and_then(|file| file.get(b"complete")) {
+        Some(Value::Int(complete)) => u64::try_from(*complete).map_err(|_| ScrapeError::Invalid("negative seeder count".to_string())),
+        _ => Err(ScrapeError::Invalid("the tracker does not know the torrent".to_string())),
+    }
+}
+
+/// Scrapes a UDP tracker at `address` (`host:port`) with the connect and scrape requests of BEP 15.
+async fn scrape_udp(address: &str, hash: &[u8; 20]) -> Result<u64, ScrapeError> {
+    let target = tokio::net::lookup_host(address).await.map_err(ScrapeError::Io)?.next().ok_or(ScrapeError::Unsupported("the tracker has no address"))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await.map_err(ScrapeError::Io)?;
+    socket.connect(target).await.map_err(ScrapeError::Io)?;
+
+    let transaction: u32 = rand::thread_rng().gen();
+    let mut request = Vec::with_capacity(36);
+    request.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+    request.extend_from_slice(&0u32.to_be_bytes());
+    request.extend_from_slice(&transaction.to_be_bytes());
+    let reply = udp_exchange(&socket, &request, 0, transaction, 16).await?;
+    let connection = &reply[8..16];
+
+    request.clear();
+    request.extend_from_slice(connection);
+    request.extend_from_slice(&2u32.to_be_bytes());
+    request.extend_from_slice(&transaction.to_be_bytes());
+    request.extend_from_slice(hash);
+    let reply = udp_exchange(&socket, &request, 2, transaction, 20).await?;
+    Ok(u64::from(u32::from_be_bytes([reply[8], reply[9], reply[10], reply[11]])))
+}
+
+/// Sends a BEP 15 request and returns the reply, checking that it answers `action` for `transaction` and is at
+/// least `min_len` bytes long.
+async fn udp_exchange(socket: &UdpSocket, request: &[u8], action: u32, transaction: u32, min_len: usize) -> Result<Vec<u8>, ScrapeError> {
+    socket.send(request).await.map_err(ScrapeError::Io)?;
+    let mut reply = vec![0; 1024];
+    let len = socket.recv(&mut reply).await.map_err(ScrapeError::Io)?;
+    reply.truncate(len);
+    if len < 8 || reply[4..8] != transaction.to_be_bytes() {
+        return Err(ScrapeError::Invalid("unexpected reply".to_string()));
+    }
+    if reply[..4] == 3u32.to_be_bytes() {
+        return Err(ScrapeError::Invalid(String::from_utf8_lossy(&reply[8..]).into_owned()));
+    }
+    if reply[..4] != action.to_be_bytes() || len < min_len {
+        return Err(ScrapeError::Invalid("unexpected reply".to_string()));
+    }
+    Ok(reply)
+}
+
+/// The 20 bytes of an info hash given as 40 hex characters.
+fn info_hash_bytes(hex: &str) -> Option<[u8; 20]> {
+    let mut bytes = [0; 20];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Applies `--min-seeders`: scrapes the trackers of the torrents, through `proxy` if given, and splits off those
+/// with fewer than `min` seeders, and those whose seeders cannot be found out if `--scrape-unknown skip`. `.torrent`
+/// files and URLs cannot be scraped, so they follow `--scrape-unknown` too. The torrents are returned in their
+/// order, and the skipped ones with the number of seeders found, if any.
+pub(crate) async fn filter_by_seeders(
+    config: &BatchTorConfig,
+    min: u64,
+    proxy: Option<&Socks5Proxy>,
+    torrents: Vec<TorrentSource>,
+) -> (Vec<TorrentSource>, Vec<(TorrentSource, Option<u64>)>) {
+    emit(
+        Level::Info,
+        "scrape_start",
+        format!("Asking the trackers how many seeders the {} torrents have.", torrents.len()),
+        json!({"torrents": torrents.len(), "min_seeders": min}),
+    );
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrency));
+    let mut tasks = JoinSet::new();
+    for (index, torrent) in torrents.iter().enumerate() {
+        let TorrentSource::Magnet(info) = torrent else { continue };
+        let (info, proxy, semaphore) = (info.clone(), proxy.cloned(), Arc::clone(&semaphore));
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("Scrape semaphore closed");
+            (index, scrape_seeders(&info, proxy.as_ref()).await)
+        });
+    }
+    let mut seeders = vec![None; torrents.len()];
+    while let Some(Ok((index, count))) = tasks.join_next().await {
+        seeders[index] = count;
+    }
+
+    let (mut kept, mut skipped) = (Vec::new(), Vec::new());
+    for (torrent, count) in torrents.into_iter().zip(seeders) {
+        let keep = match count {
+            Some(count) => {
+                emit(Level::Debug, "torrent_seeders", format!("[{}] {} seeders", torrent, count), json!({"magnet": torrent.to_string(), "seeders": count}));
+                count >= min
+            }
+            None => config.scrape_unknown == ScrapeUnknown::Download,
+        };
+        let message = match count {
+            Some(count) if count < min => Some(format!("[{}] Only {} seeders, fewer than --min-seeders ({}), skipping", torrent, count, min)),
+            Some(_) => None,
+            None if keep => Some(format!("[{}] Could not find out how many seeders it has; downloading it anyway", torrent)),
+            None => Some(format!("[{}] Could not find out how many seeders it has, skipping", torrent)),
+        };
+        if let Some(message) = message {
+            let event = if keep { "seeders_unknown" } else { "download_skipped" };
+            emit(Level::Info, event, message, json!({"magnet": torrent.to_string(), "seeders": count, "min_seeders": min}));
+        }
+        if keep {
+            kept.push(torrent);
+        } else {
+            skipped.push((torrent, count));
+        }
+    }
+    (kept, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_urls_and_responses_follow_bep_48() {
+        assert_eq!(scrape_url("http://t.example/announce").as_deref(), Some("http://t.example/scrape"));
+        assert_eq!(scrape_url("http://t.example/x/announce.php?key=1").as_deref(), Some("http://t.example/x/scrape.php?key=1"));
+        assert_eq!(scrape_url("http://t.example/a"), None);
+
+        let hash = [b'h'; 20];
+        let body = [&b"d5:filesd20:"[..], &hash, b"d8:completei7e10:downloadedi3e10:incompletei2eeee"].concat();
+        assert_eq!(parse_scrape_response(&body, &hash).unwrap(), 7);
+        assert!(matches!(parse_scrape_response(&body, &[b'x'; 20]), Err(ScrapeError::Invalid(_))));
+        assert!(matches!(parse_scrape_response(b"d14:failure reason6:no waye", &hash), Err(ScrapeError::Invalid(reason)) if reason == "no way"));
+    }
+}
@@ -0,0 +1,116 @@
+//! The `verify` subcommand (and the `--self-test` mode), which checks a new setup end to end by downloading one small,
+//! well-seeded torrent.
+
+use crate::log::{self, emit, Level, Verbosity};
+use crate::report::format_elapsed;
+use crate::{run_batch, BatchTorConfig, RunError, TorrentFilter};
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+/// The torrent downloaded by `--self-test` unless another one is given: Sintel, the 129 MiB open movie of the
+/// Blender Foundation, which WebTorrent keeps well seeded for exactly this kind of test.
+pub const SELF_TEST_MAGNET: &str = "magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10&dn=Sintel\
+    &tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce\
+    &tr=udp%3A%2F%2Ftracker.empire-js.us%3A1337&tr=wss%3A%2F%2Ftracker.webtorrent.dev\
+    &ws=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2F";
+
+/// Returns the configuration of the self-test: `config` with the proxy and downloader settings kept, but with
+/// `magnet_file` as the only input and `download_dir` as the download directory, and with every option that
+/// would skip, record, keep, or hand off the test torrent turned off. `dry_run` is kept, for `verify --leak-only`.
+fn self_test_config(config: &BatchTorConfig, magnet_file: &Path, download_dir: &Path) -> BatchTorConfig {
+    BatchTorConfig {
+        magnet_files: vec![magnet_file.display().to_string()],
+        filter: TorrentFilter::default(),
+        limit: None,
+        continue_on_missing_files: false,
+        download_dir: download_dir.display().to_string(),
+        create_dir: true,
+        subdir_per_torrent: false,
+        keep_incomplete: false,
+        file_filter: Vec::new(),
+        watch: false,
+        yes: true,
+        force: true,
+        journal: None,
+        reset_journal: false,
+        report: None,
+        on_complete: None,
+        max_total_size: None,
+        verbosity: config.verbosity.max(Verbosity::Verbose),
+        ..config.clone()
+    }
+}
+
+/// Runs `--self-test`: checks the proxies like a batch would, then downloads `magnet` into a temporary directory,
+/// with verbose output, and reports how long it took. With `dry_run`, the test stops before downloading. The temporary
+/// directory is removed afterwards, whatever the outcome.
+pub(crate) async fn run_self_test(config: &BatchTorConfig, magnet: &str) -> Result<(), RunError> {
+    let started = Instant::now();
+    let dir = std::env::temp_dir().join(format!("batch_tor-self-test-{}", std::process::id()));
+    let magnet_file = dir.join("magnet.txt");
+    let download_dir = dir.join("download");
+    if let Err(e) = write_magnet_file(&magnet_file, magnet) {
+        emit(Level::Error, "self_test_failed", format!("Self-test could not set up {}: {}", dir.display(), e), json!({"dir": dir, "error": e.to_string()}));
+        return Err(RunError::MagnetFile(e));
+    }
+    let started_message = if config.dry_run {
+        "Self-test: checking the proxies without downloading.".to_string()
+    } else {
+        format!("Self-test: checking the proxies and downloading a test torrent into {}.", download_dir.display())
+    };
+    emit(
+        Level::Info,
+        "self_test_started",
+        started_message,
+        json!({"magnet": magnet, "dir": download_dir}),
+    );
+
+    let test_config = self_test_config(config, &magnet_file, &download_dir);
+    log::set_verbosity(test_config.verbosity);
+    let result = run_batch(&test_config).await;
+    let elapsed = started.elapsed();
+    match &result {
+        Ok(()) => emit(
+            Level::Info,
+            "self_test_passed",
+            format!("Self-test passed in {}: {}.", format_elapsed(elapsed), if config.dry_run { "proxies work" } else { "proxies and downloads work" }),
+            json!({"elapsed_ms": elapsed.as_millis() as u64}),
+        ),
+        Err(e) => emit(
+            Level::Error,
+            "self_test_failed",
+            format!("Self-test failed after {}: {}", format_elapsed(elapsed), e),
+            json!({"elapsed_ms": elapsed.as_millis() as u64, "error": e.to_string(), "exit_code": e.exit_code()}),
+        ),
+    }
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        emit(Level::Warn, "self_test_cleanup_error", format!("Could not remove {}: {}", dir.display(), e), json!({"dir": dir, "error": e.to_string()}));
+    }
+    result
+}
+
+fn write_magnet_file(path: &Path, magnet: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, format!("{}\n", magnet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::TorrentSource;
+
+    #[test]
+    fn the_test_magnet_is_valid_and_nothing_is_skipped() {
+        let Ok(Some(TorrentSource::Magnet(info))) = TorrentSource::parse(SELF_TEST_MAGNET) else { panic!("invalid test magnet") };
+        assert_eq!((info.display_name.as_deref(), info.trackers.len(), info.web_seeds.len()), (Some("Sintel"), 4, 1));
+
+        let config = BatchTorConfig { journal: Some("journal.jsonl".to_string()), watch: true, ..Default::default() };
+        let test = self_test_config(&config, Path::new("/tmp/t/magnet.txt"), Path::new("/tmp/t/download"));
+        assert_eq!((test.magnet_files.as_slice(), test.download_dir.as_str()), (["/tmp/t/magnet.txt".to_string()].as_slice(), "/tmp/t/download"));
+        assert!(test.journal.is_none() && !test.watch && test.force && test.verbosity >= Verbosity::Verbose);
+    }
+}
@@ -0,0 +1,109 @@
+//! Graceful shutdown on Ctrl-C: stop starting downloads and let running `transmission-cli` processes exit cleanly.
+
+use crate::log::{emit, Level};
+use crate::metered;
+use serde_json::json;
+use std::collections::HashSet;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::Notify;
+
+/// Whether a shutdown has been requested. Once set, no new downloads or retries are started.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Wakes tasks waiting in `wait_for_shutdown` when a shutdown is requested.
+static SHUTDOWN_NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+/// The process IDs of the running `transmission-cli` children.
+static CHILDREN: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+/// Returns whether a shutdown has been requested.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Requests a graceful shutdown: no new downloads are started, and every running `transmission-cli`
+/// receives SIGTERM so it can save its state before exiting.
+pub fn request_shutdown() {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    emit(
+        Level::Warn,
+        "shutdown_requested",
+        "Interrupted; stopping downloads. Press Ctrl-C again to force quit.".to_string(),
+        json!({}),
+    );
+    SHUTDOWN_NOTIFY.get_or_init(Notify::new).notify_waiters();
+    signal_children(libc::SIGTERM);
+    if metered::paused() {
+        signal_children(libc::SIGCONT);  // A downloader stopped by --pause-on-metered only handles SIGTERM once continued.
+    }
+}
+
+/// Completes once a shutdown has been requested.
+pub(crate) async fn wait_for_shutdown() {
+    let mut notified = pin!(SHUTDOWN_NOTIFY.get_or_init(Notify::new).notified());
+    notified.as_mut().enable();  // Registers before checking the flag, so a request in between isn't missed.
+    if !shutdown_requested() {
+        notified.await;
+    }
+}
+
+/// Sends SIGKILL to every running `transmission-cli`, for when a graceful shutdown takes too long.
+pub fn force_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    signal_children(libc::SIGKILL);
+}
+
+/// Sends `signal` to every running downloader.
+pub(crate) fn signal_children(signal: libc::c_int) {
+    let children = CHILDREN.lock().expect("Child process registry poisoned");
+    for &pid in children.iter().flatten() {
+        send_signal(pid, signal);
+    }
+}
+
+/// Sends SIGTERM to a single child process, asking it to save its state and exit.
+pub(crate) fn terminate(pid: u32) {
+    send_signal(pid, libc::SIGTERM);
+}
+
+fn send_signal(pid: u32, signal: libc::c_int) {
+    // SAFETY: `kill` has no memory-safety preconditions; a stale PID at worst yields ESRCH.
+    unsafe {
+        libc::kill(pid as libc::pid_t, signal);
+    }
+}
+
+/// Keeps a child process registered for shutdown signals until it is dropped.
+#[derive(Debug)]
+pub(crate) struct TrackedChild {
+    pid: Option<u32>,
+}
+
+/// Registers a running child process so a shutdown request reaches it. If a shutdown was
+/// requested before the child was registered, it is sent SIGTERM right away, and if the downloads were
+/// paused by `--pause-on-metered` in the meantime, SIGSTOP.
+pub(crate) fn track_child(pid: Option<u32>) -> TrackedChild {
+    if let Some(pid) = pid {
+        CHILDREN.lock().expect("Child process registry poisoned").get_or_insert_with(HashSet::new).insert(pid);
+        if shutdown_requested() {
+            send_signal(pid, libc::SIGTERM);
+        } else if metered::paused() {
+            send_signal(pid, libc::SIGSTOP);
+        }
+    }
+    TrackedChild { pid }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            if let Some(children) = CHILDREN.lock().expect("Child process registry poisoned").as_mut() {
+                children.remove(&pid);
+            }
+        }
+    }
+}
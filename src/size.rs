@@ -0,0 +1,233 @@
+//! The `--size-preview` of how much disk space the torrents of a batch need, and the `--max-total-size` guard.
+
+use crate::backend::parse_size;
+use crate::files::{select_files, torrent_files, wanted_files};
+use crate::log::{emit, Level};
+use crate::report::format_bytes;
+use crate::source::TorrentSource;
+use glob::Pattern;
+use serde_json::json;
+
+/// How deeply bencoded lists and dictionaries may nest before a `.torrent` file is considered malformed.
+const MAX_NESTING: usize = 64;
+
+/// Parses a size given on the command line or in the config file, such as `50GB`, `1.5 TiB`, or a number of bytes.
+pub fn parse_byte_size(size: &str) -> Result<u64, String> {
+    match parse_size(size) {
+        Some(bytes) if bytes.is_finite() => Ok(bytes as u64),
+        _ => Err(format!("`{}` is not a size, expected e.g. 500MB, 50GB, or 1.5TiB", size)),
+    }
+}
+
+/// The size of the files of a torrent that match any of `wanted`, or of all its files without patterns, if it can be
+/// told without downloading anything: the `xl` parameter of a magnet link, or the file list of a local `.torrent`
+/// file. Magnets without `xl` and `.torrent` URLs have no known size, since finding it would mean fetching the
+/// metadata from peers or the web, and neither do magnets with patterns, since `xl` is the size of the whole torrent.
+pub fn torrent_size(source: &TorrentSource, wanted: &[Pattern]) -> Option<u64> {
+    match source {
+        TorrentSource::Magnet(info) => info.exact_length.filter(|_| wanted.is_empty()),
+        TorrentSource::File(path) => std::fs::read(path).ok().and_then(|data| selected_length(&data, wanted)),
+        TorrentSource::Url(_) => None,
+    }
+}
+
+/// What `estimate_sizes` found out about the size of a batch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    /// The combined size of the torrents whose size is known.
+    pub known_bytes: u64,
+    /// How many torrents have a known size.
+    pub known: usize,
+    /// How many torrents have no known size.
+    pub unknown: usize,
+}
+
+/// Adds up the sizes of the files to download within the torrents, those selected by `file_filter` or the `||want=`
+/// patterns of a magnet link, reporting the size of each torrent and the total if `show` is set.
+pub fn estimate_sizes(sources: &[TorrentSource], file_filter: &[Pattern], show: bool) -> SizeEstimate {
+    let mut estimate = SizeEstimate::default();
+    for source in sources {
+        let wanted = wanted_files(source, file_filter);
+        let size = torrent_size(source, wanted);
+        match size {
+            Some(bytes) => {
+                estimate.known_bytes = estimate.known_bytes.saturating_add(bytes);
+                estimate.known += 1;
+            }
+            None => estimate.unknown += 1,
+        }
+        if show {
+            emit(
+                Level::Info,
+                "torrent_size",
+                match size {
+                    Some(bytes) if wanted.is_empty() => format!("[{}] {}", source, format_bytes(bytes)),
+                    Some(bytes) => format!("[{}] {} in the selected files", source, format_bytes(bytes)),
+                    None if wanted.is_empty() => format!("[{}] size unknown", source),
+                    None => format!("[{}] size of the selected files unknown", source),
+                },
+                json!({"magnet": source.to_string(), "bytes": size}),
+            );
+        }
+    }
+    if show {
+        emit(
+            Level::Info,
+            "size_preview",
+            match estimate.unknown {
+                0 => format!("Estimated total: {} for {} torrents.", format_bytes(estimate.known_bytes), estimate.known),
+                unknown => format!(
+                    "Estimated total: at least {} for {} torrents; the size of {} more is unknown.",
+                    format_bytes(estimate.known_bytes),
+                    estimate.known,
+                    unknown,
+                ),
+            },
+            json!({"bytes": estimate.known_bytes, "known": estimate.known, "unknown": estimate.unknown}),
+        );
+    }
+    estimate
+}
+
+/// The total length of the files described by a `.torrent` file: the `length` of a single-file torrent,
+/// or the sum of the lengths in its `files` list, or in its BitTorrent v2 `file tree`.
+pub fn content_length(torrent: &[u8]) -> Option<u64> {
+    let (value, _) = Value::parse(torrent, 0)?;
+    let info = value.get(b"info")?;
+    if let Some(Value::Int(length)) = info.get(b"length") {
+        return u64::try_from(*length).ok();
+    }
+    if let Some(Value::List(files)) = info.get(b"files") {
+        return files.iter().map(|file| match file.get(b"length") {
+            Some(Value::Int(length)) => u64::try_from(*length).ok(),
+            _ => None,
+        }).sum();
+    }
+    info.get(b"file tree").and_then(Value::tree_length)
+}
+
+/// The total length of the files of a `.torrent` file that match any of `patterns`, or of all its files if there
+/// are none. `None` with patterns if the file list cannot be read, as for `torrent_files`.
+fn selected_length(torrent: &[u8], patterns: &[Pattern]) -> Option<u64> {
+    if patterns.is_empty() {
+        return content_length(torrent);
+    }
+    let (lengths, files) = (file_lengths(torrent)?, torrent_files(torrent)?);
+    select_files(&files, patterns).iter().map(|(index, _)| lengths.get(index - 1).copied()).sum()
+}
+
+/// The lengths of the files of a `.torrent` file, in the order of `torrent_files`.
+fn file_lengths(torrent: &[u8]) -> Option<Vec<u64>> {
+    let (value, _) = Value::parse(torrent, 0)?;
+    let info = value.get(b"info")?;
+    let length = |value: Option<&Value>| match value {
+        Some(Value::Int(length)) => u64::try_from(*length).ok(),
+        _ => None,
+    };
+    match info.get(b"files") {
+        Some(Value::List(files)) => files.iter().map(|file| length(file.get(b"length"))).collect(),
+        Some(_) => None,
+        None => length(info.get(b"length")).map(|length| vec![length]),
+    }
+}
+
+/// A bencoded value, borrowing its strings from the data it was parsed from.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Vec<(&'a [u8], Value<'a>)>),
+}
+
+impl<'a> Value<'a> {
+    /// Parses the value at the start of `data`, returning it with the rest of the data.
+    pub(crate) fn parse(data: &'a [u8], depth: usize) -> Option<(Value<'a>, &'a [u8])> {
+        if depth > MAX_NESTING {
+            return None;
+        }
+        match *data.first()? {
+            b'i' => {
+                let end = data.iter().position(|&byte| byte == b'e')?;
+                let number = std::str::from_utf8(&data[1..end]).ok()?.parse().ok()?;
+                Some((Value::Int(number), &data[end + 1..]))
+            }
+            b'l' => {
+                let (mut rest, mut items) = (&data[1..], Vec::new());
+                while *rest.first()? != b'e' {
+                    let (item, after) = Value::parse(rest, depth + 1)?;
+                    items.push(item);
+                    rest = after;
+                }
+                Some((Value::List(items), &rest[1..]))
+            }
+            b'd' => {
+                let (mut rest, mut entries) = (&data[1..], Vec::new());
+                while *rest.first()? != b'e' {
+                    let (Value::Bytes(key), after) = Value::parse(rest, depth + 1)? else { return None };
+                    let (value, after) = Value::parse(after, depth + 1)?;
+                    entries.push((key, value));
+                    rest = after;
+                }
+                Some((Value::Dict(entries), &rest[1..]))
+            }
+            b'0'..=b'9' => {
+                let colon = data.iter().position(|&byte| byte == b':')?;
+                let length: usize = std::str::from_utf8(&data[..colon]).ok()?.parse().ok()?;
+                let end = (colon + 1).checked_add(length).filter(|&end| end <= data.len())?;
+                Some((Value::Bytes(&data[colon + 1..end]), &data[end..]))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of `key` in a dictionary.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Value<'a>> {
+        match self {
+            Value::Dict(entries) => entries.iter().find(|(name, _)| *name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The sum of the lengths in a v2 `file tree`, where every file is a dictionary whose empty key holds its `length`.
+    fn tree_length(&self) -> Option<u64> {
+        let Value::Dict(entries) = self else { return None };
+        entries
+            .iter()
+            .map(|(name, node)| match (name.is_empty(), node.get(b"length")) {
+                (true, Some(Value::Int(length))) => u64::try_from(*length).ok(),
+                (true, _) => None,
+                (false, _) => node.tree_length(),
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths_come_from_the_info_dictionary() {
+        let single = b"d8:announce3:url4:infod6:lengthi1048576e4:name5:a.iso12:piece lengthi16384eee";
+        assert_eq!(content_length(single), Some(1048576));
+        let multi = b"d4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi23e4:pathl1:beee4:name1:deee";
+        assert_eq!(content_length(multi), Some(123));
+        let tree = b"d4:infod9:file treed1:ad0:d6:lengthi7eee1:bd1:cd0:d6:lengthi5eeeeee4:name1:tee";
+        assert_eq!(content_length(tree), Some(12));
+        assert_eq!(content_length(b"d4:infod4:name1:ae"), None);
+
+        let patterns = [Pattern::new("*.mkv").unwrap(), Pattern::new("*.srt").unwrap()];
+        let subtitled = b"d4:infod5:filesld6:lengthi100e4:pathl5:a.mkveed6:lengthi23e4:pathl5:b.nfoeed6:lengthi5e4:pathl5:c.srteee4:name1:deee";
+        assert_eq!((selected_length(subtitled, &patterns), selected_length(subtitled, &[])), (Some(105), Some(128)));
+        assert_eq!(selected_length(single, &patterns), Some(0));
+        assert_eq!(selected_length(tree, &patterns), None);
+    }
+
+    #[test]
+    fn sizes_accept_decimal_and_binary_units() {
+        assert_eq!(parse_byte_size("50GB"), Ok(50_000_000_000));
+        assert_eq!(parse_byte_size("1.5 KiB"), Ok(1536));
+        assert!(parse_byte_size("lots").is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! The torrents to download and how they are read from the input file.
+
+use crate::files::split_wanted_files;
+use crate::log::{emit, Level};
+use crate::magnet::{canonicalize_magnet, normalize_peer, validate_magnet, MagnetError, MagnetInfo};
+use crate::metalink::{parse_metalink, MetalinkFile};
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+
+/// A single torrent to download, as listed in the magnet links file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentSource {
+    /// A validated magnet link.
+    Magnet(MagnetInfo),
+    /// A path to a local `.torrent` file.
+    File(PathBuf),
+    /// An `http(s)://` URL pointing to a `.torrent` file.
+    Url(String),
+}
+
+impl TorrentSource {
+    /// Classifies a line from the input file, returning `Ok(None)` if it is not a recognized source
+    /// and an error if it looks like a magnet link but fails validation.
+    pub fn parse(line: &str) -> Result<Option<TorrentSource>, MagnetError> {
+        if line.starts_with("magnet:") {
+            validate_magnet(line).map(|info| Some(TorrentSource::Magnet(info)))
+        } else if (line.starts_with("http://") || line.starts_with("https://")) && line.ends_with(".torrent") {
+            Ok(Some(TorrentSource::Url(line.to_string())))
+        } else if line.ends_with(".torrent") {
+            Ok(Some(TorrentSource::File(PathBuf::from(line))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the argument that `transmission-cli` expects for this source; magnet links are passed
+    /// in the form returned by `canonicalize_magnet`.
+    pub fn as_transmission_arg(&self) -> OsString {
+        match self {
+            TorrentSource::Magnet(info) => canonicalize_magnet(info).into(),
+            TorrentSource::File(path) => path.clone().into_os_string(),
+            TorrentSource::Url(url) => url.into(),
+        }
+    }
+}
+
+/// Displays magnets by their display name (falling back to the info hash) and other sources by path or URL.
+impl fmt::Display for TorrentSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentSource::Magnet(info) => match &info.display_name {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", info.info_hash),
+            },
+            TorrentSource::File(path) => write!(f, "{}", path.display()),
+            TorrentSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Selects torrents by name with `--filter` and `--exclude`.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentFilter {
+    /// Only torrents whose name matches are kept.
+    pub include: Option<Regex>,
+    /// Torrents whose name matches are dropped.
+    pub exclude: Option<Regex>,
+}
+
+impl TorrentFilter {
+    /// Whether the filter selects nothing, so every torrent is kept.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    /// Whether the torrent is kept. Magnet links are matched by their display name, and `.torrent`
+    /// files and URLs by their path or URL. Magnet links without a display name always pass `include`,
+    /// and are only dropped if `exclude` matches their info hash.
+    pub fn keeps(&self, source: &TorrentSource) -> bool {
+        let (name, named) = match source {
+            TorrentSource::Magnet(info) => match &info.display_name {
+                Some(name) => (name.clone(), true),
+                None => (info.info_hash.clone(), false),
+            },
+            TorrentSource::File(path) => (path.display().to_string(), true),
+            TorrentSource::Url(url) => (url.clone(), true),
+        };
+        let included = !named || self.include.as_ref().is_none_or(|include| include.is_match(&name));
+        let excluded = self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(&name));
+        included && !excluded
+    }
+}
+
+/// The file name that stands for standard input, as in `--magnet-file -`.
+pub const STDIN_FILENAME: &str = "-";
+
+/// Opens an input list for buffered reading, treating `-` as standard input.
+pub(crate) fn open_input(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    if filename == STDIN_FILENAME {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(io::BufReader::new(File::open(filename)?)))
+    }
+}
+
+/// Expands the glob patterns among the magnet files, e.g. `lists/*.txt`, into the files they match, in sorted order.
+/// Names without glob characters, and `-`, are kept as they are, so a missing file is still reported when it is read.
+/// A pattern that matches nothing is skipped with a warning.
+pub fn expand_input_patterns(patterns: &[String]) -> Vec<String> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let is_glob = pattern != STDIN_FILENAME && pattern.contains(['*', '?', '[']);
+        let Some(paths) = is_glob.then(|| glob::glob(pattern).ok()).flatten() else {
+            files.push(pattern.clone());
+            continue;
+        };
+        let mut matched: Vec<String> = paths.flatten().filter(|path| path.is_file()).map(|path| path.display().to_string()).collect();
+        if matched.is_empty() {
+            emit(Level::Warn, "input_no_match", format!("No files match {}", pattern), json!({"pattern": pattern}));
+        }
+        matched.sort();
+        files.extend(matched);
+    }
+    files
+}
+
+/// The priority of torrents listed without a `P<n>` prefix: the lowest.
+pub const DEFAULT_PRIORITY: u32 = u32::MAX;
+
+/// Splits the `P<n>` priority prefix off a line, e.g. `P1 magnet:?...`, returning the priority and the rest of the line.
+/// Lower numbers come first. Lines without a prefix have `DEFAULT_PRIORITY`.
+pub fn split_priority(line: &str) -> (u32, &str) {
+    let Some((prefix, rest)) = line.split_once(char::is_whitespace) else { return (DEFAULT_PRIORITY, line) };
+    match prefix.strip_prefix(['P', 'p']).and_then(|number| number.parse().ok()) {
+        Some(priority) => (priority, rest.trim_start()),
+        None => (DEFAULT_PRIORITY, line),
+    }
+}
+
+/// The torrent sources read from one or more input files, deduplicated across all of them.
+#[derive(Debug, Default)]
+pub struct SourceList {
+    sources: Vec<TorrentSource>,
+    priorities: Vec<u32>,  // The priority of each source in `sources`.
+    magnet_indices: HashMap<String, usize>,  // Maps info hashes to their position in `sources`.
+}
+
+impl SourceList {
+    /// Returns an empty list.
+    pub fn new() -> SourceList {
+        SourceList::default()
+    }
+
+    /// Reads the given file and adds the torrent sources it lists.
+    /// Each line may be a magnet link starting with "magnet:", a path to a local `.torrent` file,
+    /// or an `http(s)://` URL ending in `.torrent`. Surrounding whitespace, CRLF line endings, and a leading
+    /// byte order mark are stripped; blank lines, comments starting with `#`, and any other lines are ignored.
+    /// A line may start with a priority such as `P1 `; see `split_priority`. A magnet link may end with the
+    /// patterns of the files to download, such as ` ||want=*.mkv,*.srt`; see `split_wanted_files`.
+    /// Lines that are not valid UTF-8 are skipped with a warning instead of aborting the whole read.
+    /// Malformed magnet links and `.torrent` paths that do not exist are logged and dropped before being queued.
+    /// Sources already in the list, from this file or a previous one, are skipped; for magnet links sharing
+    /// an info hash the first occurrence is kept and the trackers of later duplicates are merged into it.
+    /// A duplicate with a higher priority raises the priority of the source it duplicates.
+    /// A `.meta4` file is read as a Metalink instead; see `read_metalink`. Files with any other extension,
+    /// such as `.magnet` or `.txt`, are read line by line as above.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The path to the file containing magnet links, or `-` to read them from standard input.
+    ///
+    /// # Returns
+    ///
+    /// The number of new sources the file contributed, or the I/O error that prevented reading it.
+    pub fn read_file(&mut self, filename: &str) -> Result<usize, io::Error> {
+        let before = self.sources.len();
+        let duplicates = if is_metalink(filename) { self.read_metalink(filename)? } else { self.read_lines(filename)? };
+        if duplicates > 0 {
+            emit(
+                Level::Info,
+                "duplicates_skipped",
+                format!("Skipped {} duplicate torrents in {}", duplicates, filename),
+                json!({"file": filename, "count": duplicates}),
+            );
+        }
+        Ok(self.sources.len() - before)
+    }
+
+    /// Reads a list with one source per line, as described in `read_file`, returning the number of duplicates.
+    fn read_lines(&mut self, filename: &str) -> Result<usize, io::Error> {
+        let reader = open_input(filename)?;
+        let mut duplicates = 0;
+        for (index, bytes) in reader.split(b'\n').enumerate() {
+            let mut bytes = bytes?;
+            if bytes.last() == Some(&b'\r') {
+                bytes.pop();  // Mirrors `BufRead::lines`, which strips the `\r` of CRLF endings.
+            }
+            let line = match String::from_utf8(bytes) {
+                Ok(line) => line,
+                Err(_) => {
+                    emit(
+                        Level::Warn,
+                        "input_skipped",
+                        format!("Skipping line {} of {}: not valid UTF-8", index + 1, filename),
+                        json!({"file": filename, "line": index + 1, "reason": "invalid_utf8"}),
+                    );
+                    continue;
+                }
+            };
+            // Files written on other platforms may start with a byte order mark or indent their lines.
+            let line = if index == 0 { line.strip_prefix('\u{feff}').unwrap_or(&line) } else { &line };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (priority, line) = split_priority(line);
+            let (line, wanted_files) = match split_wanted_files(line) {
+                Ok(split) => split,
+                Err(e) => {
+                    emit(
+                        Level::Warn,
+                        "input_skipped",
+                        format!("Skipping line {} of {}: {}", index + 1, filename, e),
+                        json!({"file": filename, "line": index + 1, "reason": "invalid_file_pattern", "error": e}),
+                    );
+                    continue;
+                }
+            };
+            if !wanted_files.is_empty() && !line.starts_with("magnet:") {
+                emit(
+                    Level::Warn,
+                    "file_patterns_ignored",
+                    format!("Ignoring the ||want= patterns on line {} of {}: they only apply to magnet links", index + 1, filename),
+                    json!({"file": filename, "line": index + 1}),
+                );
+            }
+            match TorrentSource::parse(line) {
+                Ok(Some(TorrentSource::File(path))) if !path.is_file() => {
+                    emit(
+                        Level::Warn,
+                        "input_skipped",
+                        format!("Skipping line {} of {}: torrent file {} does not exist", index + 1, filename, path.display()),
+                        json!({"file": filename, "line": index + 1, "reason": "missing_torrent_file", "path": path}),
+                    );
+                }
+                Ok(Some(TorrentSource::Magnet(mut info))) => {
+                    info.wanted_files = wanted_files;
+                    warn_malformed_peers(&info, filename, index + 1);
+                    duplicates += usize::from(self.add(TorrentSource::Magnet(info), priority));
+                }
+                Ok(Some(source)) => duplicates += usize::from(self.add(source, priority)),
+                Ok(None) => {}
+                Err(e) => emit(
+                    Level::Warn,
+                    "input_skipped",
+                    format!("Skipping line {} of {}: invalid magnet link: {}", index + 1, filename, e),
+                    json!({"file": filename, "line": index + 1, "reason": "invalid_magnet", "error": e.to_string()}),
+                ),
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Reads a Metalink 4 list, returning the number of duplicates. Each `<file>` gives one torrent: the first of its
+    /// magnet links and `.torrent` URLs that is valid, with `<metaurl>` elements tried before `<url>` elements. The
+    /// `<size>` and `name` of the file fill in the exact length and display name of a magnet link that lacks them.
+    /// A file without a valid torrent is skipped with a warning naming its position in the list.
+    fn read_metalink(&mut self, filename: &str) -> Result<usize, io::Error> {
+        let mut document = String::new();
+        open_input(filename)?.read_to_string(&mut document)?;
+        let mut duplicates = 0;
+        for (index, file) in parse_metalink(&document).into_iter().enumerate() {
+            let label = file.name.clone().unwrap_or_else(|| format!("file {}", index + 1));
+            match metalink_source(&file) {
+                Ok(source) => {
+                    if let TorrentSource::Magnet(info) = &source {
+                        warn_malformed_peers(info, filename, index + 1);
+                    }
+                    duplicates += usize::from(self.add(source, DEFAULT_PRIORITY));
+                }
+                Err(reason) => emit(
+                    Level::Warn,
+                    "input_skipped",
+                    format!("Skipping {} in {}: {}", label, filename, reason),
+                    json!({"file": filename, "entry": index + 1, "name": file.name, "reason": "invalid_metalink_entry", "error": reason}),
+                ),
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Adds `source` with `priority`, returning whether it was already listed: a magnet link with the same info hash,
+    /// whose trackers it merges into the one listed, or the same `.torrent` path or URL. A duplicate raises the
+    /// priority of the source it duplicates.
+    fn add(&mut self, source: TorrentSource, priority: u32) -> bool {
+        let existing = match &source {
+            TorrentSource::Magnet(info) => self.magnet_indices.get(&info.info_hash).copied(),
+            source => self.sources.iter().position(|listed| listed == source),
+        };
+        match existing {
+            Some(existing) => {
+                if let (TorrentSource::Magnet(first), TorrentSource::Magnet(info)) = (&mut self.sources[existing], &source) {
+                    first.merge_trackers(info);
+                }
+                self.raise_priority(existing, priority);
+                true
+            }
+            None => {
+                if let TorrentSource::Magnet(info) = &source {
+                    self.magnet_indices.insert(info.info_hash.clone(), self.sources.len());
+                }
+                self.push(source, priority);
+                false
+            }
+        }
+    }
+
+    /// Appends `source`, which is not listed yet, with `priority`.
+    fn push(&mut self, source: TorrentSource, priority: u32) {
+        self.sources.push(source);
+        self.priorities.push(priority);
+    }
+
+    /// Raises the priority of the source at `index` to `priority`, if that comes first.
+    fn raise_priority(&mut self, index: usize, priority: u32) {
+        self.priorities[index] = self.priorities[index].min(priority);
+    }
+
+    /// Returns the sources read so far, by priority and, within a priority, in the order they were first listed.
+    pub fn into_sources(self) -> Vec<TorrentSource> {
+        let mut sources: Vec<(u32, TorrentSource)> = self.priorities.into_iter().zip(self.sources).collect();
+        sources.sort_by_key(|(priority, _)| *priority);  // A stable sort, so the order of the list breaks ties.
+        sources.into_iter().map(|(_, source)| source).collect()
+    }
+}
+
+/// Whether `filename` is a Metalink 4 list, by its `.meta4` extension.
+fn is_metalink(filename: &str) -> bool {
+    Path::new(filename).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("meta4"))
+}
+
+/// Returns the torrent of a Metalink `<file>`, as described in `SourceList::read_metalink`, or why it has none.
+fn metalink_source(file: &MetalinkFile) -> Result<TorrentSource, String> {
+    let mut errors = Vec::new();
+    for link in &file.links {
+        if link.starts_with("magnet:") {
+            match validate_magnet(link) {
+                Ok(mut info) => {
+                    info.exact_length = info.exact_length.or(file.size);
+                    info.display_name = info.display_name.or_else(|| file.name.clone());
+                    return Ok(TorrentSource::Magnet(info));
+                }
+                Err(e) => errors.push(format!("invalid magnet link: {}", e)),
+            }
+        } else if link.starts_with("http://") || link.starts_with("https://") {
+            return Ok(TorrentSource::Url(link.clone()));  // A `<metaurl mediatype="torrent">` need not end in `.torrent`.
+        } else {
+            errors.push(format!("{} is not an http(s):// URL", link));
+        }
+    }
+    if errors.is_empty() {
+        Err("no magnet link or .torrent URL".to_string())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+/// Warns about the `x.pe` peer addresses of a magnet link, on `line` of `filename`, that are not well-formed;
+/// they are left out of the link passed to the downloader.
+fn warn_malformed_peers(info: &MagnetInfo, filename: &str, line: usize) {
+    for peer in info.peers.iter().filter(|peer| normalize_peer(peer).is_none()) {
+        emit(
+            Level::Warn,
+            "peer_dropped",
+            format!("Dropping malformed peer address {} from line {} of {}: expected host:port", peer, line, filename),
+            json!({"file": filename, "line": line, "peer": peer}),
+        );
+    }
+}
+
+/// Reads the given file and extracts torrent sources, as described in `SourceList::read_file`.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the file containing magnet links, or `-` to read them from standard input.
+///
+/// # Returns
+///
+/// A vector of torrent sources extracted from the file, or the I/O error that prevented reading it.
+pub fn extract_magnet_links(filename: &str) -> Result<Vec<TorrentSource>, io::Error> {
+    let mut list = SourceList::new();
+    list.read_file(filename)?;
+    Ok(list.into_sources())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=a";
+    const B: &str = "magnet:?xt=urn:btih:1123456789abcdef0123456789abcdef01234567&dn=b";
+    const C: &str = "magnet:?xt=urn:btih:2123456789abcdef0123456789abcdef01234567&dn=c";
+
+    /// Creates an empty scratch directory for one test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("batch_tor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_list(dir: &Path, name: &str, lines: &[&str]) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn sources_are_deduplicated_across_files() {
+        let dir = scratch_dir("dedup");
+        let first = write_list(&dir, "first.txt", &[A, B]);
+        let second = write_list(&dir, "second.txt", &[B, C, A]);
+
+        let mut list = SourceList::new();
+        assert_eq!(list.read_file(&first).unwrap(), 2);
+        assert_eq!(list.read_file(&second).unwrap(), 1);
+        let names: Vec<String> = list.into_sources().iter().map(|source| source.to_string()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn lines_are_trimmed_and_comments_skipped() {
+        let dir = scratch_dir("trim");
+        let bom_and_crlf = format!("\u{feff}{}\r", A);
+        let indented = format!("  {}\t", B);
+        let commented = format!("# {}", C);
+        let file = write_list(&dir, "list.txt", &[&bom_and_crlf, "", &indented, &commented]);
+
+        let sources = extract_magnet_links(&file).unwrap();
+        let links: Vec<OsString> = sources.iter().map(TorrentSource::as_transmission_arg).collect();
+        assert_eq!(links, [A, B]);
+    }
+
+    #[test]
+    fn prioritized_sources_come_first() {
+        let dir = scratch_dir("priority");
+        let (a, b, c) = (format!("P2 {}", A), format!("p1\t{}", B), format!("P3 {}", C));
+        let file = write_list(&dir, "list.txt", &["magnet:?xt=urn:btih:3123456789abcdef0123456789abcdef01234567&dn=d", &a, &c, &b, &format!("P1 {}", C)]);
+
+        let names: Vec<String> = extract_magnet_links(&file).unwrap().iter().map(|source| source.to_string()).collect();
+        assert_eq!(names, ["c", "b", "a", "d"]);  // c is raised to P1 by its duplicate, and listed before b.
+        assert_eq!(split_priority("Pirates.torrent"), (DEFAULT_PRIORITY, "Pirates.torrent"));
+        assert_eq!(split_priority("P12 x.torrent"), (12, "x.torrent"));
+    }
+
+    #[test]
+    fn metalink_lists_are_read_by_extension() {
+        let dir = scratch_dir("metalink");
+        let line_based = write_list(&dir, "list.magnet", &[A]);
+        let metalink = write_list(&dir, "list.meta4", &[
+            r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink">"#,
+            r#"<file name="Named"><size>42</size><url>magnet:?xt=urn:btih:2123456789abcdef0123456789abcdef01234567</url></file>"#,
+            r#"<file name="a"><url>magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&amp;tr=udp%3A%2F%2Ft.example%3A80</url></file>"#,
+            r#"<file name="broken"><url>magnet:?xt=urn:btih:nothex</url></file>"#,
+            r#"<file name="b.iso"><metaurl mediatype="torrent">https://example.com/get?id=b</metaurl></file>"#,
+            "</metalink>",
+        ]);
+
+        let mut list = SourceList::new();
+        assert_eq!((list.read_file(&line_based).unwrap(), list.read_file(&metalink).unwrap()), (1, 2));
+        let sources = list.into_sources();
+        let TorrentSource::Magnet(a) = &sources[0] else { panic!("not a magnet") };
+        assert_eq!(a.trackers.len(), 1);  // Merged from its duplicate in the Metalink.
+        let TorrentSource::Magnet(named) = &sources[1] else { panic!("not a magnet") };
+        assert_eq!((named.display_name.as_deref(), named.exact_length), (Some("Named"), Some(42)));
+        assert_eq!(sources[2], TorrentSource::Url("https://example.com/get?id=b".to_string()));
+    }
+
+    #[test]
+    fn filters_match_display_names() {
+        let filter = TorrentFilter { include: Some(Regex::new("(?i)1080p").unwrap()), exclude: Some(Regex::new("CAM|^2123").unwrap()) };
+        let keeps = |line: &str| filter.keeps(&TorrentSource::parse(line).unwrap().unwrap());
+        assert!(keeps("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=Movie.1080P"));
+        assert!(!keeps("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=Movie.720p"));
+        assert!(!keeps("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=Movie.1080p.CAM"));
+        // Links without a display name pass the filter, unless excluded by info hash.
+        assert!(keeps("magnet:?xt=urn:btih:1123456789abcdef0123456789abcdef01234567"));
+        assert!(!keeps("magnet:?xt=urn:btih:2123456789abcdef0123456789abcdef01234567"));
+    }
+
+    #[test]
+    fn glob_patterns_expand_to_sorted_files() {
+        let dir = scratch_dir("glob");
+        let second = write_list(&dir, "b.txt", &[B]);
+        let first = write_list(&dir, "a.txt", &[A]);
+        write_list(&dir, "notes.md", &[C]);
+
+        let pattern = format!("{}/*.txt", dir.display());
+        let files = expand_input_patterns(&[pattern, "-".to_string(), "missing.txt".to_string()]);
+        assert_eq!(files, [first, second, "-".to_string(), "missing.txt".to_string()]);
+    }
+}
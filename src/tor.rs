@@ -0,0 +1,191 @@
+//! Using Tor as the proxy: connecting to a running tor or launching one, and rotating circuits.
+
+use crate::log::{emit, Level};
+use crate::proxy::{ProxyKind, Socks5Proxy};
+use serde_json::json;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+
+/// The host tor listens on.
+pub const TOR_HOST: &str = "127.0.0.1";
+
+/// The port of tor's SOCKS proxy.
+pub const TOR_SOCKS_PORT: u16 = 9050;
+
+/// The port of tor's control interface, used to request new circuits.
+pub const TOR_CONTROL_PORT: u16 = 9051;
+
+/// How long a launched tor may take to connect to the Tor network.
+pub const TOR_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The reasons Tor could not be set up or controlled.
+#[derive(Debug, Error)]
+pub enum TorError {
+    /// No tor is running and the `tor` binary is not installed.
+    #[error("tor is not running on {TOR_HOST}:{TOR_CONTROL_PORT} and is not installed; install the `tor` package (e.g. `apt install tor`)")]
+    NotInstalled,
+    /// The `tor` process could not be started.
+    #[error("failed to start tor: {0}")]
+    Spawn(#[source] io::Error),
+    /// The launched tor exited or timed out before connecting to the Tor network; carries its last output line.
+    #[error("tor failed to bootstrap: {0}")]
+    Bootstrap(String),
+    /// Talking to the control port failed.
+    #[error("tor control port error: {0}")]
+    Control(#[source] io::Error),
+    /// The control port rejected a command; carries its reply.
+    #[error("tor control port rejected the command: {0}")]
+    Rejected(String),
+}
+
+/// A tor instance used as the download proxy, either already running or launched by `batch_tor`.
+/// A launched tor is stopped when this is dropped.
+#[derive(Debug)]
+pub struct Tor {
+    /// The launched tor process, if tor was not already running; held so that dropping `Tor` stops it.
+    _child: Option<Child>,
+}
+
+impl Tor {
+    /// Connects to a tor already listening on the control port, or launches one if there is none.
+    pub async fn start() -> Result<Tor, TorError> {
+        if TcpStream::connect((TOR_HOST, TOR_CONTROL_PORT)).await.is_ok() {
+            emit(
+                Level::Info,
+                "tor_connected",
+                format!("Using the tor already running on {}:{}", TOR_HOST, TOR_CONTROL_PORT),
+                json!({"control_port": TOR_CONTROL_PORT, "socks_port": TOR_SOCKS_PORT}),
+            );
+            return Ok(Tor { _child: None });
+        }
+
+        let data_dir = std::env::temp_dir().join(format!("batch_tor-tor-{}", std::process::id()));
+        let mut child = Command::new("tor")
+            .arg("--SocksPort").arg(TOR_SOCKS_PORT.to_string())
+            .arg("--ControlPort").arg(TOR_CONTROL_PORT.to_string())
+            .arg("--CookieAuthentication").arg("1")  // The control port is authenticated with a cookie file in the data directory.
+            .arg("--DataDirectory").arg(&data_dir)
+            .arg("--Log").arg("notice stdout")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)  // Stops the launched tor when the batch ends.
+            .spawn()
+            .map_err(|e| if e.kind() == io::ErrorKind::NotFound { TorError::NotInstalled } else { TorError::Spawn(e) })?;
+        emit(
+            Level::Info,
+            "tor_launch",
+            format!("Launched tor (data directory {}); waiting for it to connect to the Tor network", data_dir.display()),
+            json!({"data_dir": data_dir, "control_port": TOR_CONTROL_PORT, "socks_port": TOR_SOCKS_PORT}),
+        );
+
+        let stdout = child.stdout.take().expect("Failed to capture tor stdout");
+        let mut lines = BufReader::new(stdout).lines();
+        let bootstrap = async {
+            let mut last = String::from("tor exited");
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.contains("Bootstrapped 100%") {
+                    return Ok(());
+                }
+                last = line;
+            }
+            Err(TorError::Bootstrap(last))
+        };
+        match tokio::time::timeout(TOR_BOOTSTRAP_TIMEOUT, bootstrap).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(TorError::Bootstrap(format!("not connected after {} s", TOR_BOOTSTRAP_TIMEOUT.as_secs()))),
+        }
+        // Keeps draining tor's output so it never blocks on a full pipe.
+        tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+        emit(Level::Info, "tor_ready", "tor is connected to the Tor network".to_string(), json!({}));
+        Ok(Tor { _child: Some(child) })
+    }
+
+    /// The SOCKS5 proxy that routes traffic through tor.
+    pub fn proxy(&self) -> Socks5Proxy {
+        Socks5Proxy { kind: ProxyKind::Socks5, host: TOR_HOST.to_string(), port: TOR_SOCKS_PORT, auth: None, chain: Vec::new() }
+    }
+
+    /// Asks tor to build new circuits for new connections (the NEWNYM signal), so the next torrent
+    /// gets a different exit node.
+    pub async fn new_identity(&self) -> Result<(), TorError> {
+        let stream = TcpStream::connect((TOR_HOST, TOR_CONTROL_PORT)).await.map_err(TorError::Control)?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        // PROTOCOLINFO tells which authentication the control port expects and where the cookie file is.
+        let info = command(&mut reader, &mut writer, "PROTOCOLINFO 1").await?;
+        let auth = match cookie_file(&info) {
+            Some(path) if info.contains("COOKIE") => {
+                let cookie = tokio::fs::read(&path).await.map_err(TorError::Control)?;
+                format!("AUTHENTICATE {}", cookie.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+            }
+            _ => "AUTHENTICATE".to_string(),
+        };
+        command(&mut reader, &mut writer, &auth).await?;
+        command(&mut reader, &mut writer, "SIGNAL NEWNYM").await?;
+        Ok(())
+    }
+}
+
+/// Sends one control port command and returns its reply, or `TorError::Rejected` unless the reply is `250`.
+async fn command(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    command: &str,
+) -> Result<String, TorError> {
+    writer.write_all(format!("{}\r\n", command).as_bytes()).await.map_err(TorError::Control)?;
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.map_err(TorError::Control)? == 0 {
+            return Err(TorError::Control(io::ErrorKind::UnexpectedEof.into()));
+        }
+        reply.push_str(&line);
+        // The last line of a reply has a space after the status code; `250-` and `250+` lines continue it.
+        if line.as_bytes().get(3) == Some(&b' ') {
+            break;
+        }
+    }
+    if reply.starts_with("250") {
+        Ok(reply)
+    } else {
+        Err(TorError::Rejected(reply.trim_end().to_string()))
+    }
+}
+
+/// Extracts the cookie file path from a PROTOCOLINFO reply such as `250-AUTH METHODS=COOKIE COOKIEFILE="/path"`.
+fn cookie_file(info: &str) -> Option<PathBuf> {
+    let (_, rest) = info.split_once("COOKIEFILE=\"")?;
+    let mut path = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(PathBuf::from(path)),
+            '\\' => path.push(chars.next()?),
+            c => path.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_file_paths_are_unquoted() {
+        let info = "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/run/tor/control.authcookie\"\r\n250 OK\r\n";
+        assert_eq!(cookie_file(info), Some(PathBuf::from("/run/tor/control.authcookie")));
+        let info = r#"250-AUTH METHODS=COOKIE COOKIEFILE="C:\\Tor\\my \"data\"\\cookie""#;
+        assert_eq!(cookie_file(info), Some(PathBuf::from(r#"C:\Tor\my "data"\cookie"#)));
+        assert_eq!(cookie_file("250-AUTH METHODS=NULL\r\n250 OK\r\n"), None);
+    }
+}
@@ -0,0 +1,73 @@
+//! The `--extra-trackers` list of trackers added to every magnet link.
+
+use crate::log::{emit, Level};
+use crate::magnet::normalize_tracker;
+use crate::source::{open_input, TorrentSource};
+use serde_json::json;
+use std::io::{self, BufRead};
+
+/// The URL schemes an extra tracker may use. WebTorrent trackers (`ws`, `wss`) are accepted in magnet links, but
+/// the downloaders cannot announce to them, so adding them to every torrent would not help.
+const EXTRA_TRACKER_SCHEMES: [&str; 3] = ["http", "https", "udp"];
+
+/// Reads a list of tracker URLs, one per line, normalized by `normalize_tracker`. Blank lines and comments
+/// starting with `#` are ignored; invalid URLs and URLs with another scheme than `udp`, `http` and `https` are
+/// skipped with a warning, and duplicates are listed once.
+pub fn read_tracker_list(filename: &str) -> io::Result<Vec<String>> {
+    let reader = open_input(filename)?;
+
+    let mut trackers: Vec<String> = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tracker = normalize_tracker(line).filter(|tracker| EXTRA_TRACKER_SCHEMES.iter().any(|scheme| tracker.starts_with(&format!("{}://", scheme))));
+        match tracker {
+            Some(tracker) if !trackers.contains(&tracker) => trackers.push(tracker),
+            Some(_) => {}
+            None => emit(
+                Level::Warn,
+                "input_skipped",
+                format!("Skipping line {} of {}: not a udp://, http:// or https:// tracker URL", index + 1, filename),
+                json!({"file": filename, "line": index + 1, "reason": "invalid_tracker"}),
+            ),
+        }
+    }
+    Ok(trackers)
+}
+
+/// Adds the `trackers` to every magnet link among `sources`, skipping those a link already lists. `.torrent`
+/// files and URLs are left as they are.
+pub fn add_trackers(sources: &mut [TorrentSource], trackers: &[String]) {
+    if trackers.is_empty() {
+        return;
+    }
+    for source in sources {
+        if let TorrentSource::Magnet(info) = source {
+            info.add_trackers(trackers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_new_supported_trackers_are_added() {
+        let path = std::env::temp_dir().join(format!("batch_tor-trackers-{}.txt", std::process::id()));
+        std::fs::write(&path, "# extra\nudp://Tracker.example:1337/announce\nwss://web.example\nftp://old.example\nhttps://b.example/announce\nudp://tracker.example:1337/announce\n").unwrap();
+        let trackers = read_tracker_list(&path.display().to_string());
+        let _ = std::fs::remove_file(&path);
+        let trackers = trackers.unwrap();
+        assert_eq!(trackers, ["udp://tracker.example:1337/announce", "https://b.example/announce"]);
+
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&tr=udp%3A%2F%2FTRACKER.example%3A1337%2Fannounce";
+        let mut sources = vec![TorrentSource::parse(magnet).unwrap().unwrap(), TorrentSource::File("a.torrent".into())];
+        add_trackers(&mut sources, &trackers);
+        let TorrentSource::Magnet(info) = &sources[0] else { panic!("not a magnet") };
+        assert_eq!(info.trackers, ["udp://TRACKER.example:1337/announce", "https://b.example/announce"]);
+    }
+}
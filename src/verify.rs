@@ -0,0 +1,275 @@
+//! The `check` subcommand (and the `--verify-proxies-only` mode), which checks every proxy of the list and writes a ranked report
+//! instead of downloading anything.
+
+use crate::log::{self, emit, Level};
+use crate::metrics;
+use crate::proxy::{check_proxy_detailed, format_throughput, CheckError, CheckOptions, FailureKind, ProxyResult, Socks5Proxy};
+use crate::shutdown::shutdown_requested;
+use crate::{load_proxies, proxy_check_bar, BatchTorConfig, RunError};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// How many of the ranked proxies the summary lists; the report file lists them all.
+const SHOWN_PROXIES: usize = 10;
+
+/// The outcome of checking one proxy.
+#[derive(Debug, Clone)]
+pub struct ProxyVerdict {
+    pub proxy: Socks5Proxy,
+    /// The result of the check, or why the proxy failed it.
+    pub result: Result<ProxyResult, CheckError>,
+}
+
+/// One row of the report, in the same shape for CSV and JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportRow {
+    /// The position of the proxy in the ranking, for the proxies that passed.
+    pub rank: Option<usize>,
+    pub host: String,
+    pub port: u16,
+    pub scheme: &'static str,
+    /// The HTTP status of the last check URL, for the proxies that passed.
+    pub status: Option<u16>,
+    pub latency_ms: Option<u64>,
+    /// The speed of the `--warmup-bytes` download, if one was run.
+    pub throughput_bps: Option<u64>,
+    pub error: Option<String>,
+    /// The kind of failure, e.g. `timeout` or `connect`; see `FailureKind`.
+    pub failure: Option<&'static str>,
+}
+
+/// Orders verdicts for the report: the proxies that passed from the lowest latency to the highest, then those
+/// that failed, which keep their order.
+fn rank(a: &ProxyVerdict, b: &ProxyVerdict) -> Ordering {
+    match (&a.result, &b.result) {
+        (Ok(a), Ok(b)) => a.latency.cmp(&b.latency),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => Ordering::Equal,
+    }
+}
+
+/// Checks every proxy, with at most `max_concurrency` checks at a time, and returns the verdicts ranked.
+/// The length of `bar` grows by the number of proxies, and it advances as each check finishes.
+/// Once a shutdown is requested, the proxies not checked yet fail with "interrupted".
+pub async fn verify_proxies(proxies: Vec<Socks5Proxy>, bar: Arc<ProgressBar>, max_concurrency: usize, options: CheckOptions) -> Vec<ProxyVerdict> {
+    bar.inc_length(proxies.len() as u64);
+    metrics::proxies_queued(proxies.len());
+    let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+    let options = Arc::new(options);
+    let tally = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+
+    let tasks: Vec<_> = proxies
+        .into_iter()
+        .map(|proxy| {
+            let (semaphore, options, bar, tally) = (Arc::clone(&semaphore), Arc::clone(&options), Arc::clone(&bar), Arc::clone(&tally));
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await.expect("Check semaphore closed");
+                if shutdown_requested() {
+                    return ProxyVerdict { proxy, result: Err(CheckError { kind: FailureKind::Other, message: "interrupted".to_string() }) };
+                }
+                let result = check_proxy_detailed(proxy.clone(), &options).await;
+                metrics::proxy_checked(result.is_ok());
+                let counter = if result.is_ok() { &tally.0 } else { &tally.1 };
+                counter.fetch_add(1, atomic::Ordering::Relaxed);
+                bar.set_message(format!("{} passed, {} failed", tally.0.load(atomic::Ordering::Relaxed), tally.1.load(atomic::Ordering::Relaxed)));
+                bar.inc(1);
+                ProxyVerdict { proxy, result }
+            })
+        })
+        .collect();
+
+    let mut verdicts = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        verdicts.push(task.await.expect("Proxy check task panicked"));
+    }
+    verdicts.sort_by(rank);
+    verdicts
+}
+
+/// Turns ranked verdicts into the rows of the report.
+pub fn report_rows(verdicts: &[ProxyVerdict]) -> Vec<ReportRow> {
+    let mut passed = 0;
+    verdicts
+        .iter()
+        .map(|verdict| {
+            let row = ReportRow {
+                rank: None,
+                host: verdict.proxy.host.clone(),
+                port: verdict.proxy.port,
+                scheme: verdict.proxy.kind.scheme(false),
+                status: None,
+                latency_ms: None,
+                throughput_bps: None,
+                error: None,
+                failure: None,
+            };
+            match &verdict.result {
+                Ok(result) => {
+                    passed += 1;
+                    ReportRow {
+                        rank: Some(passed),
+                        status: Some(result.status.as_u16()),
+                        latency_ms: Some(result.latency.as_millis() as u64),
+                        throughput_bps: result.throughput.map(|speed| speed as u64),
+                        ..row
+                    }
+                }
+                Err(e) => ReportRow { error: Some(e.message.clone()), failure: Some(e.kind.name()), ..row },
+            }
+        })
+        .collect()
+}
+
+/// Writes the report to `path`: as CSV if its name ends in `.csv`, and as a JSON array otherwise.
+pub fn write_report(rows: &[ReportRow], path: &Path) -> io::Result<()> {
+    let csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let file = File::create(path)?;
+    if csv {
+        let mut writer = csv::Writer::from_writer(file);
+        for row in rows {
+            writer.serialize(row).map_err(io::Error::other)?;
+        }
+        writer.flush()
+    } else {
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, rows)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+/// A proxy ranked in a report written by `--verify-proxies-only`, as read back by `read_proxy_order`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RankedProxy {
+    pub rank: Option<usize>,
+    pub host: String,
+    pub port: u16,
+    pub scheme: String,
+}
+
+impl RankedProxy {
+    fn matches(&self, proxy: &Socks5Proxy) -> bool {
+        self.port == proxy.port && self.host.eq_ignore_ascii_case(&proxy.host) && self.scheme == proxy.kind.scheme(false)
+    }
+}
+
+/// Reads the proxies ranked in a report written by `--verify-proxies-only` with `--report`, from the fastest to the
+/// slowest: as CSV if the name of `path` ends in `.csv`, and as JSON otherwise. The proxies that failed are left out.
+pub fn read_proxy_order(path: &Path) -> io::Result<Vec<RankedProxy>> {
+    let csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let rows: Vec<RankedProxy> = if csv {
+        csv::Reader::from_path(path).map_err(io::Error::other)?.deserialize().collect::<Result<_, _>>().map_err(io::Error::other)?
+    } else {
+        serde_json::from_reader(io::BufReader::new(File::open(path)?))?
+    };
+    let mut ranked: Vec<RankedProxy> = rows.into_iter().filter(|row| row.rank.is_some()).collect();
+    ranked.sort_by_key(|row| row.rank);
+    Ok(ranked)
+}
+
+/// Moves the proxies of `ranking` to the front of `proxies`, in the order of the ranking, for `--proxy-order-file`.
+/// The other proxies follow in their order. Returns how many entries of the ranking are no longer in the list.
+pub fn order_proxies(proxies: &mut Vec<Socks5Proxy>, ranking: &[RankedProxy]) -> usize {
+    let mut rest = std::mem::take(proxies);
+    let mut missing = 0;
+    for ranked in ranking {
+        match rest.iter().position(|proxy| ranked.matches(proxy)) {
+            Some(index) => proxies.push(rest.remove(index)),
+            None => missing += 1,
+        }
+    }
+    proxies.extend(rest);
+    missing
+}
+
+/// Runs `--verify-proxies-only`: reads and filters the proxy list like a batch would, checks every proxy,
+/// prints the ranking, and with `--report` writes it to a file. Fails with `NoValidProxies` if none passed.
+pub(crate) async fn run_verify(config: &BatchTorConfig) -> Result<(), RunError> {
+    let (source, proxies, _) = load_proxies(config).await?;
+    if proxies.is_empty() {
+        emit(Level::Error, "no_proxies", "No proxies found.".to_string(), json!({"file": source.to_string()}));
+        return Err(RunError::NoValidProxies);
+    }
+
+    let bar = proxy_check_bar(config);
+    let verdicts = verify_proxies(proxies, Arc::clone(&bar), config.max_concurrency, config.check_options()).await;
+    bar.finish();
+    log::set_progress(None);
+    if shutdown_requested() {
+        return Err(RunError::Interrupted);
+    }
+
+    let rows = report_rows(&verdicts);
+    let passed: Vec<_> = verdicts.iter().filter_map(|verdict| verdict.result.as_ref().ok()).collect();
+    let mut message = format!("{} of {} proxies passed the check.", passed.len(), verdicts.len());
+    for (rank, result) in passed.iter().take(SHOWN_PROXIES).enumerate() {
+        let speed = result.throughput.map(|speed| format!(", {}", format_throughput(speed))).unwrap_or_default();
+        message.push_str(&format!("\n  {}. {} ({}, {} ms{})", rank + 1, result.proxy, result.status, result.latency.as_millis(), speed));
+    }
+    if passed.len() > SHOWN_PROXIES {
+        message.push_str(&format!("\n  ... and {} more", passed.len() - SHOWN_PROXIES));
+    }
+    let bt_blocked = verdicts.iter().filter(|verdict| verdict.result.as_ref().is_err_and(|e| e.kind == FailureKind::BtBlocked)).count();
+    if bt_blocked > 0 {
+        message.push_str(&format!("\nOf the proxies that failed, {} passed the HTTP check but block BitTorrent connections (bt_blocked).", bt_blocked));
+    }
+    emit(Level::Info, "proxy_report", message, json!({"checked": verdicts.len(), "passed": passed.len(), "bt_blocked": bt_blocked, "proxies": rows}));
+
+    if let Some(path) = &config.report {
+        if let Err(e) = write_report(&rows, Path::new(path)) {
+            emit(Level::Warn, "report_error", format!("Could not write report {}: {}", path, e), json!({"file": path, "error": e.to_string()}));
+        }
+    }
+    if passed.is_empty() {
+        Err(RunError::NoValidProxies)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    fn verdict(line: &str, latency_ms: Option<u64>) -> ProxyVerdict {
+        let proxy = Socks5Proxy::parse(line).unwrap();
+        let result = match latency_ms {
+            Some(ms) => Ok(ProxyResult { proxy: proxy.clone(), status: StatusCode::OK, latency: Duration::from_millis(ms), throughput: None, download_speed: None }),
+            None => Err(CheckError { kind: FailureKind::Connect, message: "connection refused".to_string() }),
+        };
+        ProxyVerdict { proxy, result }
+    }
+
+    #[test]
+    fn passing_proxies_are_ranked_by_latency_before_failures() {
+        let mut verdicts = vec![verdict("10.0.0.1:1080", None), verdict("10.0.0.2:1080", Some(300)), verdict("10.0.0.3:1080", Some(40)), verdict("10.0.0.4:1080", None)];
+        verdicts.sort_by(rank);
+        let rows = report_rows(&verdicts);
+        let order: Vec<_> = rows.iter().map(|row| (row.host.as_str(), row.rank)).collect();
+        assert_eq!(order, [("10.0.0.3", Some(1)), ("10.0.0.2", Some(2)), ("10.0.0.1", None), ("10.0.0.4", None)]);
+        assert_eq!(rows[0].latency_ms, Some(40));
+        assert_eq!(rows[2].error.as_deref(), Some("connection refused"));
+
+        let path = std::env::temp_dir().join(format!("batch_tor-ranking-{}.csv", std::process::id()));
+        let written = write_report(&rows, &path);
+        let ranking = read_proxy_order(&path);
+        let _ = std::fs::remove_file(&path);
+        written.unwrap();
+        let ranking = ranking.unwrap();
+        assert_eq!(ranking.iter().map(|ranked| ranked.host.as_str()).collect::<Vec<_>>(), ["10.0.0.3", "10.0.0.2"]);
+        let mut proxies: Vec<Socks5Proxy> = ["10.0.0.1:1080", "10.0.0.2:1080", "10.0.0.5:1080"].iter().map(|line| Socks5Proxy::parse(line).unwrap()).collect();
+        assert_eq!(order_proxies(&mut proxies, &ranking), 1);
+        assert_eq!(proxies.iter().map(|proxy| proxy.host.as_str()).collect::<Vec<_>>(), ["10.0.0.2", "10.0.0.1", "10.0.0.5"]);
+    }
+}
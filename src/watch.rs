@@ -0,0 +1,159 @@
+//! The `--watch` mode, which keeps downloading the torrents added to the magnet files until Ctrl-C.
+
+use crate::journal::journal_key;
+use crate::log::{emit, Level};
+use crate::shutdown::wait_for_shutdown;
+use crate::source::{expand_input_patterns, SourceList, TorrentFilter, TorrentSource};
+use crate::trackers::add_trackers;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::json;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// How long the files must stay unchanged before they are read again, so that a file being written in
+/// several steps is read once it is complete.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Watches the magnet files and reports the torrents added to them.
+///
+/// The directories holding the files are watched rather than the files themselves, since editors often save a file
+/// by replacing it, which would end a watch on the file. A glob pattern's directory is watched too, so that files
+/// matching it that are created later are read as well.
+pub struct MagnetWatcher {
+    _watcher: RecommendedWatcher,
+    changes: UnboundedReceiver<()>,
+    patterns: Vec<String>,
+    filter: TorrentFilter,
+    /// The `--extra-trackers` added to the magnet links that are found.
+    trackers: Vec<String>,
+    /// The keys, as given by `journal_key`, of every torrent listed so far.
+    seen: HashSet<String>,
+}
+
+impl MagnetWatcher {
+    /// Starts watching the files matching `patterns`. The torrents in `seen` are already known, and are not
+    /// reported again; those that `filter` rejects are never reported. The `trackers` are added to the magnet links reported.
+    pub fn start(patterns: &[String], filter: TorrentFilter, trackers: Vec<String>, seen: HashSet<String>) -> notify::Result<MagnetWatcher> {
+        let mut names: HashSet<OsString> = HashSet::new();
+        let mut dirs: HashSet<PathBuf> = HashSet::new();
+        for file in expand_input_patterns(patterns) {
+            let (dir, name) = split(Path::new(&file));
+            dirs.insert(dir);
+            names.extend(name);
+        }
+        for pattern in patterns.iter().filter(|pattern| pattern.contains(['*', '?', '['])) {
+            let (dir, _) = split(Path::new(pattern));
+            if !dir.to_string_lossy().contains(['*', '?', '[']) {
+                dirs.insert(dir);
+            }
+        }
+        let globs: Vec<glob::Pattern> = patterns.iter().filter_map(|pattern| glob::Pattern::new(pattern).ok()).collect();
+
+        let (sender, changes) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else { return };
+            let relevant = event.paths.iter().any(|path| {
+                path.file_name().is_some_and(|name| names.contains(name)) || globs.iter().any(|glob| glob.matches_path(path) || matches_relative(glob, path))
+            });
+            if relevant {
+                let _ = sender.send(());
+            }
+        })?;
+        for dir in &dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        Ok(MagnetWatcher { _watcher: watcher, changes, patterns: patterns.to_vec(), filter, trackers, seen })
+    }
+
+    /// Waits until the magnet files change, then returns the torrents that were added to them. Returns `None`
+    /// once a shutdown is requested.
+    pub async fn next_torrents(&mut self) -> Option<Vec<TorrentSource>> {
+        loop {
+            tokio::select! {
+                change = self.changes.recv() => change?,
+                _ = wait_for_shutdown() => return None,
+            }
+            // Lets a burst of events settle before reading the files.
+            loop {
+                tokio::select! {
+                    change = self.changes.recv() => change?,
+                    _ = tokio::time::sleep(SETTLE_TIME) => break,
+                    _ = wait_for_shutdown() => return None,
+                }
+            }
+            let added = self.read_added();
+            if !added.is_empty() {
+                return Some(added);
+            }
+        }
+    }
+
+    /// Reads the magnet files again and returns the torrents that were not listed before.
+    fn read_added(&mut self) -> Vec<TorrentSource> {
+        let mut sources = SourceList::new();
+        for file in expand_input_patterns(&self.patterns) {
+            // A file can briefly be missing while it is replaced; it is read on its next change.
+            if let Err(e) = sources.read_file(&file) {
+                emit(Level::Warn, "input_error", format!("Could not read magnet file {}: {}", file, e), json!({"file": file, "error": e.to_string()}));
+            }
+        }
+        let mut added: Vec<TorrentSource> = sources
+            .into_sources()
+            .into_iter()
+            .filter(|source| self.seen.insert(journal_key(source)) && self.filter.keeps(source))
+            .collect();
+        add_trackers(&mut added, &self.trackers);
+        if !added.is_empty() {
+            emit(
+                Level::Info,
+                "watch_added",
+                format!("Found {} new torrents in the magnet files.", added.len()),
+                json!({"count": added.len(), "files": self.patterns}),
+            );
+        }
+        added
+    }
+}
+
+/// Splits a path into the directory that holds it, `.` for a bare file name, and its file name.
+fn split(path: &Path) -> (PathBuf, Option<OsString>) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    (dir, path.file_name().map(OsString::from))
+}
+
+/// Whether a glob pattern given relative to the current directory matches an absolute `path` reported by the watcher.
+fn matches_relative(glob: &glob::Pattern, path: &Path) -> bool {
+    std::env::current_dir().ok().and_then(|dir| path.strip_prefix(dir).ok().map(|relative| glob.matches_path(relative))).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn only_new_torrents_that_pass_the_filter_are_added() {
+        let dir = std::env::temp_dir().join(format!("batch_tor-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("links.txt");
+        let magnet = |digit: char, name: &str| format!("magnet:?xt=urn:btih:{}&dn={}\n", digit.to_string().repeat(40), name);
+        std::fs::write(&file, magnet('1', "first")).unwrap();
+
+        let patterns = vec![file.display().to_string()];
+        let seen = HashSet::from(["1".repeat(40)]);
+        let filter = TorrentFilter { include: None, exclude: Some(Regex::new("sample").unwrap()) };
+        let mut watcher = MagnetWatcher::start(&patterns, filter, Vec::new(), seen).unwrap();
+        std::fs::write(&file, [magnet('1', "first"), magnet('2', "second"), magnet('3', "sample")].concat()).unwrap();
+        let added: Vec<_> = watcher.read_added().iter().map(|source| source.to_string()).collect();
+        let again = watcher.read_added();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(added, ["second"]);
+        assert!(again.is_empty());
+    }
+}